@@ -0,0 +1,166 @@
+#[macro_use]
+extern crate criterion;
+extern crate mqtt3;
+
+use std::io::{BufReader, Cursor};
+use std::sync::Arc;
+use criterion::{Criterion, BenchmarkId, Throughput};
+use mqtt3::{MqttRead, MqttWrite, Packet, Publish, Subscribe, SubscribeTopic, Connect};
+use mqtt3::{Protocol, QoS, PacketIdentifier, TopicPath};
+
+const PAYLOAD_SIZES: [usize; 3] = [16, 1024, 64 * 1024];
+
+fn publish(size: usize) -> Packet {
+    Packet::Publish(Box::new(Publish {
+        dup: false,
+        qos: QoS::AtLeastOnce,
+        retain: false,
+        topic_name: "sensors/kitchen/temperature".to_owned(),
+        pid: Some(PacketIdentifier(10)),
+        payload: Arc::new(vec![0x55; size])
+    }))
+}
+
+fn packets() -> Vec<(&'static str, Packet)> {
+    vec![
+        ("connect", Packet::Connect(Box::new(Connect {
+            protocol: Protocol::MQTT(4),
+            keep_alive: 30,
+            client_id: "mqttc_bench".to_owned(),
+            clean_session: true,
+            last_will: None,
+            username: Some("user".to_owned()),
+            password: Some("password".to_owned())
+        }))),
+        ("puback", Packet::Puback(PacketIdentifier(10))),
+        ("subscribe", Packet::Subscribe(Box::new(Subscribe {
+            pid: PacketIdentifier(11),
+            topics: vec![
+                SubscribeTopic { topic_path: "sensors/+/temperature".to_owned(), qos: QoS::AtLeastOnce },
+                SubscribeTopic { topic_path: "alerts/#".to_owned(), qos: QoS::ExactlyOnce }
+            ]
+        }))),
+        ("pingreq", Packet::Pingreq)
+    ]
+}
+
+fn encode(packet: &Packet) -> Vec<u8> {
+    let mut stream = Cursor::new(Vec::new());
+    stream.write_packet(packet).unwrap();
+    stream.into_inner()
+}
+
+fn decode_benchmark(c: &mut Criterion) {
+    {
+        let mut group = c.benchmark_group("decode/publish");
+        for size in PAYLOAD_SIZES.iter() {
+            let data = encode(&publish(*size));
+            group.throughput(Throughput::Bytes(data.len() as u64));
+            group.bench_with_input(BenchmarkId::from_parameter(size), &data, |b, data| {
+                b.iter(|| Cursor::new(data.clone()).read_packet().unwrap())
+            });
+        }
+        group.finish();
+    }
+    for (name, packet) in packets() {
+        let data = encode(&packet);
+        c.bench_function(&format!("decode/{}", name), move |b| {
+            b.iter(|| Cursor::new(data.clone()).read_packet().unwrap())
+        });
+    }
+    {
+        // the acks of a publishing client arrive back to back
+        let mut data = Vec::new();
+        for pid in 0..64 {
+            data.extend(encode(&Packet::Puback(PacketIdentifier(pid))));
+        }
+        c.bench_function("decode/buffered_acks", move |b| {
+            b.iter(|| {
+                let mut stream = BufReader::new(Cursor::new(data.clone()));
+                for _ in 0..64 {
+                    stream.read_packet().unwrap();
+                }
+            })
+        });
+    }
+}
+
+fn encode_benchmark(c: &mut Criterion) {
+    {
+        let mut group = c.benchmark_group("encode/publish");
+        for size in PAYLOAD_SIZES.iter() {
+            let packet = publish(*size);
+            group.throughput(Throughput::Bytes(packet.size() as u64));
+            group.bench_with_input(BenchmarkId::from_parameter(size), &packet, |b, packet| {
+                let mut stream = Cursor::new(Vec::with_capacity(packet.size()));
+                b.iter(|| {
+                    stream.set_position(0);
+                    stream.write_packet(packet).unwrap()
+                })
+            });
+        }
+        group.finish();
+    }
+    for (name, packet) in packets() {
+        c.bench_function(&format!("encode/{}", name), move |b| {
+            let mut stream = Cursor::new(Vec::new());
+            b.iter(|| {
+                stream.set_position(0);
+                stream.write_packet(&packet).unwrap()
+            })
+        });
+    }
+}
+
+fn remaining_length_benchmark(c: &mut Criterion) {
+    // One to four bytes long encodings
+    let lengths = vec![0x7F, 0x3FFF, 0x1FFFFF, 0xFFFFFFF];
+    let encoded: Vec<Vec<u8>> = lengths.iter().map(|len| {
+        let mut stream = Cursor::new(Vec::new());
+        stream.write_remaining_length(*len).unwrap();
+        stream.into_inner()
+    }).collect();
+
+    c.bench_function("remaining_length/write", move |b| {
+        let mut stream = Cursor::new(Vec::with_capacity(16));
+        b.iter(|| {
+            stream.set_position(0);
+            for len in lengths.iter() {
+                stream.write_remaining_length(*len).unwrap();
+            }
+        })
+    });
+    c.bench_function("remaining_length/read", move |b| {
+        b.iter(|| {
+            for data in encoded.iter() {
+                Cursor::new(data.clone()).read_remaining_length().unwrap();
+            }
+        })
+    });
+}
+
+fn topic_benchmark(c: &mut Criterion) {
+    c.bench_function("topic/parse", |b| {
+        b.iter(|| TopicPath::from_str("sensors/kitchen/temperature/celsius").unwrap())
+    });
+
+    let name = TopicPath::from_str("sensors/kitchen/temperature/celsius").unwrap();
+    let filters: Vec<TopicPath> = vec![
+        "sensors/kitchen/temperature/celsius",
+        "sensors/+/temperature/+",
+        "sensors/#",
+        "#",
+        "sensors/bedroom/temperature/celsius",
+        "alerts/+"
+    ].into_iter().map(|filter| TopicPath::from_str(filter).unwrap()).collect();
+    c.bench_function("topic/match", move |b| {
+        b.iter(|| filters.iter().filter(|filter| name.matches(filter)).count())
+    });
+}
+
+criterion_group!(benches,
+                 decode_benchmark,
+                 encode_benchmark,
+                 remaining_length_benchmark,
+                 topic_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,155 @@
+//! `Display` of packets for logs. `{}` is a summary on one line, `{:#}` has
+//! every field on its own line and a hex dump of the payload, showing as
+//! many bytes as the precision (`{:#.64}`) or `DEFAULT_PREVIEW_LEN`.
+
+use std::cmp;
+use std::fmt;
+use mqtt::{Packet, SubscribeReturnCodes};
+
+/// Payload bytes `{:#}` shows without a precision
+pub const DEFAULT_PREVIEW_LEN: usize = 16;
+
+const BYTES_PER_LINE: usize = 16;
+
+// Name and value of the fields worth showing
+fn fields(packet: &Packet) -> Vec<(&'static str, String)> {
+    let mut fields = Vec::new();
+    match *packet {
+        Packet::Connect(ref connect) => {
+            fields.push(("client_id", format!("{:?}", connect.client_id)));
+            fields.push(("protocol", format!("{} {}", connect.protocol.name(), connect.protocol.level())));
+            fields.push(("keep_alive", connect.keep_alive.to_string()));
+            fields.push(("clean_session", connect.clean_session.to_string()));
+            if let Some(ref will) = connect.last_will {
+                fields.push(("will", format!("{} qos={}{}", will.topic, will.qos.to_u8(),
+                                             if will.retain { " retain" } else { "" })));
+            }
+            if let Some(ref username) = connect.username {
+                fields.push(("username", format!("{:?}", username)));
+            }
+            if connect.password.is_some() {
+                fields.push(("password", "***".to_string()));
+            }
+        }
+        Packet::Connack(ref connack) => {
+            fields.push(("code", format!("{:?}", connack.code)));
+            fields.push(("session_present", connack.session_present.to_string()));
+        }
+        Packet::Publish(ref publish) => {
+            fields.push(("topic", publish.topic_name.clone()));
+            fields.push(("qos", publish.qos.to_u8().to_string()));
+            if let Some(pid) = publish.pid {
+                fields.push(("pid", pid.0.to_string()));
+            }
+            if publish.dup {
+                fields.push(("dup", "true".to_string()));
+            }
+            if publish.retain {
+                fields.push(("retain", "true".to_string()));
+            }
+            fields.push(("payload", format!("{} bytes", publish.payload.len())));
+        }
+        Packet::Puback(pid) | Packet::Pubrec(pid) | Packet::Pubrel(pid) |
+        Packet::Pubcomp(pid) | Packet::Unsuback(pid) => {
+            fields.push(("pid", pid.0.to_string()));
+        }
+        Packet::Subscribe(ref subscribe) => {
+            fields.push(("pid", subscribe.pid.0.to_string()));
+            let topics: Vec<String> = subscribe.topics.iter()
+                .map(|topic| format!("{} qos={}", topic.topic_path, topic.qos.to_u8()))
+                .collect();
+            fields.push(("topics", topics.join(", ")));
+        }
+        Packet::Suback(ref suback) => {
+            fields.push(("pid", suback.pid.0.to_string()));
+            let codes: Vec<String> = suback.return_codes.iter().map(|code| match *code {
+                SubscribeReturnCodes::Success(qos) => format!("qos={}", qos.to_u8()),
+                SubscribeReturnCodes::Failure => "failure".to_string()
+            }).collect();
+            fields.push(("return_codes", codes.join(", ")));
+        }
+        Packet::Unsubscribe(ref unsubscribe) => {
+            fields.push(("pid", unsubscribe.pid.0.to_string()));
+            fields.push(("topics", unsubscribe.topics.join(", ")));
+        }
+        Packet::Pingreq | Packet::Pingresp | Packet::Disconnect => ()
+    }
+    fields
+}
+
+// Offset, bytes and their ASCII, `BYTES_PER_LINE` to a line
+fn hex_dump(f: &mut fmt::Formatter, payload: &[u8], limit: usize) -> fmt::Result {
+    let shown = &payload[..cmp::min(limit, payload.len())];
+    for (i, line) in shown.chunks(BYTES_PER_LINE).enumerate() {
+        try!(write!(f, "\n    {:04x} ", i * BYTES_PER_LINE));
+        for byte in line {
+            try!(write!(f, " {:02x}", byte));
+        }
+        for _ in line.len()..BYTES_PER_LINE {
+            try!(f.write_str("   "));
+        }
+        try!(f.write_str("  "));
+        for &byte in line {
+            let c = if byte >= 0x20 && byte < 0x7F { byte as char } else { '.' };
+            try!(write!(f, "{}", c));
+        }
+    }
+    if shown.len() < payload.len() {
+        try!(write!(f, "\n    ... {} more bytes", payload.len() - shown.len()));
+    }
+    Ok(())
+}
+
+impl fmt::Display for Packet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{}", self.packet_type()));
+        if !f.alternate() {
+            for (name, value) in fields(self) {
+                try!(write!(f, " {}={}", name, value));
+            }
+            return Ok(());
+        }
+        for (name, value) in fields(self) {
+            try!(write!(f, "\n  {}: {}", name, value));
+        }
+        if let Packet::Publish(ref publish) = *self {
+            try!(hex_dump(f, &publish.payload, f.precision().unwrap_or(DEFAULT_PREVIEW_LEN)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use mqtt::{Packet, Publish, Subscribe, SubscribeTopic};
+    use {QoS, PacketIdentifier};
+
+    #[test]
+    fn packet_display_test() {
+        let publish = Packet::Publish(Box::new(Publish {
+            dup: false,
+            qos: QoS::AtLeastOnce,
+            retain: true,
+            topic_name: "a/b".to_string(),
+            pid: Some(PacketIdentifier(7)),
+            payload: Arc::new(b"hello, world\n0123456789".to_vec())
+        }));
+        assert_eq!(publish.to_string(), "Publish topic=a/b qos=1 pid=7 retain=true payload=23 bytes");
+        assert_eq!(format!("{:#}", publish),
+                   "Publish\n  topic: a/b\n  qos: 1\n  pid: 7\n  retain: true\n  payload: 23 bytes\n\
+                    \x20   0000  68 65 6c 6c 6f 2c 20 77 6f 72 6c 64 0a 30 31 32  hello, world.012\n\
+                    \x20   ... 7 more bytes");
+        assert_eq!(format!("{:#.4}", publish).lines().last(), Some("    ... 19 more bytes"));
+        assert_eq!(format!("{:#.100}", publish).lines().last(),
+                   Some("    0010  33 34 35 36 37 38 39                             3456789"));
+
+        let subscribe = Packet::Subscribe(Box::new(Subscribe {
+            pid: PacketIdentifier(1),
+            topics: vec![SubscribeTopic { topic_path: "a/#".to_string(), qos: QoS::ExactlyOnce }]
+        }));
+        assert_eq!(subscribe.to_string(), "Subscribe pid=1 topics=a/# qos=2");
+        assert_eq!(Packet::Pingreq.to_string(), "Pingreq");
+        assert_eq!(format!("{:#}", Packet::Puback(PacketIdentifier(3))), "Puback\n  pid: 3");
+    }
+}
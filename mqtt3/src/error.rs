@@ -16,12 +16,20 @@ pub enum Error {
     UnsupportedQualityOfService,
     UnsupportedPacketType,
     UnsupportedConnectReturnCode,
+    UnsupportedReasonCode,
     PayloadSizeIncorrect,
     PayloadTooLong,
     PayloadRequired,
+    PacketTooLarge,
     TopicNameMustNotContainNonUtf8,
     TopicNameMustNotContainWildcard,
+    TopicMustNotBeEmpty,
+    TopicMustNotContainNull,
+    TopicTooLong,
     MalformedRemainingLength,
+    OverlongRemainingLength,
+    InvalidReservedFlags,
+    EmptyClientId,
     UnexpectedEof,
     Io(io::Error)
 }
@@ -63,12 +71,20 @@ impl error::Error for Error {
             Error::UnsupportedQualityOfService => "Unsupported Quality Of Service",
             Error::UnsupportedPacketType => "Unsupported Packet Type",
             Error::UnsupportedConnectReturnCode => "Unsupported Connect Return Code",
+            Error::UnsupportedReasonCode => "Unsupported Reason Code",
             Error::PayloadSizeIncorrect => "Payload Size Incorrect",
             Error::PayloadTooLong => "Payload Too Long",
             Error::PayloadRequired => "Payload Required",
+            Error::PacketTooLarge => "Packet Too Large",
             Error::TopicNameMustNotContainNonUtf8 => "Topic Name Must Not Contain Non Utf 8",
             Error::TopicNameMustNotContainWildcard => "Topic Name Must Not Contain Wildcard",
+            Error::TopicMustNotBeEmpty => "Topic Must Not Be Empty",
+            Error::TopicMustNotContainNull => "Topic Must Not Contain Null",
+            Error::TopicTooLong => "Topic Too Long",
             Error::MalformedRemainingLength => "Malformed Remaining Length",
+            Error::OverlongRemainingLength => "Overlong Remaining Length",
+            Error::InvalidReservedFlags => "Invalid Reserved Flags",
+            Error::EmptyClientId => "Empty Client Id",
             Error::UnexpectedEof => "Unexpected Eof",
             Error::Io(ref err) => err.description(),
         }
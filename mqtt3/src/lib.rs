@@ -5,7 +5,12 @@ mod mqtt;
 mod read;
 mod write;
 mod topic;
+mod pattern;
 mod msg;
+mod pool;
+mod display;
+mod verbose;
+mod reason;
 
 pub use error::{
     Error,
@@ -16,6 +21,10 @@ pub use msg::{
     Message
 };
 
+pub use pool::BufferPool;
+
+pub use display::DEFAULT_PREVIEW_LEN;
+
 pub use mqtt::{
     Packet,
     Connect,
@@ -25,17 +34,34 @@ pub use mqtt::{
     Suback,
     Unsubscribe,
     SubscribeTopic,
-    SubscribeReturnCodes
+    SubscribeReturnCodes,
+    remaining_length_size,
+    encode_remaining_length,
+    decode_remaining_length
 };
 
 pub use topic::{
     Topic,
     TopicPath,
-    ToTopicPath
+    ToTopicPath,
+    validate_topic_name,
+    validate_topic_filter,
+    expand_filters
 };
 
-pub use read::MqttRead;
+pub use pattern::TopicPattern;
+
+pub use read::{MqttRead, PacketReader, DecodeOptions, decode_fixed_packet};
 pub use write::MqttWrite;
+pub use verbose::{decode_verbose, DecodeFailure, Field};
+pub use reason::{
+    ConnackCode,
+    SubackCode,
+    PubackReason,
+    PubrelReason,
+    UnsubackReason,
+    DisconnectReason
+};
 
 const MULTIPLIER: usize = 0x80 * 0x80 * 0x80 * 0x80;
 const MAX_PAYLOAD_SIZE: usize = 268435455;
@@ -185,6 +211,16 @@ impl PacketType {
     pub fn from_hd(hd: u8) -> Result<PacketType> {
         Self::from_u8(hd >> 4)
     }
+
+    /// Flags the fixed header has to carry, `None` for PUBLISH where they
+    /// hold DUP, QoS and RETAIN
+    pub fn fixed_flags(&self) -> Option<u8> {
+        match *self {
+            PacketType::Publish => None,
+            PacketType::Pubrel | PacketType::Subscribe | PacketType::Unsubscribe => Some(0b0010),
+            _ => Some(0)
+        }
+    }
 }
 
 impl fmt::Display for PacketType {
@@ -196,42 +232,10 @@ impl fmt::Display for PacketType {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum ConnectReturnCode {
-    Accepted,
-    RefusedProtocolVersion,
-    RefusedIdentifierRejected,
-    ServerUnavailable,
-    BadUsernamePassword,
-    NotAuthorized
-}
+/// Former name of `ConnackCode`
+pub type ConnectReturnCode = ConnackCode;
 
-impl ConnectReturnCode {
-    pub fn to_u8(&self) -> u8 {
-        match *self {
-            ConnectReturnCode::Accepted => 0,
-            ConnectReturnCode::RefusedProtocolVersion => 1,
-            ConnectReturnCode::RefusedIdentifierRejected => 2,
-            ConnectReturnCode::ServerUnavailable => 3,
-            ConnectReturnCode::BadUsernamePassword => 4,
-            ConnectReturnCode::NotAuthorized => 5
-        }
-    }
-
-    pub fn from_u8(byte: u8) -> Result<ConnectReturnCode> {
-        match byte {
-            0 => Ok(ConnectReturnCode::Accepted),
-            1 => Ok(ConnectReturnCode::RefusedProtocolVersion),
-            2 => Ok(ConnectReturnCode::RefusedIdentifierRejected),
-            3 => Ok(ConnectReturnCode::ServerUnavailable),
-            4 => Ok(ConnectReturnCode::BadUsernamePassword),
-            5 => Ok(ConnectReturnCode::NotAuthorized),
-            _ => Err(Error::UnsupportedConnectReturnCode)
-        }
-    }
-}
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct PacketIdentifier(pub u16);
 
 impl PacketIdentifier {
@@ -239,8 +243,9 @@ impl PacketIdentifier {
         PacketIdentifier(0)
     }
 
+    /// The id after this one, 65535 is followed by 1 as 0 isn't a valid id
     pub fn next(&self) -> PacketIdentifier {
-        PacketIdentifier(self.0 + 1)
+        PacketIdentifier(self.0 % 65535 + 1)
     }
 }
 
@@ -252,6 +257,7 @@ pub struct Header {
 }
 
 impl Header {
+    /// Header as decoded, the flags aren't checked
     pub fn new(hd: u8, len: usize) -> Result<Header> {
         Ok(Header {
             hd: hd,
@@ -260,6 +266,73 @@ impl Header {
         })
     }
 
+    /// Header of a packet of `typ` other than PUBLISH, with the flags it
+    /// has to carry
+    pub fn with_type(typ: PacketType, len: usize) -> Result<Header> {
+        match typ.fixed_flags() {
+            Some(flags) => Ok(Header {
+                hd: typ.to_u8() << 4 | flags,
+                typ: typ,
+                len: len
+            }),
+            None => Err(Error::InvalidReservedFlags)
+        }
+    }
+
+    /// Header of a PUBLISH. DUP can't be set on QoS 0.
+    pub fn publish(qos: QoS, dup: bool, retain: bool, len: usize) -> Result<Header> {
+        let mut header = Header {
+            hd: PacketType::Publish.to_u8() << 4,
+            typ: PacketType::Publish,
+            len: len
+        };
+        try!(header.set_qos(qos));
+        try!(header.set_dup(dup));
+        try!(header.set_retain(retain));
+        Ok(header)
+    }
+
+    /// First byte of the fixed header, the type and the flags
+    #[inline]
+    pub fn hd(&self) -> u8 {
+        self.hd
+    }
+
+    /// Sets DUP of a PUBLISH, which QoS 0 can't have
+    pub fn set_dup(&mut self, dup: bool) -> Result<&mut Header> {
+        if self.typ != PacketType::Publish || (dup && try!(self.qos()) == QoS::AtMostOnce) {
+            return Err(Error::InvalidReservedFlags);
+        }
+        self.hd = if dup { self.hd | 0b1000 } else { self.hd & !0b1000 };
+        Ok(self)
+    }
+
+    /// Sets the QoS of a PUBLISH, QoS 0 only without DUP
+    pub fn set_qos(&mut self, qos: QoS) -> Result<&mut Header> {
+        if self.typ != PacketType::Publish || (qos == QoS::AtMostOnce && self.dup()) {
+            return Err(Error::InvalidReservedFlags);
+        }
+        self.hd = self.hd & !0b110 | qos.to_u8() << 1;
+        Ok(self)
+    }
+
+    /// Sets RETAIN of a PUBLISH
+    pub fn set_retain(&mut self, retain: bool) -> Result<&mut Header> {
+        if self.typ != PacketType::Publish {
+            return Err(Error::InvalidReservedFlags);
+        }
+        self.hd = if retain { self.hd | 1 } else { self.hd & !1 };
+        Ok(self)
+    }
+
+    /// The fixed header as written: the first byte and the remaining length
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = vec![self.hd, 0, 0, 0, 0];
+        let size = try!(encode_remaining_length(self.len, &mut bytes[1..]));
+        bytes.truncate(1 + size);
+        Ok(bytes)
+    }
+
     #[inline]
     pub fn dup(&self) -> bool {
         (self.hd & 0b1000) != 0
@@ -286,7 +359,7 @@ pub struct LastWill {
 
 #[cfg(test)]
 mod test {
-    use super::{QoS, Protocol, PacketIdentifier};
+    use super::{QoS, Protocol, PacketIdentifier, PacketType, Header};
 
     #[test]
     fn protocol_test() {
@@ -308,10 +381,30 @@ mod test {
         assert_eq!(QoS::ExactlyOnce.min(QoS::ExactlyOnce), QoS::ExactlyOnce);
     }
 
+    #[test]
+    fn header_test() {
+        let header = Header::with_type(PacketType::Subscribe, 200).unwrap();
+        assert_eq!(header.hd(), 0x82);
+        assert_eq!(header.to_bytes().unwrap(), vec![0x82, 0xC8, 0x01]);
+        assert_eq!(Header::with_type(PacketType::Puback, 2).unwrap().hd(), 0x40);
+        assert!(Header::with_type(PacketType::Publish, 2).is_err());
+
+        let mut header = Header::publish(QoS::ExactlyOnce, true, true, 10).unwrap();
+        assert_eq!(header.hd(), 0x3D);
+        assert_eq!((header.dup(), header.qos().unwrap(), header.retain()), (true, QoS::ExactlyOnce, true));
+        assert!(header.set_qos(QoS::AtMostOnce).is_err());
+        header.set_dup(false).unwrap().set_qos(QoS::AtMostOnce).unwrap().set_retain(false).unwrap();
+        assert_eq!(header.hd(), 0x30);
+        assert!(header.set_dup(true).is_err());
+        assert!(Header::publish(QoS::AtMostOnce, true, false, 0).is_err());
+        assert!(Header::with_type(PacketType::Pingreq, 0).unwrap().set_retain(true).is_err());
+    }
+
     #[test]
     fn packet_identifier_test() {
         let pid = PacketIdentifier::zero();
         assert_eq!(pid, PacketIdentifier(0));
         assert_eq!(pid.next(), PacketIdentifier(1));
+        assert_eq!(PacketIdentifier(65535).next(), PacketIdentifier(1));
     }
 }
@@ -1,5 +1,6 @@
 use std::sync::Arc;
-use super::{QoS, LastWill, PacketIdentifier, Protocol, ConnectReturnCode};
+use super::{QoS, LastWill, PacketIdentifier, PacketType, Protocol, ConnackCode, MAX_PAYLOAD_SIZE};
+use error::{Error, Result};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Packet {
@@ -19,6 +20,132 @@ pub enum Packet {
 	Disconnect
 }
 
+impl Packet {
+    pub fn packet_type(&self) -> PacketType {
+        match *self {
+            Packet::Connect(_) => PacketType::Connect,
+            Packet::Connack(_) => PacketType::Connack,
+            Packet::Publish(_) => PacketType::Publish,
+            Packet::Puback(_) => PacketType::Puback,
+            Packet::Pubrec(_) => PacketType::Pubrec,
+            Packet::Pubrel(_) => PacketType::Pubrel,
+            Packet::Pubcomp(_) => PacketType::Pubcomp,
+            Packet::Subscribe(_) => PacketType::Subscribe,
+            Packet::Suback(_) => PacketType::Suback,
+            Packet::Unsubscribe(_) => PacketType::Unsubscribe,
+            Packet::Unsuback(_) => PacketType::Unsuback,
+            Packet::Pingreq => PacketType::Pingreq,
+            Packet::Pingresp => PacketType::Pingresp,
+            Packet::Disconnect => PacketType::Disconnect
+        }
+    }
+
+    /// Size of the variable header and the payload
+    pub fn remaining_length(&self) -> usize {
+        match *self {
+            Packet::Connect(ref connect) => {
+                let mut len = 8 + connect.protocol.name().len() + connect.client_id.len();
+                if let Some(ref last_will) = connect.last_will {
+                    len += 4 + last_will.topic.len() + last_will.message.len();
+                }
+                if let Some(ref username) = connect.username {
+                    len += 2 + username.len();
+                }
+                if let Some(ref password) = connect.password {
+                    len += 2 + password.len();
+                }
+                len
+            },
+            Packet::Publish(ref publish) => {
+                let mut len = publish.topic_name.len() + 2 + publish.payload.len();
+                if publish.qos != QoS::AtMostOnce && None != publish.pid {
+                    len += 2;
+                }
+                len
+            },
+            Packet::Subscribe(ref subscribe) => {
+                2 + subscribe.topics.iter().fold(0, |s, ref t| s + t.topic_path.len() + 3)
+            },
+            Packet::Suback(ref suback) => 2 + suback.return_codes.len(),
+            Packet::Unsubscribe(ref unsubscribe) => {
+                2 + unsubscribe.topics.iter().fold(0, |s, ref topic| s + topic.len() + 2)
+            },
+            Packet::Connack(_) |
+            Packet::Puback(_) |
+            Packet::Pubrec(_) |
+            Packet::Pubrel(_) |
+            Packet::Pubcomp(_) |
+            Packet::Unsuback(_) => 2,
+            Packet::Pingreq |
+            Packet::Pingresp |
+            Packet::Disconnect => 0
+        }
+    }
+
+    /// Size of the whole encoded packet including the fixed header
+    pub fn size(&self) -> usize {
+        let len = self.remaining_length();
+        1 + remaining_length_size(len) + len
+    }
+}
+
+/// Number of bytes used to encode the remaining length
+pub fn remaining_length_size(len: usize) -> usize {
+    match len {
+        0..=127 => 1,
+        128..=16383 => 2,
+        16384..=2097151 => 3,
+        _ => 4
+    }
+}
+
+/// Encodes the remaining length `len` at the start of `buf` and returns the
+/// number of bytes written, see `remaining_length_size`. Fails with
+/// `PayloadTooLong` above 268435455.
+///
+/// Panics if `buf` is shorter than the encoding.
+pub fn encode_remaining_length(len: usize, buf: &mut [u8]) -> Result<usize> {
+    if len > MAX_PAYLOAD_SIZE {
+        return Err(Error::PayloadTooLong);
+    }
+    let mut x = len;
+    let mut i = 0;
+    loop {
+        let byte = (x % 0x80) as u8;
+        x /= 0x80;
+        if x == 0 {
+            buf[i] = byte;
+            return Ok(i + 1);
+        }
+        buf[i] = byte | 0x80;
+        i += 1;
+    }
+}
+
+/// Decodes the remaining length at the start of `buf`, returning it with
+/// the number of bytes it took, or `None` if `buf` ends before the last
+/// byte of the encoding.
+///
+/// Fails with `MalformedRemainingLength` if the encoding goes on past 4
+/// bytes and with `OverlongRemainingLength` if it uses more bytes than the
+/// length needs, like `0x80 0x00` for 0.
+pub fn decode_remaining_length(buf: &[u8]) -> Result<Option<(usize, usize)>> {
+    let mut len = 0;
+    for (i, &byte) in buf.iter().enumerate() {
+        if i == 4 {
+            return Err(Error::MalformedRemainingLength);
+        }
+        len += ((byte & 0x7F) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            if i > 0 && byte == 0 {
+                return Err(Error::OverlongRemainingLength);
+            }
+            return Ok(Some((len, i + 1)));
+        }
+    }
+    Ok(None)
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Connect {
 	pub protocol: Protocol,
@@ -33,7 +160,7 @@ pub struct Connect {
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Connack {
     pub session_present: bool,
-    pub code: ConnectReturnCode
+    pub code: ConnackCode
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -46,6 +173,14 @@ pub struct Publish {
     pub payload: Arc<Vec<u8>>
 }
 
+impl Publish {
+    /// Takes the payload out of its `Arc`, copying it only if it is shared.
+    /// A decoded payload isn't, so a single consumer gets it for free.
+    pub fn into_payload(self) -> Vec<u8> {
+        Arc::try_unwrap(self.payload).unwrap_or_else(|payload| (*payload).clone())
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Subscribe {
     pub pid: PacketIdentifier,
@@ -78,3 +213,59 @@ pub struct Unsubscribe {
     pub pid: PacketIdentifier,
 	pub topics: Vec<String>
 }
+
+#[cfg(test)]
+mod test {
+    use error::Error;
+    use std::io::Cursor;
+    use std::sync::Arc;
+    use read::MqttRead;
+    use super::{Packet, encode_remaining_length, decode_remaining_length, remaining_length_size};
+
+    #[test]
+    fn remaining_length_round_trip_test() {
+        let mut buf = [0; 4];
+        for &len in [0, 127, 128, 16383, 16384, 2097151, 2097152, 268435455].iter() {
+            let size = encode_remaining_length(len, &mut buf).unwrap();
+            assert_eq!(size, remaining_length_size(len));
+            assert_eq!(decode_remaining_length(&buf[..size]).unwrap(), Some((len, size)));
+            assert_eq!(decode_remaining_length(&buf[..size - 1]).unwrap(), None);
+        }
+        assert_eq!(&buf[..], &[0xFF, 0xFF, 0xFF, 0x7F]);
+        match encode_remaining_length(268435456, &mut buf) {
+            Err(Error::PayloadTooLong) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+    }
+
+    #[test]
+    fn decode_remaining_length_errors_test() {
+        // trailing bytes belong to the packet
+        assert_eq!(decode_remaining_length(&[0x05, 0x80]).unwrap(), Some((5, 1)));
+        match decode_remaining_length(&[0x80, 0x00]) {
+            Err(Error::OverlongRemainingLength) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+        match decode_remaining_length(&[0xFF, 0x80, 0x80, 0x00]) {
+            Err(Error::OverlongRemainingLength) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+        match decode_remaining_length(&[0xFF, 0xFF, 0xFF, 0xFF, 0x01]) {
+            Err(Error::MalformedRemainingLength) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+    }
+
+    #[test]
+    fn publish_into_payload_test() {
+        let mut stream = Cursor::new(vec![0x30, 0x06, 0x00, 0x03, b'a', b'/', b'b', b'x']);
+        let publish = match stream.read_packet().unwrap() {
+            Packet::Publish(publish) => publish,
+            packet => panic!("unexpected {:?}", packet)
+        };
+        let shared = publish.clone();
+        assert_eq!(Arc::strong_count(&publish.payload), 2);
+        assert_eq!(shared.into_payload(), b"x".to_vec());
+        assert_eq!(publish.into_payload(), b"x".to_vec());
+    }
+}
@@ -7,14 +7,28 @@ pub struct Message {
     pub topic: TopicPath,
     pub qos: QoS,
     pub retain: bool,
+    // The sender may have delivered it before, only for QoS 1,2
+    dup: bool,
     // Only for QoS 1,2
     pub pid: Option<PacketIdentifier>,
     pub payload: Arc<Vec<u8>>
 }
 
 impl Message {
+    /// Message to publish, neither retained nor with a packet identifier yet
+    pub fn new(topic: TopicPath, payload: Arc<Vec<u8>>, qos: QoS) -> Message {
+        Message {
+            topic: topic,
+            qos: qos,
+            retain: false,
+            dup: false,
+            pid: None,
+            payload: payload
+        }
+    }
+
     pub fn from_pub(publish: Box<Publish>) -> Result<Box<Message>> {
-        let topic = TopicPath::from(publish.topic_name.as_str());
+        let topic = try!(TopicPath::from_str(publish.topic_name.as_str()));
         if topic.wildcards {
             return Err(Error::TopicNameMustNotContainWildcard);
         }
@@ -22,6 +36,7 @@ impl Message {
             topic: topic,
             qos: publish.qos,
             retain: publish.retain,
+            dup: publish.dup,
             pid: publish.pid,
             payload: publish.payload.clone()
         }))
@@ -34,11 +49,17 @@ impl Message {
             topic: topic,
             qos: last_will.qos,
             retain: last_will.retain,
+            dup: false,
             pid: None,
             payload: Arc::new(last_will.message.into_bytes())
         })
     }
 
+    /// The DUP flag it was received with
+    pub fn dup(&self) -> bool {
+        self.dup
+    }
+
     pub fn to_pub(&self, qos: Option<QoS>, dup: bool) -> Box<Publish> {
         let qos = qos.unwrap_or(self.qos);
         Box::new(Publish {
@@ -58,6 +79,7 @@ impl Message {
             topic: self.topic.clone(),
             qos: qos,
             retain: self.retain,
+            dup: self.dup,
             pid: pid,
             payload: self.payload.clone()
         })
@@ -72,13 +94,8 @@ mod test {
 
     #[test]
     fn message_to_pub_test() {
-        let msg = Message {
-            topic: "/a/b".to_topic_path().unwrap(),
-            qos: QoS::AtLeastOnce,
-            retain: false,
-            pid: Some(PacketIdentifier(1)),
-            payload: Arc::new(vec![0x80, 0x40])
-        };
+        let mut msg = Message::new("/a/b".to_topic_path().unwrap(), Arc::new(vec![0x80, 0x40]), QoS::AtLeastOnce);
+        msg.pid = Some(PacketIdentifier(1));
         let publish = msg.to_pub(None, false);
 
         assert_eq!(publish, Box::new(Publish {
@@ -108,6 +125,7 @@ mod test {
         assert_eq!(msg.pid, Some(PacketIdentifier(2)));
         assert_eq!(msg.payload, Arc::new(vec![0x10, 0x20, 0x30]));
         assert!(msg.retain);
+        assert!(msg.dup());
     }
 
     #[test]
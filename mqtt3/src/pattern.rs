@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use topic::{TopicPath, validate_topic_filter};
+use {Error, Result};
+
+/// Topic filter whose levels can be named, like
+/// `devices/{device_id}/telemetry/{metric}`. A `{name}` level matches one
+/// level like `+` and captures it; `+` and a last `#` work as usual.
+#[derive(Debug, Clone)]
+pub struct TopicPattern {
+    filter: TopicPath,
+    // Name of the capture at each level
+    names: Vec<Option<String>>
+}
+
+impl TopicPattern {
+    pub fn new(pattern: &str) -> Result<TopicPattern> {
+        let mut levels = Vec::new();
+        let mut names: Vec<Option<String>> = Vec::new();
+        for level in pattern.split('/') {
+            if level.starts_with('{') && level.ends_with('}') && level.len() > 2 {
+                let name = &level[1..level.len() - 1];
+                if name.contains(|c| c == '{' || c == '}') || names.iter().any(|n| n.as_ref().map_or(false, |n| n == name)) {
+                    return Err(Error::InvalidTopicPath);
+                }
+                levels.push("+");
+                names.push(Some(name.to_string()));
+            } else if level.contains(|c| c == '{' || c == '}') {
+                return Err(Error::InvalidTopicPath);
+            } else {
+                levels.push(level);
+                names.push(None);
+            }
+        }
+        let filter = levels.join("/");
+        try!(validate_topic_filter(&filter));
+        Ok(TopicPattern {
+            filter: TopicPath::from(filter),
+            names: names
+        })
+    }
+
+    /// Topic filter to subscribe with, the named levels being `+`
+    pub fn filter(&self) -> &str {
+        &self.filter.path
+    }
+
+    pub fn matches(&self, topic: &str) -> bool {
+        TopicPath::from(topic).matches(&self.filter)
+    }
+
+    /// The named levels of `topic`, `None` if it doesn't match
+    pub fn captures(&self, topic: &str) -> Option<HashMap<String, String>> {
+        if !self.matches(topic) {
+            return None;
+        }
+        Some(topic.split('/')
+                  .zip(self.names.iter())
+                  .filter_map(|(level, name)| name.as_ref().map(|name| (name.clone(), level.to_string())))
+                  .collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::TopicPattern;
+
+    #[test]
+    fn topic_pattern_test() {
+        let pattern = TopicPattern::new("devices/{device_id}/telemetry/{metric}").unwrap();
+        assert_eq!(pattern.filter(), "devices/+/telemetry/+");
+
+        let captures = pattern.captures("devices/meter-7/telemetry/power").unwrap();
+        assert_eq!(captures.len(), 2);
+        assert_eq!(captures["device_id"], "meter-7");
+        assert_eq!(captures["metric"], "power");
+        assert!(pattern.captures("devices/meter-7/status/power").is_none());
+        assert!(pattern.captures("devices/meter-7/telemetry").is_none());
+
+        let pattern = TopicPattern::new("{site}/+/#").unwrap();
+        assert_eq!(pattern.captures("hq/floor-2/room/4").unwrap()["site"], "hq");
+        assert!(!pattern.matches("$SYS/broker/uptime"));
+
+        for invalid in ["a/{x}/{x}", "a/{x/b", "a/b{x}", "a/{}/#/b"].iter() {
+            assert!(TopicPattern::new(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
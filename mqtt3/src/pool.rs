@@ -0,0 +1,98 @@
+use std::sync::Arc;
+
+// Buffers larger than this are dropped instead of kept
+const DEFAULT_MAX_CAPACITY: usize = 64 * 1024;
+
+/// Keeps buffers of decoded payloads for reuse, so a reader that is fed the
+/// buffers back doesn't allocate for every PUBLISH. Buffers of large
+/// payloads aren't kept, so one burst of them doesn't stay allocated.
+#[derive(Debug)]
+pub struct BufferPool {
+    buffers: Vec<Vec<u8>>,
+    max_buffers: usize,
+    max_capacity: usize
+}
+
+impl BufferPool {
+    /// A pool which holds on to at most `max_buffers` buffers of up to 64 KiB
+    pub fn new(max_buffers: usize) -> BufferPool {
+        BufferPool {
+            buffers: Vec::new(),
+            max_buffers: max_buffers,
+            max_capacity: DEFAULT_MAX_CAPACITY
+        }
+    }
+
+    /// Largest buffer kept, in bytes
+    pub fn set_max_capacity(&mut self, bytes: usize) -> &mut BufferPool {
+        self.max_capacity = bytes;
+        self
+    }
+
+    /// An empty buffer with room for at least `capacity` bytes
+    pub fn take(&mut self, capacity: usize) -> Vec<u8> {
+        match self.buffers.pop() {
+            Some(mut buffer) => {
+                buffer.reserve(capacity);
+                buffer
+            }
+            None => Vec::with_capacity(capacity)
+        }
+    }
+
+    pub fn give(&mut self, mut buffer: Vec<u8>) {
+        if self.buffers.len() < self.max_buffers && buffer.capacity() <= self.max_capacity {
+            buffer.clear();
+            self.buffers.push(buffer);
+        }
+    }
+
+    /// Gives the payload back unless it's still shared
+    pub fn recycle(&mut self, payload: Arc<Vec<u8>>) {
+        if let Ok(buffer) = Arc::try_unwrap(payload) {
+            self.give(buffer);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffers.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use super::BufferPool;
+
+    #[test]
+    fn buffer_pool_test() {
+        let mut pool = BufferPool::new(1);
+        let buffer = pool.take(16);
+        assert!(buffer.capacity() >= 16);
+        let ptr = buffer.as_ptr();
+
+        let payload = Arc::new(buffer);
+        let shared = payload.clone();
+        pool.recycle(payload);
+        assert!(pool.is_empty());
+        pool.recycle(shared);
+        assert_eq!(pool.len(), 1);
+        pool.give(vec![1, 2, 3]);
+        assert_eq!(pool.len(), 1);
+
+        let mut large = BufferPool::new(1);
+        large.set_max_capacity(32);
+        large.give(Vec::with_capacity(64));
+        assert!(large.is_empty());
+        large.give(Vec::with_capacity(16));
+        assert_eq!(large.len(), 1);
+
+        let buffer = pool.take(8);
+        assert_eq!(buffer.as_ptr(), ptr);
+        assert!(buffer.is_empty());
+    }
+}
@@ -1,11 +1,14 @@
-use std::io::{BufReader, Read, Take, Cursor};
+use std::cmp;
+use std::io::{self, BufRead, BufReader, Read, Take, Cursor};
 use std::net::TcpStream;
 use std::sync::Arc;
 use byteorder::{ReadBytesExt, BigEndian};
-use {Error, Result, ConnectReturnCode, SubscribeTopic, SubscribeReturnCodes};
-use {PacketType, Header, QoS, LastWill, Protocol, PacketIdentifier, MULTIPLIER};
+use std::convert::TryFrom;
+use {Error, Result, ConnackCode, SubackCode, SubscribeTopic, SubscribeReturnCodes};
+use {PacketType, Header, QoS, LastWill, Protocol, PacketIdentifier, MULTIPLIER, validate_topic_name};
 
 use mqtt::{
+    remaining_length_size,
     Packet,
     Connect,
     Connack,
@@ -15,10 +18,194 @@ use mqtt::{
     Unsubscribe
 };
 
+// Most of a PUBLISH payload reserved before any of it is read
+const EAGER_PAYLOAD: usize = 64 * 1024;
+
+/// Decodes the packets of a fixed size, PINGREQ, PINGRESP, DISCONNECT and
+/// the 4 byte acknowledgements, from the start of `buf`. Gives the packet
+/// and the bytes it took, `None` for any other packet or if `buf` doesn't
+/// hold the whole packet yet.
+pub fn decode_fixed_packet(buf: &[u8]) -> Option<(Packet, usize)> {
+    if buf.len() < 2 {
+        return None;
+    }
+    match (buf[0], buf[1]) {
+        (0xC0, 0) => Some((Packet::Pingreq, 2)),
+        (0xD0, 0) => Some((Packet::Pingresp, 2)),
+        (0xE0, 0) => Some((Packet::Disconnect, 2)),
+        (hd, 2) if buf.len() >= 4 => {
+            let pid = PacketIdentifier((buf[2] as u16) << 8 | buf[3] as u16);
+            let packet = match hd {
+                0x40 => Packet::Puback(pid),
+                0x50 => Packet::Pubrec(pid),
+                0x62 => Packet::Pubrel(pid),
+                0x70 => Packet::Pubcomp(pid),
+                0xB0 => Packet::Unsuback(pid),
+                _ => return None
+            };
+            Some((packet, 4))
+        },
+        _ => None
+    }
+}
+
+/// How much of the protocol `MqttRead::read_packet_with` enforces.
+///
+/// The default takes what `read_packet` takes and reports the violations it
+/// lets through as warnings, which suits a tool looking at the traffic of
+/// buggy devices. A broker sets `strict`, which turns the warnings into
+/// errors. Packets that can't be made sense of fail in either mode.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodeOptions {
+    /// Fail on any violation instead of warning about it
+    pub strict: bool,
+    /// Larger packets are skipped and fail with `PacketTooLarge`
+    pub max_packet_size: Option<usize>,
+    /// Whether a CONNECT may leave the client id empty, which the protocol
+    /// allows with a clean session only
+    pub allow_empty_client_id: bool
+}
+
+impl DecodeOptions {
+    pub fn strict() -> DecodeOptions {
+        DecodeOptions { strict: true, .. DecodeOptions::default() }
+    }
+}
+
+impl Default for DecodeOptions {
+    fn default() -> DecodeOptions {
+        DecodeOptions {
+            strict: false,
+            max_packet_size: None,
+            allow_empty_client_id: true
+        }
+    }
+}
+
+// Fails in strict mode, otherwise keeps the error as a warning
+fn violation(options: &DecodeOptions, warnings: &mut Vec<Error>, err: Error) -> Result<()> {
+    if options.strict {
+        return Err(err);
+    }
+    warnings.push(err);
+    Ok(())
+}
+
 pub trait MqttRead: ReadBytesExt {
     fn read_packet(&mut self) -> Result<Packet> {
+        if let Some(packet) = self.read_fixed_packet() {
+            return Ok(packet);
+        }
         let hd = try!(self.read_u8());
         let len = try!(self.read_remaining_length());
+        self.read_packet_body(hd, len)
+    }
+
+    /// Reads the packet unless it's larger than `max_packet_size` bytes.
+    /// The body of a packet that is too large is skipped, so the stream stays
+    /// usable for the next packet.
+    fn read_packet_limited(&mut self, max_packet_size: usize) -> Result<Packet> {
+        if max_packet_size >= 4 {
+            if let Some(packet) = self.read_fixed_packet() {
+                return Ok(packet);
+            }
+        }
+        let hd = try!(self.read_u8());
+        let len = try!(self.read_remaining_length());
+        if 1 + remaining_length_size(len) + len > max_packet_size {
+            try!(io::copy(&mut self.take(len as u64), &mut io::sink()));
+            return Err(Error::PacketTooLarge);
+        }
+        self.read_packet_body(hd, len)
+    }
+
+    /// Reads a packet checking it against `options`, returns it with the
+    /// violations let through in lenient mode.
+    fn read_packet_with(&mut self, options: &DecodeOptions) -> Result<(Packet, Vec<Error>)> {
+        let mut warnings = Vec::new();
+        let hd = try!(self.read_u8());
+        let mut encoded = 0;
+        let mut len = 0;
+        loop {
+            if encoded == 4 {
+                return Err(Error::MalformedRemainingLength);
+            }
+            let byte = try!(self.read_u8());
+            len += ((byte & 0x7F) as usize) << (7 * encoded);
+            encoded += 1;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        if let Some(max_packet_size) = options.max_packet_size {
+            if 1 + encoded + len > max_packet_size {
+                try!(io::copy(&mut self.take(len as u64), &mut io::sink()));
+                return Err(Error::PacketTooLarge);
+            }
+        }
+        if encoded > remaining_length_size(len) {
+            try!(violation(options, &mut warnings, Error::OverlongRemainingLength));
+        }
+
+        let typ = try!(PacketType::from_hd(hd));
+        if let Some(flags) = typ.fixed_flags() {
+            if hd & 0x0F != flags {
+                try!(violation(options, &mut warnings, Error::InvalidReservedFlags));
+            }
+        }
+
+        let packet = if typ == PacketType::Connect {
+            // The connect flags are gone once parsed
+            let mut body = self.payload_buffer(cmp::min(len, EAGER_PAYLOAD));
+            try!(<&mut Self as Read>::take(self, len as u64).read_to_end(&mut body));
+            if body.len() < len || body.len() < 2 {
+                return Err(Error::UnexpectedEof);
+            }
+            let flags_at = 2 + ((body[0] as usize) << 8 | body[1] as usize) + 1;
+            if body.get(flags_at).map_or(false, |flags| flags & 1 != 0) {
+                try!(violation(options, &mut warnings, Error::InvalidReservedFlags));
+            }
+            Packet::Connect(try!(Cursor::new(body).read_connect(try!(Header::new(hd, len)))))
+        } else {
+            try!(self.read_packet_body(hd, len))
+        };
+
+        match packet {
+            Packet::Connect(ref connect) if connect.client_id.is_empty() => {
+                if !options.allow_empty_client_id {
+                    return Err(Error::EmptyClientId);
+                }
+                if !connect.clean_session {
+                    try!(violation(options, &mut warnings, Error::EmptyClientId));
+                }
+            },
+            Packet::Publish(ref publish) => {
+                if let Err(err) = validate_topic_name(&publish.topic_name) {
+                    try!(violation(options, &mut warnings, err));
+                }
+                if publish.dup && publish.qos == QoS::AtMostOnce {
+                    try!(violation(options, &mut warnings, Error::InvalidReservedFlags));
+                }
+            },
+            Packet::Subscribe(ref subscribe) if subscribe.topics.is_empty() => {
+                try!(violation(options, &mut warnings, Error::PayloadRequired));
+            },
+            Packet::Unsubscribe(ref unsubscribe) if unsubscribe.topics.is_empty() => {
+                try!(violation(options, &mut warnings, Error::PayloadRequired));
+            },
+            _ => ()
+        }
+        Ok((packet, warnings))
+    }
+
+    /// Fast path for the small packets that make most of the traffic of a
+    /// publishing client. Buffered readers decode them in place with
+    /// `decode_fixed_packet`, without reading the header and body apart.
+    fn read_fixed_packet(&mut self) -> Option<Packet> {
+        None
+    }
+
+    fn read_packet_body(&mut self, hd: u8, len: usize) -> Result<Packet> {
         let header = try!(Header::new(hd, len));
         //println!("Header {:?}", header);
         if len == 0 {
@@ -26,15 +213,18 @@ pub trait MqttRead: ReadBytesExt {
             return match header.typ {
                 PacketType::Pingreq => Ok(Packet::Pingreq),
                 PacketType::Pingresp => Ok(Packet::Pingresp),
+                PacketType::Disconnect => Ok(Packet::Disconnect),
                 _ => Err(Error::PayloadRequired)
             };
         }
+        if header.typ == PacketType::Publish {
+            return Ok(Packet::Publish(try!(self.read_publish_body(header))));
+        }
         let mut raw_packet = self.take(len as u64);
 
         match header.typ {
             PacketType::Connect => Ok(Packet::Connect(try!(raw_packet.read_connect(header)))),
             PacketType::Connack => Ok(Packet::Connack(try!(raw_packet.read_connack(header)))),
-            PacketType::Publish => Ok(Packet::Publish(try!(raw_packet.read_publish(header)))),
             PacketType::Puback => {
                 if len != 2 {
                     return Err(Error::PayloadSizeIncorrect)
@@ -139,7 +329,7 @@ pub trait MqttRead: ReadBytesExt {
         let return_code = try!(self.read_u8());
         Ok(Connack {
             session_present: (flags & 0x01) == 1,
-            code: try!(ConnectReturnCode::from_u8(return_code))
+            code: try!(ConnackCode::from_u8(return_code))
         })
     }
 
@@ -166,6 +356,46 @@ pub trait MqttRead: ReadBytesExt {
         ))
     }
 
+    // Unlike `read_publish` doesn't need the reader to end with the packet.
+    // The buffer grows with the payload that arrives past the first
+    // `EAGER_PAYLOAD` bytes, a header alone can't make it allocate more.
+    fn read_publish_body(&mut self, header: Header) -> Result<Box<Publish>> {
+        let qos = try!(header.qos());
+        let topic_name = try!(self.read_mqtt_string());
+        let pid = if qos != QoS::AtMostOnce {
+            Some(PacketIdentifier(try!(self.read_u16::<BigEndian>())))
+        } else {
+            None
+        };
+        let used = 2 + topic_name.len() + if pid.is_some() { 2 } else { 0 };
+        if used > header.len {
+            return Err(Error::PayloadSizeIncorrect);
+        }
+        let payload_len = header.len - used;
+        let mut payload = self.payload_buffer(cmp::min(payload_len, EAGER_PAYLOAD));
+        try!(<&mut Self as Read>::take(self, payload_len as u64).read_to_end(&mut payload));
+        if payload.len() < payload_len {
+            return Err(Error::UnexpectedEof);
+        }
+
+        Ok(Box::new(
+            Publish {
+                dup: header.dup(),
+                qos: qos,
+                retain: header.retain(),
+                topic_name: topic_name,
+                pid: pid,
+                payload: Arc::new(payload)
+            }
+        ))
+    }
+
+    /// Buffer for a PUBLISH payload of `len` bytes. Readers that keep a
+    /// `BufferPool` take it from there.
+    fn payload_buffer(&mut self, len: usize) -> Vec<u8> {
+        Vec::with_capacity(len)
+    }
+
     fn read_subscribe(&mut self, header: Header) -> Result<Box<Subscribe>> {
         let pid = try!(self.read_u16::<BigEndian>());
         let mut remaining_bytes = header.len - 2;
@@ -190,12 +420,8 @@ pub trait MqttRead: ReadBytesExt {
         let mut return_codes = Vec::with_capacity(remaining_bytes);
 
         while remaining_bytes > 0 {
-            let return_code = try!(self.read_u8());
-            if return_code >> 7 == 1 {
-                return_codes.push(SubscribeReturnCodes::Failure)
-            } else {
-                return_codes.push(SubscribeReturnCodes::Success(try!(QoS::from_u8(return_code & 0x3))));
-            }
+            let return_code = try!(SubackCode::try_from(try!(self.read_u8())));
+            return_codes.push(SubscribeReturnCodes::from(return_code));
             remaining_bytes -= 1
         };
 
@@ -235,6 +461,8 @@ pub trait MqttRead: ReadBytesExt {
         Ok(try!(String::from_utf8(data)))
     }
 
+    /// Reads the remaining length byte by byte. Unlike
+    /// `decode_remaining_length` it takes encodings longer than needed.
     fn read_remaining_length(&mut self) -> Result<usize> {
         let mut mult: usize = 1;
         let mut len: usize = 0;
@@ -255,17 +483,61 @@ pub trait MqttRead: ReadBytesExt {
     }
 }
 
+/// Decodes packets from any `Read`, like a `UnixStream`, a TLS stream or a
+/// ring buffer, for which the crate has no `MqttRead` impl. Reads go to the
+/// inner reader as they are; to decode out of a buffer wrap it in a
+/// `BufReader` instead, which also takes the acknowledgements without
+/// copying them.
+pub struct PacketReader<R> {
+    inner: R
+}
+
+impl<R: Read> PacketReader<R> {
+    pub fn new(inner: R) -> PacketReader<R> {
+        PacketReader { inner: inner }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for PacketReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<R: Read> MqttRead for PacketReader<R> {}
 impl MqttRead for TcpStream {}
-impl MqttRead for Cursor<Vec<u8>> {}
+impl<T: AsRef<[u8]>> MqttRead for Cursor<T> {}
 impl<T: Read> MqttRead for Take<T> where T: Read {}
-impl<T: Read> MqttRead for BufReader<T> {}
+impl<T: Read> MqttRead for BufReader<T> {
+    fn read_fixed_packet(&mut self) -> Option<Packet> {
+        // Only what is buffered already, filling the buffer could block
+        let (packet, len) = match decode_fixed_packet(self.buffer()) {
+            Some(decoded) => decoded,
+            None => return None
+        };
+        self.consume(len);
+        Some(packet)
+    }
+}
 
 #[cfg(test)]
 mod test {
-    use std::io::Cursor;
+    use std::io::{self, BufReader, Cursor, Read};
     use std::sync::Arc;
-    use super::MqttRead;
-    use {Protocol, LastWill, QoS, PacketIdentifier, ConnectReturnCode, SubscribeTopic, SubscribeReturnCodes};
+    use super::{MqttRead, PacketReader, DecodeOptions, decode_fixed_packet};
+    use {Error, Protocol, LastWill, QoS, PacketIdentifier, ConnackCode, SubscribeTopic, SubscribeReturnCodes};
     use mqtt::{
         Packet,
         Connect,
@@ -340,7 +612,7 @@ mod test {
 
         assert_eq!(packet, Packet::Connack(Connack {
             session_present: true,
-            code: ConnectReturnCode::Accepted
+            code: ConnackCode::Accepted
         }));
     }
 
@@ -393,6 +665,14 @@ mod test {
         assert_eq!(packet, Packet::Puback(PacketIdentifier(10)));
     }
 
+    #[test]
+    fn read_packet_disconnect_test() {
+        let mut stream = Cursor::new(vec![0b11100000, 0x00]);
+        let packet = stream.read_packet().unwrap();
+
+        assert_eq!(packet, Packet::Disconnect);
+    }
+
     #[test]
     fn read_packet_subscribe_test() {
         let mut stream = Cursor::new(vec![
@@ -440,6 +720,68 @@ mod test {
         })));
     }
 
+    #[test]
+    fn read_packet_limited_test() {
+        let mut stream = Cursor::new(vec![
+            0b00110000, 7,
+            0x00, 0x03, 'a' as u8, '/' as u8, 'b' as u8, // topic name = 'a/b'
+            0x01, 0x02,
+            0b01000000, 0x02, 0x00, 0x0A
+        ]);
+
+        match stream.read_packet_limited(8) {
+            Err(Error::PacketTooLarge) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+        assert_eq!(stream.read_packet_limited(8).unwrap(), Packet::Puback(PacketIdentifier(10)));
+    }
+
+    // Remembers the size of the payload buffer it was asked for
+    struct Reserving(Cursor<Vec<u8>>, usize);
+
+    impl Read for Reserving {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl MqttRead for Reserving {
+        fn payload_buffer(&mut self, len: usize) -> Vec<u8> {
+            self.1 = len;
+            Vec::with_capacity(len)
+        }
+    }
+
+    #[test]
+    fn read_publish_truncated_test() {
+        // claims a payload of 256 MiB, then ends
+        let mut stream = Reserving(Cursor::new(vec![
+            0b00110000, 0xFF, 0xFF, 0xFF, 0x7F,
+            0x00, 0x03, 'a' as u8, '/' as u8, 'b' as u8,
+            0x01, 0x02
+        ]), 0);
+        match stream.read_packet() {
+            Err(Error::UnexpectedEof) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+        assert!(stream.1 <= 64 * 1024);
+    }
+
+    #[test]
+    fn read_connect_truncated_test() {
+        // claims a body of 256 MiB, then ends
+        let mut stream = Reserving(Cursor::new(vec![
+            0b00010000, 0xFF, 0xFF, 0xFF, 0x7F,
+            0x00, 0x04, 'M' as u8, 'Q' as u8, 'T' as u8, 'T' as u8,
+            0x04, 0x02
+        ]), 0);
+        match stream.read_packet() {
+            Err(Error::UnexpectedEof) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+        assert!(stream.1 <= 64 * 1024);
+    }
+
     #[test]
     fn read_packet_suback_test() {
         let mut stream = Cursor::new(vec![
@@ -455,4 +797,106 @@ mod test {
             return_codes: vec![SubscribeReturnCodes::Success(QoS::AtLeastOnce), SubscribeReturnCodes::Failure]
         })));
     }
+
+    #[test]
+    fn decode_fixed_packet_test() {
+        assert_eq!(decode_fixed_packet(&[0xD0, 0x00, 0x40]), Some((Packet::Pingresp, 2)));
+        assert_eq!(decode_fixed_packet(&[0x62, 0x02, 0x01, 0x02]), Some((Packet::Pubrel(PacketIdentifier(258)), 4)));
+        // incomplete, reserved flags or other packets go the regular way
+        assert_eq!(decode_fixed_packet(&[0x40, 0x02, 0x00]), None);
+        assert_eq!(decode_fixed_packet(&[0x60, 0x02, 0x00, 0x01]), None);
+        assert_eq!(decode_fixed_packet(&[0x90, 0x02, 0x00, 0x01]), None);
+        assert_eq!(decode_fixed_packet(&[]), None);
+    }
+
+    #[test]
+    fn read_packet_buffered_test() {
+        let mut stream = BufReader::new(Cursor::new(vec![
+            0b00110000, 7,
+            0x00, 0x03, 'a' as u8, '/' as u8, 'b' as u8, // topic name = 'a/b'
+            0x01, 0x02,
+            0b01000000, 0x02, 0x00, 0x0A,
+            0xD0, 0x00,
+            0b01000000, 0x02, 0x00
+        ]));
+
+        match stream.read_packet().unwrap() {
+            Packet::Publish(_) => (),
+            packet => panic!("unexpected {:?}", packet)
+        }
+        assert_eq!(stream.read_packet().unwrap(), Packet::Puback(PacketIdentifier(10)));
+        assert_eq!(stream.read_packet().unwrap(), Packet::Pingresp);
+        // a truncated ack still fails like before
+        assert!(stream.read_packet().is_err());
+    }
+
+    #[test]
+    fn read_packet_with_test() {
+        let data = vec![
+            0x60, 0x02, 0x00, 0x01, // PUBREL with the flags of MQTT 3.1 clients
+            0x40, 0x82, 0x00, 0x00, 0x02, // overlong remaining length
+            0x38, 0x06, 0x00, 0x03, 'a' as u8, '/' as u8, '#' as u8, 0x01 // DUP on QoS 0, wildcard
+        ];
+
+        let mut stream = Cursor::new(data.clone());
+        let lenient = DecodeOptions::default();
+        let (packet, warnings) = stream.read_packet_with(&lenient).unwrap();
+        assert_eq!(packet, Packet::Pubrel(PacketIdentifier(1)));
+        match &warnings[..] {
+            [Error::InvalidReservedFlags] => (),
+            warnings => panic!("unexpected {:?}", warnings)
+        }
+        let (packet, warnings) = stream.read_packet_with(&lenient).unwrap();
+        assert_eq!(packet, Packet::Puback(PacketIdentifier(2)));
+        assert_eq!(warnings.len(), 1);
+        let (_, warnings) = stream.read_packet_with(&lenient).unwrap();
+        match &warnings[..] {
+            [Error::TopicNameMustNotContainWildcard, Error::InvalidReservedFlags] => (),
+            warnings => panic!("unexpected {:?}", warnings)
+        }
+
+        let mut stream = Cursor::new(data);
+        match stream.read_packet_with(&DecodeOptions::strict()) {
+            Err(Error::InvalidReservedFlags) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+    }
+
+    #[test]
+    fn read_packet_with_connect_test() {
+        let connect = |flags: u8| Cursor::new(vec![
+            0x10, 12,
+            0x00, 0x04, 'M' as u8, 'Q' as u8, 'T' as u8, 'T' as u8,
+            0x04,
+            flags,
+            0x00, 0x0a,
+            0x00, 0x00 // empty client_id
+        ]);
+        let options = DecodeOptions { allow_empty_client_id: false, .. DecodeOptions::default() };
+        match connect(0b10).read_packet_with(&options) {
+            Err(Error::EmptyClientId) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+
+        let (_, warnings) = connect(0b01).read_packet_with(&DecodeOptions::default()).unwrap();
+        match &warnings[..] {
+            [Error::InvalidReservedFlags, Error::EmptyClientId] => (),
+            warnings => panic!("unexpected {:?}", warnings)
+        }
+        let (_, warnings) = connect(0b10).read_packet_with(&DecodeOptions::strict()).unwrap();
+        assert!(warnings.is_empty());
+        assert!(connect(0b00).read_packet_with(&DecodeOptions::strict()).is_err());
+    }
+
+    #[test]
+    fn packet_reader_test() {
+        let data: &[u8] = &[0xD0, 0x00, 0x40, 0x02, 0x00, 0x07];
+        let mut reader = PacketReader::new(data);
+        assert_eq!(reader.read_packet().unwrap(), Packet::Pingresp);
+        assert_eq!(reader.read_packet().unwrap(), Packet::Puback(PacketIdentifier(7)));
+        assert!(reader.into_inner().is_empty());
+
+        let mut cursor = Cursor::new(&[0xD0u8, 0x00][..]);
+        assert_eq!(cursor.read_packet().unwrap(), Packet::Pingresp);
+    }
 }
@@ -0,0 +1,252 @@
+//! Return and reason codes of the acknowledgements and of DISCONNECT.
+//!
+//! MQTT 3.1.1 knows only the CONNACK and SUBACK return codes, MQTT 5 adds
+//! reason codes to the other acks. Each enum has the codes of both
+//! versions, a packet of 3.1.1 only ever carries the ones it defines.
+
+use std::convert::TryFrom;
+use {Error, QoS, SubscribeReturnCodes};
+
+macro_rules! reason_codes {
+    ($(#[$attr:meta])* pub enum $name:ident { $($(#[$vattr:meta])* $variant:ident = $code:literal),+ }
+     success $success:pat) => {
+        $(#[$attr])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub enum $name {
+            $($(#[$vattr])* $variant),+
+        }
+
+        impl $name {
+            pub fn to_u8(&self) -> u8 {
+                match *self {
+                    $($name::$variant => $code),+
+                }
+            }
+
+            pub fn is_success(&self) -> bool {
+                match self.to_u8() {
+                    $success => true,
+                    _ => false
+                }
+            }
+        }
+
+        impl TryFrom<u8> for $name {
+            type Error = Error;
+
+            fn try_from(code: u8) -> Result<$name, Error> {
+                match code {
+                    $($code => Ok($name::$variant),)+
+                    _ => Err(Error::UnsupportedReasonCode)
+                }
+            }
+        }
+    }
+}
+
+reason_codes! {
+    /// Return code of CONNACK, the refusals of MQTT 3.1.1 are below 0x80.
+    /// Codes of MQTT 5 which share the name of a 3.1.1 one end with `V5`.
+    pub enum ConnackCode {
+        Accepted = 0x00,
+        RefusedProtocolVersion = 0x01,
+        RefusedIdentifierRejected = 0x02,
+        ServerUnavailable = 0x03,
+        BadUsernamePassword = 0x04,
+        NotAuthorized = 0x05,
+        UnspecifiedError = 0x80,
+        MalformedPacket = 0x81,
+        ProtocolError = 0x82,
+        ImplementationSpecificError = 0x83,
+        UnsupportedProtocolVersion = 0x84,
+        ClientIdentifierNotValid = 0x85,
+        BadUserNameOrPassword = 0x86,
+        NotAuthorizedV5 = 0x87,
+        ServerUnavailableV5 = 0x88,
+        ServerBusy = 0x89,
+        Banned = 0x8A,
+        BadAuthenticationMethod = 0x8C,
+        TopicNameInvalid = 0x90,
+        PacketTooLarge = 0x95,
+        QuotaExceeded = 0x97,
+        PayloadFormatInvalid = 0x99,
+        RetainNotSupported = 0x9A,
+        QoSNotSupported = 0x9B,
+        UseAnotherServer = 0x9C,
+        ServerMoved = 0x9D,
+        ConnectionRateExceeded = 0x9F
+    }
+    success 0x00
+}
+
+impl ConnackCode {
+    /// Return code of MQTT 3.1.1, those of MQTT 5 are refused
+    pub fn from_u8(byte: u8) -> ::Result<ConnackCode> {
+        match ConnackCode::try_from(byte) {
+            Ok(code) if byte <= 0x05 => Ok(code),
+            _ => Err(Error::UnsupportedConnectReturnCode)
+        }
+    }
+}
+
+reason_codes! {
+    /// Return code of each filter of SUBACK. `Failure` is the only failure
+    /// of MQTT 3.1.1.
+    pub enum SubackCode {
+        GrantedQoS0 = 0x00,
+        GrantedQoS1 = 0x01,
+        GrantedQoS2 = 0x02,
+        Failure = 0x80,
+        ImplementationSpecificError = 0x83,
+        NotAuthorized = 0x87,
+        TopicFilterInvalid = 0x8F,
+        PacketIdentifierInUse = 0x91,
+        QuotaExceeded = 0x97,
+        SharedSubscriptionsNotSupported = 0x9E,
+        SubscriptionIdentifiersNotSupported = 0xA1,
+        WildcardSubscriptionsNotSupported = 0xA2
+    }
+    success 0x00..=0x7F
+}
+
+impl SubackCode {
+    pub fn granted_qos(&self) -> Option<QoS> {
+        match *self {
+            SubackCode::GrantedQoS0 => Some(QoS::AtMostOnce),
+            SubackCode::GrantedQoS1 => Some(QoS::AtLeastOnce),
+            SubackCode::GrantedQoS2 => Some(QoS::ExactlyOnce),
+            _ => None
+        }
+    }
+}
+
+impl From<SubscribeReturnCodes> for SubackCode {
+    fn from(code: SubscribeReturnCodes) -> SubackCode {
+        match code {
+            SubscribeReturnCodes::Success(QoS::AtMostOnce) => SubackCode::GrantedQoS0,
+            SubscribeReturnCodes::Success(QoS::AtLeastOnce) => SubackCode::GrantedQoS1,
+            SubscribeReturnCodes::Success(QoS::ExactlyOnce) => SubackCode::GrantedQoS2,
+            SubscribeReturnCodes::Failure => SubackCode::Failure
+        }
+    }
+}
+
+// Failures of MQTT 5 all become `Failure`
+impl From<SubackCode> for SubscribeReturnCodes {
+    fn from(code: SubackCode) -> SubscribeReturnCodes {
+        match code.granted_qos() {
+            Some(qos) => SubscribeReturnCodes::Success(qos),
+            None => SubscribeReturnCodes::Failure
+        }
+    }
+}
+
+reason_codes! {
+    /// Reason code of PUBACK and PUBREC (MQTT 5)
+    pub enum PubackReason {
+        Success = 0x00,
+        NoMatchingSubscribers = 0x10,
+        UnspecifiedError = 0x80,
+        ImplementationSpecificError = 0x83,
+        NotAuthorized = 0x87,
+        TopicNameInvalid = 0x90,
+        PacketIdentifierInUse = 0x91,
+        QuotaExceeded = 0x97,
+        PayloadFormatInvalid = 0x99
+    }
+    success 0x00..=0x7F
+}
+
+reason_codes! {
+    /// Reason code of PUBREL and PUBCOMP (MQTT 5)
+    pub enum PubrelReason {
+        Success = 0x00,
+        PacketIdentifierNotFound = 0x92
+    }
+    success 0x00..=0x7F
+}
+
+reason_codes! {
+    /// Reason code of each filter of UNSUBACK (MQTT 5)
+    pub enum UnsubackReason {
+        Success = 0x00,
+        NoSubscriptionExisted = 0x11,
+        UnspecifiedError = 0x80,
+        ImplementationSpecificError = 0x83,
+        NotAuthorized = 0x87,
+        TopicFilterInvalid = 0x8F,
+        PacketIdentifierInUse = 0x91
+    }
+    success 0x00..=0x7F
+}
+
+reason_codes! {
+    /// Reason code of DISCONNECT (MQTT 5), sent by either side
+    pub enum DisconnectReason {
+        NormalDisconnection = 0x00,
+        DisconnectWithWillMessage = 0x04,
+        UnspecifiedError = 0x80,
+        MalformedPacket = 0x81,
+        ProtocolError = 0x82,
+        ImplementationSpecificError = 0x83,
+        NotAuthorized = 0x87,
+        ServerBusy = 0x89,
+        ServerShuttingDown = 0x8B,
+        KeepAliveTimeout = 0x8D,
+        SessionTakenOver = 0x8E,
+        TopicFilterInvalid = 0x8F,
+        TopicNameInvalid = 0x90,
+        ReceiveMaximumExceeded = 0x93,
+        TopicAliasInvalid = 0x94,
+        PacketTooLarge = 0x95,
+        MessageRateTooHigh = 0x96,
+        QuotaExceeded = 0x97,
+        AdministrativeAction = 0x98,
+        PayloadFormatInvalid = 0x99,
+        RetainNotSupported = 0x9A,
+        QoSNotSupported = 0x9B,
+        UseAnotherServer = 0x9C,
+        ServerMoved = 0x9D,
+        SharedSubscriptionsNotSupported = 0x9E,
+        ConnectionRateExceeded = 0x9F,
+        MaximumConnectTime = 0xA0,
+        SubscriptionIdentifiersNotSupported = 0xA1,
+        WildcardSubscriptionsNotSupported = 0xA2
+    }
+    success 0x00..=0x7F
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::TryFrom;
+    use {QoS, SubscribeReturnCodes};
+    use super::{ConnackCode, SubackCode, PubackReason, DisconnectReason};
+
+    #[test]
+    fn reason_code_test() {
+        for byte in 0..=255u8 {
+            if let Ok(code) = DisconnectReason::try_from(byte) {
+                assert_eq!(code.to_u8(), byte);
+            }
+            if let Ok(code) = SubackCode::try_from(byte) {
+                assert_eq!(code.to_u8(), byte);
+            }
+        }
+        assert!(DisconnectReason::try_from(0x01).is_err());
+        assert!(DisconnectReason::DisconnectWithWillMessage.is_success());
+        assert!(!DisconnectReason::SessionTakenOver.is_success());
+        assert!(PubackReason::NoMatchingSubscribers.is_success());
+        assert!(!PubackReason::try_from(0x87).unwrap().is_success());
+
+        // MQTT 3.1.1 refusals
+        assert!(ConnackCode::Accepted.is_success());
+        assert!(!ConnackCode::NotAuthorized.is_success());
+        assert_eq!(ConnackCode::try_from(0x87).unwrap(), ConnackCode::NotAuthorizedV5);
+        assert_eq!(ConnackCode::from_u8(0x05).unwrap(), ConnackCode::NotAuthorized);
+        assert!(ConnackCode::from_u8(0x87).is_err());
+
+        assert_eq!(SubackCode::from(SubscribeReturnCodes::Success(QoS::AtLeastOnce)), SubackCode::GrantedQoS1);
+        assert_eq!(SubscribeReturnCodes::from(SubackCode::QuotaExceeded), SubscribeReturnCodes::Failure);
+        assert_eq!(SubackCode::GrantedQoS2.granted_qos(), Some(QoS::ExactlyOnce));
+    }
+}
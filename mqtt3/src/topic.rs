@@ -2,6 +2,8 @@ use std::vec::IntoIter;
 use {Error, Result};
 
 const TOPIC_PATH_DELIMITER: char = '/';
+// Topics are prefixed by a two byte length on the wire
+const MAX_TOPIC_LEN: usize = 65535;
 
 use self::Topic::{
     Normal,
@@ -114,17 +116,41 @@ impl TopicPath {
         }
     }
 
+    /// Whether this topic name matches `filter`. A wildcard at the first
+    /// level doesn't match a level starting with `$`.
+    pub fn matches(&self, filter: &TopicPath) -> bool {
+        for (i, pattern) in filter.topics.iter().enumerate() {
+            let topic = self.topics.get(i);
+            let system = i == 0 && match topic {
+                Some(&Topic::System(_)) => true,
+                _ => false
+            };
+            match *pattern {
+                // takes the parent level too, `a/#` matches `a`
+                Topic::MultiWildcard => return !system,
+                Topic::SingleWildcard => {
+                    if topic.is_none() || system {
+                        return false;
+                    }
+                },
+                _ => {
+                    if topic != Some(pattern) {
+                        return false;
+                    }
+                }
+            }
+        }
+        self.topics.len() == filter.topics.len()
+    }
+
     pub fn from_str<T: AsRef<str>>(path: T) -> Result<TopicPath> {
-        let mut valid = true;
+        try!(validate_topic_filter(path.as_ref()));
         let topics: Vec<Topic> = path.as_ref().split(TOPIC_PATH_DELIMITER).map( |topic| {
             match topic {
                 "+" => Topic::SingleWildcard,
                 "#" => Topic::MultiWildcard,
                 "" => Topic::Blank,
                 _ => {
-                    if !Topic::validate(topic) {
-                        valid = false;
-                    }
                     if topic.chars().nth(0) == Some('$') {
                         Topic::System(String::from(topic))
                     } else {
@@ -134,9 +160,6 @@ impl TopicPath {
             }
         }).collect();
 
-        if !valid {
-            return Err(Error::InvalidTopicPath);
-        }
         // check for wildcards
         let wildcards = topics.iter().any(|topic| {
             match *topic {
@@ -153,6 +176,69 @@ impl TopicPath {
     }
 }
 
+/// Checks a topic name to publish to: it's not empty, at most 65535 bytes
+/// long, has no null character and no wildcards. Empty levels (`a//b`) and
+/// a leading `$` are allowed, though brokers usually refuse publishing to
+/// `$` topics.
+pub fn validate_topic_name(name: &str) -> Result<()> {
+    try!(validate_topic_filter(name));
+    if name.contains(|c| c == '+' || c == '#') {
+        return Err(Error::TopicNameMustNotContainWildcard);
+    }
+    Ok(())
+}
+
+/// Checks a topic filter to subscribe to. The rules of topic names apply,
+/// except that `+` may fill a whole level and `#` the whole last level.
+pub fn validate_topic_filter(filter: &str) -> Result<()> {
+    if filter.is_empty() {
+        return Err(Error::TopicMustNotBeEmpty);
+    }
+    if filter.len() > MAX_TOPIC_LEN {
+        return Err(Error::TopicTooLong);
+    }
+    if filter.contains('\0') {
+        return Err(Error::TopicMustNotContainNull);
+    }
+    let mut levels = filter.split(TOPIC_PATH_DELIMITER).peekable();
+    while let Some(level) = levels.next() {
+        match level {
+            "+" => (),
+            "#" if levels.peek().is_none() => (),
+            _ => {
+                if level.contains(|c| c == '+' || c == '#') {
+                    return Err(Error::InvalidTopicPath);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The topics each filter matches, in the order of `filters`. Shows what a
+/// set of subscriptions or ACL rules covers; fails on the first invalid
+/// filter or topic name.
+pub fn expand_filters<'a, F, T>(filters: &[F], topics: &'a [T]) -> Result<Vec<Vec<&'a str>>>
+    where F: AsRef<str>, T: AsRef<str>
+{
+    let mut names = Vec::with_capacity(topics.len());
+    for topic in topics {
+        try!(validate_topic_name(topic.as_ref()));
+        names.push(TopicPath::from(topic.as_ref()));
+    }
+    let mut expanded = Vec::with_capacity(filters.len());
+    for filter in filters {
+        try!(validate_topic_filter(filter.as_ref()));
+        let filter = TopicPath::from(filter.as_ref());
+        expanded.push(names.iter()
+                           .zip(topics)
+                           .filter(|&(name, _)| name.matches(&filter))
+                           .map(|(_, topic)| topic.as_ref())
+                           .collect());
+    }
+    Ok(expanded)
+}
+
 impl IntoIterator for TopicPath {
     type Item = Topic;
     type IntoIter = IntoIter<Topic>;
@@ -211,7 +297,8 @@ impl<'a> ToTopicPath for &'a str {
 
 #[cfg(test)]
 mod test {
-    use super::{TopicPath, Topic};
+    use super::{TopicPath, Topic, validate_topic_name, validate_topic_filter, expand_filters};
+    use Error;
 
     #[test]
     fn topic_path_test() {
@@ -225,6 +312,37 @@ mod test {
         assert_eq!(iter.next().unwrap(), Topic::MultiWildcard);
     }
 
+    #[test]
+    fn topic_matches_test() {
+        let name = TopicPath::from("sensors/kitchen/temperature");
+        for filter in ["sensors/kitchen/temperature", "sensors/+/temperature", "sensors/#", "#", "+/+/+"].iter() {
+            assert!(name.matches(&TopicPath::from(*filter)), "{}", filter);
+        }
+        for filter in ["sensors/kitchen", "sensors/+", "+/+/+/+", "sensors/hall/temperature"].iter() {
+            assert!(!name.matches(&TopicPath::from(*filter)), "{}", filter);
+        }
+        assert!(TopicPath::from("sensors").matches(&TopicPath::from("sensors/#")));
+        assert!(TopicPath::from("/a").matches(&TopicPath::from("+/a")));
+        let system = TopicPath::from("$SYS/uptime");
+        assert!(!system.matches(&TopicPath::from("#")));
+        assert!(!system.matches(&TopicPath::from("+/uptime")));
+        assert!(system.matches(&TopicPath::from("$SYS/#")));
+    }
+
+    #[test]
+    fn expand_filters_test() {
+        let topics = ["home/kitchen/light", "home/hall/light", "home/hall/door", "$SYS/uptime"];
+        let expanded = expand_filters(&["home/+/light", "home/hall/#", "#", "office/#"], &topics).unwrap();
+        assert_eq!(expanded, vec![
+            vec!["home/kitchen/light", "home/hall/light"],
+            vec!["home/hall/light", "home/hall/door"],
+            vec!["home/kitchen/light", "home/hall/light", "home/hall/door"],
+            vec![]
+        ]);
+        assert!(expand_filters(&["home/#/light"], &topics).is_err());
+        assert!(expand_filters(&["#"], &["home/+"]).is_err());
+    }
+
     #[test]
     fn wildcards_test() {
         let topic = TopicPath::from("/a/b/c");
@@ -240,5 +358,43 @@ mod test {
         assert!(TopicPath::from_str("+wrong").is_err());
         assert!(TopicPath::from_str("wro#ng").is_err());
         assert!(TopicPath::from_str("w/r/o/n/g+").is_err());
+        assert!(TopicPath::from_str("w/#/rong").is_err());
+        assert!(TopicPath::from_str("").is_err());
+    }
+
+    #[test]
+    fn validate_topic_name_test() {
+        assert!(validate_topic_name("a/b").is_ok());
+        assert!(validate_topic_name("/a//b/").is_ok());
+        assert!(validate_topic_name("$SYS/uptime").is_ok());
+        match validate_topic_name("a/+") {
+            Err(Error::TopicNameMustNotContainWildcard) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+        match validate_topic_name("") {
+            Err(Error::TopicMustNotBeEmpty) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+        match validate_topic_name("a\0b") {
+            Err(Error::TopicMustNotContainNull) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+        match validate_topic_name(&"a".repeat(65536)) {
+            Err(Error::TopicTooLong) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+    }
+
+    #[test]
+    fn validate_topic_filter_test() {
+        for filter in &["#", "+", "a/#", "+/+/c", "/+", "$SYS/#"] {
+            assert!(validate_topic_filter(filter).is_ok(), "{} should be valid", filter);
+        }
+        for filter in &["a/#/c", "a#", "a/b+", "#/", "++"] {
+            match validate_topic_filter(filter) {
+                Err(Error::InvalidTopicPath) => (),
+                result => panic!("unexpected {:?} for {}", result, filter)
+            }
+        }
     }
 }
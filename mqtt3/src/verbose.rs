@@ -0,0 +1,287 @@
+//! Decoding that explains itself. On failure `decode_verbose` gives, along
+//! with the error, the fields the bytes were laid out in and the one the
+//! decoder was at, which is what it takes to see what a device got wrong.
+
+use std::cmp;
+use std::fmt;
+use std::io::Cursor;
+use std::result;
+use mqtt::Packet;
+use read::{MqttRead, DecodeOptions};
+use {Error, PacketType};
+
+/// Bytes `start..end` of a packet, `end` lies past the buffer for a field
+/// that is cut short
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Field {
+    pub name: &'static str,
+    pub start: usize,
+    pub end: usize
+}
+
+/// Error of `decode_verbose`. Its `Display` is the error followed by the
+/// fields, one to a line, with the failing one marked.
+#[derive(Debug)]
+pub struct DecodeFailure {
+    pub error: Error,
+    /// Bytes the decoder had read when it failed
+    pub offset: usize,
+    pub fields: Vec<Field>,
+    bytes: Vec<u8>
+}
+
+/// Decodes the packet at the start of `buf` like `read_packet_with`,
+/// returning it with the warnings of lenient mode
+pub fn decode_verbose(buf: &[u8], options: &DecodeOptions) -> result::Result<(Packet, Vec<Error>), DecodeFailure> {
+    let mut cursor = Cursor::new(buf);
+    cursor.read_packet_with(options).map_err(|err| DecodeFailure {
+        error: err,
+        offset: cursor.position() as usize,
+        fields: layout(buf),
+        bytes: buf.to_vec()
+    })
+}
+
+impl DecodeFailure {
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// The field the decoder failed in. Errors about a particular field
+    /// point at it, the others at the field the decoder stopped in.
+    pub fn field(&self) -> Option<&Field> {
+        let typ = self.bytes.first().and_then(|&hd| PacketType::from_hd(hd).ok());
+        let name = match (&self.error, typ) {
+            (&Error::UnsupportedPacketType, _) => Some("fixed header"),
+            (&Error::InvalidReservedFlags, Some(PacketType::Connect)) if self.bytes[0] & 0x0F == 0 => Some("connect flags"),
+            (&Error::InvalidReservedFlags, _) => Some("fixed header"),
+            (&Error::UnsupportedQualityOfService, Some(PacketType::Publish)) => Some("fixed header"),
+            (&Error::UnsupportedQualityOfService, Some(PacketType::Connect)) => Some("connect flags"),
+            (&Error::MalformedRemainingLength, _) |
+            (&Error::OverlongRemainingLength, _) |
+            (&Error::PacketTooLarge, _) |
+            (&Error::PayloadRequired, _) => Some("remaining length"),
+            (&Error::UnsupportedProtocolName, _) => Some("protocol name"),
+            (&Error::UnsupportedProtocolVersion, _) => Some("protocol level"),
+            (&Error::EmptyClientId, _) => Some("client id"),
+            (&Error::UnsupportedConnectReturnCode, _) |
+            (&Error::UnsupportedReasonCode, _) => Some("return code"),
+            (&Error::InvalidTopicPath, _) |
+            (&Error::TopicNameMustNotContainWildcard, _) |
+            (&Error::TopicMustNotBeEmpty, _) |
+            (&Error::TopicMustNotContainNull, _) |
+            (&Error::TopicTooLong, _) => Some("topic name"),
+            _ => None
+        };
+        if let Some(field) = name.and_then(|name| self.fields.iter().find(|field| field.name == name)) {
+            return Some(field);
+        }
+        // Running out of bytes fails in the field that is cut short, other
+        // errors come once the field is read
+        let short = match self.error {
+            Error::UnexpectedEof | Error::Io(_) => true,
+            _ => false
+        };
+        if short {
+            if let Some(field) = self.fields.iter().find(|field| field.start <= self.offset && self.offset < field.end) {
+                return Some(field);
+            }
+        }
+        self.fields.iter().rev().find(|field| field.start < self.offset)
+    }
+}
+
+impl fmt::Display for DecodeFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let failed = self.field();
+        try!(write!(f, "{} at byte {}", self.error, self.offset));
+        if let Some(field) = failed {
+            try!(write!(f, ", in the {}", field.name));
+        }
+        for field in &self.fields {
+            let shown = &self.bytes[cmp::min(field.start, self.bytes.len())..cmp::min(field.end, self.bytes.len())];
+            let mut hex: Vec<String> = shown.iter().take(8).map(|byte| format!("{:02x}", byte)).collect();
+            if shown.len() > 8 {
+                hex.push("..".to_string());
+            }
+            try!(write!(f, "\n  {:04x}  {:<26} {}", field.start, hex.join(" "), field.name));
+            if shown.len() < field.end - field.start {
+                try!(write!(f, " ({} bytes missing)", field.end - field.start - shown.len()));
+            }
+            if failed == Some(field) {
+                try!(f.write_str("  <--"));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Lays out a packet as far as its bytes tell
+struct Walker<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    end: usize,
+    fields: Vec<Field>
+}
+
+impl<'a> Walker<'a> {
+    fn field(&mut self, name: &'static str, len: usize) {
+        if self.pos < self.end {
+            self.fields.push(Field { name: name, start: self.pos, end: self.pos + len });
+            self.pos += len;
+        }
+    }
+
+    fn byte(&self) -> Option<u8> {
+        if self.pos < self.end { self.buf.get(self.pos).cloned() } else { None }
+    }
+
+    // A length prefixed string, false if its length isn't there
+    fn string(&mut self, name: &'static str) -> bool {
+        match (self.buf.get(self.pos), self.buf.get(self.pos + 1)) {
+            (Some(&high), Some(&low)) => {
+                self.field(name, 2 + ((high as usize) << 8 | low as usize));
+                true
+            }
+            _ => {
+                self.field(name, 2);
+                false
+            }
+        }
+    }
+
+    // Whether bytes of the packet are left in the buffer
+    fn more(&self) -> bool {
+        self.pos < cmp::min(self.end, self.buf.len())
+    }
+}
+
+fn layout(buf: &[u8]) -> Vec<Field> {
+    let mut walker = Walker { buf: buf, pos: 0, end: 1, fields: Vec::new() };
+    walker.field("fixed header", 1);
+    let mut len = 0;
+    let mut encoded = 0;
+    let mut complete = false;
+    while encoded < 4 && !complete {
+        match buf.get(1 + encoded) {
+            Some(&byte) => {
+                len += ((byte & 0x7F) as usize) << (7 * encoded);
+                complete = byte & 0x80 == 0;
+                encoded += 1;
+            }
+            None => {
+                encoded += 1;
+                break;
+            }
+        }
+    }
+    walker.end = 1 + encoded;
+    walker.field("remaining length", encoded);
+    let typ = match buf.first().map(|&hd| PacketType::from_hd(hd)) {
+        Some(Ok(typ)) if complete => typ,
+        _ => return walker.fields
+    };
+    walker.end += len;
+
+    match typ {
+        PacketType::Connect => {
+            if walker.string("protocol name") {
+                walker.field("protocol level", 1);
+                let flags = walker.byte();
+                walker.field("connect flags", 1);
+                walker.field("keep alive", 2);
+                if walker.string("client id") {
+                    let flags = flags.unwrap_or(0);
+                    let mut known = true;
+                    if flags & 0b100 != 0 {
+                        known = walker.string("will topic") && walker.string("will message");
+                    }
+                    if known && flags & 0b10000000 != 0 {
+                        known = walker.string("username");
+                    }
+                    if known && flags & 0b01000000 != 0 {
+                        walker.string("password");
+                    }
+                }
+            }
+        },
+        PacketType::Connack => {
+            walker.field("acknowledge flags", 1);
+            walker.field("return code", 1);
+        },
+        PacketType::Publish => {
+            if walker.string("topic name") {
+                if buf[0] & 0b110 != 0 {
+                    walker.field("packet identifier", 2);
+                }
+                let rest = walker.end.saturating_sub(walker.pos);
+                walker.field("payload", rest);
+            }
+        },
+        PacketType::Puback | PacketType::Pubrec | PacketType::Pubrel |
+        PacketType::Pubcomp | PacketType::Unsuback => walker.field("packet identifier", 2),
+        PacketType::Subscribe => {
+            walker.field("packet identifier", 2);
+            while walker.more() && walker.string("topic filter") {
+                walker.field("requested qos", 1);
+            }
+        },
+        PacketType::Suback => {
+            walker.field("packet identifier", 2);
+            while walker.more() {
+                walker.field("return code", 1);
+            }
+        },
+        PacketType::Unsubscribe => {
+            walker.field("packet identifier", 2);
+            while walker.more() && walker.string("topic filter") {}
+        },
+        _ => ()
+    }
+    if walker.more() {
+        let rest = cmp::min(walker.end, buf.len()) - walker.pos;
+        walker.field("unexpected bytes", rest);
+    }
+    walker.fields
+}
+
+#[cfg(test)]
+mod test {
+    use read::DecodeOptions;
+    use mqtt::Packet;
+    use super::decode_verbose;
+
+    #[test]
+    fn decode_verbose_test() {
+        // the topic says 3 bytes, 1 is there
+        let failure = decode_verbose(&[0x30, 0x07, 0x00, 0x03, b'a'], &DecodeOptions::default()).unwrap_err();
+        assert_eq!(failure.offset, 5);
+        assert_eq!(failure.field().unwrap().name, "topic name");
+        let shown = failure.to_string();
+        let lines: Vec<&str> = shown.lines().skip(1).collect();
+        assert_eq!(lines, vec![
+            "  0000  30                         fixed header",
+            "  0001  07                         remaining length",
+            "  0002  00 03 61                   topic name (2 bytes missing)  <--",
+            "  0007                             payload (2 bytes missing)"
+        ]);
+
+        // MQTT 5
+        let connect = [0x10, 0x0D, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x05, 0x02, 0x00, 0x3C, 0x00, 0x01, b'c'];
+        let failure = decode_verbose(&connect, &DecodeOptions::default()).unwrap_err();
+        assert_eq!(failure.field().unwrap().name, "protocol level");
+        assert_eq!(failure.fields.iter().map(|field| field.name).collect::<Vec<_>>(),
+                   vec!["fixed header", "remaining length", "protocol name", "protocol level",
+                        "connect flags", "keep alive", "client id"]);
+
+        // wrong flags on a SUBSCRIBE
+        let subscribe = [0x80, 0x06, 0x00, 0x01, 0x00, 0x01, b'a', 0x00];
+        let failure = decode_verbose(&subscribe, &DecodeOptions::strict()).unwrap_err();
+        assert_eq!(failure.field().unwrap().name, "fixed header");
+        assert!(failure.to_string().starts_with("InvalidReservedFlags at byte 2, in the fixed header"));
+        match decode_verbose(&subscribe, &DecodeOptions::default()) {
+            Ok((Packet::Subscribe(_), warnings)) => assert_eq!(warnings.len(), 1),
+            result => panic!("unexpected {:?}", result)
+        }
+    }
+}
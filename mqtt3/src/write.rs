@@ -1,7 +1,7 @@
 use byteorder::{WriteBytesExt, BigEndian};
-use std::io::{BufWriter, Write, Cursor};
+use std::io::{self, BufWriter, ErrorKind, IoSlice, Write, Cursor};
 use std::net::TcpStream;
-use {Packet, QoS, Error, Result, MAX_PAYLOAD_SIZE, SubscribeTopic, SubscribeReturnCodes};
+use {Packet, QoS, Error, Result, SubscribeTopic, SubackCode, encode_remaining_length};
 
 pub trait MqttWrite: WriteBytesExt {
     fn write_packet(&mut self, packet: &Packet) -> Result<()> {
@@ -9,17 +9,7 @@ pub trait MqttWrite: WriteBytesExt {
             &Packet::Connect(ref connect) => {
                 try!(self.write_u8(0b00010000));
                 let prot_name = connect.protocol.name();
-                let mut len = 8 + prot_name.len() + connect.client_id.len();
-                if let Some(ref last_will) = connect.last_will {
-                    len += 4 + last_will.topic.len() + last_will.message.len();
-                }
-                if let Some(ref username) = connect.username {
-                    len += 2 + username.len();
-                }
-                if let Some(ref password) = connect.password {
-                    len += 2 + password.len();
-                }
-                try!(self.write_remaining_length(len));
+                try!(self.write_remaining_length(packet.remaining_length()));
                 try!(self.write_mqtt_string(prot_name));
                 try!(self.write_u8(connect.protocol.level()));
                 let mut connect_flags = 0;
@@ -59,19 +49,17 @@ pub trait MqttWrite: WriteBytesExt {
                 Ok(())
             },
 			&Packet::Publish(ref publish) => {
-                try!(self.write_u8(0b00110000 | publish.retain as u8 | (publish.qos.to_u8() << 1) | ((publish.dup as u8) << 3)));
-                let mut len = publish.topic_name.len() + 2 + publish.payload.len();
-                if publish.qos != QoS::AtMostOnce && None != publish.pid {
-                    len += 2;
-                }
-                try!(self.write_remaining_length(len));
-                try!(self.write_mqtt_string(publish.topic_name.as_str()));
+                // Only the header is put together, the payload isn't copied
+                let mut header = Cursor::new(Vec::with_capacity(9 + publish.topic_name.len()));
+                try!(header.write_u8(0b00110000 | publish.retain as u8 | (publish.qos.to_u8() << 1) | ((publish.dup as u8) << 3)));
+                try!(header.write_remaining_length(packet.remaining_length()));
+                try!(header.write_mqtt_string(publish.topic_name.as_str()));
                 if publish.qos != QoS::AtMostOnce {
                     if let Some(pid) = publish.pid {
-                        try!(self.write_u16::<BigEndian>(pid.0));
+                        try!(header.write_u16::<BigEndian>(pid.0));
                     }
                 }
-                try!(self.write(&publish.payload.as_ref()));
+                try!(write_all_vectored(self, header.get_ref(), &publish.payload));
                 Ok(())
             },
 			&Packet::Puback(ref pid) => {
@@ -96,8 +84,7 @@ pub trait MqttWrite: WriteBytesExt {
             },
 			&Packet::Subscribe(ref subscribe) => {
                 try!(self.write(&[0x82]));
-                let len = 2 + subscribe.topics.iter().fold(0, |s, ref t| s + t.topic_path.len() + 3);
-                try!(self.write_remaining_length(len));
+                try!(self.write_remaining_length(packet.remaining_length()));
                 try!(self.write_u16::<BigEndian>(subscribe.pid.0));
                 for topic in subscribe.topics.as_ref() as &Vec<SubscribeTopic> {
                     try!(self.write_mqtt_string(topic.topic_path.as_str()));
@@ -107,21 +94,17 @@ pub trait MqttWrite: WriteBytesExt {
             },
 			&Packet::Suback(ref suback) => {
                 try!(self.write(&[0x90]));
-                try!(self.write_remaining_length(suback.return_codes.len() + 2));
+                try!(self.write_remaining_length(packet.remaining_length()));
                 try!(self.write_u16::<BigEndian>(suback.pid.0));
                 let payload: Vec<u8> = suback.return_codes.iter().map({ |&code|
-                    match code {
-                        SubscribeReturnCodes::Success(qos) => qos.to_u8(),
-                        SubscribeReturnCodes::Failure => 0x80
-                    }
+                    SubackCode::from(code).to_u8()
                 }).collect();
                 try!(self.write(&payload));
                 Ok(())
             },
 			&Packet::Unsubscribe(ref unsubscribe) => {
                 try!(self.write(&[0xA2]));
-                let len = 2 + unsubscribe.topics.iter().fold(0, |s, ref topic| s + topic.len() + 2);
-                try!(self.write_remaining_length(len));
+                try!(self.write_remaining_length(packet.remaining_length()));
                 try!(self.write_u16::<BigEndian>(unsubscribe.pid.0));
                 for topic in unsubscribe.topics.as_ref() as &Vec<String> {
                     try!(self.write_mqtt_string(topic.as_str()));
@@ -148,6 +131,14 @@ pub trait MqttWrite: WriteBytesExt {
         }
     }
 
+    /// Writes the packet unless it's larger than `max_packet_size` bytes
+    fn write_packet_limited(&mut self, packet: &Packet, max_packet_size: usize) -> Result<()> {
+        if packet.size() > max_packet_size {
+            return Err(Error::PacketTooLarge);
+        }
+        self.write_packet(packet)
+    }
+
     fn write_mqtt_string(&mut self, string: &str) -> Result<()> {
         try!(self.write_u16::<BigEndian>(string.len() as u16));
         try!(self.write(string.as_bytes()));
@@ -155,25 +146,30 @@ pub trait MqttWrite: WriteBytesExt {
     }
 
     fn write_remaining_length(&mut self, len: usize) -> Result<()> {
-        if len > MAX_PAYLOAD_SIZE {
-            return Err(Error::PayloadTooLong);
-        }
-
-        let mut done = false;
-        let mut x = len;
+        let mut buf = [0; 4];
+        let size = try!(encode_remaining_length(len, &mut buf));
+        try!(self.write_all(&buf[..size]));
+        Ok(())
+    }
+}
 
-        while !done {
-            let mut byte = (x % 128) as u8;
-            x = x / 128;
-            if x > 0 {
-                byte = byte | 128;
-            }
-            try!(self.write_u8(byte));
-            done = x <= 0;
+// Writes both with `write_vectored` until everything is written
+fn write_all_vectored<W: Write + ?Sized>(writer: &mut W, header: &[u8], payload: &[u8]) -> io::Result<()> {
+    let mut written = 0;
+    while written < header.len() + payload.len() {
+        let result = if written < header.len() {
+            writer.write_vectored(&[IoSlice::new(&header[written..]), IoSlice::new(payload)])
+        } else {
+            writer.write(&payload[written - header.len()..])
+        };
+        match result {
+            Ok(0) => return Err(io::Error::new(ErrorKind::WriteZero, "failed to write whole packet")),
+            Ok(n) => written += n,
+            Err(ref err) if err.kind() == ErrorKind::Interrupted => (),
+            Err(err) => return Err(err)
         }
-
-        Ok(())
     }
+    Ok(())
 }
 
 impl MqttWrite for TcpStream {}
@@ -182,10 +178,10 @@ impl<T: Write> MqttWrite for BufWriter<T> {}
 
 #[cfg(test)]
 mod test {
-    use std::io::Cursor;
+    use std::io::{self, Cursor, IoSlice, Write};
     use std::sync::Arc;
     use super::{MqttWrite};
-    use super::super::{Protocol, LastWill, QoS, PacketIdentifier, ConnectReturnCode, SubscribeTopic};
+    use super::super::{Error, Protocol, LastWill, QoS, PacketIdentifier, ConnackCode, SubscribeTopic};
     use super::super::mqtt::{
         Packet,
         Connect,
@@ -255,7 +251,7 @@ mod test {
     fn write_packet_connack_test() {
         let connack = Packet::Connack(Connack {
             session_present: true,
-            code: ConnectReturnCode::Accepted
+            code: ConnackCode::Accepted
         });
 
         let mut stream = Cursor::new(Vec::new());
@@ -281,6 +277,48 @@ mod test {
         assert_eq!(stream.get_ref().clone(), vec![0b00110010, 11, 0x00, 0x03, 'a' as u8, '/' as u8, 'b' as u8, 0x00, 0x0a, 0xF1, 0xF2, 0xF3, 0xF4]);
     }
 
+    // Takes at most 3 bytes a call, vectored or not
+    struct Trickle(Vec<u8>);
+
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = buf.len().min(3);
+            self.0.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+            let mut n = 0;
+            for buf in bufs {
+                let taken = buf.len().min(3 - n);
+                self.0.extend_from_slice(&buf[..taken]);
+                n += taken;
+            }
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl MqttWrite for Trickle {}
+
+    #[test]
+    fn write_packet_publish_partial_writes_test() {
+        let publish = Packet::Publish(Box::new(Publish {
+            dup: false,
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            topic_name: "a/b".to_owned(),
+            pid: Some(PacketIdentifier(10)),
+            payload: Arc::new(vec![0xF1, 0xF2, 0xF3, 0xF4])
+        }));
+        let mut trickle = Trickle(Vec::new());
+        trickle.write_packet(&publish).unwrap();
+        assert_eq!(trickle.0, vec![0x32, 11, 0x00, 0x03, b'a', b'/', b'b', 0x00, 10, 0xF1, 0xF2, 0xF3, 0xF4]);
+    }
+
     #[test]
     fn write_packet_publish_at_most_once_test() {
         let publish = Packet::Publish(Box::new(Publish {
@@ -322,4 +360,51 @@ mod test {
             0x02 // qos = 2
         ]);
     }
+
+    #[test]
+    fn packet_size_test() {
+        let packets = vec![
+            Packet::Publish(Box::new(Publish {
+                dup: false,
+                qos: QoS::AtLeastOnce,
+                retain: false,
+                topic_name: "a/b".to_owned(),
+                pid: Some(PacketIdentifier(10)),
+                payload: Arc::new(vec![0; 200])
+            })),
+            Packet::Subscribe(Box::new(Subscribe {
+                pid: PacketIdentifier(1),
+                topics: vec![SubscribeTopic { topic_path: "a/+".to_owned(), qos: QoS::AtMostOnce }]
+            })),
+            Packet::Puback(PacketIdentifier(1)),
+            Packet::Pingreq
+        ];
+
+        for packet in packets {
+            let mut stream = Cursor::new(Vec::new());
+            stream.write_packet(&packet).unwrap();
+            assert_eq!(packet.size(), stream.get_ref().len());
+        }
+    }
+
+    #[test]
+    fn write_packet_limited_test() {
+        let publish = Packet::Publish(Box::new(Publish {
+            dup: false,
+            qos: QoS::AtMostOnce,
+            retain: false,
+            topic_name: "a/b".to_owned(),
+            pid: None,
+            payload: Arc::new(vec![0xE1, 0xE2, 0xE3, 0xE4])
+        }));
+
+        let mut stream = Cursor::new(Vec::new());
+        match stream.write_packet_limited(&publish, 10) {
+            Err(Error::PacketTooLarge) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+        assert!(stream.get_ref().is_empty());
+        stream.write_packet_limited(&publish, 11).unwrap();
+        assert_eq!(stream.get_ref().len(), 11);
+    }
 }
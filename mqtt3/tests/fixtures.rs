@@ -0,0 +1,120 @@
+//! Decodes the packet fixtures in `tests/data`, see the README there.
+
+extern crate mqtt3;
+
+use std::fs::{self, File};
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+use mqtt3::{MqttRead, MqttWrite, Packet, Protocol, QoS, PacketIdentifier, SubscribeReturnCodes};
+
+fn data_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("data")
+}
+
+fn load<P: AsRef<Path>>(path: P) -> Vec<u8> {
+    let mut text = String::new();
+    File::open(path.as_ref()).unwrap().read_to_string(&mut text).unwrap();
+    let mut digits = String::new();
+    for line in text.lines() {
+        let data = line.split('#').next().unwrap();
+        digits.extend(data.chars().filter(|c| !c.is_whitespace()));
+    }
+    assert!(digits.len() % 2 == 0, "odd number of hex digits in {:?}", path.as_ref());
+    (0..digits.len()).step_by(2).map(|i| {
+        u8::from_str_radix(&digits[i..i + 2], 16).unwrap()
+    }).collect()
+}
+
+fn decode(data: &[u8]) -> Vec<Packet> {
+    let mut stream = Cursor::new(data.to_vec());
+    let mut packets = Vec::new();
+    while (stream.position() as usize) < data.len() {
+        packets.push(stream.read_packet().unwrap());
+    }
+    packets
+}
+
+fn fixture(name: &str) -> Vec<Packet> {
+    decode(&load(data_dir().join(name)))
+}
+
+#[test]
+fn fixtures_roundtrip_test() {
+    for entry in fs::read_dir(data_dir()).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().map_or(true, |ext| ext != "hex") {
+            continue;
+        }
+        let data = load(&path);
+        let packets = decode(&data);
+        assert!(!packets.is_empty(), "no packets in {:?}", path);
+
+        let mut stream = Cursor::new(Vec::new());
+        for packet in packets.iter() {
+            stream.write_packet(packet).unwrap();
+        }
+        assert_eq!(stream.into_inner(), data, "{:?} encodes differently", path);
+    }
+}
+
+#[test]
+fn fixture_connect_mqisdp_test() {
+    match fixture("connect_mqisdp.hex")[0] {
+        Packet::Connect(ref connect) => {
+            assert_eq!(connect.protocol, Protocol::MQIsdp(3));
+            assert_eq!(connect.client_id, "paho1");
+            assert_eq!(connect.keep_alive, 60);
+        }
+        ref packet => panic!("unexpected {:?}", packet)
+    }
+}
+
+#[test]
+fn fixture_connect_will_credentials_test() {
+    match fixture("connect_will_credentials.hex")[0] {
+        Packet::Connect(ref connect) => {
+            assert_eq!(connect.client_id, "");
+            assert!(connect.clean_session);
+            let will = connect.last_will.as_ref().unwrap();
+            assert_eq!((will.topic.as_ref(), will.message.as_ref()), ("w", "bye"));
+            assert_eq!(will.qos, QoS::AtLeastOnce);
+            assert!(will.retain);
+            assert_eq!(connect.username, Some("u".to_string()));
+            assert_eq!(connect.password, Some("p".to_string()));
+        }
+        ref packet => panic!("unexpected {:?}", packet)
+    }
+}
+
+#[test]
+fn fixture_session_test() {
+    let packets = fixture("session.hex");
+    assert_eq!(packets.len(), 4);
+    match packets[1] {
+        Packet::Suback(ref suback) => {
+            assert_eq!(suback.return_codes, vec![SubscribeReturnCodes::Success(QoS::AtLeastOnce),
+                                                 SubscribeReturnCodes::Failure]);
+        }
+        ref packet => panic!("unexpected {:?}", packet)
+    }
+    match packets[2] {
+        Packet::Publish(ref publish) => {
+            assert!(publish.retain);
+            assert_eq!(publish.pid, Some(PacketIdentifier(2)));
+            assert_eq!(*publish.payload, b"hi".to_vec());
+        }
+        ref packet => panic!("unexpected {:?}", packet)
+    }
+}
+
+#[test]
+fn fixture_publish_payload_test() {
+    match fixture("publish_empty_payload.hex")[0] {
+        Packet::Publish(ref publish) => assert!(publish.payload.is_empty()),
+        ref packet => panic!("unexpected {:?}", packet)
+    }
+    match fixture("publish_two_byte_length.hex")[0] {
+        Packet::Publish(ref publish) => assert_eq!(*publish.payload, vec![0x41; 200]),
+        ref packet => panic!("unexpected {:?}", packet)
+    }
+}
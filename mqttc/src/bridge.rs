@@ -0,0 +1,217 @@
+//! Topics mirrored between two brokers by a pair of clients.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+use mqtt3::{self, Message, QoS, TopicPath};
+use client::Client;
+use error::{Error, Result};
+use {PubSub, PubOpt, Payload};
+
+// Messages remembered per side to tell their echo
+const ECHO_WINDOW: usize = 256;
+
+/// Way the messages of a topic go. The local broker is the one of the
+/// first client of the bridge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From the remote broker to the local one
+    In,
+    /// From the local broker to the remote one
+    Out,
+    Both
+}
+
+/// Topic filter to mirror. The filter is the same on both sides apart from
+/// the prefix each side puts before it, so `sensors/#` with the remote
+/// prefix `site1/` goes to `site1/sensors/#`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BridgeTopic {
+    filter: String,
+    direction: Direction,
+    qos: QoS,
+    local_prefix: String,
+    remote_prefix: String
+}
+
+impl BridgeTopic {
+    /// Messages are subscribed to and forwarded with no more than `qos`
+    pub fn new(filter: &str, direction: Direction, qos: QoS) -> BridgeTopic {
+        BridgeTopic {
+            filter: filter.to_string(),
+            direction: direction,
+            qos: qos,
+            local_prefix: String::new(),
+            remote_prefix: String::new()
+        }
+    }
+
+    /// Goes before the filter on the local broker. Prefixes are plain
+    /// topic levels, `Bridge::add_topic` refuses `+` and `#` in them.
+    pub fn set_local_prefix(&mut self, prefix: &str) -> &mut BridgeTopic {
+        self.local_prefix = prefix.to_string(); self
+    }
+
+    /// Goes before the filter on the remote broker
+    pub fn set_remote_prefix(&mut self, prefix: &str) -> &mut BridgeTopic {
+        self.remote_prefix = prefix.to_string(); self
+    }
+}
+
+struct Route {
+    topic: BridgeTopic,
+    local: TopicPath,
+    remote: TopicPath
+}
+
+/// Forwards messages between the brokers of two clients.
+///
+/// A message the bridge publishes comes back to it when it subscribes to
+/// its topic on that side too. The bridge keeps the last messages it sent
+/// to each side and drops their echo, so a topic mirrored both ways doesn't
+/// go round in circles.
+pub struct Bridge {
+    local: Client,
+    remote: Client,
+    routes: Vec<Route>,
+    sent_local: VecDeque<(String, Payload)>,
+    sent_remote: VecDeque<(String, Payload)>
+}
+
+impl Bridge {
+    pub fn new(local: Client, remote: Client) -> Bridge {
+        Bridge {
+            local: local,
+            remote: remote,
+            routes: Vec::new(),
+            sent_local: VecDeque::new(),
+            sent_remote: VecDeque::new()
+        }
+    }
+
+    /// Subscribes to the topic on the sides it is forwarded from
+    pub fn add_topic(&mut self, topic: BridgeTopic) -> Result<&mut Bridge> {
+        // Forwarded topics swap the prefixes by their length
+        let wildcard = |prefix: &str| prefix.contains(|c| c == '+' || c == '#');
+        if wildcard(&topic.local_prefix) || wildcard(&topic.remote_prefix) {
+            return Err(Error::from(mqtt3::Error::TopicNameMustNotContainWildcard));
+        }
+        let local = format!("{}{}", topic.local_prefix, topic.filter);
+        let remote = format!("{}{}", topic.remote_prefix, topic.filter);
+        try!(mqtt3::validate_topic_filter(&local));
+        try!(mqtt3::validate_topic_filter(&remote));
+        if topic.direction != Direction::In {
+            try!(self.local.subscribe((local.clone(), topic.qos)));
+        }
+        if topic.direction != Direction::Out {
+            try!(self.remote.subscribe((remote.clone(), topic.qos)));
+        }
+        self.routes.push(Route {
+            local: try!(TopicPath::from_str(local)),
+            remote: try!(TopicPath::from_str(remote)),
+            topic: topic
+        });
+        Ok(self)
+    }
+
+    /// Reads from each side for up to half of `timeout`, forwarding what
+    /// arrives. Returns the number of messages forwarded.
+    pub fn poll(&mut self, timeout: Duration) -> Result<usize> {
+        let mut forwarded = 0;
+        if let Some(message) = try!(self.local.poll(timeout / 2)) {
+            if try!(self._forward(&message, true)) {
+                forwarded += 1;
+            }
+            try!(complete(&mut self.local, &message));
+        }
+        if let Some(message) = try!(self.remote.poll(timeout / 2)) {
+            if try!(self._forward(&message, false)) {
+                forwarded += 1;
+            }
+            try!(complete(&mut self.remote, &message));
+        }
+        Ok(forwarded)
+    }
+
+    /// Forwards until a client fails
+    pub fn run(&mut self) -> Result<()> {
+        loop {
+            try!(self.poll(Duration::from_secs(1)));
+        }
+    }
+
+    pub fn local(&mut self) -> &mut Client {
+        &mut self.local
+    }
+
+    pub fn remote(&mut self) -> &mut Client {
+        &mut self.remote
+    }
+
+    pub fn into_clients(self) -> (Client, Client) {
+        (self.local, self.remote)
+    }
+
+    // Publishes `message` of one side on the other, unless it's the echo of
+    // one forwarded there
+    fn _forward(&mut self, message: &Message, from_local: bool) -> Result<bool> {
+        let key = (message.topic.path.clone(), message.payload.clone());
+        let sent = if from_local { &mut self.sent_local } else { &mut self.sent_remote };
+        if let Some(i) = sent.iter().position(|sent| *sent == key) {
+            sent.remove(i);
+            return Ok(false);
+        }
+
+        let route = self.routes.iter().find(|route| {
+            let (filter, direction) = if from_local {
+                (&route.local, Direction::In)
+            } else {
+                (&route.remote, Direction::Out)
+            };
+            route.topic.direction != direction && message.topic.matches(filter)
+        });
+        let route = match route {
+            Some(route) => route,
+            None => return Ok(false)
+        };
+        let (from, to) = if from_local {
+            (&route.topic.local_prefix, &route.topic.remote_prefix)
+        } else {
+            (&route.topic.remote_prefix, &route.topic.local_prefix)
+        };
+        // `a/#` matches the parent level `a` too, which lacks the prefix
+        let path = &message.topic.path;
+        let rest = match path.get(from.len()..) {
+            Some(rest) if path.starts_with(from.as_str()) => rest,
+            _ => {
+                debug!("Not bridged {}, outside of the prefix {}", path, from);
+                return Ok(false);
+            }
+        };
+        let topic = format!("{}{}", to, rest);
+        if topic.is_empty() {
+            debug!("Not bridged {}, nothing is left of it", path);
+            return Ok(false);
+        }
+        let pubopt = PubOpt::new(message.qos.min(route.topic.qos), message.retain);
+
+        let (client, sent) = if from_local {
+            (&mut self.remote, &mut self.sent_remote)
+        } else {
+            (&mut self.local, &mut self.sent_local)
+        };
+        try!(client.publish(topic.as_str(), message.payload.clone(), pubopt));
+        if sent.len() >= ECHO_WINDOW {
+            sent.pop_front();
+        }
+        sent.push_back((topic, message.payload.clone()));
+        Ok(true)
+    }
+}
+
+// Ends the QoS 2 flow of a message handed over
+fn complete(client: &mut Client, message: &Message) -> Result<()> {
+    match (message.qos, message.pid) {
+        (QoS::ExactlyOnce, Some(pid)) => client.complete(pid),
+        _ => Ok(())
+    }
+}
@@ -0,0 +1,165 @@
+//! The latest message of each topic under a set of filters, kept as the
+//! messages come in.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::Instant;
+use mqtt3::{self, Message, QoS, SubscribeTopic, TopicPath};
+use clock::{Clock, SystemClock};
+use error::Result;
+use {PubSub, Payload};
+
+/// Latest payload of a topic
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedValue {
+    pub payload: Payload,
+    /// When the message was taken, by the clock of the cache
+    pub received: Instant,
+    /// Whether it came as a retained message, from before the subscription
+    pub retain: bool
+}
+
+/// New value of a topic, `None` once a retained message clears it
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValueChange {
+    pub topic: String,
+    pub value: Option<CachedValue>
+}
+
+/// Keeps the latest payload of every topic matching its filters.
+///
+/// The cache doesn't read from the client itself: it subscribes with
+/// `subscribe` and takes what the client receives through `accept`.
+/// Receivers of `watch` hear of every payload that differs from the one
+/// before.
+pub struct LastValueCache {
+    filters: Vec<(TopicPath, QoS)>,
+    values: HashMap<String, CachedValue>,
+    watchers: Vec<Sender<ValueChange>>,
+    clock: Arc<Clock>
+}
+
+impl LastValueCache {
+    pub fn new() -> LastValueCache {
+        LastValueCache {
+            filters: Vec::new(),
+            values: HashMap::new(),
+            watchers: Vec::new(),
+            clock: Arc::new(SystemClock)
+        }
+    }
+
+    pub fn add_filter(&mut self, filter: &str, qos: QoS) -> Result<&mut LastValueCache> {
+        try!(mqtt3::validate_topic_filter(filter));
+        self.filters.push((try!(TopicPath::from_str(filter)), qos));
+        Ok(self)
+    }
+
+    /// Time the values are stamped with, the system's by default
+    pub fn set_clock(&mut self, clock: Arc<Clock>) -> &mut LastValueCache {
+        self.clock = clock; self
+    }
+
+    /// Subscribes `client` to the filters
+    pub fn subscribe<C: PubSub>(&self, client: &mut C) -> Result<()> {
+        let topics: Vec<SubscribeTopic> = self.filters.iter().map(|&(ref filter, qos)| SubscribeTopic {
+            topic_path: filter.path(),
+            qos: qos
+        }).collect();
+        client.subscribe(topics)
+    }
+
+    /// Takes a received message, false if no filter matches its topic. An
+    /// empty retained payload clears the topic.
+    pub fn accept(&mut self, message: &Message) -> bool {
+        if !self.filters.iter().any(|&(ref filter, _)| message.topic.matches(filter)) {
+            return false;
+        }
+        let topic = &message.topic.path;
+        let value = if message.retain && message.payload.is_empty() {
+            if self.values.remove(topic).is_none() {
+                return true;
+            }
+            None
+        } else {
+            let value = CachedValue {
+                payload: message.payload.clone(),
+                received: self.clock.now(),
+                retain: message.retain
+            };
+            let previous = self.values.insert(topic.clone(), value.clone());
+            if previous.map_or(false, |previous| previous.payload == value.payload) {
+                return true;
+            }
+            Some(value)
+        };
+        let change = ValueChange { topic: topic.clone(), value: value };
+        self.watchers.retain(|watcher| watcher.send(change.clone()).is_ok());
+        true
+    }
+
+    pub fn get(&self, topic: &str) -> Option<&CachedValue> {
+        self.values.get(topic)
+    }
+
+    /// Topics with a value, in no particular order
+    pub fn topics(&self) -> Vec<&str> {
+        self.values.keys().map(|topic| topic.as_str()).collect()
+    }
+
+    /// Receiver of the changes from now on. It is dropped from the cache
+    /// once it goes away.
+    pub fn watch(&mut self) -> Receiver<ValueChange> {
+        let (sender, receiver) = mpsc::channel();
+        self.watchers.push(sender);
+        receiver
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use mqtt3::{Message, QoS, TopicPath};
+    use clock::{Clock, ManualClock};
+    use super::LastValueCache;
+
+    fn message(topic: &str, payload: &[u8], retain: bool) -> Message {
+        let mut message = Message::new(TopicPath::from(topic), Arc::new(payload.to_vec()), QoS::AtLeastOnce);
+        message.retain = retain;
+        message
+    }
+
+    #[test]
+    fn last_value_cache_test() {
+        let clock = ManualClock::new();
+        let mut cache = LastValueCache::new();
+        cache.add_filter("sensors/+/temp", QoS::AtLeastOnce).unwrap().set_clock(Arc::new(clock.clone()));
+        assert!(cache.add_filter("sensors/#/x", QoS::AtMostOnce).is_err());
+        let changes = cache.watch();
+
+        assert!(cache.accept(&message("sensors/a/temp", b"20", true)));
+        assert!(!cache.accept(&message("sensors/a/humidity", b"40", false)));
+        clock.advance(Duration::from_secs(5));
+        assert!(cache.accept(&message("sensors/a/temp", b"21", false)));
+        let value = cache.get("sensors/a/temp").unwrap().clone();
+        assert_eq!((&value.payload[..], value.retain, value.received), (&b"21"[..], false, clock.now()));
+        // the same payload again only moves the time on
+        assert!(cache.accept(&message("sensors/a/temp", b"21", false)));
+        assert_eq!(cache.topics(), vec!["sensors/a/temp"]);
+
+        // cleared
+        assert!(cache.accept(&message("sensors/a/temp", b"", true)));
+        assert!(cache.get("sensors/a/temp").is_none());
+
+        let changes: Vec<(String, Option<Vec<u8>>)> = changes.try_iter()
+            .map(|change| (change.topic, change.value.map(|value| value.payload.to_vec())))
+            .collect();
+        assert_eq!(changes, vec![
+            ("sensors/a/temp".to_string(), Some(b"20".to_vec())),
+            ("sensors/a/temp".to_string(), Some(b"21".to_vec())),
+            ("sensors/a/temp".to_string(), None)
+        ]);
+    }
+}
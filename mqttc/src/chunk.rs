@@ -0,0 +1,400 @@
+//! Payloads too large for one message, sent as a sequence of chunks.
+//!
+//! A transfer on `topic` starts with a manifest on `topic/manifest`: the id
+//! of the transfer (u32), the length of the payload (u64), the chunk size
+//! (u32), big endian, and optionally the SHA-256 of the payload. The chunks follow in order on `topic/chunk`, each
+//! being the id, its sequence number counted from 0 (u32) and the data.
+//! The receiver holds one chunk in memory, the sender the window of chunks
+//! awaiting their acknowledgement.
+
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use mqtt3::Message;
+use rand;
+use error::{Error, Result};
+use {PubSub, PubOpt, Payload};
+
+const MANIFEST_LEN: usize = 16;
+const SHA256_LEN: usize = 32;
+const CHUNK_HEADER_LEN: usize = 8;
+
+/// Chunks `ChunkSender::send` keeps unacknowledged by default
+pub const DEFAULT_WINDOW: usize = 16;
+
+fn invalid(msg: &str) -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub id: u32,
+    pub len: u64,
+    pub chunk_size: u32,
+    pub sha256: Option<[u8; 32]>
+}
+
+impl Manifest {
+    pub fn chunks(&self) -> u64 {
+        let chunk_size = self.chunk_size as u64;
+        self.len / chunk_size + (self.len % chunk_size != 0) as u64
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(MANIFEST_LEN + SHA256_LEN);
+        data.write_u32::<BigEndian>(self.id).unwrap();
+        data.write_u64::<BigEndian>(self.len).unwrap();
+        data.write_u32::<BigEndian>(self.chunk_size).unwrap();
+        if let Some(ref sha256) = self.sha256 {
+            data.extend_from_slice(sha256);
+        }
+        data
+    }
+
+    fn decode(data: &[u8]) -> Result<Manifest> {
+        if data.len() < MANIFEST_LEN {
+            return Err(invalid("manifest too short"));
+        }
+        let mut manifest = Manifest {
+            id: BigEndian::read_u32(&data[0..4]),
+            len: BigEndian::read_u64(&data[4..12]),
+            chunk_size: BigEndian::read_u32(&data[12..16]),
+            sha256: None
+        };
+        if data.len() >= MANIFEST_LEN + SHA256_LEN {
+            let mut sha256 = [0; SHA256_LEN];
+            sha256.copy_from_slice(&data[MANIFEST_LEN..MANIFEST_LEN + SHA256_LEN]);
+            manifest.sha256 = Some(sha256);
+        }
+        if manifest.chunk_size == 0 {
+            return Err(invalid("chunk size is 0"));
+        }
+        if manifest.chunks() > u32::max_value() as u64 {
+            return Err(invalid("too many chunks"));
+        }
+        Ok(manifest)
+    }
+}
+
+/// Reads `len` bytes from `reader` and splits them into chunk messages
+pub struct ChunkSender<R> {
+    topic: String,
+    reader: R,
+    manifest: Manifest,
+    seq: u32,
+    sent: u64,
+    window: usize
+}
+
+impl<R: Read> ChunkSender<R> {
+    /// Fails if `chunk_size` is 0 or the sequence numbers of the chunks
+    /// wouldn't fit in a u32
+    pub fn new(topic: &str, reader: R, len: u64, chunk_size: u32) -> Result<ChunkSender<R>> {
+        let manifest = Manifest {
+            id: rand::random(),
+            len: len,
+            chunk_size: chunk_size,
+            sha256: None
+        };
+        if chunk_size == 0 {
+            return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidInput, "chunk size is 0")));
+        }
+        if manifest.chunks() > u32::max_value() as u64 {
+            return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidInput, "too many chunks")));
+        }
+        Ok(ChunkSender {
+            topic: topic.to_string(),
+            reader: reader,
+            manifest: manifest,
+            seq: 0,
+            sent: 0,
+            window: DEFAULT_WINDOW
+        })
+    }
+
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    /// Sends the SHA-256 of the payload in the manifest. The id of the
+    /// transfer is taken from it, so a receiver can tell the same payload
+    /// sent again and resume it.
+    pub fn set_sha256(&mut self, sha256: [u8; 32]) -> &mut ChunkSender<R> {
+        self.manifest.id = BigEndian::read_u32(&sha256[..4]);
+        self.manifest.sha256 = Some(sha256);
+        self
+    }
+
+    /// Most chunks `send` leaves unacknowledged, the others wait. At least 1.
+    pub fn set_window(&mut self, chunks: usize) -> &mut ChunkSender<R> {
+        assert!(chunks > 0, "window must not be 0");
+        self.window = chunks;
+        self
+    }
+
+    /// Topic and payload of the manifest, to publish before the chunks
+    pub fn manifest_message(&self) -> (String, Payload) {
+        (format!("{}/manifest", self.topic), Arc::new(self.manifest.encode()))
+    }
+
+    /// Topic and payload of the next chunk, `None` after the last. Fails
+    /// if the reader ends early.
+    pub fn next_chunk(&mut self) -> Result<Option<(String, Payload)>> {
+        if self.sent == self.manifest.len {
+            return Ok(None);
+        }
+        let size = (self.manifest.len - self.sent).min(self.manifest.chunk_size as u64) as usize;
+        let mut data = Vec::with_capacity(CHUNK_HEADER_LEN + size);
+        data.write_u32::<BigEndian>(self.manifest.id).unwrap();
+        data.write_u32::<BigEndian>(self.seq).unwrap();
+        data.resize(CHUNK_HEADER_LEN + size, 0);
+        try!(self.reader.read_exact(&mut data[CHUNK_HEADER_LEN..]));
+        self.seq += 1;
+        self.sent += size as u64;
+        Ok(Some((format!("{}/chunk", self.topic), Arc::new(data))))
+    }
+
+    /// Publishes the manifest and every chunk with `pubopt`. A chunk is
+    /// read once fewer than the window of messages await their
+    /// acknowledgement, see `PubSub::wait_in_flight`. Returns the manifest
+    /// and the messages received meanwhile.
+    pub fn send<C: PubSub>(mut self, client: &mut C, pubopt: PubOpt) -> Result<(Manifest, Vec<Box<Message>>)> {
+        let (topic, payload) = self.manifest_message();
+        try!(client.publish(topic, payload, pubopt));
+        let mut messages = Vec::new();
+        loop {
+            messages.extend(try!(client.wait_in_flight(self.window - 1)));
+            match try!(self.next_chunk()) {
+                Some((topic, payload)) => try!(client.publish(topic, payload, pubopt)),
+                None => return Ok((self.manifest, messages))
+            }
+        }
+    }
+}
+
+struct Transfer<W> {
+    manifest: Manifest,
+    sink: W,
+    next: u32,
+    received: u64
+}
+
+/// Puts the chunks of transfers on a topic back together, writing them to
+/// a sink as they come
+pub struct ChunkReceiver<W> {
+    topic: String,
+    transfer: Option<Transfer<W>>
+}
+
+impl<W: Write> ChunkReceiver<W> {
+    pub fn new(topic: &str) -> ChunkReceiver<W> {
+        ChunkReceiver {
+            topic: topic.to_string(),
+            transfer: None
+        }
+    }
+
+    /// Topic filter to subscribe to
+    pub fn filter(&self) -> String {
+        format!("{}/+", self.topic)
+    }
+
+    /// Takes a received message. A manifest starts a transfer into the sink
+    /// `open` returns, an unfinished one is dropped. Once the last chunk is
+    /// written the manifest and the sink are returned.
+    ///
+    /// Messages on other topics, chunks of other transfers and chunks seen
+    /// before are skipped. A missing chunk fails and drops the transfer.
+    pub fn accept<F>(&mut self, message: &Message, open: F) -> Result<Option<(Manifest, W)>>
+        where F: FnOnce(&Manifest) -> io::Result<W>
+    {
+        self.accept_resumable(message, |manifest| open(manifest).map(|sink| (sink, 0)))
+    }
+
+    /// Like `accept`, but `open` also returns how many chunks the sink holds
+    /// already. Those are skipped and the transfer goes on after them.
+    pub fn accept_resumable<F>(&mut self, message: &Message, open: F) -> Result<Option<(Manifest, W)>>
+        where F: FnOnce(&Manifest) -> io::Result<(W, u32)>
+    {
+        let path = &message.topic.path;
+        if !path.starts_with(&self.topic) {
+            return Ok(None);
+        }
+        match &path[self.topic.len()..] {
+            "/manifest" => {
+                let manifest = try!(Manifest::decode(&message.payload));
+                let (sink, chunks) = try!(open(&manifest));
+                let received = (chunks as u64 * manifest.chunk_size as u64).min(manifest.len);
+                self.transfer = Some(Transfer {
+                    manifest: manifest,
+                    sink: sink,
+                    next: chunks,
+                    received: received
+                });
+                self._finish()
+            }
+            "/chunk" => {
+                if message.payload.len() < CHUNK_HEADER_LEN {
+                    return Err(invalid("chunk too short"));
+                }
+                let id = BigEndian::read_u32(&message.payload[0..4]);
+                let seq = BigEndian::read_u32(&message.payload[4..8]);
+                let data = &message.payload[CHUNK_HEADER_LEN..];
+                let result = match self.transfer {
+                    Some(ref mut transfer) if transfer.manifest.id == id => {
+                        if seq < transfer.next {
+                            return Ok(None);
+                        }
+                        let expected = (transfer.manifest.len - transfer.received)
+                                           .min(transfer.manifest.chunk_size as u64);
+                        if seq > transfer.next {
+                            Err(invalid("missing chunk"))
+                        } else if data.len() as u64 != expected {
+                            Err(invalid("chunk of the wrong size"))
+                        } else {
+                            transfer.sink.write_all(data).map_err(Error::from).map(|_| {
+                                transfer.next += 1;
+                                transfer.received += data.len() as u64;
+                            })
+                        }
+                    }
+                    _ => return Ok(None)
+                };
+                if let Err(err) = result {
+                    self.transfer = None;
+                    return Err(err);
+                }
+                self._finish()
+            }
+            _ => Ok(None)
+        }
+    }
+
+    fn _finish(&mut self) -> Result<Option<(Manifest, W)>> {
+        let done = match self.transfer {
+            Some(ref transfer) => transfer.received == transfer.manifest.len,
+            None => false
+        };
+        if done {
+            let mut transfer = self.transfer.take().unwrap();
+            try!(transfer.sink.flush());
+            Ok(Some((transfer.manifest, transfer.sink)))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cmp;
+    use std::io::Cursor;
+    use std::sync::Arc;
+    use mqtt3::{Message, QoS, TopicPath, ToTopicPath};
+    use error::Result;
+    use {PubSub, PubOpt, ToPayload, ToSubTopics, ToUnSubTopics};
+    use super::{ChunkSender, ChunkReceiver, Manifest};
+
+    // Keeps what is published as messages, which stay in flight until
+    // `wait_in_flight` acknowledges them
+    struct Published {
+        messages: Vec<Message>,
+        in_flight: usize,
+        most_in_flight: usize
+    }
+
+    impl Published {
+        fn new() -> Published {
+            Published {
+                messages: Vec::new(),
+                in_flight: 0,
+                most_in_flight: 0
+            }
+        }
+    }
+
+    impl PubSub for Published {
+        fn publish<T: ToTopicPath, P: ToPayload>(&mut self, topic: T, payload: P, _: PubOpt) -> Result<()> {
+            self.in_flight += 1;
+            self.most_in_flight = cmp::max(self.most_in_flight, self.in_flight);
+            self.messages.push(Message::new(try!(topic.to_topic_name()), payload.to_payload(), QoS::AtLeastOnce));
+            Ok(())
+        }
+
+        fn subscribe<S: ToSubTopics>(&mut self, _: S) -> Result<()> {
+            Ok(())
+        }
+
+        fn unsubscribe<U: ToUnSubTopics>(&mut self, _: U) -> Result<()> {
+            Ok(())
+        }
+
+        fn disconnect(self) -> Result<()> {
+            Ok(())
+        }
+
+        fn wait_in_flight(&mut self, max: usize) -> Result<Vec<Box<Message>>> {
+            self.in_flight = cmp::min(self.in_flight, max);
+            Ok(Vec::new())
+        }
+    }
+
+    #[test]
+    fn chunk_transfer_test() {
+        let data: Vec<u8> = (0..10).collect();
+        let mut published = Published::new();
+        let (manifest, _) = ChunkSender::new("fw", Cursor::new(data.clone()), 10, 4).unwrap()
+                                .send(&mut published, PubOpt::at_least_once())
+                                .unwrap();
+        assert_eq!(manifest.chunks(), 3);
+        let topics: Vec<&str> = published.messages.iter().map(|m| m.topic.path.as_str()).collect();
+        assert_eq!(topics, vec!["fw/manifest", "fw/chunk", "fw/chunk", "fw/chunk"]);
+
+        let mut receiver = ChunkReceiver::new("fw");
+        assert_eq!(receiver.filter(), "fw/+");
+        let mut done = None;
+        for (i, message) in published.messages.iter().enumerate() {
+            done = receiver.accept(message, |_| Ok(Vec::new())).unwrap();
+            // a redelivered chunk is skipped
+            if i == 1 {
+                assert!(receiver.accept(message, |_| Ok(Vec::new())).unwrap().is_none());
+            }
+        }
+        assert_eq!(done, Some((manifest, data)));
+
+        // the second chunk went missing
+        receiver.accept(&published.messages[0], |_| Ok(Vec::new())).unwrap();
+        receiver.accept(&published.messages[1], |_| Ok(Vec::new())).unwrap();
+        assert!(receiver.accept(&published.messages[3], |_| Ok(Vec::new())).is_err());
+
+        // short reader
+        let mut sender = ChunkSender::new("fw", Cursor::new(vec![1, 2]), 10, 4).unwrap();
+        assert!(sender.next_chunk().is_err());
+
+        let other = Message::new(TopicPath::from("fwx/chunk"), Arc::new(Vec::new()), QoS::AtMostOnce);
+        assert!(receiver.accept(&other, |_| Ok(Vec::new())).unwrap().is_none());
+    }
+    #[test]
+    fn chunk_window_test() {
+        let data: Vec<u8> = (0..100).collect();
+        let mut published = Published::new();
+        let mut sender = ChunkSender::new("fw", Cursor::new(data), 100, 10).unwrap();
+        sender.set_window(3);
+        sender.send(&mut published, PubOpt::at_least_once()).unwrap();
+        assert_eq!(published.messages.len(), 11);
+        assert_eq!(published.most_in_flight, 3);
+
+        let manifest = Manifest {
+            id: 0,
+            len: u64::max_value(),
+            chunk_size: 1 << 31,
+            sha256: None
+        };
+        assert_eq!(manifest.chunks(), 1 << 33);
+        // sequence numbers are u32
+        assert!(Manifest::decode(&manifest.encode()).is_err());
+        assert!(ChunkSender::new("fw", Cursor::new(vec![]), manifest.len, manifest.chunk_size).is_err());
+        assert!(ChunkSender::new("fw", Cursor::new(vec![]), u32::max_value() as u64, 1).is_ok());
+        assert!(ChunkSender::new("fw", Cursor::new(vec![]), 10, 0).is_err());
+    }
+}
@@ -0,0 +1,162 @@
+//! End-to-end encryption of payloads, so the broker only sees ciphertext.
+
+use std::io;
+
+/// Encrypts payloads before they are published and decrypts the received
+/// ones. The topic is passed along so a cipher can bind the ciphertext to
+/// it, a message copied to another topic then fails to decrypt.
+pub trait PayloadCipher {
+    fn encrypt(&self, topic: &str, payload: &[u8]) -> io::Result<Vec<u8>>;
+    fn decrypt(&self, topic: &str, payload: &[u8]) -> io::Result<Vec<u8>>;
+}
+
+#[cfg(feature = "aes-gcm")]
+pub use self::aes::AesGcmCipher;
+
+#[cfg(feature = "aes-gcm")]
+mod aes {
+    use std::collections::HashMap;
+    use std::io;
+    use aes_gcm::{Aes256Gcm, Key, Nonce};
+    use aes_gcm::aead::{Aead, KeyInit, Payload};
+    use rand::{self, Rng};
+    use super::PayloadCipher;
+
+    const KEY_ID_LEN: usize = 4;
+    const NONCE_LEN: usize = 12;
+
+    fn invalid(msg: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, msg)
+    }
+
+    /// AES-256-GCM with the topic as associated data.
+    ///
+    /// A payload is the id of the key (u32, big endian), a random nonce of
+    /// 12 bytes and the ciphertext with its tag. New payloads use the
+    /// current key, the others added are kept to decrypt older messages
+    /// while the keys are rotated.
+    pub struct AesGcmCipher {
+        current: u32,
+        keys: HashMap<u32, Aes256Gcm>
+    }
+
+    impl AesGcmCipher {
+        pub fn new(key_id: u32, key: &[u8; 32]) -> AesGcmCipher {
+            let mut keys = HashMap::new();
+            keys.insert(key_id, Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)));
+            AesGcmCipher {
+                current: key_id,
+                keys: keys
+            }
+        }
+
+        /// Key to decrypt with, `set_current` moves encryption to it
+        pub fn add_key(&mut self, key_id: u32, key: &[u8; 32]) -> &mut AesGcmCipher {
+            self.keys.insert(key_id, Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)));
+            self
+        }
+
+        /// Encrypts with the key of `key_id` from now on, which has to be
+        /// added first
+        pub fn set_current(&mut self, key_id: u32) -> io::Result<&mut AesGcmCipher> {
+            if !self.keys.contains_key(&key_id) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "unknown key"));
+            }
+            self.current = key_id;
+            Ok(self)
+        }
+
+        pub fn remove_key(&mut self, key_id: u32) -> &mut AesGcmCipher {
+            if key_id != self.current {
+                self.keys.remove(&key_id);
+            }
+            self
+        }
+    }
+
+    impl PayloadCipher for AesGcmCipher {
+        fn encrypt(&self, topic: &str, payload: &[u8]) -> io::Result<Vec<u8>> {
+            let mut nonce = [0; NONCE_LEN];
+            rand::thread_rng().fill_bytes(&mut nonce);
+            let ciphertext = try!(self.keys[&self.current].encrypt(Nonce::from_slice(&nonce), Payload {
+                msg: payload,
+                aad: topic.as_bytes()
+            }).map_err(|_| invalid("encryption failed")));
+
+            let mut framed = Vec::with_capacity(KEY_ID_LEN + NONCE_LEN + ciphertext.len());
+            framed.extend_from_slice(&self.current.to_be_bytes());
+            framed.extend_from_slice(&nonce);
+            framed.extend_from_slice(&ciphertext);
+            Ok(framed)
+        }
+
+        fn decrypt(&self, topic: &str, payload: &[u8]) -> io::Result<Vec<u8>> {
+            if payload.len() < KEY_ID_LEN + NONCE_LEN {
+                return Err(invalid("payload too short"));
+            }
+            let key_id = (payload[0] as u32) << 24 | (payload[1] as u32) << 16 |
+                         (payload[2] as u32) << 8 | payload[3] as u32;
+            let cipher = match self.keys.get(&key_id) {
+                Some(cipher) => cipher,
+                None => return Err(invalid("unknown key"))
+            };
+            let nonce = Nonce::from_slice(&payload[KEY_ID_LEN..KEY_ID_LEN + NONCE_LEN]);
+            cipher.decrypt(nonce, Payload {
+                msg: &payload[KEY_ID_LEN + NONCE_LEN..],
+                aad: topic.as_bytes()
+            }).map_err(|_| invalid("decryption failed"))
+        }
+    }
+}
+
+#[cfg(all(test, feature = "aes-gcm"))]
+mod test {
+    use super::{AesGcmCipher, PayloadCipher};
+
+    #[test]
+    fn aes_gcm_round_trip_test() {
+        let cipher = AesGcmCipher::new(7, &[1; 32]);
+        let encrypted = cipher.encrypt("a/b", b"secret").unwrap();
+        // key id, nonce, ciphertext and the 16 byte tag
+        assert_eq!(&encrypted[..4], &[0, 0, 0, 7]);
+        assert_eq!(encrypted.len(), 4 + 12 + 6 + 16);
+        assert!(!encrypted.windows(6).any(|w| w == b"secret"));
+        assert_eq!(cipher.decrypt("a/b", &encrypted).unwrap(), b"secret");
+        // a fresh nonce each time
+        assert!(cipher.encrypt("a/b", b"secret").unwrap() != encrypted);
+    }
+
+    #[test]
+    fn aes_gcm_rotation_test() {
+        let mut cipher = AesGcmCipher::new(1, &[1; 32]);
+        let old = cipher.encrypt("a/b", b"old").unwrap();
+        assert!(cipher.set_current(2).is_err());
+        cipher.add_key(2, &[2; 32]);
+        cipher.set_current(2).unwrap();
+        let new = cipher.encrypt("a/b", b"new").unwrap();
+        assert_eq!(&new[..4], &[0, 0, 0, 2]);
+        assert_eq!(cipher.decrypt("a/b", &old).unwrap(), b"old");
+        assert_eq!(cipher.decrypt("a/b", &new).unwrap(), b"new");
+
+        // the current key stays
+        cipher.remove_key(1).remove_key(2);
+        assert!(cipher.decrypt("a/b", &old).is_err());
+        assert_eq!(cipher.decrypt("a/b", &new).unwrap(), b"new");
+    }
+
+    #[test]
+    fn aes_gcm_rejects_test() {
+        let cipher = AesGcmCipher::new(1, &[1; 32]);
+        let encrypted = cipher.encrypt("a/b", b"secret").unwrap();
+        // copied to another topic
+        assert!(cipher.decrypt("a/c", &encrypted).is_err());
+        let mut tampered = encrypted.clone();
+        tampered[16] ^= 1;
+        assert!(cipher.decrypt("a/b", &tampered).is_err());
+        let mut tag = encrypted.clone();
+        *tag.last_mut().unwrap() ^= 1;
+        assert!(cipher.decrypt("a/b", &tag).is_err());
+        assert!(cipher.decrypt("a/b", &encrypted[..15]).is_err());
+        assert!(AesGcmCipher::new(1, &[2; 32]).decrypt("a/b", &encrypted).is_err());
+    }
+}
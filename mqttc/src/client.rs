@@ -1,16 +1,29 @@
-use std::collections::{HashMap, VecDeque};
-use std::io::{Write, ErrorKind};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::vec_deque::Drain;
+use std::io::{self, Write, ErrorKind};
 use std::net::{SocketAddr, ToSocketAddrs};
 use std::time::{Duration, Instant};
+use std::sync::Arc;
 use std::thread;
-use netopt::{Connection, NetworkOptions, NetworkStream};
 use rand::{self, Rng};
+use netopt::{Connection, NetworkOptions, Waiter, Wake};
 use mqtt3::{MqttRead, MqttWrite, Message, QoS, SubscribeReturnCodes, SubscribeTopic};
-use mqtt3::{self, Protocol, Packet, ConnectReturnCode, PacketIdentifier, LastWill, ToTopicPath};
+use mqtt3::{self, Protocol, Packet, PacketType, PacketIdentifier, LastWill, TopicPath, ToTopicPath};
 use error::{Error, Result};
 use sub::Subscription;
-use {PubSub, ClientState, ReconnectMethod, PubOpt, ToPayload, ToSubTopics, ToUnSubTopics};
-use store::Store;
+use event::{Event, DisconnectReason};
+use {PubSub, ClientState, ReconnectMethod, Failover, Overflow, Backpressure, PubOpt, Payload, ToPayload, ToSubTopics, ToUnSubTopics};
+use store::{MessageStore, MessageQueue};
+use handle::{self, ClientHandle};
+use token::{self, DeliveryToken, Completion};
+use compress::{Compression, Compressor};
+use cipher::PayloadCipher;
+use middleware::Middleware;
+use clock::{Clock, SystemClock};
+use id::ClientId;
+
+// Outbox messages in flight at once
+const OUTBOX_WINDOW: usize = 32;
 
 // #[derive(Clone)]
 pub struct ClientOptions {
@@ -22,9 +35,28 @@ pub struct ClientOptions {
     username: Option<String>,
     password: Option<String>,
     reconnect: ReconnectMethod,
-
-    incomming_store: Option<Box<Store + Send>>,
-    outgoing_store: Option<Box<Store + Send>>,
+    brokers: Vec<SocketAddr>,
+    failover: Failover,
+    max_packet_size: Option<usize>,
+    write_batch: Option<(usize, Duration)>,
+    offline_queue: [Option<(usize, Overflow)>; 3],
+    topic_window: Option<usize>,
+    compression: Option<Compressor>,
+    cipher: Option<Box<PayloadCipher + Send>>,
+    middlewares: Vec<Box<Middleware + Send>>,
+    filters: Vec<(TopicPath, Box<Fn(&Message) -> bool + Send>)>,
+    delivery_channel: Option<(usize, Backpressure)>,
+    dedup_window: Option<usize>,
+    events: bool,
+    clock: Arc<Clock>,
+    rng: Box<Rng + Send>,
+    random_pids: bool,
+    reconnect_jitter: Option<Duration>,
+    thread_name: Option<String>,
+
+    incomming_store: Option<Box<MessageStore + Send>>,
+    outgoing_store: Option<Box<MessageStore + Send>>,
+    outbox: Option<Box<MessageQueue + Send>>,
 }
 
 impl ClientOptions {
@@ -46,11 +78,32 @@ impl ClientOptions {
             username: None,
             password: None,
             reconnect: ReconnectMethod::ForeverDisconnect,
+            brokers: Vec::new(),
+            failover: Failover::Ordered,
+            max_packet_size: None,
+            write_batch: None,
+            offline_queue: [None; 3],
+            topic_window: None,
+            compression: None,
+            cipher: None,
+            middlewares: Vec::new(),
+            filters: Vec::new(),
+            delivery_channel: None,
+            dedup_window: None,
+            events: false,
+            clock: Arc::new(SystemClock),
+            rng: Box::new(rand::weak_rng()),
+            random_pids: false,
+            reconnect_jitter: None,
+            thread_name: None,
             incomming_store: None,
             outgoing_store: None,
+            outbox: None,
         }
     }
 
+    /// Interval of PINGREQs when nothing else is written. The socket
+    /// timeouts and the wait for PINGRESP are 1.5 times as long.
     pub fn set_keep_alive(&mut self, secs: u16) -> &mut ClientOptions {
         self.keep_alive = Some(Duration::new(secs as u64, 0));
         self
@@ -71,20 +124,71 @@ impl ClientOptions {
         self
     }
 
-    pub fn set_incomming_store(&mut self, store: Box<Store + Send>) -> &mut ClientOptions {
+    pub fn set_incomming_store(&mut self, store: Box<MessageStore + Send>) -> &mut ClientOptions {
         self.incomming_store = Some(store);
         self
     }
 
-    pub fn set_outgoing_store(&mut self, store: Box<Store + Send>) -> &mut ClientOptions {
+    pub fn set_outgoing_store(&mut self, store: Box<MessageStore + Send>) -> &mut ClientOptions {
         self.outgoing_store = Some(store);
         self
     }
 
+    /// Passes QoS 1 publishes through a durable queue, so they survive
+    /// being offline and restarts. A message leaves the queue once the
+    /// broker acknowledged it, so after a restart the unacknowledged ones
+    /// are sent again. Queued messages are sent in order on connecting, up
+    /// to 32 at a time.
+    pub fn set_outbox(&mut self, outbox: Box<MessageQueue + Send>) -> &mut ClientOptions {
+        self.outbox = Some(outbox);
+        self
+    }
+
+    /// Compresses published payloads of `threshold` bytes and more and
+    /// decompresses received ones, the subscribers have to use the same
+    /// codec. See `Compressor` for the framing. Decompressed payloads are
+    /// limited to the maximum packet size, if one is set.
+    pub fn set_compression(&mut self, codec: Box<Compression + Send>, threshold: usize) -> &mut ClientOptions {
+        self.compression = Some(Compressor::new(codec, threshold));
+        self
+    }
+
+    /// Encrypts published payloads and decrypts received ones, after the
+    /// compression and before the decompression. Every received payload has
+    /// to decrypt, the others fail.
+    pub fn set_cipher(&mut self, cipher: Box<PayloadCipher + Send>) -> &mut ClientOptions {
+        self.cipher = Some(cipher);
+        self
+    }
+
+    /// Runs `middleware` on the published and received messages, after the
+    /// ones added before it when publishing and before them when receiving
+    pub fn add_middleware(&mut self, middleware: Box<Middleware + Send>) -> &mut ClientOptions {
+        self.middlewares.push(middleware);
+        self
+    }
+
+    /// Drops the received messages on topics matching `filter` for which
+    /// `keep` returns false, after the payload is decoded. With a
+    /// `ClientHandle` they never leave the I/O thread. Dropped messages are
+    /// still acknowledged.
+    pub fn add_filter<T, F>(&mut self, filter: T, keep: F) -> Result<&mut ClientOptions>
+        where T: ToTopicPath, F: Fn(&Message) -> bool + Send + 'static
+    {
+        let filter = try!(filter.to_topic_path());
+        self.filters.push((filter, Box::new(keep)));
+        Ok(self)
+    }
+
+    /// Name of the I/O thread of `Client::spawn`, `mqttc-` and the client
+    /// id by default
+    pub fn set_thread_name(&mut self, name: String) -> &mut ClientOptions {
+        self.thread_name = Some(name);
+        self
+    }
+
     pub fn generate_client_id(&mut self) -> &mut ClientOptions {
-        let mut rng = rand::thread_rng();
-        let id = rng.gen::<u32>();
-        self.client_id = Some(format!("mqttc_{}", id));
+        self.client_id = Some(ClientId::random_from(&mut self.rng).into_string());
         self
     }
 
@@ -123,53 +227,292 @@ impl ClientOptions {
         self
     }
 
+    /// Waits up to `max` longer than `ReconnectMethod::ReconnectAfter`
+    /// says, so clients cut off together don't all come back at once
+    pub fn set_reconnect_jitter(&mut self, max: Duration) -> &mut ClientOptions {
+        self.reconnect_jitter = Some(max);
+        self
+    }
+
+    /// Randomness for generated client ids, packet ids and reconnect
+    /// jitter. A seeded generator makes them the same on every run, on
+    /// embedded targets a hardware one can be plugged in.
+    pub fn set_rng<R: Rng + Send + 'static>(&mut self, rng: R) -> &mut ClientOptions {
+        self.rng = Box::new(rng);
+        self
+    }
+
+    /// Starts the packet ids of a new session at a random one instead of 1,
+    /// so they don't repeat the ids of the session before a restart
+    pub fn set_random_packet_ids(&mut self, random: bool) -> &mut ClientOptions {
+        self.random_pids = random;
+        self
+    }
+
+    /// Broker to fail over to, with every address `addr` resolves to. The
+    /// brokers are tried in the order added after the address passed to
+    /// `connect`, until one can be connected to. The session carries over
+    /// like on any reconnect: it's resumed if the new broker has it.
+    pub fn add_broker<A: ToSocketAddrs>(&mut self, addr: A) -> Result<&mut ClientOptions> {
+        self.brokers.extend(try!(addr.to_socket_addrs()));
+        Ok(self)
+    }
+
+    /// Broker tried first when connecting again, `Failover::Ordered` by
+    /// default
+    pub fn set_failover(&mut self, failover: Failover) -> &mut ClientOptions {
+        self.failover = failover;
+        self
+    }
+
+    /// Limits the size of packets in both directions. Publishing a larger
+    /// message fails with `PacketTooLarge` before anything is written. A
+    /// larger incomming packet is skipped and logged, `poll` goes on with the
+    /// next one. A skipped QoS 1/2 message isn't acknowledged, so the broker
+    /// sends it again after a reconnect.
+    pub fn set_max_packet_size(&mut self, bytes: usize) -> &mut ClientOptions {
+        self.max_packet_size = Some(bytes);
+        self
+    }
+
+    /// Lets acknowledgements wait in the write buffer while more incomming
+    /// packets are already buffered, so a burst of messages is acknowledged
+    /// with a single write. The buffer is flushed once it holds `max_bytes`,
+    /// once the oldest waiting packet is `max_delay` old, and before the
+    /// client blocks on reading.
+    pub fn set_write_batch(&mut self, max_bytes: usize, max_delay: Duration) -> &mut ClientOptions {
+        self.write_batch = Some((max_bytes, max_delay));
+        self
+    }
+
+    /// Keeps up to `limit` publishes of the QoS made while the client is
+    /// disconnected, and sends them in order once it reconnected. `overflow`
+    /// decides what happens to a publish when the queue is full.
+    ///
+    /// Without a queue publishing while disconnected fails.
+    pub fn set_offline_queue(&mut self, qos: QoS, limit: usize, overflow: Overflow) -> &mut ClientOptions {
+        self.offline_queue[qos.to_u8() as usize] = Some((limit, overflow));
+        self
+    }
+
+    /// Limits the unacknowledged QoS 1 and 2 publishes per topic. Further
+    /// publishes of the topic, QoS 0 ones too, wait in order until the
+    /// broker acknowledged enough of them; other topics aren't held up.
+    ///
+    /// Publishes of a topic are always written in the order they are made,
+    /// acknowledgements may come in any order, and after reconnecting to a
+    /// kept session the unacknowledged ones are sent again in their
+    /// original order. Still, a message resent with QoS 1 can be delivered
+    /// again after later ones. With a window of 1 a message is only sent
+    /// once the one before is acknowledged, so subscribers see the topic in
+    /// order even then. The outbox has a window of its own, see
+    /// `set_outbox`.
+    pub fn set_topic_window(&mut self, max_in_flight: usize) -> &mut ClientOptions {
+        self.topic_window = Some(max_in_flight);
+        self
+    }
+
+    /// Drops QoS 1 messages with the DUP flag whose packet identifier and
+    /// topic were among the last `capacity` QoS 1 messages of the session.
+    /// The broker sends a message again when it missed the PUBACK, so the
+    /// application sees it once. Dropped messages are still acknowledged.
+    pub fn set_dedup_window(&mut self, capacity: usize) -> &mut ClientOptions {
+        self.dedup_window = Some(capacity);
+        self
+    }
+
+    /// Bounds the channel `ClientHandle` hands received messages over with
+    /// to `capacity` messages, `backpressure` decides what happens when the
+    /// application doesn't keep up. Unbounded by default. Only used by
+    /// `Client::spawn`.
+    pub fn set_delivery_channel(&mut self, capacity: usize, backpressure: Backpressure) -> &mut ClientOptions {
+        self.delivery_channel = Some((capacity, backpressure));
+        self
+    }
+
+    /// Makes the client collect `Event`s, they are taken with
+    /// `Client::events`. Off by default so an application that never asks
+    /// for them doesn't keep them in memory.
+    pub fn enable_events(&mut self) -> &mut ClientOptions {
+        self.events = true;
+        self
+    }
+
+    /// Clock the timers of the client go by, `ManualClock` lets a test
+    /// trigger them without sleeping
+    pub fn set_clock<C: Clock + 'static>(&mut self, clock: C) -> &mut ClientOptions {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Connects to the broker at `addr`. A name with IPv6 and IPv4
+    /// addresses is tried at each of them in the order they resolve to,
+    /// before the brokers of `add_broker`.
     pub fn connect<A: ToSocketAddrs>(mut self, addr: A, netopt: NetworkOptions) -> Result<Client> {
-        if self.client_id == None {
-            self.generate_client_id();
+        let mut client_id = match self.client_id.take() {
+            Some(id) => try!(ClientId::new(id)),
+            None => ClientId::random_from(&mut self.rng)
+        };
+        // The server would refuse an empty id without a clean session
+        if client_id.is_empty() && client_id.check_protocol(self.protocol, self.clean_session).is_err() {
+            client_id = ClientId::random_from(&mut self.rng);
+            info!("Empty client id replaced by {}", client_id);
+        }
+        try!(client_id.check_protocol(self.protocol, self.clean_session));
+        self.client_id = Some(client_id.into_string());
+        if let (Some(max_packet_size), Some(compressor)) = (self.max_packet_size, self.compression.as_mut()) {
+            compressor.set_limit(max_packet_size);
         }
 
-        let addr = try!(addr.to_socket_addrs()).next().expect("Socket address is broken");
-
-        info!(" Connecting to {}", addr);
-        let (conn, _) = try!(self._reconnect(addr, &netopt));
+        // All the addresses of a dual-stack name, tried in turn
+        let addrs: Vec<SocketAddr> = try!(addr.to_socket_addrs()).collect();
+        if addrs.is_empty() {
+            return Err(Error::from(io::Error::new(io::ErrorKind::AddrNotAvailable, "address resolves to nothing")));
+        }
+        let first = self.brokers.len();
+        self.brokers.extend(addrs);
+        self.brokers.rotate_left(first);
+        let (broker, conn) = try!(self._connect_any(0, &netopt));
 
+        let now = self.clock.now();
+        let last_pid = self._initial_pid();
         let mut client = Client {
-            addr: addr,
+            broker: broker,
             state: ClientState::Disconnected,
             netopt: netopt,
             opts: self,
             conn: conn,
             session_present: false,
+            reconnect_attempt: 0,
+            poll_timeout: None,
 
             // Queues
-            last_flush: Instant::now(),
-            last_pid: PacketIdentifier::zero(),
+            last_flush: now,
+            unflushed: 0,
+            unflushed_since: None,
+            last_pid: last_pid,
             await_ping: false,
+            ping_sent: None,
+            stats: Stats::default(),
+            defer_acks: false,
             incomming_pub: VecDeque::new(),
+            received_pub: VecDeque::new(),
             incomming_rec: VecDeque::new(),
             incomming_rel: VecDeque::new(),
             outgoing_ack: VecDeque::new(),
             outgoing_rec: VecDeque::new(),
             outgoing_comp: VecDeque::new(),
+            expired: HashSet::new(),
+            offline: VecDeque::new(),
+            waiting: HashMap::new(),
+            published: 0,
+            outbox_sent: None,
+            outbox_tokens: HashMap::new(),
             await_suback: VecDeque::new(),
             await_unsuback: VecDeque::new(),
             subscriptions: HashMap::new(), // Subscriptions
+            resubscribe_pid: None,
+            events: VecDeque::new(),
         };
 
         // Send CONNECT then wait CONNACK
         try!(client._handshake());
+        try!(client._send_outbox());
 
         Ok(client)
     }
 
+    // Connects to the first broker that can be reached, from `first` on
+    fn _connect_any(&self, first: usize, netopt: &NetworkOptions) -> Result<(usize, Connection)> {
+        let mut error = None;
+        for i in 0..self.brokers.len() {
+            let broker = (first + i) % self.brokers.len();
+            info!(" Connecting to {}", self.brokers[broker]);
+            match self._reconnect(self.brokers[broker], netopt) {
+                Ok(conn) => return Ok((broker, conn)),
+                Err(err) => {
+                    warn!("Can't connect to {}: {}", self.brokers[broker], err);
+                    error = Some(err);
+                }
+            }
+        }
+        Err(error.unwrap())
+    }
+
     fn _reconnect(&self,
                   addr: SocketAddr,
                   netopt: &NetworkOptions)
-                  -> Result<(Connection, NetworkStream)> {
+                  -> Result<Connection> {
         let stream = try!(netopt.connect(addr));
-        stream.set_read_timeout(self.keep_alive).unwrap();
-        stream.set_write_timeout(self.keep_alive).unwrap();
-        Ok((try!(Connection::new(&stream)), stream))
+        let timeout = self.keep_alive.map(grace);
+        try!(stream.set_read_timeout(timeout));
+        try!(stream.set_write_timeout(timeout));
+        Ok(Connection::new(stream))
+    }
+
+    // Packet id before the first one of a session
+    fn _initial_pid(&mut self) -> PacketIdentifier {
+        if self.random_pids {
+            PacketIdentifier(self.rng.gen_range(0, 65535))
+        } else {
+            PacketIdentifier::zero()
+        }
+    }
+
+    // Time to wait before reconnecting, with the jitter
+    fn _reconnect_delay(&mut self, dur: Duration) -> Duration {
+        match self.reconnect_jitter {
+            Some(max) => {
+                let max_ms = max.as_secs() * 1000 + max.subsec_millis() as u64;
+                dur + Duration::from_millis(self.rng.gen_range(0, max_ms + 1))
+            }
+            None => dur
+        }
+    }
+
+    fn _encode_payload(&self, topic: &str, mut payload: Payload) -> Result<Payload> {
+        if let Some(ref compressor) = self.compression {
+            payload = try!(compressor.encode(payload));
+        }
+        if let Some(ref cipher) = self.cipher {
+            payload = Arc::new(try!(cipher.encrypt(topic, &payload)));
+        }
+        Ok(payload)
+    }
+
+    fn _decode_payload(&self, topic: &str, mut payload: Payload) -> Result<Payload> {
+        if let Some(ref cipher) = self.cipher {
+            payload = Arc::new(try!(cipher.decrypt(topic, &payload)));
+        }
+        if let Some(ref compressor) = self.compression {
+            payload = try!(compressor.decode(payload));
+        }
+        Ok(payload)
+    }
+
+    fn _intercept_publish(&self, topic: TopicPath, payload: Payload, pubopt: PubOpt)
+                          -> Result<(TopicPath, Payload, PubOpt)> {
+        if self.middlewares.is_empty() {
+            return Ok((topic, payload, pubopt));
+        }
+        let mut message = Message::new(topic, payload, pubopt.qos());
+        message.retain = pubopt.is_retain();
+        for middleware in &self.middlewares {
+            try!(middleware.publish(&mut message));
+        }
+        let topic = try!(message.topic.path.to_topic_name());
+        Ok((topic, message.payload, PubOpt::new(message.qos, message.retain)))
+    }
+
+    fn _intercept_receive(&self, message: &mut Message) -> Result<()> {
+        for middleware in self.middlewares.iter().rev() {
+            try!(middleware.receive(message));
+        }
+        Ok(())
+    }
+
+    fn _keep_message(&self, message: &Message) -> bool {
+        self.filters.iter().all(|&(ref filter, ref keep)| !message.topic.matches(filter) || keep(message))
     }
 
     fn _generate_connect_packet(&self) -> Box<mqtt3::Connect> {
@@ -191,28 +534,202 @@ impl ClientOptions {
     }
 }
 
+/// Outgoing QoS 1 or 2 publish which isn't acknowledged yet, see
+/// `Client::pending`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pending {
+    pub pid: PacketIdentifier,
+    pub topic: String,
+    pub qos: QoS,
+    /// Time since the first send
+    pub age: Duration,
+    /// Times it was sent again after reconnecting
+    pub retries: u32,
+    /// QoS 2 publish the broker received, waiting for PUBCOMP
+    pub released: bool
+}
+
+/// Subscription acknowledged by the broker, see `Client::subscriptions`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActiveSubscription {
+    pub filter: String,
+    /// Granted by the broker, at most the requested one
+    pub qos: QoS,
+    pub requested_qos: QoS,
+    /// Messages received on a topic matching the filter, across reconnects
+    pub messages: u64,
+    /// Payload bytes of those messages
+    pub bytes: u64
+}
+
+/// Packets and bytes of one packet type, or all of them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Traffic {
+    pub packets: u64,
+    pub bytes: u64
+}
+
+impl Traffic {
+    fn add(&mut self, packet: &Packet) {
+        self.packets += 1;
+        self.bytes += packet.size() as u64;
+    }
+}
+
+/// Counters of a client since it was created, see `Client::stats`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    // Indexed by the packet type
+    sent: [Traffic; 15],
+    received: [Traffic; 15],
+    /// Reconnects that got through the handshake
+    pub reconnects: u32,
+    /// Between the last PINGREQ and its PINGRESP
+    pub last_rtt: Option<Duration>,
+    /// Moving average of the ping round trips, each new one weighs 1/8
+    pub smoothed_rtt: Option<Duration>,
+    /// Outgoing QoS 1 and 2 publishes not acknowledged yet
+    pub inflight_out: usize,
+    /// Incomming QoS 2 publishes not completed yet
+    pub inflight_in: usize
+}
+
+impl Stats {
+    pub fn sent(&self, packet_type: PacketType) -> Traffic {
+        self.sent[packet_type.to_u8() as usize]
+    }
+
+    pub fn received(&self, packet_type: PacketType) -> Traffic {
+        self.received[packet_type.to_u8() as usize]
+    }
+
+    pub fn total_sent(&self) -> Traffic {
+        total(&self.sent)
+    }
+
+    pub fn total_received(&self) -> Traffic {
+        total(&self.received)
+    }
+}
+
+fn total(traffic: &[Traffic]) -> Traffic {
+    traffic.iter().fold(Traffic::default(), |total, t| {
+        Traffic {
+            packets: total.packets + t.packets,
+            bytes: total.bytes + t.bytes
+        }
+    })
+}
+
+struct Inflight {
+    message: Box<Message>,
+    order: u64,
+    sent: Instant,
+    retries: u32,
+    completion: Option<Completion>,
+    outbox_seq: Option<u64>
+}
+
+impl Inflight {
+    fn new(message: Box<Message>, order: u64, sent: Instant, completion: Option<Completion>) -> Inflight {
+        Inflight {
+            message: message,
+            order: order,
+            sent: sent,
+            retries: 0,
+            completion: completion,
+            outbox_seq: None
+        }
+    }
+
+    fn complete(self) {
+        if let Some(completion) = self.completion {
+            completion.complete();
+        }
+    }
+
+    fn pid(&self) -> PacketIdentifier {
+        self.message.pid.unwrap()
+    }
+
+    fn pending(&self, released: bool, now: Instant) -> Pending {
+        Pending {
+            pid: self.pid(),
+            topic: self.message.topic.path(),
+            qos: self.message.qos,
+            age: now.duration_since(self.sent),
+            retries: self.retries,
+            released: released
+        }
+    }
+}
+
+// Publish made while disconnected or held back by the topic window
+struct Queued {
+    topic: TopicPath,
+    payload: Payload,
+    pubopt: PubOpt,
+    completion: Option<Completion>
+}
+
+// Acknowledgements may come in any order, so look for the pid
+fn take_inflight(queue: &mut VecDeque<Inflight>, pid: PacketIdentifier) -> Option<Inflight> {
+    match queue.iter().position(|inflight| inflight.pid() == pid) {
+        Some(i) => queue.remove(i),
+        None => None
+    }
+}
+
+// The broker gives the client 1.5 times the keep alive before dropping it,
+// the client gives the broker as long for PINGRESP and blocked writes
+fn grace(keep_alive: Duration) -> Duration {
+    keep_alive * 3 / 2
+}
+
 pub struct Client {
-    addr: SocketAddr,
+    // Index of the broker in `opts.brokers`
+    broker: usize,
     state: ClientState,
     netopt: NetworkOptions,
     opts: ClientOptions,
     conn: Connection,
     session_present: bool,
+    reconnect_attempt: u32,
+    poll_timeout: Option<Duration>,
 
     // Queues
     last_flush: Instant,
+    unflushed: usize,
+    unflushed_since: Option<Instant>,
     last_pid: PacketIdentifier,
     await_ping: bool,
+    ping_sent: Option<Instant>,
+    stats: Stats,
+    // QoS 1 messages are acknowledged with `acknowledge`
+    defer_acks: bool,
     incomming_pub: VecDeque<Box<Message>>, // QoS 1
+    // Last QoS 1 messages, see `set_dedup_window`
+    received_pub: VecDeque<(PacketIdentifier, String)>,
     incomming_rec: VecDeque<Box<Message>>, // QoS 2
     incomming_rel: VecDeque<PacketIdentifier>, // QoS 2
-    outgoing_ack: VecDeque<Box<Message>>, // QoS 1
-    outgoing_rec: VecDeque<Box<Message>>, // QoS 2
-    outgoing_comp: VecDeque<PacketIdentifier>, // QoS 2
+    outgoing_ack: VecDeque<Inflight>, // QoS 1
+    outgoing_rec: VecDeque<Inflight>, // QoS 2
+    outgoing_comp: VecDeque<Inflight>, // QoS 2
+    expired: HashSet<PacketIdentifier>,
+    offline: VecDeque<Queued>,
+    // Publishes held back by the topic window
+    waiting: HashMap<String, VecDeque<Queued>>,
+    // Publishes written so far, orders the in-flight ones
+    published: u64,
+    // Last outbox message sent on this session
+    outbox_sent: Option<u64>,
+    outbox_tokens: HashMap<u64, Completion>,
     await_suback: VecDeque<Box<mqtt3::Subscribe>>,
     await_unsuback: VecDeque<Box<mqtt3::Unsubscribe>>,
     // Subscriptions
     subscriptions: HashMap<String, Subscription>,
+    resubscribe_pid: Option<PacketIdentifier>,
+    events: VecDeque<Event>,
 }
 
 impl PubSub for Client {
@@ -220,8 +737,7 @@ impl PubSub for Client {
         where T: ToTopicPath,
               P: ToPayload
     {
-        try!(self._publish(topic, payload, pubopt));
-        self._flush()
+        self._publish(topic, payload, pubopt, None)
     }
 
     fn subscribe<S: ToSubTopics>(&mut self, subs: S) -> Result<()> {
@@ -238,9 +754,33 @@ impl PubSub for Client {
         // self._disconnect();
         self._flush()
     }
+
+    fn wait_in_flight(&mut self, max: usize) -> Result<Vec<Box<Message>>> {
+        let mut messages = Vec::new();
+        while self.outgoing_ack.len() + self.outgoing_rec.len() + self.outgoing_comp.len() > max {
+            match self.accept() {
+                Ok(Some(message)) => messages.push(message),
+                Ok(None) => (),
+                Err(Error::Timeout) if self.state == ClientState::Connected => self._keep_alive(),
+                Err(err) => return Err(err)
+            }
+        }
+        Ok(messages)
+    }
 }
 
 impl Client {
+    /// Like `publish` but the returned token tells when the broker has the
+    /// message, see `DeliveryToken`
+    pub fn publish_with_token<T, P>(&mut self, topic: T, payload: P, pubopt: PubOpt) -> Result<DeliveryToken>
+        where T: ToTopicPath,
+              P: ToPayload
+    {
+        let (token, completion) = token::new();
+        try!(self._publish(topic, payload, pubopt, Some(completion)));
+        Ok(token)
+    }
+
     pub fn await(&mut self) -> Result<Option<Box<Message>>> {
         loop {
             match self.accept() {
@@ -253,11 +793,7 @@ impl Client {
                     match e {
                         Error::Timeout => {
                             if self.state == ClientState::Connected {
-                                if !self.await_ping {
-                                    let _ = self.ping();
-                                } else {
-                                    self._unbind();
-                                }
+                                self._keep_alive();
                             } else {
                                 return Err(Error::Timeout);
                             }
@@ -275,23 +811,53 @@ impl Client {
     pub fn accept(&mut self) -> Result<Option<Box<Message>>> {
         match self.state {
             ClientState::Connected | ClientState::Handshake => {
+                if self.unflushed > 0 && self._batch_ready() {
+                    try!(self._flush());
+                }
+
                 // Don't forget to send PING packets in time
-                if let Some(keep_alive) = self.opts.keep_alive {
-                    let elapsed = self.last_flush.elapsed();
-                    if elapsed >= keep_alive {
+                let mut timeout = self.poll_timeout;
+                if let Some(limit) = self._keep_alive_limit() {
+                    let elapsed = self._now().duration_since(self.last_flush);
+                    if elapsed >= limit {
                         return Err(Error::Timeout);
                     }
-                    try!(self.conn.set_read_timeout(Some(keep_alive - elapsed)));
+                    let remaining = limit - elapsed;
+                    if timeout.map_or(true, |timeout| remaining < timeout) {
+                        timeout = Some(remaining);
+                    }
                 }
+                try!(self.conn.set_read_timeout(timeout));
 
-                match self.conn.read_packet() {
+                let packet = match self.opts.max_packet_size {
+                    Some(max_packet_size) => self.conn.read_packet_limited(max_packet_size),
+                    None => self.conn.read_packet()
+                };
+                match packet {
                     Ok(packet) => {
+                        self.stats.received[packet.packet_type().to_u8() as usize].add(&packet);
                         match self._parse_packet(packet) {
-                            Ok(message) => Ok(message),
+                            Ok(Some(mut message)) => {
+                                if self._duplicate(&message) {
+                                    debug!("     Duplicate {}", message.topic.path);
+                                    try!(self._discard(&message));
+                                    return Ok(None);
+                                }
+                                message.payload = try!(self.opts._decode_payload(&message.topic.path, message.payload));
+                                try!(self.opts._intercept_receive(&mut message));
+                                if self.opts._keep_message(&message) {
+                                    Ok(Some(message))
+                                } else {
+                                    debug!("      Filtered {}", message.topic.path);
+                                    try!(self._discard(&message));
+                                    Ok(None)
+                                }
+                            }
+                            Ok(None) => Ok(None),
                             Err(err) => {
                                 match err {
                                     Error::ConnectionAbort => {
-                                        self._unbind();
+                                        self._unbind(DisconnectReason::ConnectionLost);
                                         Err(Error::ConnectionAbort)
                                     }
                                     err => {
@@ -306,6 +872,7 @@ impl Client {
                         match err {
                             mqtt3::Error::UnexpectedEof => {
                                 error!("{:?}", err);
+                                self._unbind(DisconnectReason::ConnectionLost);
                                 if self._try_reconnect() {
                                     Ok(None)
                                 } else {
@@ -322,7 +889,7 @@ impl Client {
                                     ErrorKind::ConnectionReset |
                                     ErrorKind::ConnectionAborted => {
                                         error!("{:?}", e);
-                                        self._unbind();
+                                        self._unbind(DisconnectReason::ConnectionLost);
                                         if self._try_reconnect() {
                                             Ok(None)
                                         } else {
@@ -331,11 +898,16 @@ impl Client {
                                     }
                                     _ => {
                                         error!("{:?}", e);
-                                        self._unbind();
+                                        self._unbind(DisconnectReason::Io);
                                         Err(Error::from(e))
                                     }
                                 }
                             }
+                            mqtt3::Error::PacketTooLarge => {
+                                // The reader skipped the body already
+                                error!("Skipped a packet over the maximum packet size");
+                                Ok(None)
+                            }
                             _ => {
                                 error!("{:?}", err);
                                 Err(Error::from(err))
@@ -354,32 +926,105 @@ impl Client {
         }
     }
 
+    /// Reads at most one packet, waiting no longer than `timeout`. Unlike
+    /// `await` it returns `Ok(None)` when nothing arrived, so the caller can
+    /// do other work between reads. PINGREQ is still sent in time.
+    pub fn poll(&mut self, timeout: Duration) -> Result<Option<Box<Message>>> {
+        self.poll_timeout = Some(timeout);
+        let result = self.accept();
+        self.poll_timeout = None;
+        match result {
+            Err(Error::Timeout) => {
+                if self.state != ClientState::Connected {
+                    return Err(Error::Timeout);
+                }
+                if let Some(limit) = self._keep_alive_limit() {
+                    if self._now().duration_since(self.last_flush) >= limit {
+                        self._keep_alive();
+                    }
+                }
+                Ok(None)
+            }
+            result => result,
+        }
+    }
+
+    /// Moves the client to a background thread, see `ClientHandle`
+    pub fn spawn(self) -> ClientHandle {
+        handle::spawn(self)
+    }
+
     pub fn reconnect(&mut self) -> Result<()> {
         if self.state == ClientState::Connected {
             warn!("mqttc is already connected");
             return Ok(());
         };
-        let (conn, _) = try!(self.opts._reconnect(self.addr, &self.netopt));
+        let first = match self.opts.failover {
+            Failover::Ordered => 0,
+            Failover::RoundRobin => (self.broker + 1) % self.opts.brokers.len()
+        };
+        let (broker, conn) = try!(self.opts._connect_any(first, &self.netopt));
+        self.broker = broker;
         self.conn = conn;
         try!(self._handshake());
+        self.stats.reconnects += 1;
 
-        self._resubscribe();
-
-        Ok(())
+        if self.session_present {
+            // The broker still has the session, complete the interrupted flows
+            try!(self._resend());
+        } else {
+            self._reset_session();
+            try!(self._resubscribe());
+        }
+        try!(self._send_outbox());
+        let topics: Vec<String> = self.waiting.keys().cloned().collect();
+        for topic in topics {
+            try!(self._send_waiting(&topic));
+        }
+        self._send_offline()
     }
 
     pub fn ping(&mut self) -> Result<()> {
         debug!("       Pingreq");
         self.await_ping = true;
+        self.ping_sent = Some(self._now());
         self._write_packet(&Packet::Pingreq);
         self._flush()
     }
 
+    /// Sends PINGREQ and waits up to `timeout` for PINGRESP. Returns the
+    /// round trip, `None` if PINGRESP didn't come in time, and the messages
+    /// which arrived meanwhile.
+    pub fn measure_rtt(&mut self, timeout: Duration) -> Result<(Option<Duration>, Vec<Box<Message>>)> {
+        let started = Instant::now();
+        let mut messages = Vec::new();
+        try!(self.ping());
+        while self.ping_sent.is_some() {
+            if self.state != ClientState::Connected {
+                return Err(Error::Disconnected);
+            }
+            let elapsed = started.elapsed();
+            if elapsed >= timeout {
+                return Ok((None, messages));
+            }
+            if let Some(message) = try!(self.poll(timeout - elapsed)) {
+                messages.push(message);
+            }
+        }
+        Ok((self.stats.last_rtt, messages))
+    }
+
+    /// Smoothed round trip of the pings, see `Stats::smoothed_rtt`. The
+    /// keep alive pings keep it up to date.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.stats.smoothed_rtt
+    }
+
     pub fn complete(&mut self, pid: PacketIdentifier) -> Result<()> {
-        let same_pid = self.incomming_rel.pop_back();
-        if same_pid == Some(pid) {
+        if let Some(i) = self.incomming_rel.iter().position(|&rel| rel == pid) {
+            self.incomming_rel.remove(i);
             self._write_packet(&Packet::Pubcomp(pid));
-            try!(self._flush());
+            try!(self._flush_ack());
 
             if let Some(ref mut store) = self.opts.incomming_store {
                 try!(store.delete(pid));
@@ -392,8 +1037,49 @@ impl Client {
         }
     }
 
+    /// Waits up to `timeout` for the outgoing QoS 1 and 2 flows to complete,
+    /// then sends DISCONNECT and closes the connection. Messages which arrive
+    /// meanwhile are returned.
+    ///
+    /// Flows that didn't complete in time are kept, so `reconnect` resumes
+    /// them if the broker keeps the session (`clean_session` is false).
+    pub fn disconnect_gracefully(&mut self, timeout: Duration) -> Result<Vec<Box<Message>>> {
+        let started = Instant::now();
+        let mut messages = Vec::new();
+        while self._in_flight() && self.state != ClientState::Disconnected {
+            let elapsed = started.elapsed();
+            if elapsed >= timeout {
+                break;
+            }
+            match self.poll(timeout - elapsed) {
+                Ok(Some(message)) => messages.push(message),
+                Ok(None) => (),
+                Err(Error::Disconnected) => return Err(Error::Disconnected),
+                Err(err) => error!("{:?}", err),
+            }
+        }
+        if self._in_flight() {
+            warn!("Disconnecting with {} unfinished publishes",
+                  self.outgoing_ack.len() + self.outgoing_rec.len() + self.outgoing_comp.len());
+        }
+
+        if self.state == ClientState::Connected {
+            debug!("    Disconnect");
+            self._disconnect();
+            try!(self._flush());
+        }
+        self._unbind(DisconnectReason::Requested);
+        Ok(messages)
+    }
+
+    /// Hands a received message back once it's processed, so the buffer of
+    /// its payload is reused for the next one
+    pub fn recycle(&mut self, message: Box<Message>) {
+        self.conn.recycle(message.payload);
+    }
+
     pub fn terminate(&mut self) {
-        self._unbind();
+        self._unbind(DisconnectReason::Requested);
     }
 
     pub fn set_reconnect(&mut self, reconnect: ReconnectMethod) {
@@ -404,6 +1090,107 @@ impl Client {
         self.session_present
     }
 
+    /// Address of the broker connected to, see `ClientOptions::add_broker`
+    pub fn broker(&self) -> SocketAddr {
+        self.opts.brokers[self.broker]
+    }
+
+    /// Client id sent in CONNECT, including a generated one
+    pub fn client_id(&self) -> &str {
+        self.opts.client_id.as_ref().map_or("", |id| id.as_str())
+    }
+
+    /// Keep alive interval in use, see `ClientOptions::set_keep_alive`
+    pub fn keep_alive(&self) -> Option<Duration> {
+        self.opts.keep_alive
+    }
+
+    /// Packets and bytes by packet type, reconnects, the round trip of
+    /// the last ping and the publishes in flight
+    pub fn stats(&self) -> Stats {
+        let mut stats = self.stats.clone();
+        stats.inflight_out = self.outgoing_ack.len() + self.outgoing_rec.len() + self.outgoing_comp.len();
+        stats.inflight_in = self.incomming_rec.len() + self.incomming_rel.len();
+        stats
+    }
+
+    /// Takes the events collected since the last call, see
+    /// `ClientOptions::enable_events`
+    pub fn events(&mut self) -> Drain<'_, Event> {
+        self.events.drain(..)
+    }
+
+    /// Outgoing QoS 1 and 2 publishes the broker hasn't acknowledged yet,
+    /// oldest first
+    pub fn pending(&self) -> Vec<Pending> {
+        let mut inflight: Vec<(&Inflight, bool)> = self.outgoing_ack
+                                                       .iter()
+                                                       .chain(self.outgoing_rec.iter())
+                                                       .map(|inflight| (inflight, false))
+                                                       .chain(self.outgoing_comp.iter().map(|inflight| (inflight, true)))
+                                                       .collect();
+        inflight.sort_by_key(|&(inflight, _)| (inflight.sent, inflight.order));
+        let now = self._now();
+        inflight.into_iter().map(|(inflight, released)| inflight.pending(released, now)).collect()
+    }
+
+    /// Filters the broker granted and not unsubscribed from, by filter.
+    /// They are the ones replayed after reconnecting to a new session.
+    pub fn subscriptions(&self) -> Vec<ActiveSubscription> {
+        let mut subscriptions: Vec<ActiveSubscription> = self.subscriptions.iter().map(|(filter, sub)| {
+            ActiveSubscription {
+                filter: filter.clone(),
+                qos: sub.qos,
+                requested_qos: sub.requested_qos,
+                messages: sub.messages,
+                bytes: sub.bytes
+            }
+        }).collect();
+        subscriptions.sort_by(|a, b| a.filter.cmp(&b.filter));
+        subscriptions
+    }
+
+    /// Stops tracking an unacknowledged publish and returns it, `None` if
+    /// there is none with the pid. It isn't sent again after reconnecting; a
+    /// late acknowledgement from the broker is ignored.
+    pub fn cancel(&mut self, pid: PacketIdentifier) -> Result<Option<Box<Message>>> {
+        let inflight = if let Some(inflight) = take_inflight(&mut self.outgoing_ack, pid) {
+            inflight
+        } else if let Some(inflight) = take_inflight(&mut self.outgoing_rec, pid) {
+            if let Some(ref mut store) = self.opts.outgoing_store {
+                try!(store.delete(pid));
+            }
+            inflight
+        } else if let Some(inflight) = take_inflight(&mut self.outgoing_comp, pid) {
+            inflight
+        } else {
+            return Ok(None);
+        };
+        if let (Some(seq), Some(ref mut outbox)) = (inflight.outbox_seq, self.opts.outbox.as_mut()) {
+            try!(outbox.remove(seq));
+        }
+        debug!("        Cancel {:?}", pid);
+        self.expired.insert(pid);
+        try!(self._send_waiting(&inflight.message.topic.path));
+        Ok(Some(inflight.message))
+    }
+
+    /// Cancels the unacknowledged publishes older than `max_age`, see `cancel`
+    pub fn expire(&mut self, max_age: Duration) -> Result<Vec<Box<Message>>> {
+        let pids: Vec<PacketIdentifier> = self.pending()
+                                              .into_iter()
+                                              .filter(|pending| pending.age > max_age)
+                                              .map(|pending| pending.pid)
+                                              .collect();
+        let mut messages = Vec::with_capacity(pids.len());
+        for pid in pids {
+            if let Some(message) = try!(self.cancel(pid)) {
+                messages.push(message);
+            }
+        }
+        Ok(messages)
+    }
+
     fn _normalized(&self) -> bool {
         (self.state == ClientState::Connected) && (!self.await_ping) &&
         (self.outgoing_ack.len() == 0) && (self.outgoing_rec.len() == 0) &&
@@ -412,16 +1199,23 @@ impl Client {
         (self.await_unsuback.len() == 0)
     }
 
+    fn _in_flight(&self) -> bool {
+        !self.outgoing_ack.is_empty() || !self.outgoing_rec.is_empty() ||
+        !self.outgoing_comp.is_empty()
+    }
+
     fn _parse_packet(&mut self, packet: Packet) -> Result<Option<Box<Message>>> {
         trace!("{:?}", packet);
         match self.state {
             ClientState::Handshake => {
                 match packet {
                     Packet::Connack(ref connack) => {
-                        if connack.code == ConnectReturnCode::Accepted {
+                        if connack.code.is_success() {
                             self.session_present = connack.session_present;
                             self.state = ClientState::Connected;
+                            self.reconnect_attempt = 0;
                             info!("    Connection accepted");
+                            self._event(Event::Connected { session_present: connack.session_present });
                             Ok(None)
                         } else {
                             Err(Error::ConnectionRefused(connack.code))
@@ -433,38 +1227,52 @@ impl Client {
             ClientState::Connected => {
                 match packet {
                     Packet::Connack(_) => Err(Error::AlreadyConnected),
-                    Packet::Publish(ref publish) => {
-                        let message = try!(Message::from_pub(publish.clone()));
+                    Packet::Publish(publish) => {
+                        let message = try!(Message::from_pub(publish));
+                        self._count_message(&message);
                         self._handle_message(message)
                     }
                     Packet::Puback(pid) => {
-                        if let Some(message) = self.outgoing_ack.pop_front() {
-                            if message.pid == Some(pid) {
-                                Ok(None)
-                            } else {
-                                Err(Error::UnhandledPuback(pid))
+                        if let Some(inflight) = take_inflight(&mut self.outgoing_ack, pid) {
+                            let outbox_seq = inflight.outbox_seq;
+                            let topic = inflight.message.topic.path();
+                            inflight.complete();
+                            self._event(Event::PublishComplete { pid: pid });
+                            if let Some(seq) = outbox_seq {
+                                if let Some(ref mut outbox) = self.opts.outbox {
+                                    try!(outbox.remove(seq));
+                                }
+                                try!(self._send_outbox());
                             }
+                            try!(self._send_waiting(&topic));
+                            Ok(None)
+                        } else if self.expired.remove(&pid) {
+                            Ok(None)
                         } else {
                             Err(Error::UnhandledPuback(pid))
                         }
                     }
                     Packet::Pubrec(pid) => {
-                        if let Some(message) = self.outgoing_rec.pop_front() {
-                            if message.pid == Some(pid) {
-                                self._write_packet(&Packet::Pubrel(pid));
-                                try!(self._flush());
-
-                                self.outgoing_comp.push_back(pid);
-                                if let Some(ref mut store) = self.opts.outgoing_store {
-                                    try!(store.delete(pid));
-                                } else {
-                                    return Err(Error::IncommingStorageAbsent);
-                                }
-
-                                Ok(None)
+                        if let Some(inflight) = take_inflight(&mut self.outgoing_rec, pid) {
+                            self._write_packet(&Packet::Pubrel(pid));
+                            try!(self._flush_ack());
+
+                            let topic = inflight.message.topic.path();
+                            self.outgoing_comp.push_back(inflight);
+                            if let Some(ref mut store) = self.opts.outgoing_store {
+                                try!(store.delete(pid));
                             } else {
-                                Err(Error::UnhandledPubrec(pid))
+                                return Err(Error::IncommingStorageAbsent);
                             }
+
+                            // The broker has it, the next one of the topic can go
+                            try!(self._send_waiting(&topic));
+                            Ok(None)
+                        } else if self.expired.contains(&pid) {
+                            // Let the broker finish the flow of a cancelled publish
+                            self._write_packet(&Packet::Pubrel(pid));
+                            try!(self._flush_ack());
+                            Ok(None)
                         } else {
                             Err(Error::UnhandledPubrec(pid))
                         }
@@ -488,7 +1296,11 @@ impl Client {
                         }
                     }
                     Packet::Pubcomp(pid) => {
-                        if let Some(_) = self.outgoing_comp.pop_front() {
+                        if let Some(inflight) = take_inflight(&mut self.outgoing_comp, pid) {
+                            inflight.complete();
+                            self._event(Event::PublishComplete { pid: pid });
+                            Ok(None)
+                        } else if self.expired.remove(&pid) {
                             Ok(None)
                         } else {
                             Err(Error::UnhandledPubcomp(pid))
@@ -498,24 +1310,49 @@ impl Client {
                         if let Some(subscribe) = self.await_suback.pop_front() {
                             if subscribe.pid == suback.pid {
                                 if subscribe.topics.len() == suback.return_codes.len() {
+                                    let resubscribed = self.resubscribe_pid == Some(subscribe.pid);
                                     let iter = suback.return_codes.iter().zip(&subscribe.topics);
                                     for (ref code, ref sub_topic) in iter {
                                         match **code {
                                             SubscribeReturnCodes::Success(qos) => {
+                                                // counters carry on over a resubscribe
+                                                let (messages, bytes) = self.subscriptions
+                                                                            .get(&sub_topic.topic_path)
+                                                                            .map_or((0, 0), |sub| (sub.messages, sub.bytes));
                                                 let sub = Subscription {
                                                     pid: subscribe.pid,
                                                     topic_path: try!(sub_topic.topic_path
                                                                               .to_topic_path()),
                                                     qos: qos,
+                                                    requested_qos: sub_topic.qos,
+                                                    messages: messages,
+                                                    bytes: bytes
                                                 };
                                                 self.subscriptions
                                                     .insert(sub_topic.topic_path.clone(), sub);
                                             }
                                             SubscribeReturnCodes::Failure => {
                                                 // ignore subscription
+                                                if resubscribed {
+                                                    self.subscriptions.remove(&sub_topic.topic_path);
+                                                }
                                             }
                                         }
                                     }
+                                    let return_codes = subscribe.topics
+                                                                .iter()
+                                                                .map(|t| t.topic_path.clone())
+                                                                .zip(suback.return_codes.iter().cloned())
+                                                                .collect();
+                                    if resubscribed {
+                                        self.resubscribe_pid = None;
+                                        self._event(Event::Resubscribed(return_codes));
+                                    } else {
+                                        self._event(Event::SubscribeAck {
+                                            pid: suback.pid,
+                                            return_codes: return_codes,
+                                        });
+                                    }
                                     Ok(None)
                                 } else {
                                     Err(Error::ProtocolViolation)
@@ -533,6 +1370,7 @@ impl Client {
                                 for topic in unsubscribe.topics.iter() {
                                     self.subscriptions.remove(topic);
                                 }
+                                self._event(Event::UnsubscribeAck { pid: pid });
                                 Ok(None)
                             } else {
                                 Err(Error::ProtocolViolation)
@@ -543,6 +1381,15 @@ impl Client {
                     }
                     Packet::Pingresp => {
                         self.await_ping = false;
+                        if let Some(sent) = self.ping_sent.take() {
+                            let rtt = self._now().duration_since(sent);
+                            self.stats.last_rtt = Some(rtt);
+                            self.stats.smoothed_rtt = Some(match self.stats.smoothed_rtt {
+                                Some(smoothed) => (smoothed * 7 + rtt) / 8,
+                                None => rtt
+                            });
+                            self._event(Event::Pong { rtt: rtt });
+                        }
                         Ok(None)
                     }
                     _ => Err(Error::UnrecognizedPacket),
@@ -559,12 +1406,13 @@ impl Client {
                message.payload.len());
         match message.qos {
             QoS::AtMostOnce => Ok(Some(message)),
+            QoS::AtLeastOnce if self.defer_acks => Ok(Some(message)),
             QoS::AtLeastOnce => {
                 self.incomming_pub.push_back(message.clone());
                 let pid = message.pid.unwrap();
                 // debug!("        Puback {}", pid.0);
                 self._write_packet(&Packet::Puback(pid));
-                try!(self._flush());
+                try!(self._flush_ack());
                 // FIXME: can be repeated
                 let _ = self.incomming_pub.pop_front();
 
@@ -581,27 +1429,90 @@ impl Client {
                 }
 
                 self._write_packet(&Packet::Pubrec(pid));
-                try!(self._flush());
+                try!(self._flush_ack());
 
                 Ok(None)
             }
         }
     }
 
-    fn _handshake(&mut self) -> Result<()> {
-        self.state = ClientState::Handshake;
-        // send CONNECT
-        try!(self._connect());
-        // wait CONNACK
-        let _ = try!(self.await());
-        Ok(())
+    fn _count_message(&mut self, message: &Message) {
+        for sub in self.subscriptions.values_mut() {
+            if message.topic.matches(&sub.topic_path) {
+                sub.messages += 1;
+                sub.bytes += message.payload.len() as u64;
+            }
+        }
+    }
+
+    // Remembers QoS 1 messages, true for a redelivered one seen before
+    fn _duplicate(&mut self, message: &Message) -> bool {
+        let capacity = match (self.opts.dedup_window, message.qos, message.pid) {
+            (Some(capacity), QoS::AtLeastOnce, Some(_)) if capacity > 0 => capacity,
+            _ => return false
+        };
+        let pid = message.pid.unwrap();
+        if let Some(i) = self.received_pub.iter().position(|&(received, _)| received == pid) {
+            if message.dup() && self.received_pub[i].1 == message.topic.path {
+                return true;
+            }
+            // The broker reused the pid for a new message
+            self.received_pub.remove(i);
+        }
+        if self.received_pub.len() >= capacity {
+            self.received_pub.pop_front();
+        }
+        self.received_pub.push_back((pid, message.topic.path.clone()));
+        false
+    }
+
+    // Ends the flow of a message the application won't get
+    fn _discard(&mut self, message: &Message) -> Result<()> {
+        match (message.qos, message.pid) {
+            (QoS::AtLeastOnce, Some(pid)) if self.defer_acks => acknowledge(self, pid),
+            (QoS::ExactlyOnce, Some(pid)) => self.complete(pid),
+            _ => Ok(())
+        }
+    }
+
+    // Time without writes after which a PINGREQ is due, or after the
+    // PINGREQ the PINGRESP is late
+    fn _keep_alive_limit(&self) -> Option<Duration> {
+        self.opts.keep_alive.map(|keep_alive| {
+            if self.await_ping { grace(keep_alive) } else { keep_alive }
+        })
+    }
+
+    // Nothing was written for the keep alive interval
+    fn _keep_alive(&mut self) {
+        if !self.await_ping {
+            let _ = self.ping();
+        } else {
+            self._event(Event::PingTimeout);
+            self._unbind(DisconnectReason::PingTimeout);
+        }
+    }
+
+    fn _handshake(&mut self) -> Result<()> {
+        self.state = ClientState::Handshake;
+        // send CONNECT
+        try!(self._connect());
+        // wait CONNACK
+        while self.state == ClientState::Handshake {
+            let _ = try!(self.accept());
+        }
+        Ok(())
     }
 
     fn _try_reconnect(&mut self) -> bool {
         match self.opts.reconnect {
             ReconnectMethod::ForeverDisconnect => false,
             ReconnectMethod::ReconnectAfter(dur) => {
-                info!("  Reconnect in {} seconds", dur.as_secs());
+                self.reconnect_attempt += 1;
+                let attempt = self.reconnect_attempt;
+                self._event(Event::Reconnecting { attempt: attempt });
+                let dur = self.opts._reconnect_delay(dur);
+                info!("  Reconnect in {} ms", dur.as_secs() * 1000 + dur.subsec_millis() as u64);
                 thread::sleep(dur);
                 let _ = self.reconnect();
                 true
@@ -620,30 +1531,88 @@ impl Client {
     fn _publish<T: ToTopicPath, P: ToPayload>(&mut self,
                                               topic: T,
                                               payload: P,
-                                              pubopt: PubOpt)
+                                              pubopt: PubOpt,
+                                              completion: Option<Completion>)
                                               -> Result<()> {
-        let mut message = Box::new(Message {
-            topic: try!(topic.to_topic_name()),
-            qos: pubopt.qos(),
-            retain: pubopt.is_retain(),
-            pid: None,
-            payload: payload.to_payload(),
-        });
+        let topic = try!(topic.to_topic_name());
+        let (topic, payload, pubopt) = try!(self.opts._intercept_publish(topic, payload.to_payload(), pubopt));
+        let payload = try!(self.opts._encode_payload(&topic.path, payload));
+        if pubopt.qos() == QoS::AtLeastOnce && self.opts.outbox.is_some() {
+            let mut message = Message::new(topic, payload, QoS::AtLeastOnce);
+            message.retain = pubopt.is_retain();
+            let seq = try!(self.opts.outbox.as_mut().unwrap().push(&message));
+            if let Some(completion) = completion {
+                self.outbox_tokens.insert(seq, completion);
+            }
+            return self._send_outbox();
+        }
+        if self.state == ClientState::Disconnected {
+            if let Some((limit, overflow)) = self.opts.offline_queue[pubopt.qos().to_u8() as usize] {
+                let queued = Queued {
+                    topic: topic,
+                    payload: payload,
+                    pubopt: pubopt,
+                    completion: completion
+                };
+                return self._queue(queued, limit, overflow);
+            }
+        }
+        if let Some(window) = self.opts.topic_window {
+            if self.waiting.contains_key(&topic.path) ||
+               (pubopt.qos() != QoS::AtMostOnce && self._in_flight_on(&topic.path) >= window) {
+                debug!("          Wait {}", topic.path);
+                let queued = Queued {
+                    topic: topic,
+                    payload: payload,
+                    pubopt: pubopt,
+                    completion: completion
+                };
+                self.waiting.entry(queued.topic.path.clone()).or_insert_with(VecDeque::new).push_back(queued);
+                return Ok(());
+            }
+        }
+        self._send_publish(topic, payload, pubopt, completion)
+    }
+
+    fn _send_publish(&mut self,
+                     topic: TopicPath,
+                     payload: Payload,
+                     pubopt: PubOpt,
+                     completion: Option<Completion>)
+                     -> Result<()> {
+        let mut message = Box::new(Message::new(topic, payload, pubopt.qos()));
+        message.retain = pubopt.is_retain();
+
+        if let Some(max_packet_size) = self.opts.max_packet_size {
+            // Sized with a stand-in pid, a refused message doesn't use one up
+            let mut publish = message.to_pub(None, false);
+            if message.qos != QoS::AtMostOnce {
+                publish.pid = Some(PacketIdentifier::zero());
+            }
+            if Packet::Publish(publish).size() > max_packet_size {
+                return Err(Error::Mqtt(mqtt3::Error::PacketTooLarge));
+            }
+        }
+        if message.qos != QoS::AtMostOnce {
+            message.pid = Some(self._next_pid());
+        }
+        let packet = Packet::Publish(message.to_pub(None, false));
 
+        let mut written = None;
         match message.qos {
-            QoS::AtMostOnce => (),
+            QoS::AtMostOnce => written = completion,
             QoS::AtLeastOnce => {
-                message.pid = Some(self._next_pid());
-                self.outgoing_ack.push_back(message.clone());
+                let order = self._next_order();
+                self.outgoing_ack.push_back(Inflight::new(message.clone(), order, self._now(), completion));
             }
             QoS::ExactlyOnce => {
-                message.pid = Some(self._next_pid());
                 if let Some(ref mut store) = self.opts.outgoing_store {
                     try!(store.put(message.clone()));
                 } else {
                     return Err(Error::OutgoingStorageAbsent);
                 }
-                self.outgoing_rec.push_back(message.clone());
+                let order = self._next_order();
+                self.outgoing_rec.push_back(Inflight::new(message.clone(), order, self._now(), completion));
             }
         }
 
@@ -651,8 +1620,122 @@ impl Client {
                message.qos.to_u8(),
                message.topic.path(),
                message.payload.len());
-        let packet = Packet::Publish(message.to_pub(None, false));
         self._write_packet(&packet);
+        try!(self._flush());
+        if let Some(completion) = written {
+            completion.complete();
+        }
+        Ok(())
+    }
+
+    fn _queue(&mut self, queued: Queued, limit: usize, overflow: Overflow) -> Result<()> {
+        let qos = queued.pubopt.qos();
+        let queued_qos = self.offline.iter().filter(|other| other.pubopt.qos() == qos).count();
+        if queued_qos >= limit {
+            match overflow {
+                Overflow::Block => {
+                    while self.state == ClientState::Disconnected {
+                        if !self._try_reconnect() {
+                            return Err(Error::Disconnected);
+                        }
+                    }
+                    return self._publish(queued.topic, queued.payload, queued.pubopt, queued.completion);
+                }
+                Overflow::DropOldest => {
+                    if let Some(i) = self.offline.iter().position(|other| other.pubopt.qos() == qos) {
+                        let dropped = self.offline.remove(i).unwrap();
+                        warn!("Offline queue is full, dropped {}", dropped.topic.path);
+                    }
+                }
+                Overflow::DropNewest => {
+                    warn!("Offline queue is full, dropped {}", queued.topic.path);
+                    return Ok(());
+                }
+                Overflow::Error => return Err(Error::QueueFull),
+            }
+        }
+        if limit > 0 {
+            debug!("         Queue {}", queued.topic.path);
+            self.offline.push_back(queued);
+        }
+        Ok(())
+    }
+
+    // Sends the next messages of the outbox, keeping up to OUTBOX_WINDOW in
+    // flight
+    fn _send_outbox(&mut self) -> Result<()> {
+        if self.state != ClientState::Connected {
+            return Ok(());
+        }
+        let in_flight = self.outgoing_ack.iter().filter(|inflight| inflight.outbox_seq.is_some()).count();
+        if in_flight >= OUTBOX_WINDOW {
+            return Ok(());
+        }
+        let seqs = match self.opts.outbox {
+            Some(ref outbox) => try!(outbox.seqs(self.outbox_sent, OUTBOX_WINDOW - in_flight)),
+            None => return Ok(()),
+        };
+        if seqs.is_empty() {
+            return Ok(());
+        }
+        for seq in seqs {
+            let mut message = try!(self.opts.outbox.as_mut().unwrap().get(seq));
+            message.pid = Some(self._next_pid());
+            let packet = Packet::Publish(message.to_pub(None, false));
+            debug!("       Publish {} {} > {} bytes from the outbox",
+                   message.qos.to_u8(),
+                   message.topic.path(),
+                   message.payload.len());
+            let completion = self.outbox_tokens.remove(&seq);
+            let order = self._next_order();
+            let mut inflight = Inflight::new(message, order, self._now(), completion);
+            inflight.outbox_seq = Some(seq);
+            self.outgoing_ack.push_back(inflight);
+            self._write_packet(&packet);
+            self.outbox_sent = Some(seq);
+        }
+        self._flush()
+    }
+
+    fn _in_flight_on(&self, topic: &str) -> usize {
+        self.outgoing_ack
+            .iter()
+            .chain(self.outgoing_rec.iter())
+            .filter(|inflight| inflight.message.topic.path == topic)
+            .count()
+    }
+
+    // Sends the publishes of the topic the window has room for now
+    fn _send_waiting(&mut self, topic: &str) -> Result<()> {
+        let window = match self.opts.topic_window {
+            Some(window) => window,
+            None => return Ok(()),
+        };
+        while self.state == ClientState::Connected {
+            let ready = match self.waiting.get(topic).and_then(|waiting| waiting.front()) {
+                Some(queued) => queued.pubopt.qos() == QoS::AtMostOnce || self._in_flight_on(topic) < window,
+                None => break,
+            };
+            if !ready {
+                return Ok(());
+            }
+            let queued = self.waiting.get_mut(topic).unwrap().pop_front().unwrap();
+            try!(self._send_publish(queued.topic, queued.payload, queued.pubopt, queued.completion));
+        }
+        if self.waiting.get(topic).map_or(false, |waiting| waiting.is_empty()) {
+            self.waiting.remove(topic);
+        }
+        Ok(())
+    }
+
+    // Sends what was published while disconnected
+    fn _send_offline(&mut self) -> Result<()> {
+        while self.state == ClientState::Connected {
+            match self.offline.pop_front() {
+                Some(queued) => try!(self._publish(queued.topic, queued.payload, queued.pubopt, queued.completion)),
+                None => break,
+            }
+        }
         Ok(())
     }
 
@@ -680,12 +1763,74 @@ impl Client {
         Ok(())
     }
 
-    fn _resubscribe(&mut self) {
+    fn _resubscribe(&mut self) -> Result<()> {
         let subs: Vec<SubscribeTopic> = self.subscriptions
                                             .values()
                                             .map(|sub| sub.to_subscribe_topic())
                                             .collect();
-        let _ = self._subscribe(subs);
+        if subs.is_empty() {
+            return Ok(());
+        }
+        try!(self._subscribe(subs));
+        self.resubscribe_pid = Some(self.last_pid);
+        self._flush()
+    }
+
+    // Retransmits unacknowledged PUBLISH and PUBREL packets of a resumed session
+    fn _resend(&mut self) -> Result<()> {
+        // QoS 1 and 2 ones in the order they were first sent
+        let mut publishes: Vec<&mut Inflight> = self.outgoing_ack.iter_mut().chain(self.outgoing_rec.iter_mut()).collect();
+        publishes.sort_by_key(|inflight| inflight.order);
+        let mut packets = Vec::new();
+        for inflight in publishes {
+            inflight.retries += 1;
+            packets.push(Packet::Publish(inflight.message.to_pub(None, true)));
+        }
+        for inflight in self.outgoing_comp.iter_mut() {
+            inflight.retries += 1;
+            packets.push(Packet::Pubrel(inflight.pid()));
+        }
+        if packets.is_empty() {
+            return Ok(());
+        }
+        debug!("        Resend {} packets", packets.len());
+        for packet in packets.iter() {
+            self._write_packet(packet);
+        }
+        self._flush()
+    }
+
+    // The broker has no session, so the in-flight messages are lost and packet
+    // identifiers can start over
+    fn _reset_session(&mut self) {
+        if let Some(ref mut store) = self.opts.outgoing_store {
+            for inflight in self.outgoing_rec.iter() {
+                let _ = store.delete(inflight.pid());
+            }
+        }
+        if let Some(ref mut store) = self.opts.incomming_store {
+            for message in self.incomming_rec.iter() {
+                let _ = store.delete(message.pid.unwrap());
+            }
+            for pid in self.incomming_rel.iter() {
+                let _ = store.delete(*pid);
+            }
+        }
+        self.incomming_pub.clear();
+        self.received_pub.clear();
+        self.incomming_rec.clear();
+        self.incomming_rel.clear();
+        // Outbox messages are sent again, with their tokens
+        for inflight in self.outgoing_ack.drain(..) {
+            if let (Some(seq), Some(completion)) = (inflight.outbox_seq, inflight.completion) {
+                self.outbox_tokens.insert(seq, completion);
+            }
+        }
+        self.outbox_sent = None;
+        self.outgoing_rec.clear();
+        self.outgoing_comp.clear();
+        self.expired.clear();
+        self.last_pid = self.opts._initial_pid();
     }
 
     fn _disconnect(&mut self) {
@@ -696,37 +1841,144 @@ impl Client {
     fn _write_packet(&mut self, packet: &Packet) {
         trace!("{:?}", packet);
         self.conn.write_packet(&packet).unwrap();
+        self.stats.sent[packet.packet_type().to_u8() as usize].add(packet);
+        self.unflushed += packet.size();
+        if self.unflushed_since.is_none() {
+            self.unflushed_since = Some(self._now());
+        }
     }
 
     fn _flush(&mut self) -> Result<()> {
         // TODO: in case of disconnection, trying to reconnect
         try!(self.conn.flush());
-        self.last_flush = Instant::now();
+        self.last_flush = self._now();
+        self.unflushed = 0;
+        self.unflushed_since = None;
         Ok(())
     }
 
-    fn _unbind(&mut self) {
+    // Acknowledgements may wait for the next ones, see `set_write_batch`
+    fn _flush_ack(&mut self) -> Result<()> {
+        if self._batch_ready() {
+            self._flush()
+        } else {
+            Ok(())
+        }
+    }
+
+    fn _batch_ready(&self) -> bool {
+        match self.opts.write_batch {
+            Some((max_bytes, max_delay)) => {
+                self.unflushed >= max_bytes || !self.conn.has_buffered_input() ||
+                self.unflushed_since.map_or(false, |since| self._now().duration_since(since) >= max_delay)
+            }
+            None => true,
+        }
+    }
+
+    fn _unbind(&mut self, reason: DisconnectReason) {
+        if self.state == ClientState::Disconnected {
+            return;
+        }
         let _ = self.conn.terminate();
         self.await_unsuback.clear();
         self.await_suback.clear();
+        self.resubscribe_pid = None;
         self.await_ping = false;
         self.state = ClientState::Disconnected;
         info!("  Disconnected {}", self.opts.client_id.clone().unwrap());
+        self._event(Event::Disconnected { reason: reason });
+    }
+
+    #[inline]
+    fn _now(&self) -> Instant {
+        self.opts.clock.now()
+    }
+
+    #[inline]
+    fn _event(&mut self, event: Event) {
+        if self.opts.events {
+            self.events.push_back(event);
+        }
+    }
+
+    #[inline]
+    fn _next_order(&mut self) -> u64 {
+        self.published += 1;
+        self.published
     }
 
     #[inline]
     fn _next_pid(&mut self) -> PacketIdentifier {
         self.last_pid = self.last_pid.next();
+        // The pid of a cancelled publish is taken again
+        self.expired.remove(&self.last_pid);
         self.last_pid
     }
 }
 
+// Publishes for `ClientHandle`, which made the token already
+pub fn publish_with_completion(client: &mut Client,
+                               topic: TopicPath,
+                               payload: Payload,
+                               pubopt: PubOpt,
+                               completion: Completion)
+                               -> Result<()> {
+    client._publish(topic, payload, pubopt, Some(completion))
+}
+
+// Waits for input on the connection for `ClientHandle`
+pub fn wait_input(client: &Client, waiter: &mut Waiter, timeout: Duration) -> io::Result<Wake> {
+    waiter.wait(&client.conn, Some(timeout))
+}
+
+pub fn delivery_channel(client: &Client) -> Option<(usize, Backpressure)> {
+    client.opts.delivery_channel
+}
+
+pub fn thread_name(client: &Client) -> String {
+    client.opts.thread_name.clone().unwrap_or_else(|| format!("mqttc-{}", client.client_id()))
+}
+
+// From now on `ClientHandle` acknowledges QoS 1 messages once it handed
+// them over
+pub fn defer_acks(client: &mut Client) {
+    client.defer_acks = true;
+}
+
+pub fn acks_deferred(client: &Client) -> bool {
+    client.defer_acks
+}
+
+pub fn acknowledge(client: &mut Client, pid: PacketIdentifier) -> Result<()> {
+    client._write_packet(&Packet::Puback(pid));
+    client._flush_ack()
+}
+
 #[cfg(test)]
 mod test {
-    use std::io::Cursor;
-    use super::ClientOptions;
+    use std::env;
+    use std::fs;
+    use std::io::{self, Cursor, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::process;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+    use rand::{SeedableRng, XorShiftRng};
+    use super::{ActiveSubscription, ClientOptions, Traffic};
+    use mqtt3::{self, MqttRead, Packet, PacketType, PacketIdentifier, QoS, SubscribeReturnCodes, SubscribeTopic,
+                Message, TopicPath};
+    use event::{Event, DisconnectReason};
+    use error::Error;
+    use {PubSub, PubOpt, Overflow, Failover, ManualClock};
     use netopt::{NetworkStream, NetworkOptions};
     use netopt::mock::MockStream;
+    use store::{FileStore, FileQueue, MessageQueue};
+    use compress::Compression;
+    use cipher::PayloadCipher;
+    use middleware::Middleware;
 
     #[test]
     fn client_connect_test() {
@@ -736,5 +1988,827 @@ mod test {
         netopt.attach(stream);
         // Connect and create MQTT client
         let client = options.connect("127.0.0.1:1883", netopt).unwrap();
+
+        let none: &[SocketAddr] = &[];
+        match ClientOptions::new().connect(none, NetworkOptions::new()) {
+            Err(Error::Io(ref err)) if err.kind() == io::ErrorKind::AddrNotAvailable => (),
+            _ => panic!("empty address should fail")
+        }
+    }
+
+    #[test]
+    fn client_keep_alive_limit_test() {
+        let stream = NetworkStream::Mock(MockStream::with_vec(vec![0b00100000, 0x02, 0x00, 0x00]));
+        let mut options = ClientOptions::new();
+        options.set_keep_alive(10);
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(stream);
+        let mut client = options.connect("127.0.0.1:1883", netopt).unwrap();
+
+        assert_eq!(client.keep_alive(), Some(Duration::from_secs(10)));
+        assert_eq!(client._keep_alive_limit(), Some(Duration::from_secs(10)));
+        client.ping().unwrap();
+        assert_eq!(client._keep_alive_limit(), Some(Duration::from_secs(15)));
+    }
+
+    #[test]
+    fn client_max_packet_size_test() {
+        let mut mock = MockStream::with_vec(vec![0b00100000, 0x02, 0x01, 0x00]);
+        let mut options = ClientOptions::new();
+        options.set_max_packet_size(16);
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(NetworkStream::Mock(mock.clone()));
+        let mut client = options.connect("127.0.0.1:1883", netopt).unwrap();
+        mock.take_vec();
+
+        client.publish("a/b", "short", PubOpt::at_most_once()).unwrap();
+        match client.publish("a/b", "far too long payload", PubOpt::at_least_once()) {
+            Err(Error::Mqtt(mqtt3::Error::PacketTooLarge)) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+        // the refused publish didn't take a pid
+        client.publish("a/b", "short", PubOpt::at_least_once()).unwrap();
+        match written_packets(&mut mock)[1] {
+            Packet::Publish(ref publish) => assert_eq!(publish.pid, Some(PacketIdentifier(1))),
+            ref packet => panic!("unexpected {:?}", packet)
+        }
+
+        // a larger incomming packet is skipped, the next one is read
+        mock.next_vec(vec![0x32, 0x13, 0x00, 0x03, b'a', b'/', b'b', 0x00, 0x01,
+                           b'f', b'a', b'r', b' ', b't', b'o', b'o', b' ', b'l', b'o', b'n', b'g',
+                           0x30, 0x06, 0x00, 0x03, b'a', b'/', b'b', b'x']);
+        assert!(client.accept().unwrap().is_none());
+        assert_eq!(*client.accept().unwrap().unwrap().payload, b"x".to_vec());
+    }
+
+    fn written_packets(mock: &mut MockStream) -> Vec<Packet> {
+        let mut cursor = Cursor::new(mock.take_vec());
+        let mut packets = Vec::new();
+        while (cursor.position() as usize) < cursor.get_ref().len() {
+            packets.push(cursor.read_packet().unwrap());
+        }
+        packets
+    }
+
+    fn written_payloads(mock: &mut MockStream) -> Vec<Vec<u8>> {
+        written_packets(mock).into_iter().filter_map(|packet| {
+            match packet {
+                Packet::Publish(publish) => Some((*publish.payload).clone()),
+                _ => None
+            }
+        }).collect()
+    }
+
+    fn connect_with_id(client_id: &str, clean_session: bool) -> String {
+        let mut mock = MockStream::with_vec(vec![0b00100000, 0x02, 0x00, 0x00]);
+        let mut options = ClientOptions::new();
+        options.set_client_id(client_id.to_string()).set_clean_session(clean_session);
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(NetworkStream::Mock(mock.clone()));
+        let client = options.connect("127.0.0.1:1883", netopt).unwrap();
+        match written_packets(&mut mock)[0] {
+            Packet::Connect(ref connect) => {
+                assert_eq!(client.client_id(), connect.client_id);
+                connect.client_id.clone()
+            },
+            ref packet => panic!("unexpected {:?}", packet)
+        }
+    }
+
+    #[test]
+    fn client_empty_id_test() {
+        // the server assigns one
+        assert_eq!(connect_with_id("", true), "");
+        // a persistent session needs an id of its own
+        assert!(connect_with_id("", false).starts_with("mqttc"));
+        assert_eq!(connect_with_id("meter", false), "meter");
+
+        let mut options = ClientOptions::new();
+        options.set_client_id("a\u{0}b".to_string());
+        match options.connect("127.0.0.1:1883", NetworkOptions::new()) {
+            Err(Error::InvalidClientId) => (),
+            Err(err) => panic!("unexpected {:?}", err),
+            Ok(_) => panic!("connected")
+        }
+    }
+
+    #[test]
+    fn client_reconnect_session_present_test() {
+        let mut mock = MockStream::with_vec(vec![0b00100000, 0x02, 0x00, 0x00]);
+        let mut options = ClientOptions::new();
+        options.set_clean_session(false);
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(NetworkStream::Mock(mock.clone()));
+        let mut client = options.connect("127.0.0.1:1883", netopt).unwrap();
+        client.publish("a/b", "hello", PubOpt::at_least_once()).unwrap();
+        client.terminate();
+
+        mock.take_vec();
+        mock.next_vec(vec![0b00100000, 0x02, 0x01, 0x00]);
+        client.reconnect().unwrap();
+        assert!(client.session_present());
+
+        let packets = written_packets(&mut mock);
+        assert_eq!(packets.len(), 2);
+        match packets[1] {
+            Packet::Publish(ref publish) => {
+                assert!(publish.dup);
+                assert_eq!(publish.pid, Some(PacketIdentifier(1)));
+            }
+            ref packet => panic!("unexpected {:?}", packet)
+        }
+    }
+
+    #[test]
+    fn client_reconnect_new_session_test() {
+        let mut mock = MockStream::with_vec(vec![
+            0b00100000, 0x02, 0x00, 0x00, // connack
+            0x90, 0x03, 0x00, 0x01, 0x01 // suback
+        ]);
+        let mut options = ClientOptions::new();
+        options.set_clean_session(false).enable_events();
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(NetworkStream::Mock(mock.clone()));
+        let mut client = options.connect("127.0.0.1:1883", netopt).unwrap();
+        client.subscribe("a/b").unwrap();
+        client.await().unwrap();
+        client.publish("a/b", "hello", PubOpt::at_least_once()).unwrap();
+        client.terminate();
+        // connected, subscribed, disconnected
+        assert_eq!(client.events().count(), 3);
+
+        mock.take_vec();
+        mock.next_vec(vec![0b00100000, 0x02, 0x00, 0x00]);
+        client.reconnect().unwrap();
+        assert!(!client.session_present());
+
+        // the publish is not resent, the subscription is replayed from pid 1
+        let packets = written_packets(&mut mock);
+        assert_eq!(packets.len(), 2);
+        match packets[1] {
+            Packet::Subscribe(ref subscribe) => {
+                assert_eq!(subscribe.pid, PacketIdentifier(1));
+                assert_eq!(subscribe.topics[0].topic_path, "a/b");
+            }
+            ref packet => panic!("unexpected {:?}", packet)
+        }
+
+        mock.next_vec(vec![0x90, 0x03, 0x00, 0x01, 0x00]);
+        client.await().unwrap();
+        let events: Vec<Event> = client.events().collect();
+        assert_eq!(events, vec![
+            Event::Connected { session_present: false },
+            Event::Resubscribed(vec![("a/b".to_string(), SubscribeReturnCodes::Success(QoS::AtMostOnce))])
+        ]);
+    }
+
+    #[test]
+    fn client_subscriptions_test() {
+        let mut mock = MockStream::with_vec(vec![
+            0b00100000, 0x02, 0x00, 0x00, // connack
+            0x90, 0x04, 0x00, 0x01, 0x01, 0x80, // suback
+            0x30, 0x07, 0x00, 0x03, b'a', b'/', b'b', b'h', b'i' // publish
+        ]);
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(NetworkStream::Mock(mock.clone()));
+        let mut client = ClientOptions::new().connect("127.0.0.1:1883", netopt).unwrap();
+        client.subscribe(vec![
+            SubscribeTopic { topic_path: "a/+".to_string(), qos: QoS::ExactlyOnce },
+            SubscribeTopic { topic_path: "c".to_string(), qos: QoS::AtMostOnce }
+        ]).unwrap();
+        assert!(client.subscriptions().is_empty());
+        client.await().unwrap();
+        client.await().unwrap();
+        mock.take_vec();
+
+        assert_eq!(client.subscriptions(), vec![ActiveSubscription {
+            filter: "a/+".to_string(),
+            qos: QoS::AtLeastOnce,
+            requested_qos: QoS::ExactlyOnce,
+            messages: 1,
+            bytes: 2
+        }]);
+    }
+
+    #[test]
+    fn client_wait_in_flight_test() {
+        let mut mock = MockStream::with_vec(vec![0b00100000, 0x02, 0x00, 0x00]);
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(NetworkStream::Mock(mock.clone()));
+        let mut client = ClientOptions::new().connect("127.0.0.1:1883", netopt).unwrap();
+        for _ in 0..3 {
+            client.publish("a/b", "hello", PubOpt::at_least_once()).unwrap();
+        }
+        mock.next_vec(vec![
+            0x40, 0x02, 0x00, 0x01, // puback
+            0x30, 0x07, 0x00, 0x03, b'c', b'/', b'd', b'h', b'i',
+            0x40, 0x02, 0x00, 0x02 // puback
+        ]);
+        let messages = client.wait_in_flight(1).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].topic.path, "c/d");
+        assert_eq!(client.pending().len(), 1);
+        assert!(client.wait_in_flight(1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn client_events_test() {
+        let mut mock = MockStream::with_vec(vec![
+            0b00100000, 0x02, 0x00, 0x00, // connack
+            0x90, 0x03, 0x00, 0x01, 0x80, // suback
+            0x40, 0x02, 0x00, 0x02, // puback
+            0xb0, 0x02, 0x00, 0x03 // unsuback
+        ]);
+        let mut options = ClientOptions::new();
+        options.enable_events();
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(NetworkStream::Mock(mock.clone()));
+        let mut client = options.connect("127.0.0.1:1883", netopt).unwrap();
+        client.subscribe("a/b").unwrap();
+        client.publish("a/b", "hello", PubOpt::at_least_once()).unwrap();
+        client.unsubscribe("a/b").unwrap();
+        client.await().unwrap();
+        client.terminate();
+        mock.take_vec();
+
+        let events: Vec<Event> = client.events().collect();
+        assert_eq!(events, vec![
+            Event::Connected { session_present: false },
+            Event::SubscribeAck {
+                pid: PacketIdentifier(1),
+                return_codes: vec![("a/b".to_string(), SubscribeReturnCodes::Failure)]
+            },
+            Event::PublishComplete { pid: PacketIdentifier(2) },
+            Event::UnsubscribeAck { pid: PacketIdentifier(3) },
+            Event::Disconnected { reason: DisconnectReason::Requested }
+        ]);
+        assert_eq!(client.events().count(), 0);
+    }
+
+    #[test]
+    fn client_disconnect_gracefully_test() {
+        let mut mock = MockStream::with_vec(vec![
+            0b00100000, 0x02, 0x00, 0x00, // connack
+            0x40, 0x02, 0x00, 0x01 // puback
+        ]);
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(NetworkStream::Mock(mock.clone()));
+        let mut client = ClientOptions::new().connect("127.0.0.1:1883", netopt).unwrap();
+        client.publish("a/b", "hello", PubOpt::at_least_once()).unwrap();
+        mock.take_vec();
+
+        let messages = client.disconnect_gracefully(Duration::from_secs(1)).unwrap();
+        assert!(messages.is_empty());
+        assert_eq!(written_packets(&mut mock), vec![Packet::Disconnect]);
+    }
+
+    #[test]
+    fn client_pending_test() {
+        let mock = MockStream::with_vec(vec![
+            0b00100000, 0x02, 0x00, 0x00, // connack
+            0x40, 0x02, 0x00, 0x03, // puback
+            0x40, 0x02, 0x00, 0x01 // puback of the cancelled one
+        ]);
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(NetworkStream::Mock(mock));
+        let mut client = ClientOptions::new().connect("127.0.0.1:1883", netopt).unwrap();
+        client.publish("a/1", "one", PubOpt::at_least_once()).unwrap();
+        client.publish("a/2", "two", PubOpt::at_least_once()).unwrap();
+        client.publish("a/3", "three", PubOpt::at_least_once()).unwrap();
+        client.publish("a/4", "four", PubOpt::at_most_once()).unwrap();
+
+        let pending = client.pending();
+        let pids: Vec<PacketIdentifier> = pending.iter().map(|pending| pending.pid).collect();
+        assert_eq!(pids, vec![PacketIdentifier(1), PacketIdentifier(2), PacketIdentifier(3)]);
+        assert_eq!(pending[1].topic, "a/2");
+        assert_eq!(pending[1].qos, QoS::AtLeastOnce);
+        assert_eq!(pending[1].retries, 0);
+        assert!(!pending[1].released);
+
+        let message = client.cancel(PacketIdentifier(1)).unwrap().unwrap();
+        assert_eq!(&*message.payload, b"one");
+        assert!(client.cancel(PacketIdentifier(1)).unwrap().is_none());
+
+        // acknowledged out of order, the late one is ignored
+        client.accept().unwrap();
+        client.accept().unwrap();
+        let pids: Vec<PacketIdentifier> = client.pending().iter().map(|pending| pending.pid).collect();
+        assert_eq!(pids, vec![PacketIdentifier(2)]);
+        assert_eq!(client.expire(Duration::from_secs(60)).unwrap().len(), 0);
+        assert_eq!(client.expire(Duration::from_secs(0)).unwrap().len(), 1);
+        assert!(client.pending().is_empty());
+    }
+
+    #[test]
+    fn client_delivery_token_test() {
+        let mock = MockStream::with_vec(vec![
+            0b00100000, 0x02, 0x00, 0x00, // connack
+            0x40, 0x02, 0x00, 0x01 // puback
+        ]);
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(NetworkStream::Mock(mock));
+        let mut client = ClientOptions::new().connect("127.0.0.1:1883", netopt).unwrap();
+
+        let token = client.publish_with_token("a/b", "one", PubOpt::at_most_once()).unwrap();
+        assert_eq!(token.try_wait().unwrap(), true);
+
+        let acked = client.publish_with_token("a/b", "two", PubOpt::at_least_once()).unwrap();
+        let cancelled = client.publish_with_token("a/b", "three", PubOpt::at_least_once()).unwrap();
+        assert_eq!(acked.try_wait().unwrap(), false);
+        client.accept().unwrap();
+        assert_eq!(acked.try_wait().unwrap(), true);
+
+        client.cancel(PacketIdentifier(2)).unwrap();
+        match cancelled.try_wait() {
+            Err(Error::NotDelivered) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+    }
+
+    #[test]
+    fn client_offline_queue_test() {
+        let mut mock = MockStream::with_vec(vec![0b00100000, 0x02, 0x00, 0x00]);
+        let mut options = ClientOptions::new();
+        options.set_offline_queue(QoS::AtMostOnce, 2, Overflow::DropOldest)
+               .set_offline_queue(QoS::AtLeastOnce, 1, Overflow::Error);
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(NetworkStream::Mock(mock.clone()));
+        let mut client = options.connect("127.0.0.1:1883", netopt).unwrap();
+        client.terminate();
+
+        let dropped = client.publish_with_token("a/b", "one", PubOpt::at_most_once()).unwrap();
+        client.publish("a/b", "two", PubOpt::at_most_once()).unwrap();
+        client.publish("a/b", "three", PubOpt::at_most_once()).unwrap();
+        match dropped.try_wait() {
+            Err(Error::NotDelivered) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+        client.publish("a/c", "four", PubOpt::at_least_once()).unwrap();
+        match client.publish("a/c", "five", PubOpt::at_least_once()) {
+            Err(Error::QueueFull) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+        match client.publish("a/d", "six", PubOpt::exactly_once()) {
+            Err(Error::OutgoingStorageAbsent) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+
+        mock.take_vec();
+        mock.next_vec(vec![0b00100000, 0x02, 0x00, 0x00]);
+        client.reconnect().unwrap();
+        let payloads = written_payloads(&mut mock);
+        assert_eq!(payloads, vec![b"two".to_vec(), b"three".to_vec(), b"four".to_vec()]);
+    }
+
+    #[test]
+    fn client_outbox_test() {
+        let path = env::temp_dir().join(format!("mqttc_outbox_{}.log", process::id()));
+        let _ = fs::remove_file(&path);
+        {
+            let mut options = ClientOptions::new();
+            options.set_outbox(Box::new(FileQueue::open(&path).unwrap()));
+            let mut netopt = NetworkOptions::new();
+            netopt.attach(NetworkStream::Mock(MockStream::with_vec(vec![0b00100000, 0x02, 0x00, 0x00])));
+            let mut client = options.connect("127.0.0.1:1883", netopt).unwrap();
+            client.terminate();
+            client.publish("a/b", "one", PubOpt::at_least_once()).unwrap();
+            client.publish("a/b", "two", PubOpt::at_least_once()).unwrap();
+        }
+
+        // restarted, the backlog is sent in order
+        let mut mock = MockStream::with_vec(vec![
+            0b00100000, 0x02, 0x00, 0x00, // connack
+            0x40, 0x02, 0x00, 0x01 // puback
+        ]);
+        let mut options = ClientOptions::new();
+        options.set_outbox(Box::new(FileQueue::open(&path).unwrap()));
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(NetworkStream::Mock(mock.clone()));
+        let mut client = options.connect("127.0.0.1:1883", netopt).unwrap();
+        client.accept().unwrap();
+        let payloads = written_payloads(&mut mock);
+        assert_eq!(payloads, vec![b"one".to_vec(), b"two".to_vec()]);
+        drop(client);
+
+        // the unacknowledged one is kept
+        let mut outbox = FileQueue::open(&path).unwrap();
+        assert_eq!(outbox.seqs(None, 10).unwrap(), vec![1]);
+        assert_eq!(outbox.get(1).unwrap().payload, Arc::new(b"two".to_vec()));
+    }
+
+    #[test]
+    fn client_topic_window_test() {
+        let mut mock = MockStream::with_vec(vec![0b00100000, 0x02, 0x00, 0x00]);
+        let mut options = ClientOptions::new();
+        options.set_topic_window(1);
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(NetworkStream::Mock(mock.clone()));
+        let mut client = options.connect("127.0.0.1:1883", netopt).unwrap();
+        mock.take_vec();
+
+        client.publish("a/1", "one", PubOpt::at_least_once()).unwrap();
+        client.publish("a/1", "two", PubOpt::at_least_once()).unwrap();
+        client.publish("a/2", "other", PubOpt::at_least_once()).unwrap();
+        client.publish("a/1", "three", PubOpt::at_most_once()).unwrap();
+        assert_eq!(written_payloads(&mut mock), vec![b"one".to_vec(), b"other".to_vec()]);
+
+        // acknowledged out of order
+        mock.next_vec(vec![0x40, 0x02, 0x00, 0x02, 0x40, 0x02, 0x00, 0x01]);
+        client.accept().unwrap();
+        assert!(written_payloads(&mut mock).is_empty());
+        client.accept().unwrap();
+        assert_eq!(written_payloads(&mut mock), vec![b"two".to_vec(), b"three".to_vec()]);
+    }
+
+    #[test]
+    fn client_resend_order_test() {
+        let path = env::temp_dir().join(format!("mqttc_resend_order_{}.log", process::id()));
+        let _ = fs::remove_file(&path);
+        let mut mock = MockStream::with_vec(vec![0b00100000, 0x02, 0x00, 0x00]);
+        let mut options = ClientOptions::new();
+        options.set_clean_session(false).set_outgoing_store(Box::new(FileStore::open(&path).unwrap()));
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(NetworkStream::Mock(mock.clone()));
+        let mut client = options.connect("127.0.0.1:1883", netopt).unwrap();
+        client.publish("a/b", "one", PubOpt::exactly_once()).unwrap();
+        client.publish("a/b", "two", PubOpt::at_least_once()).unwrap();
+        client.publish("a/b", "three", PubOpt::exactly_once()).unwrap();
+        client.terminate();
+
+        mock.take_vec();
+        mock.next_vec(vec![0b00100000, 0x02, 0x01, 0x00]);
+        client.reconnect().unwrap();
+        assert_eq!(written_payloads(&mut mock), vec![b"one".to_vec(), b"two".to_vec(), b"three".to_vec()]);
+    }
+
+    #[test]
+    fn client_write_batch_test() {
+        let mut mock = MockStream::with_vec(vec![
+            0b00100000, 0x02, 0x00, 0x00, // connack
+            0x32, 0x08, 0x00, 0x03, b'a', b'/', b'b', 0x00, 0x01, b'x', // publish
+            0x32, 0x08, 0x00, 0x03, b'a', b'/', b'b', 0x00, 0x02, b'y' // publish
+        ]);
+        let mut options = ClientOptions::new();
+        options.set_write_batch(1024, Duration::from_secs(60));
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(NetworkStream::Mock(mock.clone()));
+        let mut client = options.connect("127.0.0.1:1883", netopt).unwrap();
+        mock.take_vec();
+
+        // the second publish is already buffered, so the first puback waits
+        assert!(client.accept().unwrap().is_some());
+        assert!(mock.take_vec().is_empty());
+        assert!(client.accept().unwrap().is_some());
+        assert_eq!(written_packets(&mut mock), vec![
+            Packet::Puback(PacketIdentifier(1)),
+            Packet::Puback(PacketIdentifier(2))
+        ]);
+    }
+
+    #[test]
+    fn client_publish_retained_test() {
+        let mut mock = MockStream::with_vec(vec![0b00100000, 0x02, 0x00, 0x00]);
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(NetworkStream::Mock(mock.clone()));
+        let mut client = ClientOptions::new().connect("127.0.0.1:1883", netopt).unwrap();
+        mock.take_vec();
+
+        client.publish_retained("a/b", "on", PubOpt::at_most_once()).unwrap();
+        client.clear_retained("a/b").unwrap();
+        let packets = written_packets(&mut mock);
+        match (&packets[0], &packets[1]) {
+            (&Packet::Publish(ref set), &Packet::Publish(ref clear)) => {
+                assert!(set.retain && clear.retain);
+                assert_eq!(*set.payload, b"on".to_vec());
+                assert_eq!(clear.qos, QoS::AtLeastOnce);
+                assert!(clear.payload.is_empty());
+            }
+            packets => panic!("unexpected {:?}", packets)
+        }
+    }
+
+    // Turns every payload into nothing, and nothing into `decoded`
+    struct Vanish;
+
+    impl Compression for Vanish {
+        fn id(&self) -> u8 {
+            3
+        }
+
+        fn compress(&self, _: &[u8]) -> io::Result<Vec<u8>> {
+            Ok(Vec::new())
+        }
+
+        fn decompress(&self, _: &[u8], _: usize) -> io::Result<Vec<u8>> {
+            Ok(b"decoded".to_vec())
+        }
+    }
+
+    #[test]
+    fn client_compression_test() {
+        let mut mock = MockStream::with_vec(vec![0b00100000, 0x02, 0x00, 0x00]);
+        let mut options = ClientOptions::new();
+        options.set_compression(Box::new(Vanish), 16);
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(NetworkStream::Mock(mock.clone()));
+        let mut client = options.connect("127.0.0.1:1883", netopt).unwrap();
+        mock.take_vec();
+
+        client.publish("a/b", vec![0; 64], PubOpt::at_most_once()).unwrap();
+        client.publish("a/b", "short", PubOpt::at_most_once()).unwrap();
+        assert_eq!(written_payloads(&mut mock), vec![b"\x00MQZ\x03".to_vec(), b"short".to_vec()]);
+
+        mock.next_vec(vec![0x30, 0x0A, 0x00, 0x03, b'a', b'/', b'b', 0x00, b'M', b'Q', b'Z', 0x03]);
+        let message = client.accept().unwrap().unwrap();
+        assert_eq!(*message.payload, b"decoded".to_vec());
+    }
+
+    // Prefixes the topic and reverses the payload
+    struct Mirror;
+
+    impl PayloadCipher for Mirror {
+        fn encrypt(&self, topic: &str, payload: &[u8]) -> io::Result<Vec<u8>> {
+            let mut encrypted = topic.as_bytes().to_vec();
+            encrypted.extend(payload.iter().rev());
+            Ok(encrypted)
+        }
+
+        fn decrypt(&self, topic: &str, payload: &[u8]) -> io::Result<Vec<u8>> {
+            if !payload.starts_with(topic.as_bytes()) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "wrong topic"));
+            }
+            Ok(payload[topic.len()..].iter().rev().cloned().collect())
+        }
+    }
+
+    #[test]
+    fn client_cipher_test() {
+        let mut mock = MockStream::with_vec(vec![0b00100000, 0x02, 0x00, 0x00]);
+        let mut options = ClientOptions::new();
+        options.set_cipher(Box::new(Mirror));
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(NetworkStream::Mock(mock.clone()));
+        let mut client = options.connect("127.0.0.1:1883", netopt).unwrap();
+        mock.take_vec();
+
+        client.publish("a/b", "abc", PubOpt::at_most_once()).unwrap();
+        assert_eq!(written_payloads(&mut mock), vec![b"a/bcba".to_vec()]);
+
+        mock.next_vec(vec![0x30, 0x0A, 0x00, 0x03, b'a', b'/', b'b', b'a', b'/', b'b', b'i', b'h',
+                           0x30, 0x0A, 0x00, 0x03, b'a', b'/', b'c', b'a', b'/', b'b', b'i', b'h']);
+        let message = client.accept().unwrap().unwrap();
+        assert_eq!(*message.payload, b"hi".to_vec());
+        match client.accept() {
+            Err(Error::Io(ref err)) if err.kind() == io::ErrorKind::InvalidData => (),
+            result => panic!("unexpected {:?}", result)
+        }
+    }
+
+    // Keeps the messages of a tenant under its prefix and counts them
+    struct Tenant(Arc<AtomicUsize>);
+
+    impl Middleware for Tenant {
+        fn publish(&self, message: &mut Message) -> io::Result<()> {
+            message.topic = TopicPath::from(format!("tenant/{}", message.topic.path));
+            message.qos = QoS::AtMostOnce;
+            Ok(())
+        }
+
+        fn receive(&self, message: &mut Message) -> io::Result<()> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            match message.topic.path.clone().split_at(7) {
+                ("tenant/", topic) => message.topic = TopicPath::from(topic),
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "other tenant"))
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn client_middleware_test() {
+        let mut mock = MockStream::with_vec(vec![0b00100000, 0x02, 0x00, 0x00]);
+        let received = Arc::new(AtomicUsize::new(0));
+        let mut options = ClientOptions::new();
+        options.add_middleware(Box::new(Tenant(received.clone())));
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(NetworkStream::Mock(mock.clone()));
+        let mut client = options.connect("127.0.0.1:1883", netopt).unwrap();
+        mock.take_vec();
+
+        client.publish("a/b", "abc", PubOpt::at_least_once()).unwrap();
+        match written_packets(&mut mock)[0] {
+            Packet::Publish(ref publish) => {
+                assert_eq!((publish.topic_name.as_str(), publish.qos), ("tenant/a/b", QoS::AtMostOnce));
+            }
+            ref packet => panic!("unexpected {:?}", packet)
+        }
+
+        mock.next_vec(vec![0x30, 0x0C, 0x00, 0x08, b't', b'e', b'n', b'a', b'n', b't', b'/', b'c', b'h', b'i',
+                           0x30, 0x0C, 0x00, 0x08, b'o', b't', b'h', b'e', b'r', b'/', b'c', b'd', b'h', b'i']);
+        assert_eq!(client.accept().unwrap().unwrap().topic.path, "c");
+        match client.accept() {
+            Err(Error::Io(ref err)) if err.kind() == io::ErrorKind::InvalidData => (),
+            result => panic!("unexpected {:?}", result)
+        }
+        assert_eq!(received.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn client_stats_test() {
+        let mut mock = MockStream::with_vec(vec![0b00100000, 0x02, 0x00, 0x00]);
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(NetworkStream::Mock(mock.clone()));
+        let mut client = ClientOptions::new().connect("127.0.0.1:1883", netopt).unwrap();
+        let connect_len = mock.take_vec().len() as u64;
+
+        client.publish("a/b", "abc", PubOpt::at_least_once()).unwrap();
+        client.ping().unwrap();
+        mock.next_vec(vec![0b11010000, 0x00]);
+        assert!(client.accept().unwrap().is_none());
+
+        let stats = client.stats();
+        assert_eq!(stats.sent(PacketType::Connect), Traffic { packets: 1, bytes: connect_len });
+        assert_eq!(stats.sent(PacketType::Publish), Traffic { packets: 1, bytes: 12 });
+        assert_eq!(stats.sent(PacketType::Pingreq), Traffic { packets: 1, bytes: 2 });
+        assert_eq!(stats.received(PacketType::Connack), Traffic { packets: 1, bytes: 4 });
+        assert_eq!(stats.total_received(), Traffic { packets: 2, bytes: 6 });
+        assert!(stats.last_rtt.is_some());
+        assert_eq!(stats.inflight_out, 1);
+        assert_eq!(stats.inflight_in, 0);
+        assert_eq!(stats.reconnects, 0);
+    }
+
+    #[test]
+    fn client_measure_rtt_test() {
+        let mut mock = MockStream::with_vec(vec![0b00100000, 0x02, 0x00, 0x00]);
+        let mut options = ClientOptions::new();
+        options.enable_events();
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(NetworkStream::Mock(mock.clone()));
+        let mut client = options.connect("127.0.0.1:1883", netopt).unwrap();
+        assert_eq!(client.rtt(), None);
+
+        mock.next_vec(vec![0x30, 0x05, 0x00, 0x03, b'a', b'/', b'b', 0b11010000, 0x00]);
+        let (rtt, messages) = client.measure_rtt(Duration::from_secs(1)).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(client.rtt(), rtt);
+        let events: Vec<Event> = client.events().collect();
+        assert_eq!(events.last(), Some(&Event::Pong { rtt: rtt.unwrap() }));
+    }
+
+    #[test]
+    fn client_dedup_window_test() {
+        let mut mock = MockStream::with_vec(vec![0b00100000, 0x02, 0x00, 0x00]);
+        let mut options = ClientOptions::new();
+        options.set_dedup_window(8);
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(NetworkStream::Mock(mock.clone()));
+        let mut client = options.connect("127.0.0.1:1883", netopt).unwrap();
+        mock.take_vec();
+
+        // the message, the same one again with DUP, a new one reusing the pid
+        mock.next_vec(vec![0x32, 0x08, 0x00, 0x03, b'a', b'/', b'b', 0x00, 0x01, b'x',
+                           0x3A, 0x08, 0x00, 0x03, b'a', b'/', b'b', 0x00, 0x01, b'x',
+                           0x32, 0x08, 0x00, 0x03, b'a', b'/', b'b', 0x00, 0x01, b'y']);
+        assert_eq!(*client.accept().unwrap().unwrap().payload, b"x".to_vec());
+        assert!(client.accept().unwrap().is_none());
+        assert_eq!(*client.accept().unwrap().unwrap().payload, b"y".to_vec());
+        assert_eq!(written_packets(&mut mock), vec![Packet::Puback(PacketIdentifier(1)); 3]);
+    }
+
+    #[test]
+    fn client_filter_test() {
+        let mut mock = MockStream::with_vec(vec![0b00100000, 0x02, 0x00, 0x00]);
+        let mut options = ClientOptions::new();
+        options.add_filter("a/+", |message| message.payload[0] != b'x').unwrap();
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(NetworkStream::Mock(mock.clone()));
+        let mut client = options.connect("127.0.0.1:1883", netopt).unwrap();
+        mock.take_vec();
+
+        // dropped, kept, other topic
+        mock.next_vec(vec![0x30, 0x06, 0x00, 0x03, b'a', b'/', b'b', b'x',
+                           0x30, 0x06, 0x00, 0x03, b'a', b'/', b'b', b'y',
+                           0x30, 0x06, 0x00, 0x03, b'b', b'/', b'b', b'x']);
+        assert!(client.accept().unwrap().is_none());
+        assert_eq!(*client.accept().unwrap().unwrap().payload, b"y".to_vec());
+        assert_eq!(*client.accept().unwrap().unwrap().payload, b"x".to_vec());
+
+        // a dropped QoS 1 message is still acknowledged
+        mock.next_vec(vec![0x32, 0x08, 0x00, 0x03, b'a', b'/', b'b', 0x00, 0x07, b'x']);
+        assert!(client.accept().unwrap().is_none());
+        assert_eq!(written_packets(&mut mock), vec![Packet::Puback(PacketIdentifier(7))]);
+    }
+
+    #[test]
+    fn client_last_will_test() {
+        let mock = MockStream::with_vec(vec![0b00100000, 0x02, 0x00, 0x00]);
+        let mut options = ClientOptions::new();
+        options.enable_events();
+        options.set_last_will::<&str, &str>("status/a", "offline".to_string(), PubOpt::at_least_once() | PubOpt::retain()).unwrap();
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(NetworkStream::Mock(mock.clone()));
+        let mut client = options.connect("127.0.0.1:1883", netopt).unwrap();
+
+        let will = mock.last_will().unwrap();
+        assert_eq!((will.topic.as_str(), will.message.as_str()), ("status/a", "offline"));
+        assert_eq!((will.qos, will.retain), (QoS::AtLeastOnce, true));
+
+        // the network goes away, the broker would publish the will
+        assert_eq!(mock.drop_connection(), Some(will));
+        assert!(client.accept().is_err());
+        assert_eq!(client.events().last(), Some(Event::Disconnected { reason: DisconnectReason::ConnectionLost }));
+    }
+
+    #[test]
+    fn client_manual_clock_test() {
+        let mock = MockStream::with_vec(vec![0b00100000, 0x02, 0x00, 0x00]);
+        let clock = ManualClock::new();
+        let mut options = ClientOptions::new();
+        options.set_keep_alive(10)
+               .enable_events()
+               .set_clock(clock.clone());
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(NetworkStream::Mock(mock));
+        let mut client = options.connect("127.0.0.1:1883", netopt).unwrap();
+
+        client.publish("a/1", "one", PubOpt::at_least_once()).unwrap();
+        clock.advance(Duration::from_secs(30));
+        client.publish("a/2", "two", PubOpt::at_least_once()).unwrap();
+        let ages: Vec<Duration> = client.pending().iter().map(|pending| pending.age).collect();
+        assert_eq!(ages, vec![Duration::from_secs(30), Duration::from_secs(0)]);
+        assert_eq!(client.expire(Duration::from_secs(20)).unwrap().len(), 1);
+
+        // keep alive is due, then the PINGRESP is late
+        clock.advance(Duration::from_secs(10));
+        assert!(client.poll(Duration::from_secs(1)).unwrap().is_none());
+        assert_eq!(client._keep_alive_limit(), Some(Duration::from_secs(15)));
+        clock.advance(Duration::from_secs(15));
+        assert!(client.poll(Duration::from_secs(1)).unwrap().is_none());
+        assert_eq!(client.events().last(), Some(Event::Disconnected { reason: DisconnectReason::PingTimeout }));
+    }
+
+    #[test]
+    fn client_rng_test() {
+        let connect = |seed: u32| {
+            let mut options = ClientOptions::new();
+            options.set_rng(XorShiftRng::from_seed([seed, 2, 3, 4]))
+                   .set_random_packet_ids(true);
+            let mut netopt = NetworkOptions::new();
+            netopt.attach(NetworkStream::Mock(MockStream::with_vec(vec![0b00100000, 0x02, 0x00, 0x00])));
+            let mut client = options.connect("127.0.0.1:1883", netopt).unwrap();
+            client.publish("a/b", "x", PubOpt::at_least_once()).unwrap();
+            (client.client_id().to_string(), client.pending()[0].pid)
+        };
+        // the same seed makes the same ids
+        assert_eq!(connect(1), connect(1));
+        let (client_id, pid) = connect(1);
+        assert!(client_id.starts_with("mqttc"));
+        assert!(pid != PacketIdentifier(1));
+        assert!(connect(1) != connect(5));
+    }
+
+    // Broker which takes one client
+    fn accept_one() -> (SocketAddr, thread::JoinHandle<TcpStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        (addr, thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            assert_eq!(stream.read_packet().unwrap().packet_type(), PacketType::Connect);
+            stream.write_all(&[0b00100000, 0x02, 0x00, 0x00]).unwrap();
+            stream
+        }))
+    }
+
+    #[test]
+    fn client_failover_test() {
+        // nothing listens on the port of a dropped listener
+        let down = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let (first, first_broker) = accept_one();
+        let (second, second_broker) = accept_one();
+
+        let mut options = ClientOptions::new();
+        options.add_broker(first).unwrap()
+               .add_broker(second).unwrap()
+               .set_failover(Failover::RoundRobin);
+        let mut client = options.connect(down, NetworkOptions::new()).unwrap();
+        assert_eq!(client.broker(), first);
+        let _first = first_broker.join().unwrap();
+
+        // round robin goes on to the broker after the lost one
+        client.terminate();
+        client.reconnect().unwrap();
+        assert_eq!(client.broker(), second);
+        let _second = second_broker.join().unwrap();
+
+        // with no broker up reconnecting fails
+        let (first, first_broker) = accept_one();
+        let mut options = ClientOptions::new();
+        options.add_broker(first).unwrap();
+        let mut client = options.connect(down, NetworkOptions::new()).unwrap();
+        let _first = first_broker.join().unwrap();
+        client.terminate();
+        assert!(client.reconnect().is_err());
+        assert_eq!(client.broker(), first);
     }
 }
@@ -0,0 +1,46 @@
+//! The time the client goes by, so tests can move it on by hand.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Source of the time for keep alive, write batches, round trips and the
+/// age of pending publishes, see `ClientOptions::set_clock`. Waiting on the
+/// network is still bounded by the time of the system.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// Time of the system, the default
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Clock which stands still until it's advanced. Its clones share the
+/// time, so a test keeps one to move the client's on.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<Instant>>
+}
+
+impl ManualClock {
+    pub fn new() -> ManualClock {
+        ManualClock {
+            now: Arc::new(Mutex::new(Instant::now()))
+        }
+    }
+
+    pub fn advance(&self, dur: Duration) {
+        *self.now.lock().unwrap() += dur;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
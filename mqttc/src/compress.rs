@@ -0,0 +1,264 @@
+//! Payload compression above the MQTT layer.
+//!
+//! A compressed payload is framed by a header of the magic `\0MQZ` and the
+//! id of the codec, so both sides have to use the layer. Payloads below the
+//! threshold, or which don't shrink, go with id 0 if they could be taken
+//! for a frame and unchanged otherwise.
+
+use std::io;
+#[cfg(any(feature = "deflate", feature = "zstd"))]
+use std::io::Read;
+use std::sync::Arc;
+use Payload;
+
+const MAGIC: &'static [u8] = b"\x00MQZ";
+const HEADER_LEN: usize = 5;
+// Id of payloads framed without compression
+const STORED: u8 = 0;
+// Most bytes a packet can carry
+const MAX_DECODED: usize = 268_435_455;
+
+pub trait Compression {
+    /// Id of the codec in the frame header, 0 is reserved
+    fn id(&self) -> u8;
+    fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>>;
+    /// Fails if `data` decompresses to more than `limit` bytes
+    fn decompress(&self, data: &[u8], limit: usize) -> io::Result<Vec<u8>>;
+}
+
+/// Compresses payloads of `threshold` bytes and more with a `Compression`
+pub struct Compressor {
+    codec: Box<Compression + Send>,
+    threshold: usize,
+    limit: usize
+}
+
+impl Compressor {
+    pub fn new(codec: Box<Compression + Send>, threshold: usize) -> Compressor {
+        Compressor {
+            codec: codec,
+            threshold: threshold,
+            limit: MAX_DECODED
+        }
+    }
+
+    /// Largest decoded payload, larger ones fail to decode. Defaults to the
+    /// most a packet can carry.
+    pub fn set_limit(&mut self, bytes: usize) -> &mut Compressor {
+        self.limit = bytes;
+        self
+    }
+
+    pub fn encode(&self, payload: Payload) -> io::Result<Payload> {
+        if payload.len() >= self.threshold {
+            let compressed = try!(self.codec.compress(&payload));
+            if compressed.len() + HEADER_LEN < payload.len() {
+                return Ok(Arc::new(frame(self.codec.id(), &compressed)));
+            }
+        }
+        if payload.starts_with(MAGIC) {
+            return Ok(Arc::new(frame(STORED, &payload)));
+        }
+        Ok(payload)
+    }
+
+    pub fn decode(&self, payload: Payload) -> io::Result<Payload> {
+        if payload.len() < HEADER_LEN || !payload.starts_with(MAGIC) {
+            return Ok(payload);
+        }
+        let data = &payload[HEADER_LEN..];
+        match payload[MAGIC.len()] {
+            STORED => Ok(Arc::new(data.to_vec())),
+            id if id == self.codec.id() => Ok(Arc::new(try!(self.codec.decompress(data, self.limit)))),
+            id => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown compression {}", id)))
+        }
+    }
+}
+
+// Reads `reader` to the end, failing once it gives more than `limit` bytes
+#[cfg(any(feature = "deflate", feature = "zstd"))]
+fn read_limited<R: Read>(reader: R, limit: usize) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    try!(reader.take(limit as u64 + 1).read_to_end(&mut data));
+    if data.len() > limit {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "decompressed payload over the limit"));
+    }
+    Ok(data)
+}
+
+fn frame(id: u8, data: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(HEADER_LEN + data.len());
+    framed.extend_from_slice(MAGIC);
+    framed.push(id);
+    framed.extend_from_slice(data);
+    framed
+}
+
+#[cfg(feature = "deflate")]
+pub use self::deflate::Deflate;
+
+#[cfg(feature = "deflate")]
+mod deflate {
+    use std::io::{self, Write};
+    use flate2::Compression as Level;
+    use flate2::read::DeflateDecoder;
+    use flate2::write::DeflateEncoder;
+    use super::{Compression, read_limited};
+
+    /// Raw deflate, id 1
+    pub struct Deflate {
+        level: u32
+    }
+
+    impl Deflate {
+        /// `level` from 0 (none) to 9 (best)
+        pub fn new(level: u32) -> Deflate {
+            Deflate { level: level }
+        }
+    }
+
+    impl Compression for Deflate {
+        fn id(&self) -> u8 {
+            1
+        }
+
+        fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Level::new(self.level));
+            try!(encoder.write_all(data));
+            encoder.finish()
+        }
+
+        fn decompress(&self, data: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+            read_limited(DeflateDecoder::new(data), limit)
+        }
+    }
+}
+
+#[cfg(feature = "zstd")]
+pub use self::zstandard::Zstd;
+
+#[cfg(feature = "zstd")]
+mod zstandard {
+    use std::io;
+    use zstd;
+    use super::{Compression, read_limited};
+
+    /// Zstandard, id 2
+    pub struct Zstd {
+        level: i32
+    }
+
+    impl Zstd {
+        /// `level` from 1 to 22, 0 picks the default
+        pub fn new(level: i32) -> Zstd {
+            Zstd { level: level }
+        }
+    }
+
+    impl Compression for Zstd {
+        fn id(&self) -> u8 {
+            2
+        }
+
+        fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+            zstd::encode_all(data, self.level)
+        }
+
+        fn decompress(&self, data: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+            read_limited(try!(zstd::Decoder::new(data)), limit)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::sync::Arc;
+    use super::{Compression, Compressor};
+
+    // Run-length encoding of one byte values, enough to see the framing
+    struct Rle;
+
+    impl Compression for Rle {
+        fn id(&self) -> u8 {
+            7
+        }
+
+        fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+            let mut compressed: Vec<u8> = Vec::new();
+            for &b in data {
+                let len = compressed.len();
+                if len >= 2 && compressed[len - 1] == b && compressed[len - 2] < 255 {
+                    compressed[len - 2] += 1;
+                } else {
+                    compressed.push(1);
+                    compressed.push(b);
+                }
+            }
+            Ok(compressed)
+        }
+
+        fn decompress(&self, data: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+            let mut decompressed = Vec::new();
+            for run in data.chunks(2) {
+                if decompressed.len() + run[0] as usize > limit {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "over the limit"));
+                }
+                for _ in 0..run[0] {
+                    decompressed.push(run[1]);
+                }
+            }
+            Ok(decompressed)
+        }
+    }
+
+    #[test]
+    fn compressor_test() {
+        let compressor = Compressor::new(Box::new(Rle), 16);
+
+        let long = Arc::new(vec![b'a'; 100]);
+        let encoded = compressor.encode(long.clone()).unwrap();
+        assert_eq!(&encoded[..], b"\x00MQZ\x07\x64a");
+        assert_eq!(compressor.decode(encoded).unwrap(), long);
+
+        // too short, or doesn't shrink
+        let short = Arc::new(b"aaaa".to_vec());
+        assert_eq!(compressor.encode(short.clone()).unwrap(), short);
+        let mixed = Arc::new((0..32).collect::<Vec<u8>>());
+        assert_eq!(compressor.encode(mixed.clone()).unwrap(), mixed);
+        assert_eq!(compressor.decode(mixed.clone()).unwrap(), mixed);
+
+        // looks like a frame
+        let tricky = Arc::new(b"\x00MQZ\x07".to_vec());
+        let encoded = compressor.encode(tricky.clone()).unwrap();
+        assert_eq!(&encoded[..], b"\x00MQZ\x00\x00MQZ\x07");
+        assert_eq!(compressor.decode(encoded).unwrap(), tricky);
+
+        assert!(compressor.decode(Arc::new(b"\x00MQZ\x09abc".to_vec())).is_err());
+
+        let mut compressor = Compressor::new(Box::new(Rle), 16);
+        compressor.set_limit(99);
+        let encoded = compressor.encode(long.clone()).unwrap();
+        assert_eq!(compressor.decode(encoded.clone()).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        compressor.set_limit(100);
+        assert_eq!(compressor.decode(encoded).unwrap(), long);
+    }
+
+    #[test]
+    #[cfg(any(feature = "deflate", feature = "zstd"))]
+    fn codec_limit_test() {
+        let mut codecs: Vec<Box<Compression>> = Vec::new();
+        #[cfg(feature = "deflate")]
+        codecs.push(Box::new(::compress::Deflate::new(6)));
+        #[cfg(feature = "zstd")]
+        codecs.push(Box::new(::compress::Zstd::new(0)));
+
+        let data = vec![0; 1 << 20];
+        for codec in codecs {
+            let compressed = codec.compress(&data).unwrap();
+            assert_eq!(codec.decompress(&compressed, data.len()).unwrap(), data);
+            let err = codec.decompress(&compressed, data.len() - 1).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
@@ -0,0 +1,286 @@
+//! Brokers on the local network found with DNS-SD over multicast DNS
+//! (RFC 6762, RFC 6763), for clients that have no host name to connect to.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+macro_rules! try_opt {
+    ($e:expr) => (match $e { Some(value) => value, None => return None })
+}
+
+/// Service type brokers announce
+pub const MQTT_SERVICE: &'static str = "_mqtt._tcp.local";
+
+const MDNS_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+// Asks for a unicast response
+const CLASS_IN_QU: u16 = 0x8001;
+
+/// Broker announced on the network
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Broker {
+    /// Instance name, like `Living room`
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+    pub addrs: Vec<IpAddr>
+}
+
+impl Broker {
+    /// Addresses to connect to, the host name is only known to mDNS
+    pub fn socket_addrs(&self) -> Vec<SocketAddr> {
+        self.addrs.iter().map(|&addr| SocketAddr::new(addr, self.port)).collect()
+    }
+}
+
+/// Browses for `_mqtt._tcp` brokers, collecting answers for `timeout`
+pub fn browse(timeout: Duration) -> io::Result<Vec<Broker>> {
+    browse_service(MQTT_SERVICE, timeout)
+}
+
+/// Browses for instances of `service`, like `_secure-mqtt._tcp.local`.
+/// Brokers are listed once they have answered with their port and an
+/// address.
+pub fn browse_service(service: &str, timeout: Duration) -> io::Result<Vec<Broker>> {
+    let socket = try!(UdpSocket::bind("0.0.0.0:0"));
+    try!(socket.send_to(&query(service), (MDNS_ADDR, MDNS_PORT)));
+
+    let deadline = Instant::now() + timeout;
+    let mut records = Vec::new();
+    let mut buf = [0; 9000];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        try!(socket.set_read_timeout(Some(deadline - now)));
+        match socket.recv_from(&mut buf) {
+            // Malformed answers are skipped
+            Ok((len, _)) => records.extend(parse(&buf[..len]).unwrap_or_default()),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock ||
+                            err.kind() == io::ErrorKind::TimedOut => break,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err)
+        }
+    }
+    Ok(brokers(service, &records))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Record {
+    // Service type to instance
+    Ptr(String, String),
+    // Instance to host and port
+    Srv(String, String, u16),
+    // Host to address
+    Addr(String, IpAddr)
+}
+
+fn write_name(packet: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+}
+
+fn query(service: &str) -> Vec<u8> {
+    // Id 0, no flags, one question
+    let mut packet = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    write_name(&mut packet, service);
+    packet.extend_from_slice(&[(TYPE_PTR >> 8) as u8, TYPE_PTR as u8,
+                               (CLASS_IN_QU >> 8) as u8, CLASS_IN_QU as u8]);
+    packet
+}
+
+fn read_u16(packet: &[u8], pos: usize) -> Option<u16> {
+    if pos + 2 > packet.len() {
+        return None;
+    }
+    Some((packet[pos] as u16) << 8 | packet[pos + 1] as u16)
+}
+
+// Name at `pos` and the position after it, following compression pointers
+fn read_name(packet: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    // Bounds the pointers followed, a loop of them would never end
+    for _ in 0..128 {
+        let len = *try_opt!(packet.get(pos)) as usize;
+        if len == 0 {
+            return Some((labels.join("."), end.unwrap_or(pos + 1)));
+        } else if len & 0xC0 == 0xC0 {
+            let target = try_opt!(read_u16(packet, pos)) as usize & 0x3FFF;
+            if end.is_none() {
+                end = Some(pos + 2);
+            }
+            pos = target;
+        } else {
+            let label = try_opt!(packet.get(pos + 1..pos + 1 + len));
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            pos += 1 + len;
+        }
+    }
+    None
+}
+
+// Records of the answer and additional sections
+fn parse(packet: &[u8]) -> Option<Vec<Record>> {
+    let flags = try_opt!(read_u16(packet, 2));
+    // Only responses
+    if flags & 0x8000 == 0 {
+        return Some(Vec::new());
+    }
+    let questions = try_opt!(read_u16(packet, 4));
+    let count = try_opt!(read_u16(packet, 6)) as usize + try_opt!(read_u16(packet, 8)) as usize +
+                try_opt!(read_u16(packet, 10)) as usize;
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = try_opt!(read_name(packet, pos)).1 + 4;
+    }
+    let mut records = Vec::new();
+    for _ in 0..count {
+        let (name, next) = try_opt!(read_name(packet, pos));
+        let rtype = try_opt!(read_u16(packet, next));
+        let rdlen = try_opt!(read_u16(packet, next + 8)) as usize;
+        let rdata = next + 10;
+        let data = try_opt!(packet.get(rdata..rdata + rdlen));
+        match rtype {
+            TYPE_PTR => records.push(Record::Ptr(name, try_opt!(read_name(packet, rdata)).0)),
+            TYPE_SRV => {
+                let port = try_opt!(read_u16(packet, rdata + 4));
+                let target = try_opt!(read_name(packet, rdata + 6)).0;
+                records.push(Record::Srv(name, target, port));
+            }
+            TYPE_A if rdlen == 4 => {
+                let addr = Ipv4Addr::new(data[0], data[1], data[2], data[3]);
+                records.push(Record::Addr(name, IpAddr::V4(addr)));
+            }
+            TYPE_AAAA if rdlen == 16 => {
+                let mut segments = [0; 8];
+                for (i, segment) in segments.iter_mut().enumerate() {
+                    *segment = (data[2 * i] as u16) << 8 | data[2 * i + 1] as u16;
+                }
+                let addr = Ipv6Addr::new(segments[0], segments[1], segments[2], segments[3],
+                                         segments[4], segments[5], segments[6], segments[7]);
+                records.push(Record::Addr(name, IpAddr::V6(addr)));
+            }
+            _ => ()
+        }
+        pos = rdata + rdlen;
+    }
+    Some(records)
+}
+
+// Brokers whose instance, port and address are all known. Names are
+// compared without case like DNS does.
+fn brokers(service: &str, records: &[Record]) -> Vec<Broker> {
+    let service = service.trim_end_matches('.').to_lowercase();
+    let mut brokers: Vec<Broker> = Vec::new();
+    for record in records {
+        let instance = match *record {
+            Record::Ptr(ref name, ref instance) if name.to_lowercase() == service => instance,
+            _ => continue
+        };
+        if brokers.iter().any(|broker| broker.name == instance_name(instance, &service)) {
+            continue;
+        }
+        let srv = records.iter().filter_map(|record| match *record {
+            Record::Srv(ref name, ref host, port) if name.to_lowercase() == instance.to_lowercase() => Some((host, port)),
+            _ => None
+        }).next();
+        let (host, port) = match srv {
+            Some(srv) => srv,
+            None => continue
+        };
+        let mut addrs = Vec::new();
+        for record in records {
+            if let Record::Addr(ref name, addr) = *record {
+                if name.to_lowercase() == host.to_lowercase() && !addrs.contains(&addr) {
+                    addrs.push(addr);
+                }
+            }
+        }
+        if addrs.is_empty() {
+            continue;
+        }
+        brokers.push(Broker {
+            name: instance_name(instance, &service),
+            host: host.clone(),
+            port: port,
+            addrs: addrs
+        });
+    }
+    brokers
+}
+
+// `Living room._mqtt._tcp.local` is `Living room`
+fn instance_name(instance: &str, service: &str) -> String {
+    let suffix = format!(".{}", service);
+    if instance.to_lowercase().ends_with(&suffix) {
+        instance[..instance.len() - suffix.len()].to_string()
+    } else {
+        instance.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, Ipv4Addr};
+    use super::{Broker, MQTT_SERVICE, query, parse, brokers, write_name};
+
+    fn record(packet: &mut Vec<u8>, name: &[u8], rtype: u16, rdata: &[u8]) {
+        packet.extend_from_slice(name);
+        packet.extend_from_slice(&[(rtype >> 8) as u8, rtype as u8, 0x80, 1, 0, 0, 0x11, 0x94,
+                                   (rdata.len() >> 8) as u8, rdata.len() as u8]);
+        packet.extend_from_slice(rdata);
+    }
+
+    #[test]
+    fn discovery_test() {
+        let query = query(MQTT_SERVICE);
+        assert_eq!(&query[12..], &b"\x05_mqtt\x04_tcp\x05local\x00\x00\x0c\x80\x01"[..]);
+        // a query isn't an answer
+        assert_eq!(parse(&query), Some(Vec::new()));
+
+        // response with 1 answer and 2 additional records
+        let mut packet = vec![0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 2];
+        let mut service = Vec::new();
+        write_name(&mut service, MQTT_SERVICE);
+        // the instance points back at the service name at offset 12
+        let mut instance = vec![11];
+        instance.extend_from_slice(b"Living room\xc0\x0c");
+        record(&mut packet, &service, 12, &instance);
+        let instance_at = packet.len() - instance.len();
+        let mut srv = vec![0, 0, 0, 0, 0x07, 0x5b];
+        write_name(&mut srv, "hub.local");
+        record(&mut packet, &[0xc0, instance_at as u8], 33, &srv);
+        let mut host = Vec::new();
+        write_name(&mut host, "HUB.local");
+        record(&mut packet, &host, 1, &[192, 168, 1, 20]);
+
+        let records = parse(&packet).unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(brokers(MQTT_SERVICE, &records), vec![Broker {
+            name: "Living room".to_string(),
+            host: "hub.local".to_string(),
+            port: 1883,
+            addrs: vec![IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20))]
+        }]);
+        assert_eq!(brokers(MQTT_SERVICE, &records)[0].socket_addrs()[0].to_string(), "192.168.1.20:1883");
+
+        // without the address there is nothing to connect to
+        assert!(brokers(MQTT_SERVICE, &records[..2]).is_empty());
+        // cut short
+        assert!(parse(&packet[..packet.len() - 2]).is_none());
+        // a pointer to itself
+        assert!(parse(&[0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0xc0, 12]).is_none());
+    }
+}
@@ -2,7 +2,7 @@ use std::result;
 use std::io;
 use std::fmt;
 use std::error;
-use mqtt3::{ConnectReturnCode, PacketIdentifier};
+use mqtt3::{ConnackCode, PacketIdentifier};
 use mqtt3::Error as MqttError;
 use store::Error as StorageError;
 
@@ -17,14 +17,17 @@ pub enum Error {
     IncommingStorageAbsent,
     OutgoingStorageAbsent,
     HandshakeFailed,
+    InvalidClientId,
     ProtocolViolation,
     Disconnected,
+    NotDelivered,
+    QueueFull,
     Timeout,
     UnhandledPuback(PacketIdentifier),
     UnhandledPubrec(PacketIdentifier),
     UnhandledPubrel(PacketIdentifier),
     UnhandledPubcomp(PacketIdentifier),
-    ConnectionRefused(ConnectReturnCode),
+    ConnectionRefused(ConnackCode),
     Storage(StorageError),
     Mqtt(MqttError),
     Io(io::Error)
@@ -81,8 +84,11 @@ impl error::Error for Error {
             Error::IncommingStorageAbsent => "IncommingStorageAbsent",
             Error::OutgoingStorageAbsent => "OutgoingStorageAbsent",
             Error::HandshakeFailed => "HandshakeFailed",
+            Error::InvalidClientId => "InvalidClientId",
             Error::ProtocolViolation => "ProtocolViolation",
             Error::Disconnected => "Disconnected",
+            Error::NotDelivered => "NotDelivered",
+            Error::QueueFull => "QueueFull",
             Error::Timeout => "Timeout",
             Error::UnhandledPuback(_) => "UnhandledPuback",
             Error::UnhandledPubrec(_) => "UnhandledPubrec",
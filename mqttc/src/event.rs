@@ -0,0 +1,44 @@
+use std::time::Duration;
+use mqtt3::{PacketIdentifier, SubscribeReturnCodes};
+
+/// Something that happened to the connection, not a message
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// CONNACK accepted the connection
+    Connected { session_present: bool },
+    /// The connection is gone, the client is in the `Disconnected` state
+    Disconnected { reason: DisconnectReason },
+    /// The client is about to reconnect, `attempt` counts from 1 and starts
+    /// over once a connection is accepted
+    Reconnecting { attempt: u32 },
+    /// SUBACK for a subscribe request, every topic filter with its return code
+    SubscribeAck {
+        pid: PacketIdentifier,
+        return_codes: Vec<(String, SubscribeReturnCodes)>
+    },
+    /// UNSUBACK for an unsubscribe request
+    UnsubscribeAck { pid: PacketIdentifier },
+    /// A QoS 1 (PUBACK) or QoS 2 (PUBCOMP) publish is done
+    PublishComplete { pid: PacketIdentifier },
+    /// PINGRESP didn't come within 1.5 times the keep alive interval
+    PingTimeout,
+    /// PINGRESP came `rtt` after the PINGREQ was written
+    Pong { rtt: Duration },
+    /// The broker had no session after a reconnect, so the client subscribed
+    /// again. Carries every topic filter with the code the broker returned.
+    Resubscribed(Vec<(String, SubscribeReturnCodes)>)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// `Client::terminate` was called
+    Requested,
+    /// The broker didn't answer PINGREQ
+    PingTimeout,
+    /// The socket was closed or reset
+    ConnectionLost,
+    /// Reading or writing failed with an unexpected IO error
+    Io,
+    /// The I/O thread of a `ClientHandle` panicked
+    Panic
+}
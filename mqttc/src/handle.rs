@@ -0,0 +1,457 @@
+use std::any::Any;
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Sender, SyncSender, Receiver, TryRecvError, TrySendError, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use netopt::{Waiter, Waker, Wake};
+use mqtt3::{Message, QoS, PacketIdentifier, SubscribeTopic, TopicPath, ToTopicPath};
+use error::{Error, Result};
+use client::{self, Client};
+use event::{Event, DisconnectReason};
+use token::{self, DeliveryToken, Completion};
+use {PubSub, PubOpt, Backpressure, Payload, ToPayload, ToSubTopics, ToUnSubTopics};
+
+// Longest the I/O thread waits for input, the timers of the client run
+// at least this often. Streams which can't be waited on are read with it as
+// the timeout, commands wait for it then.
+const POLL_INTERVAL_MS: u64 = 50;
+// Read timeout when only the timers are due
+const TICK_MS: u64 = 1;
+
+enum Command {
+    Publish(TopicPath, Payload, PubOpt, Completion),
+    Subscribe(Vec<SubscribeTopic>),
+    Unsubscribe(Vec<String>),
+    Disconnect,
+    DisconnectGracefully(Duration)
+}
+
+// Sending side of the message channel
+enum Delivery {
+    Unbounded(Sender<Box<Message>>),
+    Bounded(SyncSender<Box<Message>>, Backpressure)
+}
+
+/// Handle to a client running on its own I/O thread, created with
+/// `Client::spawn`.
+///
+/// The thread owns the connection. Requests are passed to it over a channel,
+/// so `publish`, `subscribe` and `unsubscribe` only queue the request, wake
+/// the thread and return; failures of the request itself are logged by the
+/// thread. The
+/// token `publish` returns tells when the broker has the message. Incoming
+/// messages and events come back over channels of their own. The handle can
+/// be shared between threads. The message channel is unbounded unless
+/// `ClientOptions::set_delivery_channel` bounds it.
+///
+/// Dropping the handle stops the thread. A panic on the thread is logged
+/// and ends it like a lost connection, with the `DisconnectReason::Panic`
+/// event.
+pub struct ClientHandle {
+    commands: Mutex<Sender<Command>>,
+    waker: Waker,
+    messages: Mutex<Receiver<Box<Message>>>,
+    events: Mutex<Receiver<Event>>,
+    thread: JoinHandle<Result<()>>
+}
+
+impl ClientHandle {
+    pub fn publish<T, P>(&self, topic: T, payload: P, pubopt: PubOpt) -> Result<DeliveryToken>
+        where T: ToTopicPath,
+              P: ToPayload
+    {
+        let topic = try!(topic.to_topic_name());
+        let (token, completion) = token::new();
+        try!(self._send(Command::Publish(topic, payload.to_payload(), pubopt, completion)));
+        Ok(token)
+    }
+
+    /// See `PubSub::publish_retained`
+    pub fn publish_retained<T, P>(&self, topic: T, payload: P, pubopt: PubOpt) -> Result<DeliveryToken>
+        where T: ToTopicPath,
+              P: ToPayload
+    {
+        self.publish(topic, payload, pubopt | PubOpt::retain())
+    }
+
+    /// See `PubSub::clear_retained`
+    pub fn clear_retained<T: ToTopicPath>(&self, topic: T) -> Result<DeliveryToken> {
+        self.publish(topic, Vec::new(), PubOpt::at_least_once() | PubOpt::retain())
+    }
+
+    pub fn subscribe<S: ToSubTopics>(&self, subs: S) -> Result<()> {
+        let topics = try!(subs.to_subscribe_topics()).collect();
+        self._send(Command::Subscribe(topics))
+    }
+
+    pub fn unsubscribe<U: ToUnSubTopics>(&self, unsubs: U) -> Result<()> {
+        let topics = try!(unsubs.to_unsubscribe_topics()).collect();
+        self._send(Command::Unsubscribe(topics))
+    }
+
+    /// Waits for the next message. Fails with `Error::Disconnected` once the
+    /// thread stopped and every message was taken.
+    pub fn recv(&self) -> Result<Box<Message>> {
+        self.messages.lock().unwrap().recv().map_err(|_| Error::Disconnected)
+    }
+
+    pub fn try_recv(&self) -> Result<Option<Box<Message>>> {
+        match self.messages.lock().unwrap().try_recv() {
+            Ok(message) => Ok(Some(message)),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(Error::Disconnected),
+        }
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Option<Box<Message>>> {
+        match self.messages.lock().unwrap().recv_timeout(timeout) {
+            Ok(message) => Ok(Some(message)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(Error::Disconnected),
+        }
+    }
+
+    /// Takes the events collected since the last call, see
+    /// `ClientOptions::enable_events`
+    pub fn events(&self) -> Vec<Event> {
+        self.events.lock().unwrap().try_iter().collect()
+    }
+
+    /// Stops the thread and returns how it ended. If the connection was lost
+    /// before, that error is returned.
+    pub fn disconnect(self) -> Result<()> {
+        let _ = self._send(Command::Disconnect);
+        self._join()
+    }
+
+    /// Like `disconnect` but lets the thread finish the requests queued
+    /// before, then runs `Client::disconnect_gracefully`. Messages which
+    /// arrive meanwhile can still be taken with `recv`.
+    pub fn disconnect_gracefully(self, timeout: Duration) -> Result<()> {
+        let _ = self._send(Command::DisconnectGracefully(timeout));
+        self._join()
+    }
+
+    /// Name of the I/O thread, see `ClientOptions::set_thread_name`
+    pub fn thread_name(&self) -> Option<&str> {
+        self.thread.thread().name()
+    }
+
+    fn _join(self) -> Result<()> {
+        // Unblocks the thread if it waits for room in the message channel
+        let ClientHandle { messages, thread, .. } = self;
+        drop(messages);
+        match thread.join() {
+            Ok(result) => result,
+            Err(_) => Err(Error::Disconnected),
+        }
+    }
+
+    fn _send(&self, command: Command) -> Result<()> {
+        try!(self.commands.lock().unwrap().send(command).map_err(|_| Error::Disconnected));
+        self.waker.wake();
+        Ok(())
+    }
+}
+
+pub fn spawn(mut client: Client) -> ClientHandle {
+    let (commands_tx, commands_rx) = mpsc::channel();
+    let (delivery, messages_rx) = match client::delivery_channel(&client) {
+        Some((capacity, backpressure)) => {
+            if backpressure == Backpressure::WithholdAcks {
+                client::defer_acks(&mut client);
+            }
+            let (messages_tx, messages_rx) = mpsc::sync_channel(capacity);
+            (Delivery::Bounded(messages_tx, backpressure), messages_rx)
+        }
+        None => {
+            let (messages_tx, messages_rx) = mpsc::channel();
+            (Delivery::Unbounded(messages_tx), messages_rx)
+        }
+    };
+    let (events_tx, events_rx) = mpsc::channel();
+    let waiter = Waiter::new().expect("failed to create the waiter of the I/O thread");
+    let waker = waiter.waker();
+    let name = client::thread_name(&client);
+    let panic_events = events_tx.clone();
+    let thread = thread::Builder::new().name(name).spawn(move || {
+        match panic::catch_unwind(AssertUnwindSafe(|| run(client, waiter, delivery, events_tx, commands_rx))) {
+            Ok(result) => result,
+            Err(payload) => {
+                error!("I/O thread panicked: {}", panic_message(&*payload));
+                let _ = panic_events.send(Event::Disconnected { reason: DisconnectReason::Panic });
+                Err(Error::Disconnected)
+            }
+        }
+    }).expect("failed to spawn the I/O thread");
+    ClientHandle {
+        commands: Mutex::new(commands_tx),
+        waker: waker,
+        messages: Mutex::new(messages_rx),
+        events: Mutex::new(events_rx),
+        thread: thread
+    }
+}
+
+// Text a panic was raised with
+pub fn panic_message(payload: &(Any + Send)) -> &str {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => payload.downcast_ref::<String>().map_or("", |message| message.as_str())
+    }
+}
+
+// The arguments are dropped in reverse order, so the commands are refused
+// before the message channel tells the handle the thread stopped
+fn run(mut client: Client,
+       mut waiter: Waiter,
+       delivery: Delivery,
+       events: Sender<Event>,
+       commands: Receiver<Command>)
+       -> Result<()> {
+    // Messages withheld until the channel has room
+    let mut withheld = VecDeque::new();
+    loop {
+        if let Err(err) = deliver_withheld(&mut client, &delivery, &mut withheld) {
+            error!("{:?}", err);
+        }
+
+        loop {
+            let result = match commands.try_recv() {
+                Ok(Command::Publish(topic, payload, pubopt, completion)) => {
+                    client::publish_with_completion(&mut client, topic, payload, pubopt, completion)
+                }
+                Ok(Command::Subscribe(topics)) => client.subscribe(topics),
+                Ok(Command::Unsubscribe(topics)) => client.unsubscribe(topics),
+                // Asked to stop or the handle is gone
+                Ok(Command::Disconnect) | Err(TryRecvError::Disconnected) => return client.disconnect(),
+                Ok(Command::DisconnectGracefully(timeout)) => {
+                    let result = client.disconnect_gracefully(timeout);
+                    for event in client.events() {
+                        let _ = events.send(event);
+                    }
+                    for message in try!(result) {
+                        delivery.send(message);
+                    }
+                    return Ok(());
+                }
+                Err(TryRecvError::Empty) => break,
+            };
+            if let Err(err) = result {
+                error!("{:?}", err);
+            }
+        }
+
+        let interval = Duration::from_millis(POLL_INTERVAL_MS);
+        let timeout = match client::wait_input(&client, &mut waiter, interval) {
+            Ok(Wake::Woken) => continue,
+            Ok(Wake::Timeout) => Duration::from_millis(TICK_MS),
+            Ok(Wake::Input) | Ok(Wake::Unsupported) => interval,
+            Err(err) => {
+                error!("{:?}", err);
+                interval
+            }
+        };
+        let result = client.poll(timeout);
+        for event in client.events() {
+            let _ = events.send(event);
+        }
+        match result {
+            Ok(Some(message)) => {
+                if let Err(err) = deliver(&mut client, &delivery, &mut withheld, message) {
+                    error!("{:?}", err);
+                }
+            }
+            Ok(None) => (),
+            Err(Error::Disconnected) => return Err(Error::Disconnected),
+            Err(err) => error!("{:?}", err),
+        }
+    }
+}
+
+impl Delivery {
+    // Hands the message over unless the channel is gone, waiting for room
+    fn send(&self, message: Box<Message>) {
+        let _ = match *self {
+            Delivery::Unbounded(ref messages) => messages.send(message),
+            Delivery::Bounded(ref messages, _) => messages.send(message),
+        };
+    }
+}
+
+fn deliver(client: &mut Client,
+           delivery: &Delivery,
+           withheld: &mut VecDeque<Box<Message>>,
+           message: Box<Message>)
+           -> Result<()> {
+    let qos = message.qos;
+    let pid = message.pid;
+    if let Delivery::Bounded(ref messages, backpressure) = *delivery {
+        // Keeps the order of the withheld ones
+        if !withheld.is_empty() && qos != QoS::AtMostOnce {
+            withheld.push_back(message);
+            return Ok(());
+        }
+        if let Err(TrySendError::Full(message)) = messages.try_send(message) {
+            match (backpressure, qos) {
+                (Backpressure::Block, _) => delivery.send(message),
+                (_, QoS::AtMostOnce) => {
+                    debug!("       Dropped {}", message.topic.path);
+                    return Ok(());
+                }
+                (Backpressure::DropAtMostOnce, _) => delivery.send(message),
+                (Backpressure::WithholdAcks, _) => {
+                    withheld.push_back(message);
+                    return Ok(());
+                }
+            }
+        }
+    } else {
+        delivery.send(message);
+    }
+    acknowledge(client, qos, pid)
+}
+
+fn deliver_withheld(client: &mut Client,
+                    delivery: &Delivery,
+                    withheld: &mut VecDeque<Box<Message>>)
+                    -> Result<()> {
+    if let Delivery::Bounded(ref messages, _) = *delivery {
+        while let Some(message) = withheld.pop_front() {
+            let qos = message.qos;
+            let pid = message.pid;
+            if let Err(TrySendError::Full(message)) = messages.try_send(message) {
+                withheld.push_front(message);
+                break;
+            }
+            try!(acknowledge(client, qos, pid));
+        }
+    }
+    Ok(())
+}
+
+// The message is handed over, so its flow can end
+fn acknowledge(client: &mut Client, qos: QoS, pid: Option<PacketIdentifier>) -> Result<()> {
+    match (qos, pid) {
+        (QoS::AtLeastOnce, Some(pid)) if client::acks_deferred(client) => client::acknowledge(client, pid),
+        (QoS::ExactlyOnce, Some(pid)) => client.complete(pid),
+        _ => Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+    use std::io::{self, Cursor};
+    use std::sync::Arc;
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use super::{ClientHandle, Delivery, deliver, deliver_withheld};
+    use mqtt3::{Message, MqttRead, Packet, PacketIdentifier};
+    use client::{self, ClientOptions};
+    use error::Error;
+    use event::{Event, DisconnectReason};
+    use {PubOpt, Backpressure, Middleware};
+    use netopt::{NetworkStream, NetworkOptions};
+    use netopt::mock::MockStream;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn client_handle_test() {
+        assert_send_sync::<ClientHandle>();
+
+        let mock = MockStream::with_vec(vec![
+            0b00100000, 0x02, 0x00, 0x00, // connack
+            0x30, 0x07, 0x00, 0x03, b'a', b'/', b'b', b'h', b'i' // publish
+        ]);
+        let mut options = ClientOptions::new();
+        options.enable_events();
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(NetworkStream::Mock(mock));
+        let handle = options.connect("127.0.0.1:1883", netopt).unwrap().spawn();
+
+        let message = handle.recv().unwrap();
+        assert_eq!(message.topic.path(), "a/b");
+        assert_eq!(message.payload, Arc::new(b"hi".to_vec()));
+
+        // the mock runs out of data, which ends the I/O thread
+        match handle.recv() {
+            Err(Error::Disconnected) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+        assert_eq!(handle.events(), vec![
+            Event::Connected { session_present: false },
+            Event::Disconnected { reason: DisconnectReason::ConnectionLost }
+        ]);
+        assert!(handle.publish("a/b", "hello", PubOpt::at_most_once()).is_err());
+        assert!(handle.disconnect().is_err());
+    }
+
+    struct Panic;
+
+    impl Middleware for Panic {
+        fn receive(&self, _: &mut Message) -> io::Result<()> {
+            panic!("middleware failed")
+        }
+    }
+
+    #[test]
+    fn client_handle_panic_test() {
+        let mock = MockStream::with_vec(vec![
+            0b00100000, 0x02, 0x00, 0x00, // connack
+            0x30, 0x07, 0x00, 0x03, b'a', b'/', b'b', b'h', b'i' // publish
+        ]);
+        let mut options = ClientOptions::new();
+        options.enable_events().set_thread_name("io".to_string()).add_middleware(Box::new(Panic));
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(NetworkStream::Mock(mock));
+        let handle = options.connect("127.0.0.1:1883", netopt).unwrap().spawn();
+        assert_eq!(handle.thread_name(), Some("io"));
+
+        match handle.recv() {
+            Err(Error::Disconnected) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+        // sent once the thread unwound, the events of the poll that panicked are lost
+        let event = handle.events.lock().unwrap().recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(event, Event::Disconnected { reason: DisconnectReason::Panic });
+        assert!(handle.disconnect().is_err());
+    }
+
+    #[test]
+    fn withhold_acks_test() {
+        let mut mock = MockStream::with_vec(vec![0b00100000, 0x02, 0x00, 0x00]);
+        let mut netopt = NetworkOptions::new();
+        netopt.attach(NetworkStream::Mock(mock.clone()));
+        let mut client = ClientOptions::new().connect("127.0.0.1:1883", netopt).unwrap();
+        client::defer_acks(&mut client);
+        mock.take_vec();
+
+        let (messages_tx, messages_rx) = mpsc::sync_channel(1);
+        let delivery = Delivery::Bounded(messages_tx, Backpressure::WithholdAcks);
+        let mut withheld = VecDeque::new();
+
+        // QoS 1 pid 1 and 2, then QoS 0
+        mock.next_vec(vec![0x32, 0x08, 0x00, 0x03, b'a', b'/', b'b', 0x00, 0x01, b'x',
+                           0x32, 0x08, 0x00, 0x03, b'a', b'/', b'b', 0x00, 0x02, b'y',
+                           0x30, 0x06, 0x00, 0x03, b'a', b'/', b'b', b'z']);
+        for _ in 0..3 {
+            let message = client.accept().unwrap().unwrap();
+            deliver(&mut client, &delivery, &mut withheld, message).unwrap();
+        }
+        // the channel holds one, the second waits unacknowledged, the third is dropped
+        let mut written = Cursor::new(mock.take_vec());
+        assert_eq!(written.read_packet().unwrap(), Packet::Puback(PacketIdentifier(1)));
+        assert_eq!(written.position() as usize, written.get_ref().len());
+        assert_eq!(withheld.len(), 1);
+
+        assert_eq!(*messages_rx.recv().unwrap().payload, b"x".to_vec());
+        deliver_withheld(&mut client, &delivery, &mut withheld).unwrap();
+        assert_eq!(Cursor::new(mock.take_vec()).read_packet().unwrap(), Packet::Puback(PacketIdentifier(2)));
+        assert_eq!(*messages_rx.recv().unwrap().payload, b"y".to_vec());
+        assert!(messages_rx.try_recv().is_err());
+    }
+}
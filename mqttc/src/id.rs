@@ -0,0 +1,164 @@
+use std::fmt;
+use std::ops;
+use rand::{self, Rng};
+use mqtt3::Protocol;
+use error::{Error, Result};
+
+// Characters every server has to accept in a client id
+const SAFE_CHARS: &'static [u8] = b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+// Longest id every server has to accept, and the limit of MQTT 3.1
+const MAX_SAFE_LEN: usize = 23;
+// Longest UTF-8 string of the protocol
+const MAX_LEN: usize = 65535;
+
+/// Client identifier as sent in CONNECT.
+///
+/// Any UTF-8 string up to 65535 bytes without null characters is an id, but
+/// servers only have to accept 1 to 23 letters and digits. `is_spec_safe`
+/// tells whether an id stays inside those rules, `check_len` checks the
+/// limit of a particular server.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ClientId(String);
+
+impl ClientId {
+    pub fn new<S: Into<String>>(id: S) -> Result<ClientId> {
+        let id = id.into();
+        if id.len() > MAX_LEN || id.contains('\u{0}') {
+            return Err(Error::InvalidClientId);
+        }
+        Ok(ClientId(id))
+    }
+
+    /// Random id every server accepts, `mqttc` followed by 18 random
+    /// letters and digits
+    pub fn random() -> ClientId {
+        ClientId::random_with("mqttc", MAX_SAFE_LEN).unwrap()
+    }
+
+    /// `prefix` followed by random letters and digits up to `len`
+    /// characters. Fails if the prefix isn't made of letters and digits or
+    /// doesn't leave room for at least one random character.
+    pub fn random_with(prefix: &str, len: usize) -> Result<ClientId> {
+        ClientId::random_with_rng(&mut rand::thread_rng(), prefix, len)
+    }
+
+    /// Like `random` with the characters drawn from `rng`
+    pub fn random_from<R: Rng>(rng: &mut R) -> ClientId {
+        ClientId::random_with_rng(rng, "mqttc", MAX_SAFE_LEN).unwrap()
+    }
+
+    /// Like `random_with` with the characters drawn from `rng`
+    pub fn random_with_rng<R: Rng>(rng: &mut R, prefix: &str, len: usize) -> Result<ClientId> {
+        if prefix.len() >= len || len > MAX_LEN || !prefix.bytes().all(|b| SAFE_CHARS.contains(&b)) {
+            return Err(Error::InvalidClientId);
+        }
+        let mut id = String::with_capacity(len);
+        id.push_str(prefix);
+        for _ in prefix.len()..len {
+            id.push(SAFE_CHARS[rng.gen_range(0, SAFE_CHARS.len())] as char);
+        }
+        Ok(ClientId(id))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Whether every server has to accept the id: 1 to 23 letters and digits
+    pub fn is_spec_safe(&self) -> bool {
+        !self.0.is_empty() && self.0.len() <= MAX_SAFE_LEN &&
+            self.0.bytes().all(|b| SAFE_CHARS.contains(&b))
+    }
+
+    /// Checks the id against a server which takes ids up to `max_len` bytes
+    pub fn check_len(&self, max_len: usize) -> Result<()> {
+        if self.0.len() > max_len {
+            return Err(Error::InvalidClientId);
+        }
+        Ok(())
+    }
+
+    /// MQTT 3.1 servers refuse ids which are empty or longer than 23
+    /// characters, 3.1.1 ones take an empty id with a clean session only
+    pub fn check_protocol(&self, protocol: Protocol, clean_session: bool) -> Result<()> {
+        match protocol {
+            Protocol::MQIsdp(_) => {
+                if self.0.is_empty() {
+                    return Err(Error::InvalidClientId);
+                }
+                self.check_len(MAX_SAFE_LEN)
+            },
+            Protocol::MQTT(_) => {
+                if self.0.is_empty() && !clean_session {
+                    return Err(Error::InvalidClientId);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub fn into_string(self) -> String {
+        self.0
+    }
+}
+
+impl ops::Deref for ClientId {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for ClientId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mqtt3::Protocol;
+    use super::ClientId;
+
+    #[test]
+    fn client_id_random_test() {
+        let id = ClientId::random();
+        assert!(id.starts_with("mqttc"));
+        assert_eq!(id.len(), 23);
+        assert!(id.is_spec_safe());
+        assert!(id != ClientId::random());
+
+        let id = ClientId::random_with("sensor", 12).unwrap();
+        assert!(id.starts_with("sensor"));
+        assert_eq!(id.len(), 12);
+        assert!(ClientId::random_with("sensor", 6).is_err());
+        assert!(ClientId::random_with("sensor/", 12).is_err());
+    }
+
+    #[test]
+    fn client_id_validate_test() {
+        assert!(ClientId::new("a\u{0}b").is_err());
+        assert!(ClientId::new(String::from_utf8(vec![b'a'; 65536]).unwrap()).is_err());
+
+        let id = ClientId::new("building-7/meter").unwrap();
+        assert!(!id.is_spec_safe());
+        assert!(id.check_len(16).is_ok());
+        assert!(id.check_len(15).is_err());
+        assert!(id.check_protocol(Protocol::MQTT(4), false).is_ok());
+
+        let long = ClientId::new("abcdefghijklmnopqrstuvwxyz").unwrap();
+        assert!(long.check_protocol(Protocol::MQTT(4), true).is_ok());
+        assert!(long.check_protocol(Protocol::MQIsdp(3), true).is_err());
+
+        let empty = ClientId::new("").unwrap();
+        assert!(!empty.is_spec_safe());
+        assert!(empty.check_protocol(Protocol::MQTT(4), true).is_ok());
+        assert!(empty.check_protocol(Protocol::MQTT(4), false).is_err());
+        assert!(empty.check_protocol(Protocol::MQIsdp(3), true).is_err());
+    }
+}
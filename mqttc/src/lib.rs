@@ -3,11 +3,43 @@ extern crate rand;
 extern crate byteorder;
 extern crate mqtt3;
 extern crate netopt;
+#[cfg(feature = "sled")]
+extern crate sled;
+#[cfg(feature = "sqlite")]
+extern crate rusqlite;
+#[cfg(feature = "ssl")]
+extern crate openssl;
+#[cfg(feature = "deflate")]
+extern crate flate2;
+#[cfg(feature = "zstd")]
+extern crate zstd;
+#[cfg(feature = "aes-gcm")]
+extern crate aes_gcm;
 
 mod error;
 mod sub;
 mod client;
+mod event;
+mod handle;
+mod id;
+mod pool;
+mod token;
+mod compress;
+mod cipher;
+mod middleware;
+mod clock;
+mod chunk;
+mod sha256;
+mod transfer;
+mod cache;
+mod bridge;
+mod proxy;
+mod violation;
 pub mod store;
+#[cfg(feature = "ssl")]
+pub mod cloud;
+#[cfg(feature = "discovery")]
+pub mod discovery;
 
 pub use error::{
     Error,
@@ -20,14 +52,51 @@ pub use sub::{
 };
 
 pub use client::{
+    ActiveSubscription,
     Client,
-    ClientOptions
+    ClientOptions,
+    Pending,
+    Stats,
+    Traffic
 };
 
+pub use handle::ClientHandle;
+
+pub use id::ClientId;
+
+pub use pool::ClientPool;
+
+pub use token::DeliveryToken;
+
+pub use compress::{Compression, Compressor};
+#[cfg(feature = "deflate")]
+pub use compress::Deflate;
+#[cfg(feature = "zstd")]
+pub use compress::Zstd;
+
+pub use clock::{Clock, SystemClock, ManualClock};
+
+pub use cipher::PayloadCipher;
+#[cfg(feature = "aes-gcm")]
+pub use cipher::AesGcmCipher;
+
+pub use middleware::Middleware;
+
+pub use chunk::{Manifest, ChunkSender, ChunkReceiver};
+pub use transfer::{FileReceiver, send_file, DEFAULT_CHUNK_SIZE};
+
+pub use cache::{LastValueCache, CachedValue, ValueChange};
+pub use bridge::{Bridge, BridgeTopic, Direction};
+pub use proxy::{Proxy, ProxyHandle};
+pub use violation::{ViolationReporter, Violation};
+
+pub use event::{Event, DisconnectReason};
+
 use std::sync::Arc;
 use std::ops;
+use std::path::Path;
 use std::time::Duration;
-use mqtt3::{QoS, ToTopicPath};
+use mqtt3::{Message, QoS, ToTopicPath};
 
 const MAX_QOS: QoS = mqtt3::QoS::AtLeastOnce;
 
@@ -36,6 +105,34 @@ pub trait PubSub {
     fn subscribe<S: ToSubTopics>(&mut self, subs: S) -> Result<()>;
     fn unsubscribe<U: ToUnSubTopics>(&mut self, unsubs: U) -> Result<()>;
     fn disconnect(self) -> Result<()>;
+
+    /// Publishes with the retain flag set, so the broker keeps the message
+    /// for future subscribers. Retained deliveries have `Message::retain` set.
+    fn publish_retained<T: ToTopicPath, P: ToPayload>(&mut self, topic: T, payload: P, pubopt: PubOpt) -> Result<()> {
+        self.publish(topic, payload, pubopt | PubOpt::retain())
+    }
+
+    /// Removes the retained message of the topic, by publishing an empty
+    /// retained message
+    fn clear_retained<T: ToTopicPath>(&mut self, topic: T) -> Result<()> {
+        self.publish(topic, Vec::new(), PubOpt::at_least_once() | PubOpt::retain())
+    }
+
+    /// Waits until at most `max` published QoS 1 and 2 messages await
+    /// their acknowledgement and returns the messages received meanwhile,
+    /// so a sender of many messages holds only a window of them. Clients
+    /// which don't track acknowledgements return at once.
+    fn wait_in_flight(&mut self, _max: usize) -> Result<Vec<Box<Message>>> {
+        Ok(Vec::new())
+    }
+
+    /// Sends the file at `path` on `topic` in QoS 1 chunks of
+    /// `DEFAULT_CHUNK_SIZE` bytes, see `send_file` and `FileReceiver`
+    fn send_file<P: AsRef<Path>>(&mut self, topic: &str, path: P) -> Result<(Manifest, Vec<Box<Message>>)>
+        where Self: Sized
+    {
+        transfer::send_file(self, topic, path, DEFAULT_CHUNK_SIZE, PubOpt::at_least_once())
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -51,6 +148,46 @@ pub enum ReconnectMethod {
     ReconnectAfter(Duration)
 }
 
+/// Which broker of `ClientOptions::add_broker` is tried first when
+/// connecting again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failover {
+    /// From the first one on, the address passed to `connect`, so the
+    /// client goes back to it once it's up again
+    Ordered,
+    /// From the one after the broker that was lost
+    RoundRobin
+}
+
+/// What a publish does when the offline queue of its QoS is full, see
+/// `ClientOptions::set_offline_queue`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Reconnects right away, until the queue is sent or reconnecting is
+    /// given up
+    Block,
+    DropOldest,
+    DropNewest,
+    /// Fails with `Error::QueueFull`
+    Error
+}
+
+/// What the I/O thread of a `ClientHandle` does with a message when the
+/// channel of received messages is full, see
+/// `ClientOptions::set_delivery_channel`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backpressure {
+    /// Waits until the application takes a message. Nothing is read or
+    /// written meanwhile, so keep alive fails if it waits too long.
+    Block,
+    /// Drops QoS 0 messages and waits for the others like `Block`
+    DropAtMostOnce,
+    /// Drops QoS 0 messages and keeps the others on the I/O thread without
+    /// acknowledging them until there is room. The broker stops sending
+    /// QoS 1 and 2 messages once its in-flight window is used up.
+    WithholdAcks
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct PubOpt(u8);
 
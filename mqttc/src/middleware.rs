@@ -0,0 +1,24 @@
+//! Hooks on the messages going through a client, for tracing, topic
+//! policies or payload transforms of the application's own.
+
+use std::io;
+use mqtt3::Message;
+
+/// Sees and may change the messages a client publishes and receives, see
+/// `ClientOptions::add_middleware`.
+///
+/// `publish` gets an outgoing message before it is compressed and
+/// encrypted, `receive` an incoming one after it is decrypted and
+/// decompressed, before the filters. Middlewares run in the order they
+/// were added on the way out and in reverse on the way in, so each one can
+/// undo its own change. An error fails the publish or the read. The packet
+/// identifier and the duplicate flag are the client's, don't change them.
+pub trait Middleware {
+    fn publish(&self, _: &mut Message) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn receive(&self, _: &mut Message) -> io::Result<()> {
+        Ok(())
+    }
+}
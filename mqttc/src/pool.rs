@@ -0,0 +1,230 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::RwLock;
+use std::thread;
+use std::time::Duration;
+use mqtt3::ToTopicPath;
+use netopt::NetworkOptions;
+use error::{Error, Result};
+use client::ClientOptions;
+use handle::ClientHandle;
+use token::DeliveryToken;
+use {PubOpt, ToPayload};
+
+// Points of each connection on the hash ring, more points spread the topics
+// more evenly
+const RING_POINTS: usize = 16;
+
+/// Several connections to the same broker for services which publish more
+/// than one connection's in-flight window allows.
+///
+/// Every topic is published over one connection, picked by consistent
+/// hashing of the topic name, so the messages of a topic stay in order. When
+/// a connection is lost its topics move to the others, the rest keep their
+/// connection. The pool is for publishing, messages of subscriptions aren't
+/// read.
+pub struct ClientPool {
+    clients: Vec<ClientHandle>,
+    ring: RwLock<Ring>
+}
+
+impl ClientPool {
+    /// Opens `connections` connections, `options` is called with the number
+    /// of each. Client ids are generated unless the options set them.
+    pub fn connect<A, F>(addr: A, connections: usize, options: F) -> Result<ClientPool>
+        where A: ToSocketAddrs,
+              F: Fn(usize) -> (ClientOptions, NetworkOptions)
+    {
+        // Resolved once, every connection tries the same addresses
+        let addrs: Vec<SocketAddr> = try!(addr.to_socket_addrs()).collect();
+        if addrs.is_empty() {
+            return Err(Error::from(io::Error::new(io::ErrorKind::AddrNotAvailable, "address resolves to nothing")));
+        }
+        let mut clients = Vec::with_capacity(connections);
+        for i in 0..connections {
+            let (opts, netopt) = options(i);
+            clients.push(try!(opts.connect(&addrs[..], netopt)).spawn());
+        }
+        Ok(ClientPool::new(clients))
+    }
+
+    pub fn new(clients: Vec<ClientHandle>) -> ClientPool {
+        let ring = Ring::new(clients.len());
+        ClientPool {
+            clients: clients,
+            ring: RwLock::new(ring)
+        }
+    }
+
+    pub fn publish<T, P>(&self, topic: T, payload: P, pubopt: PubOpt) -> Result<DeliveryToken>
+        where T: ToTopicPath,
+              P: ToPayload
+    {
+        let topic = try!(topic.to_topic_name());
+        let payload = payload.to_payload();
+        loop {
+            let index = match self.ring.read().unwrap().pick(&topic.path) {
+                Some(index) => index,
+                None => return Err(Error::Disconnected)
+            };
+            match self.clients[index].publish(topic.clone(), payload.clone(), pubopt) {
+                Err(Error::Disconnected) => {
+                    warn!("connection {} of the pool is lost", index);
+                    self.ring.write().unwrap().remove(index);
+                }
+                result => return result
+            }
+        }
+    }
+
+    /// Connections still in use
+    pub fn len(&self) -> usize {
+        self.ring.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Runs `ClientHandle::disconnect_gracefully` on all connections at once
+    /// and returns the first error
+    pub fn disconnect_gracefully(self, timeout: Duration) -> Result<()> {
+        let threads: Vec<_> = self.clients.into_iter().map(|client| {
+            thread::Builder::new().name("mqttc-pool-disconnect".to_string())
+                                  .spawn(move || client.disconnect_gracefully(timeout))
+        }).collect();
+        let mut result = Ok(());
+        for thread in threads {
+            let disconnected = match thread {
+                Ok(thread) => thread.join().unwrap_or(Err(Error::Disconnected)),
+                Err(err) => Err(Error::Io(err))
+            };
+            if result.is_ok() {
+                result = disconnected;
+            }
+        }
+        result
+    }
+}
+
+fn hash<H: Hash>(value: H) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+// Points of the connections sorted by hash, a topic goes to the first point
+// at or after its own hash
+struct Ring {
+    points: Vec<(u64, usize)>
+}
+
+impl Ring {
+    fn new(connections: usize) -> Ring {
+        let mut points = Vec::with_capacity(connections * RING_POINTS);
+        for index in 0..connections {
+            for point in 0..RING_POINTS {
+                points.push((hash((index, point)), index));
+            }
+        }
+        points.sort();
+        Ring { points: points }
+    }
+
+    fn pick(&self, topic: &str) -> Option<usize> {
+        if self.points.is_empty() {
+            return None;
+        }
+        let hash = hash(topic);
+        let i = match self.points.binary_search_by(|&(point, _)| point.cmp(&hash)) {
+            Ok(i) | Err(i) => i % self.points.len()
+        };
+        Some(self.points[i].1)
+    }
+
+    fn remove(&mut self, index: usize) {
+        self.points.retain(|&(_, i)| i != index);
+    }
+
+    fn len(&self) -> usize {
+        let mut indexes: Vec<usize> = self.points.iter().map(|&(_, i)| i).collect();
+        indexes.sort();
+        indexes.dedup();
+        indexes.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::net::SocketAddr;
+    use std::thread;
+    use std::time::Duration;
+    use super::{Ring, ClientPool};
+    use client::ClientOptions;
+    use error::Error;
+    use PubOpt;
+    use netopt::{NetworkStream, NetworkOptions};
+    use netopt::mock::MockStream;
+
+    #[test]
+    fn pool_ring_test() {
+        let topics: Vec<String> = (0..200).map(|i| format!("sensors/{}", i)).collect();
+        let mut ring = Ring::new(4);
+        assert_eq!(ring.len(), 4);
+        let picked: Vec<usize> = topics.iter().map(|topic| ring.pick(topic).unwrap()).collect();
+        for index in 0..4 {
+            assert!(picked.contains(&index));
+        }
+        assert_eq!(ring.pick("sensors/7"), ring.pick("sensors/7"));
+
+        // only the topics of the removed connection move
+        ring.remove(2);
+        assert_eq!(ring.len(), 3);
+        for (topic, &before) in topics.iter().zip(picked.iter()) {
+            let after = ring.pick(topic).unwrap();
+            assert!(after != 2);
+            if before != 2 {
+                assert_eq!(after, before);
+            }
+        }
+        for index in 0..4 {
+            ring.remove(index);
+        }
+        assert_eq!(ring.pick("sensors/7"), None);
+    }
+
+    #[test]
+    fn pool_lost_connections_test() {
+        let options = |_| {
+            let mut netopt = NetworkOptions::new();
+            netopt.attach(NetworkStream::Mock(MockStream::with_vec(vec![0b00100000, 0x02, 0x00, 0x00])));
+            (ClientOptions::new(), netopt)
+        };
+        let pool = ClientPool::connect("127.0.0.1:1883", 2, options).unwrap();
+        assert_eq!(pool.len(), 2);
+
+        // the mocks run out of data, which ends both connections
+        let mut result = Ok(());
+        for _ in 0..100 {
+            if let Err(err) = pool.publish("a/b", "hello", PubOpt::at_most_once()) {
+                result = Err(err);
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        match result {
+            Err(Error::Disconnected) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+        assert!(pool.is_empty());
+
+        let none: &[SocketAddr] = &[];
+        match ClientPool::connect(none, 2, options) {
+            Err(Error::Io(ref err)) if err.kind() == io::ErrorKind::AddrNotAvailable => (),
+            _ => panic!("empty address should fail")
+        }
+    }
+}
@@ -0,0 +1,273 @@
+//! Store-and-forward proxy for sites that are often offline, like vehicles.
+//!
+//! Local clients connect to the proxy as to a broker. Their publishes are
+//! put in a durable queue and acknowledged at once, and go on to the
+//! upstream broker in order whenever it can be reached. The proxy only
+//! takes publishes, subscriptions are refused.
+
+use std::io::BufReader;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use mqtt3::{self, Connack, ConnackCode, Message, MqttRead, MqttWrite, Packet, PacketIdentifier, QoS,
+            Suback, SubscribeReturnCodes};
+use netopt::{NetworkListener, NetworkStream};
+use client::Client;
+use error::{Error, Result};
+use handle::{self, ClientHandle};
+use store::MessageQueue;
+use token::DeliveryToken;
+use violation::ViolationReporter;
+use PubOpt;
+
+// Pause of the forwarder once the queue is empty
+const FORWARD_INTERVAL_MS: u64 = 100;
+// Wait for the upstream broker to take a message
+const FORWARD_TIMEOUT_SECS: u64 = 5;
+// Interval of the summaries of the clients' protocol violations
+const VIOLATION_INTERVAL_SECS: u64 = 60;
+
+pub struct Proxy {
+    upstream: ClientHandle,
+    queue: Mutex<Box<MessageQueue + Send>>,
+    // Message forwarded and not acknowledged yet
+    pending: Mutex<Option<(u64, DeliveryToken)>>,
+    violations: Mutex<ViolationReporter>
+}
+
+impl Proxy {
+    /// Forwards to `upstream` what is put in `queue`. The client runs on a
+    /// thread of its own, give it a `ReconnectMethod` so it comes back
+    /// after losing the connection. Messages left in the queue by an
+    /// earlier run are forwarded first.
+    pub fn new(upstream: Client, queue: Box<MessageQueue + Send>) -> Proxy {
+        Proxy {
+            upstream: upstream.spawn(),
+            queue: Mutex::new(queue),
+            pending: Mutex::new(None),
+            violations: Mutex::new(ViolationReporter::new(Duration::from_secs(VIOLATION_INTERVAL_SECS)))
+        }
+    }
+
+    /// Serves a local client until it disconnects
+    pub fn serve(&self, stream: NetworkStream) -> Result<()> {
+        let mut reader = BufReader::new(try!(stream.try_clone()));
+        let mut writer = stream;
+        match try!(reader.read_packet()) {
+            Packet::Connect(_) => try!(writer.write_packet(&Packet::Connack(Connack {
+                session_present: false,
+                code: ConnackCode::Accepted
+            }))),
+            _ => return Err(Error::HandshakeFailed)
+        }
+        // QoS 2 messages queued and waiting for PUBREL
+        let mut incomming_rel: Vec<PacketIdentifier> = Vec::new();
+        loop {
+            let reply = match try!(reader.read_packet()) {
+                Packet::Publish(publish) => {
+                    let message = try!(Message::from_pub(publish));
+                    // Sent again before PUBREL, it's queued already
+                    let queued = match (message.qos, message.pid) {
+                        (QoS::ExactlyOnce, Some(pid)) => incomming_rel.contains(&pid),
+                        _ => false
+                    };
+                    if !queued {
+                        try!(self.queue.lock().unwrap().push(&message));
+                    }
+                    match (message.qos, message.pid) {
+                        (QoS::AtLeastOnce, Some(pid)) => Packet::Puback(pid),
+                        (QoS::ExactlyOnce, Some(pid)) => {
+                            if !queued {
+                                incomming_rel.push(pid);
+                            }
+                            Packet::Pubrec(pid)
+                        }
+                        _ => continue
+                    }
+                }
+                Packet::Pubrel(pid) => {
+                    incomming_rel.retain(|&rel| rel != pid);
+                    Packet::Pubcomp(pid)
+                }
+                Packet::Pingreq => Packet::Pingresp,
+                Packet::Subscribe(subscribe) => Packet::Suback(Box::new(Suback {
+                    pid: subscribe.pid,
+                    return_codes: vec![SubscribeReturnCodes::Failure; subscribe.topics.len()]
+                })),
+                Packet::Unsubscribe(unsubscribe) => Packet::Unsuback(unsubscribe.pid),
+                Packet::Disconnect => return Ok(()),
+                _ => return Err(Error::ProtocolViolation)
+            };
+            try!(writer.write_packet(&reply));
+        }
+    }
+
+    /// Forwards the queued messages in order, each waiting up to `timeout`
+    /// for the upstream broker. Stops at the first one not taken yet, it
+    /// is waited for again on the next call. Returns the number of
+    /// messages forwarded.
+    pub fn forward(&self, timeout: Duration) -> Result<usize> {
+        let mut pending = self.pending.lock().unwrap();
+        let mut forwarded = 0;
+        loop {
+            if pending.is_none() {
+                let seq = match try!(self.queue.lock().unwrap().seqs(None, 1)).first() {
+                    Some(&seq) => seq,
+                    None => return Ok(forwarded)
+                };
+                let message = try!(self.queue.lock().unwrap().get(seq));
+                let pubopt = PubOpt::new(message.qos, message.retain);
+                let token = try!(self.upstream.publish(message.topic.clone(), message.payload.clone(), pubopt));
+                *pending = Some((seq, token));
+            }
+            let delivered = match *pending {
+                Some((_, ref token)) => token.wait_timeout(timeout),
+                None => unreachable!()
+            };
+            match delivered {
+                Ok(true) => {
+                    let (seq, _) = pending.take().unwrap();
+                    try!(self.queue.lock().unwrap().remove(seq));
+                    forwarded += 1;
+                }
+                Ok(false) => return Ok(forwarded),
+                // Still queued, sent again next time
+                Err(err) => {
+                    *pending = None;
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    /// Serves the clients `listener` accepts, each on a thread of its own,
+    /// while another thread forwards. Protocol violations of the clients
+    /// are logged through a `ViolationReporter`, a panic while serving a
+    /// client only ends its connection. Returns when accepting fails, once
+    /// the serving threads and the forwarder are done.
+    pub fn run(self, listener: NetworkListener) -> Result<()> {
+        self._run(listener, Arc::new(AtomicBool::new(false)))
+    }
+
+    /// Like `run` but on a thread of its own, the handle shuts it down
+    pub fn spawn(self, listener: NetworkListener) -> Result<ProxyHandle> {
+        let mut addr = try!(listener.local_addr());
+        // Unspecified can't be connected to on every platform
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr.ip() {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1))
+            });
+        }
+        let stop = Arc::new(AtomicBool::new(false));
+        let running = stop.clone();
+        let thread = try!(thread::Builder::new().name("mqttc-proxy".to_string()).spawn(move || {
+            self._run(listener, running)
+        }));
+        Ok(ProxyHandle {
+            stop: stop,
+            addr: addr,
+            thread: thread
+        })
+    }
+
+    fn _run(self, mut listener: NetworkListener, stop: Arc<AtomicBool>) -> Result<()> {
+        let proxy = Arc::new(self);
+        let forwarder = {
+            let proxy = proxy.clone();
+            let stop = stop.clone();
+            try!(thread::Builder::new().name("mqttc-proxy-forward".to_string()).spawn(move || {
+                while !stop.load(Ordering::SeqCst) {
+                    if let Err(err) = proxy.forward(Duration::from_secs(FORWARD_TIMEOUT_SECS)) {
+                        error!("{:?}", err);
+                    }
+                    proxy.violations.lock().unwrap().tick();
+                    thread::sleep(Duration::from_millis(FORWARD_INTERVAL_MS));
+                }
+            }))
+        };
+        // Serving threads with a stream to stop their reads
+        let mut clients: Vec<(NetworkStream, JoinHandle<()>)> = Vec::new();
+        let result = loop {
+            let accepted = listener.accept();
+            if stop.load(Ordering::SeqCst) {
+                break Ok(());
+            }
+            let (stream, addr) = match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => break Err(Error::from(err))
+            };
+            clients.retain(|&(_, ref thread)| !thread.is_finished());
+            let control = match stream.try_clone() {
+                Ok(control) => control,
+                Err(err) => {
+                    error!("{}: {:?}", addr, err);
+                    continue;
+                }
+            };
+            match Proxy::_spawn_serving(&proxy, stream, addr) {
+                Ok(thread) => clients.push((control, thread)),
+                Err(err) => break Err(err)
+            }
+        };
+        stop.store(true, Ordering::SeqCst);
+        // The packet at hand is still queued and acknowledged
+        for &(ref stream, _) in &clients {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+        for (_, thread) in clients {
+            let _ = thread.join();
+        }
+        let _ = forwarder.join();
+        result
+    }
+
+    fn _spawn_serving(proxy: &Arc<Proxy>, stream: NetworkStream, addr: SocketAddr) -> Result<JoinHandle<()>> {
+        let proxy = proxy.clone();
+        let thread = try!(thread::Builder::new().name(format!("mqttc-proxy-{}", addr)).spawn(move || {
+            let result = match panic::catch_unwind(AssertUnwindSafe(|| proxy.serve(stream))) {
+                Ok(result) => result,
+                Err(payload) => {
+                    error!("{}: serving thread panicked: {}", addr, handle::panic_message(&*payload));
+                    return;
+                }
+            };
+            if let Err(err) = result {
+                match err {
+                    Error::Mqtt(mqtt3::Error::UnexpectedEof) => (),
+                    Error::Mqtt(_) | Error::ProtocolViolation | Error::HandshakeFailed => {
+                        // by address, a device reconnects from another port
+                        proxy.violations.lock().unwrap().report(&addr.ip().to_string(), &err);
+                    }
+                    err => error!("{}: {:?}", addr, err)
+                }
+            }
+        }));
+        Ok(thread)
+    }
+}
+
+/// Proxy started by `Proxy::spawn`
+pub struct ProxyHandle {
+    stop: Arc<AtomicBool>,
+    addr: SocketAddr,
+    thread: JoinHandle<Result<()>>
+}
+
+impl ProxyHandle {
+    /// Stops accepting clients and ends their connections once the packet
+    /// at hand is handled. Returns when the serving threads and the
+    /// forwarder are done, with the error accepting failed with if any.
+    pub fn shutdown(self) -> Result<()> {
+        self.stop.store(true, Ordering::SeqCst);
+        // Wakes the accepting thread
+        let _ = TcpStream::connect(self.addr);
+        match self.thread.join() {
+            Ok(result) => result,
+            Err(_) => Err(Error::Disconnected)
+        }
+    }
+}
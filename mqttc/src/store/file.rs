@@ -0,0 +1,432 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write, Seek, SeekFrom, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use mqtt3::{Message, PacketIdentifier};
+use super::{MessageStore, Error, Result, encode, decode};
+
+const LOG_MAGIC: &'static [u8] = b"MQL1";
+const INDEX_MAGIC: &'static [u8] = b"MQI1";
+// magic + epoch
+const HEADER_LEN: u64 = 12;
+// op + pid + data length
+const RECORD_HEADER_LEN: u64 = 7;
+// pid + offset + record length
+const INDEX_ENTRY_LEN: usize = 18;
+const OP_PUT: u8 = 1;
+const OP_DELETE: u8 = 2;
+const DEFAULT_COMPACT_THRESHOLD: u64 = 64 * 1024;
+
+/// When the log file is flushed to the disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Sync after every write
+    Always,
+    /// Sync after every N writes
+    Every(usize),
+    /// Leave it to the operating system
+    Never
+}
+
+/// Append-only log of messages with an index file.
+///
+/// Every `put` and `delete` appends a record to the log. The in-memory index
+/// maps a packet identifier to its live record and is saved next to the log
+/// (`<path>.idx`) on `checkpoint` and after compaction, so reopening only
+/// replays the records written since. A torn record at the end of the log,
+/// left by a crash in the middle of a write, is truncated on open.
+///
+/// Once the dead records take more space than the live ones (and more than the
+/// compaction threshold) the log is rewritten with the live records only.
+pub struct FileStore {
+    path: PathBuf,
+    log: File,
+    epoch: u64,
+    // pid -> (offset, record length)
+    index: BTreeMap<PacketIdentifier, (u64, u64)>,
+    len: u64,
+    live: u64,
+    sync: SyncPolicy,
+    unsynced: usize,
+    compact_threshold: u64
+}
+
+impl FileStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<FileStore> {
+        let path = path.as_ref().to_path_buf();
+        let mut log = try!(OpenOptions::new().read(true).append(true).create(true).open(&path));
+        let len = try!(log.metadata()).len();
+        let epoch = if len < HEADER_LEN {
+            // new log or crashed before the header was written
+            try!(log.set_len(0));
+            try!(log.write_all(&header(LOG_MAGIC, 0)));
+            0
+        } else {
+            let mut buf = [0; 12];
+            try!(log.seek(SeekFrom::Start(0)));
+            try!(log.read_exact(&mut buf));
+            if &buf[..4] != LOG_MAGIC {
+                return Err(Error::Corrupted(0));
+            }
+            be_u64(&buf[4..])
+        };
+
+        let mut store = FileStore {
+            path: path,
+            log: log,
+            epoch: epoch,
+            index: BTreeMap::new(),
+            len: if len < HEADER_LEN { HEADER_LEN } else { len },
+            live: 0,
+            sync: SyncPolicy::Always,
+            unsynced: 0,
+            compact_threshold: DEFAULT_COMPACT_THRESHOLD
+        };
+        let offset = store.load_index();
+        try!(store.replay(offset));
+        Ok(store)
+    }
+
+    pub fn set_sync_policy(&mut self, sync: SyncPolicy) -> &mut FileStore {
+        self.sync = sync;
+        self
+    }
+
+    /// Dead bytes in the log required before the compaction is considered
+    pub fn set_compact_threshold(&mut self, bytes: u64) -> &mut FileStore {
+        self.compact_threshold = bytes;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    pub fn pids(&self) -> Vec<PacketIdentifier> {
+        self.index.keys().cloned().collect()
+    }
+
+    /// Syncs the log and saves the index file
+    pub fn checkpoint(&mut self) -> Result<()> {
+        try!(self.log.sync_data());
+        self.unsynced = 0;
+
+        let mut buf = header(INDEX_MAGIC, self.epoch);
+        let mut entry = [0; INDEX_ENTRY_LEN];
+        entry[..8].copy_from_slice(&self.len.to_be_bytes());
+        buf.extend_from_slice(&entry[..8]);
+        for (pid, &(offset, record_len)) in self.index.iter() {
+            entry[..2].copy_from_slice(&pid.0.to_be_bytes());
+            entry[2..10].copy_from_slice(&offset.to_be_bytes());
+            entry[10..].copy_from_slice(&record_len.to_be_bytes());
+            buf.extend_from_slice(&entry);
+        }
+
+        let tmp_path = with_suffix(&self.path, "idx.tmp");
+        {
+            let mut tmp = try!(File::create(&tmp_path));
+            try!(tmp.write_all(&buf));
+            try!(tmp.sync_data());
+        }
+        try!(fs::rename(&tmp_path, with_suffix(&self.path, "idx")));
+        Ok(())
+    }
+
+    /// Rewrites the log keeping the live records only
+    pub fn compact(&mut self) -> Result<()> {
+        let tmp_path = with_suffix(&self.path, "compact");
+        let epoch = self.epoch + 1;
+        let mut index = BTreeMap::new();
+        let mut offset = HEADER_LEN;
+        {
+            let tmp = try!(File::create(&tmp_path));
+            let mut writer = BufWriter::new(&tmp);
+            try!(writer.write_all(&header(LOG_MAGIC, epoch)));
+            let mut record = Vec::new();
+            for (&pid, &(old_offset, record_len)) in self.index.iter() {
+                record.resize(record_len as usize, 0);
+                try!(self.log.seek(SeekFrom::Start(old_offset)));
+                try!(self.log.read_exact(&mut record));
+                try!(writer.write_all(&record));
+                index.insert(pid, (offset, record_len));
+                offset += record_len;
+            }
+            try!(writer.flush());
+            try!(tmp.sync_all());
+        }
+        try!(fs::rename(&tmp_path, &self.path));
+
+        self.log = try!(OpenOptions::new().read(true).append(true).open(&self.path));
+        self.epoch = epoch;
+        self.index = index;
+        self.len = offset;
+        self.checkpoint()
+    }
+
+    // Returns the offset covered by the index file, the rest of the log
+    // should be replayed.
+    fn load_index(&mut self) -> u64 {
+        let mut buf = Vec::new();
+        let loaded = File::open(with_suffix(&self.path, "idx")).and_then(|mut f| f.read_to_end(&mut buf));
+        if loaded.is_err() || buf.len() < HEADER_LEN as usize + 8 || &buf[..4] != INDEX_MAGIC {
+            return HEADER_LEN;
+        }
+        let covered = be_u64(&buf[12..20]);
+        // index of an older log generation or the log was truncated under it
+        if be_u64(&buf[4..12]) != self.epoch || covered > self.len {
+            return HEADER_LEN;
+        }
+        for entry in buf[20..].chunks(INDEX_ENTRY_LEN) {
+            if entry.len() != INDEX_ENTRY_LEN {
+                self.index.clear();
+                self.live = 0;
+                return HEADER_LEN;
+            }
+            let record_len = be_u64(&entry[10..]);
+            self.index.insert(PacketIdentifier(be_u16(&entry[..2])),
+                              (be_u64(&entry[2..10]), record_len));
+            self.live += record_len;
+        }
+        covered
+    }
+
+    fn replay(&mut self, mut offset: u64) -> Result<()> {
+        try!(self.log.seek(SeekFrom::Start(offset)));
+        {
+            let mut reader = BufReader::new(&self.log);
+            let mut head = [0; 7];
+            while offset + RECORD_HEADER_LEN <= self.len {
+                if reader.read_exact(&mut head).is_err() {
+                    break;
+                }
+                let pid = PacketIdentifier(be_u16(&head[1..3]));
+                let size = be_u32(&head[3..]) as u64;
+                let record_len = RECORD_HEADER_LEN + size;
+                if offset + record_len > self.len {
+                    break;
+                }
+                match head[0] {
+                    OP_PUT => {
+                        try!(io::copy(&mut (&mut reader).take(size), &mut io::sink()));
+                        if let Some((_, old)) = self.index.insert(pid, (offset, record_len)) {
+                            self.live -= old;
+                        }
+                        self.live += record_len;
+                    }
+                    OP_DELETE if size == 0 => {
+                        if let Some((_, old)) = self.index.remove(&pid) {
+                            self.live -= old;
+                        }
+                    }
+                    _ => break
+                }
+                offset += record_len;
+            }
+        }
+        if offset < self.len {
+            warn!("Truncate the message log {:?} at {} of {} bytes", self.path, offset, self.len);
+            try!(self.log.set_len(offset));
+            self.len = offset;
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, op: u8, pid: PacketIdentifier, data: &[u8]) -> Result<u64> {
+        let mut record = vec![op, 0, 0, 0, 0, 0, 0];
+        record[1..3].copy_from_slice(&pid.0.to_be_bytes());
+        record[3..].copy_from_slice(&(data.len() as u32).to_be_bytes());
+        record.extend_from_slice(data);
+        try!(self.log.write_all(&record));
+        self.len += record.len() as u64;
+
+        self.unsynced += 1;
+        let sync = match self.sync {
+            SyncPolicy::Always => true,
+            SyncPolicy::Every(n) => self.unsynced >= n,
+            SyncPolicy::Never => false
+        };
+        if sync {
+            try!(self.log.sync_data());
+            self.unsynced = 0;
+        }
+        Ok(record.len() as u64)
+    }
+
+    fn dead(&self) -> u64 {
+        self.len - HEADER_LEN - self.live
+    }
+}
+
+impl MessageStore for FileStore {
+    fn put(&mut self, message: Box<Message>) -> Result<()> {
+        let (pid, data) = try!(encode(&message));
+        let offset = self.len;
+        let record_len = try!(self.append(OP_PUT, pid, &data));
+        if let Some((_, old)) = self.index.insert(pid, (offset, record_len)) {
+            self.live -= old;
+        }
+        self.live += record_len;
+        Ok(())
+    }
+
+    fn get(&mut self, pid: PacketIdentifier) -> Result<Box<Message>> {
+        let (offset, record_len) = match self.index.get(&pid) {
+            Some(&entry) => entry,
+            None => return Err(Error::NotFound(pid))
+        };
+        let mut data = vec![0; (record_len - RECORD_HEADER_LEN) as usize];
+        try!(self.log.seek(SeekFrom::Start(offset + RECORD_HEADER_LEN)));
+        try!(self.log.read_exact(&mut data));
+
+        decode(pid, data).ok_or(Error::Corrupted(offset))
+    }
+
+    fn delete(&mut self, pid: PacketIdentifier) -> Result<()> {
+        if let Some((_, record_len)) = self.index.remove(&pid) {
+            self.live -= record_len;
+            try!(self.append(OP_DELETE, pid, &[]));
+            let dead = self.dead();
+            if dead >= self.compact_threshold && dead > self.live {
+                try!(self.compact());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn header(magic: &[u8], epoch: u64) -> Vec<u8> {
+    let mut buf = vec![0; HEADER_LEN as usize];
+    buf[..4].copy_from_slice(magic);
+    buf[4..].copy_from_slice(&epoch.to_be_bytes());
+    buf
+}
+
+pub fn be_u16(buf: &[u8]) -> u16 {
+    (buf[0] as u16) << 8 | buf[1] as u16
+}
+
+pub fn be_u32(buf: &[u8]) -> u32 {
+    (be_u16(&buf[..2]) as u32) << 16 | be_u16(&buf[2..]) as u32
+}
+
+pub fn be_u64(buf: &[u8]) -> u64 {
+    (be_u32(&buf[..4]) as u64) << 32 | be_u32(&buf[4..]) as u64
+}
+
+pub fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_os_string();
+    path.push(".");
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use mqtt3::{Message, PacketIdentifier, QoS, ToTopicPath};
+    use super::{FileStore, with_suffix};
+    use store::{MessageStore, Error};
+
+    fn temp_log(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("mqttc_{}_{}.log", name, ::std::process::id()));
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(with_suffix(&path, "idx"));
+        path
+    }
+
+    fn message(pid: u16, payload: Vec<u8>) -> Box<Message> {
+        let mut message = Message::new("a/b".to_topic_path().unwrap(), Arc::new(payload), QoS::ExactlyOnce);
+        message.pid = Some(PacketIdentifier(pid));
+        Box::new(message)
+    }
+
+    #[test]
+    fn file_store_put_get_delete_test() {
+        let path = temp_log("put_get_delete");
+        let mut store = FileStore::open(&path).unwrap();
+        store.put(message(1, vec![1, 2, 3])).unwrap();
+        store.put(message(2, vec![4, 5])).unwrap();
+
+        let msg = store.get(PacketIdentifier(1)).unwrap();
+        assert_eq!(msg.topic.path(), "a/b");
+        assert_eq!(msg.payload, Arc::new(vec![1, 2, 3]));
+        assert_eq!(msg.pid, Some(PacketIdentifier(1)));
+
+        store.delete(PacketIdentifier(1)).unwrap();
+        match store.get(PacketIdentifier(1)) {
+            Err(Error::NotFound(PacketIdentifier(1))) => (),
+            _ => panic!("message should be deleted")
+        }
+        assert_eq!(store.pids(), vec![PacketIdentifier(2)]);
+
+        let mut no_pid = message(3, vec![6]);
+        no_pid.pid = None;
+        match store.put(no_pid) {
+            Err(Error::MissingPid) => (),
+            _ => panic!("message without pid should be refused")
+        }
+    }
+
+    #[test]
+    fn file_store_reopen_test() {
+        let path = temp_log("reopen");
+        {
+            let mut store = FileStore::open(&path).unwrap();
+            store.put(message(1, vec![1])).unwrap();
+            store.put(message(2, vec![2])).unwrap();
+            store.checkpoint().unwrap();
+            store.put(message(3, vec![3])).unwrap();
+            store.delete(PacketIdentifier(1)).unwrap();
+        }
+        let mut store = FileStore::open(&path).unwrap();
+        assert_eq!(store.pids(), vec![PacketIdentifier(2), PacketIdentifier(3)]);
+        assert_eq!(store.get(PacketIdentifier(3)).unwrap().payload, Arc::new(vec![3]));
+    }
+
+    #[test]
+    fn file_store_torn_record_test() {
+        let path = temp_log("torn");
+        {
+            let mut store = FileStore::open(&path).unwrap();
+            store.put(message(1, vec![1, 2])).unwrap();
+        }
+        let len = fs::metadata(&path).unwrap().len();
+        {
+            // crash in the middle of the next record
+            let mut log = OpenOptions::new().append(true).open(&path).unwrap();
+            log.write_all(&[1, 0, 2, 0, 0, 0, 20, 0x32]).unwrap();
+        }
+        let mut store = FileStore::open(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+        assert_eq!(store.pids(), vec![PacketIdentifier(1)]);
+        store.put(message(2, vec![3])).unwrap();
+        assert_eq!(store.get(PacketIdentifier(2)).unwrap().payload, Arc::new(vec![3]));
+    }
+
+    #[test]
+    fn file_store_compact_test() {
+        let path = temp_log("compact");
+        {
+            let mut store = FileStore::open(&path).unwrap();
+            store.set_compact_threshold(256);
+            for pid in 1..100 {
+                store.put(message(pid, vec![0; 16])).unwrap();
+                if pid != 50 {
+                    store.delete(PacketIdentifier(pid)).unwrap();
+                }
+            }
+            assert!(fs::metadata(&path).unwrap().len() < 512);
+        }
+        let mut store = FileStore::open(&path).unwrap();
+        assert_eq!(store.pids(), vec![PacketIdentifier(50)]);
+        assert_eq!(store.get(PacketIdentifier(50)).unwrap().payload, Arc::new(vec![0; 16]));
+    }
+}
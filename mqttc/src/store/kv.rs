@@ -0,0 +1,109 @@
+use std::path::Path;
+use sled;
+use mqtt3::{Message, PacketIdentifier};
+use super::{MessageStore, Error, Result, encode, decode};
+
+/// Message store backed by a sled tree.
+///
+/// A single database can hold several stores (e.g. incomming and outgoing
+/// messages) by opening a tree per store and passing it to `SledStore::new`.
+pub struct SledStore {
+    tree: sled::Tree
+}
+
+impl SledStore {
+    pub fn new(tree: sled::Tree) -> SledStore {
+        SledStore {
+            tree: tree
+        }
+    }
+
+    /// Opens a database at `path` and uses its default tree
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SledStore> {
+        let db = try!(sled::open(path));
+        let tree = try!(db.open_tree("messages"));
+        Ok(SledStore::new(tree))
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    pub fn pids(&self) -> Result<Vec<PacketIdentifier>> {
+        let mut pids = Vec::new();
+        for key in self.tree.iter().keys() {
+            let key = try!(key);
+            pids.push(PacketIdentifier((key[0] as u16) << 8 | key[1] as u16));
+        }
+        Ok(pids)
+    }
+
+    /// Waits until all the changes are written to the disk
+    pub fn flush(&self) -> Result<()> {
+        try!(self.tree.flush());
+        Ok(())
+    }
+}
+
+impl MessageStore for SledStore {
+    fn put(&mut self, message: Box<Message>) -> Result<()> {
+        let (pid, data) = try!(encode(&message));
+        try!(self.tree.insert(key(pid), data));
+        Ok(())
+    }
+
+    fn get(&mut self, pid: PacketIdentifier) -> Result<Box<Message>> {
+        match try!(self.tree.get(key(pid))) {
+            Some(data) => decode(pid, data.to_vec()).ok_or(Error::Unavailable(pid)),
+            None => Err(Error::NotFound(pid))
+        }
+    }
+
+    fn delete(&mut self, pid: PacketIdentifier) -> Result<()> {
+        try!(self.tree.remove(key(pid)));
+        Ok(())
+    }
+}
+
+impl From<sled::Error> for Error {
+    fn from(err: sled::Error) -> Error {
+        Error::Io(err.into())
+    }
+}
+
+// Big endian keys keep the tree ordered by packet identifier
+fn key(pid: PacketIdentifier) -> [u8; 2] {
+    [(pid.0 >> 8) as u8, pid.0 as u8]
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use sled;
+    use mqtt3::{Message, PacketIdentifier, QoS, ToTopicPath};
+    use super::SledStore;
+    use store::{MessageStore, Error};
+
+    #[test]
+    fn sled_store_test() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut store = SledStore::new(db.open_tree("outgoing").unwrap());
+        for pid in vec![300, 2] {
+            let mut message = Message::new("a/b".to_topic_path().unwrap(), Arc::new(vec![pid as u8]), QoS::AtLeastOnce);
+            message.pid = Some(PacketIdentifier(pid));
+            store.put(Box::new(message)).unwrap();
+        }
+        assert_eq!(store.pids().unwrap(), vec![PacketIdentifier(2), PacketIdentifier(300)]);
+        assert_eq!(store.get(PacketIdentifier(300)).unwrap().payload, Arc::new(vec![44]));
+
+        store.delete(PacketIdentifier(300)).unwrap();
+        match store.get(PacketIdentifier(300)) {
+            Err(Error::NotFound(PacketIdentifier(300))) => (),
+            _ => panic!("message should be deleted")
+        }
+    }
+}
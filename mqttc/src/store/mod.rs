@@ -0,0 +1,130 @@
+use std::result;
+use std::error;
+use std::fmt;
+use std::io::{self, Cursor};
+use mqtt3::{Message, Packet, PacketIdentifier, MqttRead, MqttWrite};
+
+mod file;
+mod queue;
+#[cfg(feature = "sled")]
+mod kv;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+pub use self::file::{
+    FileStore,
+    SyncPolicy
+};
+
+pub use self::queue::FileQueue;
+
+#[cfg(feature = "sled")]
+pub use self::kv::SledStore;
+
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::{SqliteStore, SqliteQueue};
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// Storage for messages which are still part of a QoS 1/2 flow.
+///
+/// Messages are keyed by their packet identifier, so every message passed to
+/// `put` must carry one.
+pub trait MessageStore {
+    fn put(&mut self, message: Box<Message>) -> Result<()>;
+    fn get(&mut self, pid: PacketIdentifier) -> Result<Box<Message>>;
+    fn delete(&mut self, pid: PacketIdentifier) -> Result<()>;
+//    fn iter() -> Iterator<Message>;
+}
+
+/// Former name of `MessageStore`
+pub use self::MessageStore as Store;
+
+/// Durable queue of messages waiting to be published, see
+/// `ClientOptions::set_outbox`.
+///
+/// Every message gets a sequence number, which grows with every push.
+pub trait MessageQueue {
+    fn push(&mut self, message: &Message) -> Result<u64>;
+    fn get(&mut self, seq: u64) -> Result<Box<Message>>;
+    fn remove(&mut self, seq: u64) -> Result<()>;
+    /// Up to `max` sequence numbers of queued messages after `after`,
+    /// oldest first
+    fn seqs(&self, after: Option<u64>, max: usize) -> Result<Vec<u64>>;
+}
+
+#[derive(Debug)]
+pub enum Error {
+    NotFound(PacketIdentifier),
+    Unavailable(PacketIdentifier),
+    NotQueued(u64),
+    Corrupted(u64),
+    MissingPid,
+    Io(io::Error)
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::NotFound(PacketIdentifier(packet_identifier)) =>
+                fmt::write(f, format_args!("Packet {} not found", packet_identifier)),
+            Error::Unavailable(PacketIdentifier(packet_identifier)) =>
+                fmt::write(f, format_args!("Packet {} unavailable", packet_identifier)),
+            Error::NotQueued(seq) =>
+                fmt::write(f, format_args!("Message {} not queued", seq)),
+            Error::Corrupted(offset) =>
+                fmt::write(f, format_args!("Store corrupted at offset {}", offset)),
+            Error::MissingPid => write!(f, "Message has no packet identifier"),
+            Error::Io(ref err) => write!(f, "IO error: {}", err),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::NotFound(PacketIdentifier(_)) =>  "Packet not found",
+            Error::Unavailable(PacketIdentifier(_)) => "Packet unavailable",
+            Error::NotQueued(_) => "Message not queued",
+            Error::Corrupted(_) => "Store corrupted",
+            Error::MissingPid => "Message has no packet identifier",
+            Error::Io(ref err) => err.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+// Messages are persisted as encoded PUBLISH packets
+fn encode(message: &Message) -> Result<(PacketIdentifier, Vec<u8>)> {
+    let pid = try!(message.pid.ok_or(Error::MissingPid));
+    let mut packet = Cursor::new(Vec::new());
+    if packet.write_packet(&Packet::Publish(message.to_pub(None, message.dup()))).is_err() {
+        return Err(Error::Unavailable(pid));
+    }
+    Ok((pid, packet.into_inner()))
+}
+
+fn decode(pid: PacketIdentifier, data: Vec<u8>) -> Option<Box<Message>> {
+    match Cursor::new(data).read_packet() {
+        Ok(Packet::Publish(publish)) => {
+            Message::from_pub(publish).ok().map(|mut message| {
+                // QoS 0 messages doesn't carry pid in the packet
+                message.pid = Some(pid);
+                message
+            })
+        }
+        _ => None
+    }
+}
@@ -0,0 +1,286 @@
+use std::collections::{BTreeMap, Bound};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write, Seek, SeekFrom, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use mqtt3::{Message, PacketIdentifier};
+use super::{MessageQueue, Error, Result, encode, decode};
+use super::file::{SyncPolicy, be_u32, be_u64, with_suffix};
+
+const QUEUE_MAGIC: &'static [u8] = b"MQQ1";
+const HEADER_LEN: u64 = 4;
+// op + seq + data length
+const RECORD_HEADER_LEN: u64 = 13;
+const OP_PUSH: u8 = 1;
+const OP_REMOVE: u8 = 2;
+const DEFAULT_COMPACT_THRESHOLD: u64 = 64 * 1024;
+// Messages are encoded with a placeholder, the pid is assigned when sent
+const PLACEHOLDER_PID: PacketIdentifier = PacketIdentifier(0);
+
+/// Append-only log of queued messages.
+///
+/// Pushing appends a record, removing appends a tombstone. The log is read
+/// once on open, a torn record at its end is truncated. Once the removed
+/// records take more space than the queued ones (and more than the
+/// compaction threshold) the log is rewritten.
+pub struct FileQueue {
+    path: PathBuf,
+    log: File,
+    // seq -> (offset, record length)
+    index: BTreeMap<u64, (u64, u64)>,
+    next_seq: u64,
+    len: u64,
+    live: u64,
+    sync: SyncPolicy,
+    unsynced: usize,
+    compact_threshold: u64
+}
+
+impl FileQueue {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<FileQueue> {
+        let path = path.as_ref().to_path_buf();
+        let mut log = try!(OpenOptions::new().read(true).append(true).create(true).open(&path));
+        let len = try!(log.metadata()).len();
+        if len < HEADER_LEN {
+            try!(log.set_len(0));
+            try!(log.write_all(QUEUE_MAGIC));
+        } else {
+            let mut magic = [0; 4];
+            try!(log.seek(SeekFrom::Start(0)));
+            try!(log.read_exact(&mut magic));
+            if &magic[..] != QUEUE_MAGIC {
+                return Err(Error::Corrupted(0));
+            }
+        }
+
+        let mut queue = FileQueue {
+            path: path,
+            log: log,
+            index: BTreeMap::new(),
+            next_seq: 0,
+            len: if len < HEADER_LEN { HEADER_LEN } else { len },
+            live: 0,
+            sync: SyncPolicy::Always,
+            unsynced: 0,
+            compact_threshold: DEFAULT_COMPACT_THRESHOLD
+        };
+        try!(queue.replay());
+        Ok(queue)
+    }
+
+    pub fn set_sync_policy(&mut self, sync: SyncPolicy) -> &mut FileQueue {
+        self.sync = sync;
+        self
+    }
+
+    /// Removed bytes in the log required before the compaction is considered
+    pub fn set_compact_threshold(&mut self, bytes: u64) -> &mut FileQueue {
+        self.compact_threshold = bytes;
+        self
+    }
+
+    /// Rewrites the log keeping the queued records only
+    pub fn compact(&mut self) -> Result<()> {
+        let tmp_path = with_suffix(&self.path, "compact");
+        let mut index = BTreeMap::new();
+        let mut offset = HEADER_LEN;
+        {
+            let tmp = try!(File::create(&tmp_path));
+            let mut writer = BufWriter::new(&tmp);
+            try!(writer.write_all(QUEUE_MAGIC));
+            let mut record = Vec::new();
+            for (&seq, &(old_offset, record_len)) in self.index.iter() {
+                record.resize(record_len as usize, 0);
+                try!(self.log.seek(SeekFrom::Start(old_offset)));
+                try!(self.log.read_exact(&mut record));
+                try!(writer.write_all(&record));
+                index.insert(seq, (offset, record_len));
+                offset += record_len;
+            }
+            try!(writer.flush());
+            try!(tmp.sync_all());
+        }
+        try!(fs::rename(&tmp_path, &self.path));
+
+        self.log = try!(OpenOptions::new().read(true).append(true).open(&self.path));
+        self.index = index;
+        self.len = offset;
+        Ok(())
+    }
+
+    fn replay(&mut self) -> Result<()> {
+        let mut offset = HEADER_LEN;
+        try!(self.log.seek(SeekFrom::Start(offset)));
+        {
+            let mut reader = BufReader::new(&self.log);
+            let mut head = [0; 13];
+            while offset + RECORD_HEADER_LEN <= self.len {
+                if reader.read_exact(&mut head).is_err() {
+                    break;
+                }
+                let seq = be_u64(&head[1..9]);
+                let size = be_u32(&head[9..]) as u64;
+                let record_len = RECORD_HEADER_LEN + size;
+                if offset + record_len > self.len {
+                    break;
+                }
+                match head[0] {
+                    OP_PUSH => {
+                        try!(io::copy(&mut (&mut reader).take(size), &mut io::sink()));
+                        self.index.insert(seq, (offset, record_len));
+                        self.live += record_len;
+                    }
+                    OP_REMOVE if size == 0 => {
+                        if let Some((_, old)) = self.index.remove(&seq) {
+                            self.live -= old;
+                        }
+                    }
+                    _ => break
+                }
+                if seq >= self.next_seq {
+                    self.next_seq = seq + 1;
+                }
+                offset += record_len;
+            }
+        }
+        if offset < self.len {
+            warn!("Truncate the message queue {:?} at {} of {} bytes", self.path, offset, self.len);
+            try!(self.log.set_len(offset));
+            self.len = offset;
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, op: u8, seq: u64, data: &[u8]) -> Result<u64> {
+        let mut record = vec![op];
+        record.extend_from_slice(&seq.to_be_bytes());
+        record.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        record.extend_from_slice(data);
+        try!(self.log.write_all(&record));
+        self.len += record.len() as u64;
+
+        self.unsynced += 1;
+        let sync = match self.sync {
+            SyncPolicy::Always => true,
+            SyncPolicy::Every(n) => self.unsynced >= n,
+            SyncPolicy::Never => false
+        };
+        if sync {
+            try!(self.log.sync_data());
+            self.unsynced = 0;
+        }
+        Ok(record.len() as u64)
+    }
+}
+
+impl MessageQueue for FileQueue {
+    fn push(&mut self, message: &Message) -> Result<u64> {
+        let (_, data) = try!(encode(&message.transform(Some(PLACEHOLDER_PID), None)));
+        let seq = self.next_seq;
+        let offset = self.len;
+        let record_len = try!(self.append(OP_PUSH, seq, &data));
+        self.index.insert(seq, (offset, record_len));
+        self.live += record_len;
+        self.next_seq += 1;
+        Ok(seq)
+    }
+
+    fn get(&mut self, seq: u64) -> Result<Box<Message>> {
+        let (offset, record_len) = match self.index.get(&seq) {
+            Some(&entry) => entry,
+            None => return Err(Error::NotQueued(seq))
+        };
+        let mut data = vec![0; (record_len - RECORD_HEADER_LEN) as usize];
+        try!(self.log.seek(SeekFrom::Start(offset + RECORD_HEADER_LEN)));
+        try!(self.log.read_exact(&mut data));
+
+        let mut message = try!(decode(PLACEHOLDER_PID, data).ok_or(Error::Corrupted(offset)));
+        message.pid = None;
+        Ok(message)
+    }
+
+    fn remove(&mut self, seq: u64) -> Result<()> {
+        if let Some((_, record_len)) = self.index.remove(&seq) {
+            self.live -= record_len;
+            try!(self.append(OP_REMOVE, seq, &[]));
+            let dead = self.len - HEADER_LEN - self.live;
+            if dead >= self.compact_threshold && dead > self.live {
+                try!(self.compact());
+            }
+        }
+        Ok(())
+    }
+
+    fn seqs(&self, after: Option<u64>, max: usize) -> Result<Vec<u64>> {
+        let from = match after {
+            Some(seq) => Bound::Excluded(seq),
+            None => Bound::Unbounded
+        };
+        Ok(self.index.range((from, Bound::Unbounded)).map(|(&seq, _)| seq).take(max).collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use mqtt3::{Message, QoS, ToTopicPath};
+    use super::FileQueue;
+    use store::MessageQueue;
+
+    fn temp_queue(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("mqttc_queue_{}_{}.log", name, ::std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn message(payload: Vec<u8>) -> Message {
+        Message::new("a/b".to_topic_path().unwrap(), Arc::new(payload), QoS::AtLeastOnce)
+    }
+
+    #[test]
+    fn file_queue_test() {
+        let path = temp_queue("reopen");
+        {
+            let mut queue = FileQueue::open(&path).unwrap();
+            assert_eq!(queue.push(&message(vec![1])).unwrap(), 0);
+            assert_eq!(queue.push(&message(vec![2])).unwrap(), 1);
+            assert_eq!(queue.push(&message(vec![3])).unwrap(), 2);
+            queue.remove(1).unwrap();
+        }
+        {
+            // crash in the middle of the next record
+            let mut log = OpenOptions::new().append(true).open(&path).unwrap();
+            log.write_all(&[1, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 20, 0x32]).unwrap();
+        }
+        let mut queue = FileQueue::open(&path).unwrap();
+        assert_eq!(queue.seqs(None, 10).unwrap(), vec![0, 2]);
+        assert_eq!(queue.seqs(Some(0), 10).unwrap(), vec![2]);
+        assert_eq!(queue.seqs(None, 1).unwrap(), vec![0]);
+        let message = queue.get(2).unwrap();
+        assert_eq!(message.topic.path(), "a/b");
+        assert_eq!(message.qos, QoS::AtLeastOnce);
+        assert_eq!(message.pid, None);
+        assert_eq!(message.payload, Arc::new(vec![3]));
+        assert_eq!(queue.push(&self::message(vec![4])).unwrap(), 3);
+    }
+
+    #[test]
+    fn file_queue_compact_test() {
+        let path = temp_queue("compact");
+        let mut queue = FileQueue::open(&path).unwrap();
+        queue.set_compact_threshold(256);
+        for i in 0..100 {
+            let seq = queue.push(&message(vec![0; 16])).unwrap();
+            if i != 50 {
+                queue.remove(seq).unwrap();
+            }
+        }
+        assert!(fs::metadata(&path).unwrap().len() < 512);
+        let mut queue = FileQueue::open(&path).unwrap();
+        assert_eq!(queue.seqs(None, 10).unwrap(), vec![50]);
+        assert_eq!(queue.get(50).unwrap().payload, Arc::new(vec![0; 16]));
+    }
+}
@@ -0,0 +1,228 @@
+use std::io;
+use std::path::Path;
+use rusqlite::{self, Connection, OptionalExtension, ToSql};
+use mqtt3::{Message, PacketIdentifier};
+use super::{MessageStore, MessageQueue, Error, Result, encode, decode};
+
+// Queued messages are encoded with a placeholder, the pid is assigned when sent
+const PLACEHOLDER_PID: PacketIdentifier = PacketIdentifier(0);
+
+// Table name as a quoted identifier, quotes in it doubled
+fn quote(table: &str) -> String {
+    format!("\"{}\"", table.replace('"', "\"\""))
+}
+
+/// Message store backed by a table of a SQLite database.
+///
+/// Each message is a row of the encoded PUBLISH packet keyed by its packet
+/// identifier, so the contents can be looked at with the `sqlite3` shell.
+/// Several stores can share a database, each in a table of its own.
+pub struct SqliteStore {
+    conn: Connection,
+    // Quoted name of the table
+    table: String
+}
+
+impl SqliteStore {
+    /// Keeps the messages in `table`, created if missing. The name is
+    /// quoted in the statements, so any name works.
+    pub fn new(conn: Connection, table: &str) -> Result<SqliteStore> {
+        let table = quote(table);
+        try!(conn.execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS {} (pid INTEGER PRIMARY KEY, data BLOB NOT NULL)",
+            table)));
+        Ok(SqliteStore {
+            conn: conn,
+            table: table
+        })
+    }
+
+    /// Opens the database at `path` and uses its `messages` table
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SqliteStore> {
+        SqliteStore::new(try!(Connection::open(path)), "messages")
+    }
+
+    pub fn len(&self) -> Result<usize> {
+        let sql = format!("SELECT COUNT(*) FROM {}", self.table);
+        let len: i64 = try!(self.conn.query_row(&sql, &[] as &[&ToSql], |row| row.get(0)));
+        Ok(len as usize)
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(try!(self.len()) == 0)
+    }
+
+    pub fn pids(&self) -> Result<Vec<PacketIdentifier>> {
+        let sql = format!("SELECT pid FROM {} ORDER BY pid", self.table);
+        let mut stmt = try!(self.conn.prepare(&sql));
+        let rows = try!(stmt.query_map(&[] as &[&ToSql], |row| row.get::<_, i64>(0)));
+        let mut pids = Vec::new();
+        for pid in rows {
+            pids.push(PacketIdentifier(try!(pid) as u16));
+        }
+        Ok(pids)
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+}
+
+impl MessageStore for SqliteStore {
+    fn put(&mut self, message: Box<Message>) -> Result<()> {
+        let (pid, data) = try!(encode(&message));
+        let sql = format!("INSERT OR REPLACE INTO {} (pid, data) VALUES (?1, ?2)", self.table);
+        try!(self.conn.execute(&sql, &[&(pid.0 as i64) as &ToSql, &data]));
+        Ok(())
+    }
+
+    fn get(&mut self, pid: PacketIdentifier) -> Result<Box<Message>> {
+        let sql = format!("SELECT data FROM {} WHERE pid = ?1", self.table);
+        let data: Option<Vec<u8>> = try!(self.conn.query_row(&sql, &[&(pid.0 as i64)], |row| row.get(0))
+                                                  .optional());
+        match data {
+            Some(data) => decode(pid, data).ok_or(Error::Unavailable(pid)),
+            None => Err(Error::NotFound(pid))
+        }
+    }
+
+    fn delete(&mut self, pid: PacketIdentifier) -> Result<()> {
+        let sql = format!("DELETE FROM {} WHERE pid = ?1", self.table);
+        try!(self.conn.execute(&sql, &[&(pid.0 as i64)]));
+        Ok(())
+    }
+}
+
+/// Message queue backed by a table of a SQLite database, see `SqliteStore`.
+///
+/// The sequence number of a message is its row id, which keeps growing
+/// across restarts.
+pub struct SqliteQueue {
+    conn: Connection,
+    // Quoted name of the table
+    table: String
+}
+
+impl SqliteQueue {
+    /// Keeps the messages in `table`, created if missing. The name is
+    /// quoted in the statements, so any name works.
+    pub fn new(conn: Connection, table: &str) -> Result<SqliteQueue> {
+        let table = quote(table);
+        try!(conn.execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS {} (seq INTEGER PRIMARY KEY AUTOINCREMENT, data BLOB NOT NULL)",
+            table)));
+        Ok(SqliteQueue {
+            conn: conn,
+            table: table
+        })
+    }
+
+    /// Opens the database at `path` and uses its `queue` table
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SqliteQueue> {
+        SqliteQueue::new(try!(Connection::open(path)), "queue")
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+}
+
+impl MessageQueue for SqliteQueue {
+    fn push(&mut self, message: &Message) -> Result<u64> {
+        let (_, data) = try!(encode(&message.transform(Some(PLACEHOLDER_PID), None)));
+        let sql = format!("INSERT INTO {} (data) VALUES (?1)", self.table);
+        try!(self.conn.execute(&sql, &[&data]));
+        Ok(self.conn.last_insert_rowid() as u64)
+    }
+
+    fn get(&mut self, seq: u64) -> Result<Box<Message>> {
+        let sql = format!("SELECT data FROM {} WHERE seq = ?1", self.table);
+        let data: Option<Vec<u8>> = try!(self.conn.query_row(&sql, &[&(seq as i64)], |row| row.get(0))
+                                                  .optional());
+        let data = try!(data.ok_or(Error::NotQueued(seq)));
+        let mut message = try!(decode(PLACEHOLDER_PID, data).ok_or(Error::Corrupted(seq)));
+        message.pid = None;
+        Ok(message)
+    }
+
+    fn remove(&mut self, seq: u64) -> Result<()> {
+        let sql = format!("DELETE FROM {} WHERE seq = ?1", self.table);
+        try!(self.conn.execute(&sql, &[&(seq as i64)]));
+        Ok(())
+    }
+
+    fn seqs(&self, after: Option<u64>, max: usize) -> Result<Vec<u64>> {
+        let sql = format!("SELECT seq FROM {} WHERE seq > ?1 ORDER BY seq LIMIT ?2", self.table);
+        let after = after.map_or(-1, |seq| seq as i64);
+        let mut stmt = try!(self.conn.prepare(&sql));
+        let rows = try!(stmt.query_map(&[&after, &(max as i64)], |row| row.get::<_, i64>(0)));
+        let mut seqs = Vec::new();
+        for seq in rows {
+            seqs.push(try!(seq) as u64);
+        }
+        Ok(seqs)
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Error {
+        Error::Io(io::Error::new(io::ErrorKind::Other, err))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use rusqlite::Connection;
+    use mqtt3::{Message, PacketIdentifier, QoS, ToTopicPath};
+    use super::{SqliteStore, SqliteQueue};
+    use store::{MessageStore, MessageQueue, Error};
+
+    fn message(pid: Option<u16>, payload: u8) -> Message {
+        let mut message = Message::new("a/b".to_topic_path().unwrap(), Arc::new(vec![payload]), QoS::AtLeastOnce);
+        message.pid = pid.map(PacketIdentifier);
+        message
+    }
+
+    #[test]
+    fn sqlite_store_test() {
+        let mut store = SqliteStore::new(Connection::open_in_memory().unwrap(), "outgoing").unwrap();
+        for pid in vec![300, 2] {
+            store.put(Box::new(message(Some(pid), pid as u8))).unwrap();
+        }
+        assert_eq!(store.pids().unwrap(), vec![PacketIdentifier(2), PacketIdentifier(300)]);
+        assert_eq!(store.get(PacketIdentifier(300)).unwrap().payload, Arc::new(vec![44]));
+
+        store.delete(PacketIdentifier(300)).unwrap();
+        match store.get(PacketIdentifier(300)) {
+            Err(Error::NotFound(PacketIdentifier(300))) => (),
+            _ => panic!("message should be deleted")
+        }
+        assert_eq!(store.len().unwrap(), 1);
+
+        // quotes in the name don't end it
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE victim (id INTEGER)").unwrap();
+        let mut store = SqliteStore::new(conn, "x\" (a INTEGER); DROP TABLE victim; --").unwrap();
+        store.put(Box::new(message(Some(1), 1))).unwrap();
+        assert_eq!(store.pids().unwrap(), vec![PacketIdentifier(1)]);
+        store.connection().execute_batch("SELECT * FROM victim").unwrap();
+    }
+
+    #[test]
+    fn sqlite_queue_test() {
+        let mut queue = SqliteQueue::new(Connection::open_in_memory().unwrap(), "queue").unwrap();
+        let seqs: Vec<u64> = (0..3).map(|i| queue.push(&message(None, i)).unwrap()).collect();
+        assert_eq!(queue.seqs(None, 10).unwrap(), seqs);
+        assert_eq!(queue.seqs(Some(seqs[0]), 1).unwrap(), vec![seqs[1]]);
+
+        queue.remove(seqs[1]).unwrap();
+        assert_eq!(queue.seqs(None, 10).unwrap(), vec![seqs[0], seqs[2]]);
+        let message = queue.get(seqs[2]).unwrap();
+        assert_eq!((message.pid, message.payload.clone()), (None, Arc::new(vec![2])));
+        match queue.get(seqs[1]) {
+            Err(Error::NotQueued(seq)) if seq == seqs[1] => (),
+            _ => panic!("message should be removed")
+        }
+    }
+}
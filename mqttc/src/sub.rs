@@ -8,7 +8,12 @@ use mqtt3::{SubscribeTopic, TopicPath, PacketIdentifier, QoS};
 pub struct Subscription {
     pub pid: PacketIdentifier,
     pub topic_path: TopicPath,
-    pub qos: QoS
+    /// Granted by the broker
+    pub qos: QoS,
+    pub requested_qos: QoS,
+    // Messages and payload bytes received on the filter
+    pub messages: u64,
+    pub bytes: u64
 }
 
 impl Subscription {
@@ -0,0 +1,134 @@
+use std::sync::{Arc, Mutex, Condvar};
+use std::time::{Duration, Instant};
+use error::{Error, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Pending,
+    Delivered,
+    NotDelivered
+}
+
+struct Inner {
+    state: Mutex<State>,
+    changed: Condvar
+}
+
+/// Tells when a publish is delivered: a QoS 0 one once it's written, QoS 1
+/// on PUBACK and QoS 2 on PUBCOMP.
+///
+/// A publish that is given up fails with `Error::NotDelivered`, i.e. when
+/// the client refused it, it was cancelled, the broker didn't keep the
+/// session or the client was dropped. A lost connection alone doesn't fail
+/// it while the client reconnects to the same session.
+///
+/// With a `Client` the acknowledgement is only read while the client is
+/// polled, so use `try_wait` there; `ClientHandle` reads it on its thread.
+pub struct DeliveryToken {
+    inner: Arc<Inner>
+}
+
+impl DeliveryToken {
+    /// Waits until the publish is delivered or given up
+    pub fn wait(&self) -> Result<()> {
+        let mut state = self.inner.state.lock().unwrap();
+        while *state == State::Pending {
+            state = self.inner.changed.wait(state).unwrap();
+        }
+        outcome(*state)
+    }
+
+    /// Whether the publish is delivered yet, without waiting
+    pub fn try_wait(&self) -> Result<bool> {
+        let state = *self.inner.state.lock().unwrap();
+        match state {
+            State::Pending => Ok(false),
+            state => outcome(state).map(|_| true)
+        }
+    }
+
+    /// Like `wait` but gives up after `timeout`, `false` if the publish
+    /// wasn't delivered meanwhile
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<bool> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.inner.state.lock().unwrap();
+        while *state == State::Pending {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(false);
+            }
+            state = self.inner.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        outcome(*state).map(|_| true)
+    }
+}
+
+fn outcome(state: State) -> Result<()> {
+    match state {
+        State::NotDelivered => Err(Error::NotDelivered),
+        _ => Ok(())
+    }
+}
+
+/// Side of the client which resolves a `DeliveryToken`. Dropping it before
+/// `complete` fails the token.
+pub struct Completion {
+    inner: Arc<Inner>
+}
+
+impl Completion {
+    pub fn complete(self) {
+        self.resolve(State::Delivered);
+    }
+
+    fn resolve(&self, to: State) {
+        let mut state = self.inner.state.lock().unwrap();
+        if *state == State::Pending {
+            *state = to;
+            self.inner.changed.notify_all();
+        }
+    }
+}
+
+impl Drop for Completion {
+    fn drop(&mut self) {
+        self.resolve(State::NotDelivered);
+    }
+}
+
+pub fn new() -> (DeliveryToken, Completion) {
+    let inner = Arc::new(Inner {
+        state: Mutex::new(State::Pending),
+        changed: Condvar::new()
+    });
+    (DeliveryToken { inner: inner.clone() }, Completion { inner: inner })
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use std::time::Duration;
+    use error::Error;
+
+    #[test]
+    fn token_test() {
+        let (token, completion) = super::new();
+        assert_eq!(token.try_wait().unwrap(), false);
+        assert_eq!(token.wait_timeout(Duration::from_millis(10)).unwrap(), false);
+
+        let complete = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            completion.complete();
+        });
+        token.wait().unwrap();
+        assert_eq!(token.try_wait().unwrap(), true);
+        complete.join().unwrap();
+
+        let (token, completion) = super::new();
+        drop(completion);
+        match token.wait_timeout(Duration::from_secs(1)) {
+            Err(Error::NotDelivered) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+    }
+}
@@ -0,0 +1,209 @@
+//! Files sent as chunked transfers, checked with the SHA-256 of the
+//! manifest.
+
+use std::cmp;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use mqtt3::Message;
+use chunk::{Manifest, ChunkSender, ChunkReceiver};
+use error::{Error, Result};
+use sha256::Sha256;
+use {PubSub, PubOpt};
+
+/// Chunk size of `PubSub::send_file`
+pub const DEFAULT_CHUNK_SIZE: u32 = 64 * 1024;
+
+/// Sends the file at `path` on `topic` in chunks of `chunk_size` bytes, see
+/// `ChunkSender::send`. The file is read twice, once for its SHA-256 and
+/// once to send it.
+pub fn send_file<C, P>(client: &mut C, topic: &str, path: P, chunk_size: u32, pubopt: PubOpt)
+                       -> Result<(Manifest, Vec<Box<Message>>)>
+    where C: PubSub, P: AsRef<Path>
+{
+    let mut file = try!(File::open(path));
+    let mut sha = Sha256::new();
+    let len = try!(hash(&mut file, &mut sha, None));
+    try!(file.seek(SeekFrom::Start(0)));
+
+    let mut sender = try!(ChunkSender::new(topic, file, len, chunk_size));
+    sender.set_sha256(sha.finish());
+    sender.send(client, pubopt)
+}
+
+// Feeds the file from its position to the hash, up to `limit` bytes
+fn hash(file: &mut File, sha: &mut Sha256, limit: Option<u64>) -> io::Result<u64> {
+    let mut buf = vec![0; DEFAULT_CHUNK_SIZE as usize];
+    let mut len = 0;
+    loop {
+        let want = limit.map_or(buf.len() as u64, |limit| cmp::min(limit - len, buf.len() as u64)) as usize;
+        if want == 0 {
+            return Ok(len);
+        }
+        match file.read(&mut buf[..want]) {
+            Ok(0) => return Ok(len),
+            Ok(n) => {
+                sha.update(&buf[..n]);
+                len += n as u64;
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err)
+        }
+    }
+}
+
+// The file a transfer is received into, hashed on the way
+struct PartFile {
+    file: File,
+    sha: Sha256,
+    path: PathBuf
+}
+
+impl Write for PartFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = try!(self.file.write(buf));
+        self.sha.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Receives the files `send_file` sends on a topic into `path`.
+///
+/// Chunks are written to `<path>.<id>.part` as they come, which is moved to
+/// `path` once the SHA-256 matches. The part stays when the transfer breaks
+/// off, so when the same file is sent again the chunks it holds are
+/// skipped.
+pub struct FileReceiver {
+    receiver: ChunkReceiver<PartFile>,
+    path: PathBuf
+}
+
+impl FileReceiver {
+    pub fn new<P: AsRef<Path>>(topic: &str, path: P) -> FileReceiver {
+        FileReceiver {
+            receiver: ChunkReceiver::new(topic),
+            path: path.as_ref().to_path_buf()
+        }
+    }
+
+    /// Topic filter to subscribe to
+    pub fn filter(&self) -> String {
+        self.receiver.filter()
+    }
+
+    /// Takes a received message, returns the manifest once the file is in
+    /// place. Fails and removes the part if the SHA-256 doesn't match.
+    pub fn accept(&mut self, message: &Message) -> Result<Option<Manifest>> {
+        let path = &self.path;
+        let done = try!(self.receiver.accept_resumable(message, |manifest| open_part(path, manifest)));
+        let (manifest, part) = match done {
+            Some(done) => done,
+            None => return Ok(None)
+        };
+        let PartFile { file, sha, path: part_path } = part;
+        drop(file);
+        if manifest.sha256.map_or(false, |sha256| sha256 != sha.finish()) {
+            let _ = fs::remove_file(&part_path);
+            return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidData, "SHA-256 mismatch")));
+        }
+        try!(fs::rename(&part_path, &self.path));
+        Ok(Some(manifest))
+    }
+}
+
+fn part_path(path: &Path, id: u32) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(format!(".{:08x}.part", id));
+    PathBuf::from(name)
+}
+
+// Opens the part of the transfer, keeping the whole chunks it holds
+fn open_part(path: &Path, manifest: &Manifest) -> io::Result<(PartFile, u32)> {
+    let path = part_path(path, manifest.id);
+    let mut file = try!(OpenOptions::new().read(true).write(true).create(true).open(&path));
+    let chunks = cmp::min(try!(file.metadata()).len() / manifest.chunk_size as u64, manifest.chunks());
+    let kept = cmp::min(chunks * manifest.chunk_size as u64, manifest.len);
+    try!(file.set_len(kept));
+
+    let mut sha = Sha256::new();
+    try!(file.seek(SeekFrom::Start(0)));
+    try!(hash(&mut file, &mut sha, Some(kept)));
+    Ok((PartFile {
+        file: file,
+        sha: sha,
+        path: path
+    }, chunks as u32))
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::process;
+    use mqtt3::{Message, QoS, TopicPath};
+    use chunk::ChunkSender;
+    use sha256::Sha256;
+    use super::{FileReceiver, part_path};
+
+    fn message(topic: &str, payload: ::Payload) -> Message {
+        Message::new(TopicPath::from(topic), payload, QoS::AtLeastOnce)
+    }
+
+    #[test]
+    fn file_receiver_resume_test() {
+        let dir = env::temp_dir();
+        let path = dir.join(format!("mqttc_transfer_{}.bin", process::id()));
+        let _ = fs::remove_file(&path);
+        let data: Vec<u8> = (0..10).collect();
+        let mut sha = Sha256::new();
+        sha.update(&data);
+        let sha256 = sha.finish();
+
+        let send = |chunks: usize| {
+            let mut sender = ChunkSender::new("ota", &data[..], 10, 4).unwrap();
+            sender.set_sha256(sha256);
+            let mut messages = vec![sender.manifest_message()];
+            for _ in 0..chunks {
+                messages.push(sender.next_chunk().unwrap().unwrap());
+            }
+            (sender.manifest().id, messages)
+        };
+
+        // the first try breaks off after a chunk
+        let mut receiver = FileReceiver::new("ota", &path);
+        let (id, messages) = send(1);
+        for (topic, payload) in messages {
+            assert!(receiver.accept(&message(&topic, payload)).unwrap().is_none());
+        }
+        assert_eq!(fs::metadata(part_path(&path, id)).unwrap().len(), 4);
+
+        // a new receiver keeps that chunk, with a stray byte cut off
+        File::create(part_path(&path, id)).unwrap().write_all(&[0, 1, 2, 3, 4]).unwrap();
+        let mut receiver = FileReceiver::new("ota", &path);
+        let (_, messages) = send(3);
+        let mut done = None;
+        for (topic, payload) in messages {
+            done = receiver.accept(&message(&topic, payload)).unwrap();
+        }
+        assert_eq!(done.unwrap().sha256, Some(sha256));
+        assert_eq!(fs::read(&path).unwrap(), data);
+        assert!(!part_path(&path, id).exists());
+
+        // a corrupted part fails the check
+        File::create(part_path(&path, id)).unwrap().write_all(&[9, 9, 9, 9]).unwrap();
+        let (_, messages) = send(3);
+        let mut result = Ok(None);
+        for (topic, payload) in messages {
+            result = receiver.accept(&message(&topic, payload));
+        }
+        assert!(result.is_err());
+        assert!(!part_path(&path, id).exists());
+        fs::remove_file(&path).unwrap();
+    }
+}
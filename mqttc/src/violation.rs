@@ -0,0 +1,145 @@
+//! Logging of the protocol violations of many peers, which stays readable
+//! when a fleet of broken devices sends garbage.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use clock::{Clock, SystemClock};
+use error::Error;
+
+const DEFAULT_MAX_LINES: usize = 100;
+
+/// Violations of one kind by one peer left out of the log during an
+/// interval
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    pub client: String,
+    pub kind: String,
+    pub count: u64
+}
+
+/// Logs the first violation of each kind by each peer in an interval and
+/// counts the ones after it, they are logged as a summary once the
+/// interval is over. No more than `max_lines` are logged per interval, the
+/// rest only go into the summaries.
+pub struct ViolationReporter {
+    interval: Duration,
+    max_lines: usize,
+    clock: Arc<Clock>,
+    started: Instant,
+    lines: usize,
+    // (client, kind) -> violations not logged
+    counts: HashMap<(String, String), u64>
+}
+
+impl ViolationReporter {
+    pub fn new(interval: Duration) -> ViolationReporter {
+        let clock = Arc::new(SystemClock);
+        ViolationReporter {
+            interval: interval,
+            max_lines: DEFAULT_MAX_LINES,
+            started: clock.now(),
+            clock: clock,
+            lines: 0,
+            counts: HashMap::new()
+        }
+    }
+
+    pub fn set_max_lines(&mut self, max_lines: usize) -> &mut ViolationReporter {
+        self.max_lines = max_lines; self
+    }
+
+    /// Time the intervals go by, the system's by default
+    pub fn set_clock(&mut self, clock: Arc<Clock>) -> &mut ViolationReporter {
+        self.started = clock.now();
+        self.clock = clock; self
+    }
+
+    /// Takes a violation of `client`, true if it was logged at once
+    pub fn report(&mut self, client: &str, err: &Error) -> bool {
+        self.tick();
+        let key = (client.to_string(), kind(err));
+        if !self.counts.contains_key(&key) && self.lines < self.max_lines {
+            error!("{}: {:?}", client, err);
+            self.lines += 1;
+            self.counts.insert(key, 0);
+            return true;
+        }
+        *self.counts.entry(key).or_insert(0) += 1;
+        false
+    }
+
+    /// Logs the summaries once the interval is over and starts the next
+    /// one. Returns the violations summed up.
+    pub fn tick(&mut self) -> Vec<Violation> {
+        if self.clock.now() - self.started < self.interval {
+            return Vec::new();
+        }
+        self.flush()
+    }
+
+    /// Logs the summaries now and starts the next interval
+    pub fn flush(&mut self) -> Vec<Violation> {
+        let secs = (self.clock.now() - self.started).as_secs();
+        let mut violations: Vec<Violation> = self.counts.drain()
+            .filter(|&(_, count)| count > 0)
+            .map(|((client, kind), count)| Violation { client: client, kind: kind, count: count })
+            .collect();
+        violations.sort_by(|a, b| (&a.client, &a.kind).cmp(&(&b.client, &b.kind)));
+        for violation in violations.iter().take(self.max_lines) {
+            error!("{}: {} x{} in the last {}s", violation.client, violation.kind, violation.count, secs);
+        }
+        if violations.len() > self.max_lines {
+            let count: u64 = violations[self.max_lines..].iter().map(|violation| violation.count).sum();
+            error!("{} more violations in the last {}s", count, secs);
+        }
+        self.started = self.clock.now();
+        self.lines = 0;
+        violations
+    }
+}
+
+// Name of the variant, without what it carries
+fn kind(err: &Error) -> String {
+    let name = match *err {
+        Error::Mqtt(ref err) => format!("{:?}", err),
+        ref err => format!("{:?}", err)
+    };
+    let end = name.find(|c: char| !c.is_alphanumeric()).unwrap_or(name.len());
+    name[..end].to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use mqtt3;
+    use clock::ManualClock;
+    use error::Error;
+    use super::{ViolationReporter, Violation};
+
+    #[test]
+    fn violation_reporter_test() {
+        let clock = ManualClock::new();
+        let mut reporter = ViolationReporter::new(Duration::from_secs(10));
+        reporter.set_max_lines(2).set_clock(Arc::new(clock.clone()));
+
+        assert!(reporter.report("a", &Error::Mqtt(mqtt3::Error::IncorrectPacketFormat)));
+        assert!(!reporter.report("a", &Error::Mqtt(mqtt3::Error::IncorrectPacketFormat)));
+        assert!(!reporter.report("a", &Error::Mqtt(mqtt3::Error::IncorrectPacketFormat)));
+        assert!(reporter.report("a", &Error::UnhandledPuback(mqtt3::PacketIdentifier(1))));
+        // over the lines of the interval
+        assert!(!reporter.report("b", &Error::ProtocolViolation));
+        assert!(!reporter.report("a", &Error::UnhandledPuback(mqtt3::PacketIdentifier(2))));
+        assert!(reporter.tick().is_empty());
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(reporter.tick(), vec![
+            Violation { client: "a".to_string(), kind: "IncorrectPacketFormat".to_string(), count: 2 },
+            Violation { client: "a".to_string(), kind: "UnhandledPuback".to_string(), count: 1 },
+            Violation { client: "b".to_string(), kind: "ProtocolViolation".to_string(), count: 1 }
+        ]);
+        assert!(reporter.report("b", &Error::ProtocolViolation));
+        assert!(reporter.flush().is_empty());
+    }
+}
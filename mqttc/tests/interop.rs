@@ -0,0 +1,190 @@
+//! Runs the client against a real broker to catch wire format bugs the mock
+//! based tests can't.
+//!
+//! The tests are skipped unless `MQTT_BROKER` is set, e.g.
+//!
+//! ```text
+//! docker run -d -p 1883:1883 eclipse-mosquitto:1.6
+//! MQTT_BROKER=127.0.0.1:1883 cargo test -p mqttc --test interop -- --test-threads=1
+//! ```
+//!
+//! `MQTT_USERNAME` and `MQTT_PASSWORD` are used when the broker needs them.
+
+extern crate mqttc;
+extern crate mqtt3;
+extern crate netopt;
+
+use std::env;
+use std::process;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use netopt::NetworkOptions;
+use mqtt3::{Message, QoS};
+use mqttc::{Client, ClientOptions, PubSub, PubOpt};
+use mqttc::store::FileStore;
+
+fn broker() -> Option<String> {
+    match env::var("MQTT_BROKER") {
+        Ok(addr) => Some(addr),
+        Err(_) => {
+            println!("MQTT_BROKER is not set, skipping");
+            None
+        }
+    }
+}
+
+fn options() -> ClientOptions {
+    let mut opts = ClientOptions::new();
+    opts.set_keep_alive(10);
+    if let Ok(username) = env::var("MQTT_USERNAME") {
+        opts.set_username(username);
+    }
+    if let Ok(password) = env::var("MQTT_PASSWORD") {
+        opts.set_password(password);
+    }
+    opts
+}
+
+// Every test works on topics of its own, so runs don't see each other's
+// retained messages
+fn unique(name: &str) -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+    format!("rust-mq/interop/{}/{}-{}", name, process::id(), nanos)
+}
+
+fn with_stores(mut opts: ClientOptions, name: &str) -> ClientOptions {
+    let dir = env::temp_dir();
+    let id = unique(name).replace('/', "_");
+    opts.set_incomming_store(Box::new(FileStore::open(dir.join(format!("{}.in", id))).unwrap()));
+    opts.set_outgoing_store(Box::new(FileStore::open(dir.join(format!("{}.out", id))).unwrap()));
+    opts
+}
+
+fn connect(addr: &str, opts: ClientOptions) -> Client {
+    opts.connect(addr, NetworkOptions::new()).unwrap()
+}
+
+fn subscribe(client: &mut Client, topic: &str, qos: QoS) {
+    client.subscribe((topic.to_string(), qos)).unwrap();
+    client.await().unwrap();
+}
+
+fn recv(client: &mut Client, timeout: Duration) -> Option<Box<Message>> {
+    let started = Instant::now();
+    while started.elapsed() < timeout {
+        if let Some(message) = client.poll(Duration::from_millis(100)).unwrap() {
+            if message.qos == QoS::ExactlyOnce {
+                client.complete(message.pid.unwrap()).unwrap();
+            }
+            return Some(message);
+        }
+    }
+    None
+}
+
+fn roundtrip(addr: &str, name: &str, qos: QoS, payload: Vec<u8>) {
+    let topic = unique(name);
+    let mut subscriber = connect(addr, with_stores(options(), name));
+    subscribe(&mut subscriber, &topic, qos);
+
+    let mut publisher = connect(addr, with_stores(options(), name));
+    publisher.publish(topic.as_str(), payload.clone(), PubOpt::new(qos, false)).unwrap();
+
+    let message = recv(&mut subscriber, Duration::from_secs(5)).expect("message not delivered");
+    assert_eq!(message.topic.path, topic);
+    assert_eq!(message.qos, qos);
+    assert_eq!(*message.payload, payload);
+
+    // the publisher's flow completes too
+    assert!(publisher.disconnect_gracefully(Duration::from_secs(5)).unwrap().is_empty());
+    subscriber.disconnect_gracefully(Duration::from_secs(1)).unwrap();
+}
+
+#[test]
+fn interop_connect_clean_session_test() {
+    let addr = match broker() { Some(addr) => addr, None => return };
+    let mut client = connect(&addr, options());
+    assert!(!client.session_present());
+    client.disconnect_gracefully(Duration::from_secs(1)).unwrap();
+}
+
+#[test]
+fn interop_connect_persistent_session_test() {
+    let addr = match broker() { Some(addr) => addr, None => return };
+    let client_id = unique("session").replace('/', "_");
+
+    // drop whatever an earlier run left
+    let mut opts = options();
+    opts.set_client_id(client_id.clone());
+    connect(&addr, opts).disconnect_gracefully(Duration::from_secs(1)).unwrap();
+
+    for &present in [false, true].iter() {
+        let mut opts = options();
+        opts.set_client_id(client_id.clone()).set_clean_session(false);
+        let mut client = connect(&addr, opts);
+        assert_eq!(client.session_present(), present);
+        client.disconnect_gracefully(Duration::from_secs(1)).unwrap();
+    }
+}
+
+#[test]
+fn interop_qos0_test() {
+    let addr = match broker() { Some(addr) => addr, None => return };
+    roundtrip(&addr, "qos0", QoS::AtMostOnce, b"at most once".to_vec());
+}
+
+#[test]
+fn interop_qos1_test() {
+    let addr = match broker() { Some(addr) => addr, None => return };
+    roundtrip(&addr, "qos1", QoS::AtLeastOnce, b"at least once".to_vec());
+}
+
+#[test]
+fn interop_qos2_test() {
+    let addr = match broker() { Some(addr) => addr, None => return };
+    roundtrip(&addr, "qos2", QoS::ExactlyOnce, b"exactly once".to_vec());
+}
+
+#[test]
+fn interop_large_payload_test() {
+    let addr = match broker() { Some(addr) => addr, None => return };
+    // needs a four byte remaining length
+    let payload: Vec<u8> = (0..3 * 1024 * 1024).map(|i| i as u8).collect();
+    roundtrip(&addr, "large", QoS::AtLeastOnce, payload);
+}
+
+#[test]
+fn interop_retained_test() {
+    let addr = match broker() { Some(addr) => addr, None => return };
+    let topic = unique("retained");
+    let mut publisher = connect(&addr, options());
+    publisher.publish_retained(topic.as_str(), "retained", PubOpt::at_least_once()).unwrap();
+    publisher.await().unwrap();
+
+    let mut subscriber = connect(&addr, options());
+    subscribe(&mut subscriber, &topic, QoS::AtLeastOnce);
+    let message = recv(&mut subscriber, Duration::from_secs(5)).expect("retained message not delivered");
+    assert!(message.retain);
+    assert_eq!(*message.payload, b"retained".to_vec());
+
+    publisher.clear_retained(topic.as_str()).unwrap();
+    publisher.disconnect_gracefully(Duration::from_secs(5)).unwrap();
+    subscriber.disconnect_gracefully(Duration::from_secs(1)).unwrap();
+}
+
+#[test]
+fn interop_last_will_test() {
+    let addr = match broker() { Some(addr) => addr, None => return };
+    let topic = unique("will");
+    let mut opts = options();
+    opts.set_last_will::<&str, &str>(topic.as_str(), "gone".to_string(), PubOpt::at_least_once()).unwrap();
+    let mut dying = connect(&addr, opts);
+
+    let mut watcher = connect(&addr, options());
+    subscribe(&mut watcher, &topic, QoS::AtLeastOnce);
+
+    // closing the socket without DISCONNECT makes the broker publish the will
+    dying.terminate();
+    let message = recv(&mut watcher, Duration::from_secs(5)).expect("will not delivered");
+    assert_eq!(*message.payload, b"gone".to_vec());
+    watcher.disconnect_gracefully(Duration::from_secs(1)).unwrap();
+}
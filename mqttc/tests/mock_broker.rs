@@ -0,0 +1,259 @@
+//! Runs clients against the in-process `MockBroker` of netopt.
+
+extern crate mqttc;
+extern crate mqtt3;
+extern crate netopt;
+
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use netopt::NetworkOptions;
+use netopt::mock::MockBroker;
+use mqtt3::{Message, QoS};
+use mqttc::{Client, ClientOptions, PubSub, PubOpt, Bridge, BridgeTopic, Direction, Proxy};
+use mqttc::store::{FileQueue, MessageQueue};
+
+fn connect(broker: &MockBroker, mut opts: ClientOptions, client_id: &str) -> Client {
+    opts.set_client_id(client_id.to_string());
+    let mut netopt = NetworkOptions::new();
+    netopt.attach_broker(broker.clone());
+    opts.connect("127.0.0.1:1883", netopt).unwrap()
+}
+
+fn next_message(client: &mut Client) -> Message {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < deadline {
+        if let Some(message) = client.poll(Duration::from_millis(50)).unwrap() {
+            return *message;
+        }
+    }
+    panic!("no message");
+}
+
+#[test]
+fn mock_broker_publish_subscribe_test() {
+    let broker = MockBroker::new();
+    let mut sub = connect(&broker, ClientOptions::new(), "sub");
+    let mut publisher = connect(&broker, ClientOptions::new(), "pub");
+    assert_eq!(broker.clients(), vec!["pub", "sub"]);
+
+    sub.subscribe(("sensors/+".to_string(), QoS::AtMostOnce)).unwrap();
+    assert!(sub.poll(Duration::from_millis(50)).unwrap().is_none());
+    assert_eq!(broker.subscriptions("sub"), vec![("sensors/+".to_string(), QoS::AtMostOnce)]);
+
+    publisher.publish("sensors/temp", "21", PubOpt::at_least_once()).unwrap();
+    publisher.publish("other/temp", "5", PubOpt::at_most_once()).unwrap();
+    let message = next_message(&mut sub);
+    assert_eq!(message.topic.path, "sensors/temp");
+    assert_eq!(*message.payload, b"21".to_vec());
+    // delivered with the QoS of the subscription
+    assert_eq!(message.qos, QoS::AtMostOnce);
+
+    let published: Vec<String> = broker.published().iter().map(|m| m.topic.path.clone()).collect();
+    assert_eq!(published, vec!["sensors/temp", "other/temp"]);
+
+    sub.unsubscribe("sensors/+").unwrap();
+    assert!(sub.poll(Duration::from_millis(50)).unwrap().is_none());
+    assert!(broker.subscriptions("sub").is_empty());
+}
+
+#[test]
+fn mock_broker_retain_and_will_test() {
+    let broker = MockBroker::new();
+    let mut opts = ClientOptions::new();
+    opts.set_last_will::<&str, &str>("status/device", "offline".to_string(), PubOpt::at_least_once()).unwrap();
+    let mut device = connect(&broker, opts, "device");
+    device.publish("status/device", "online", PubOpt::at_least_once() | PubOpt::retain()).unwrap();
+    assert_eq!(*broker.retained("status/device").unwrap().payload, b"online".to_vec());
+    broker.publish("status/gateway", b"online", QoS::AtMostOnce, true);
+    broker.publish("sensors/temp", b"21", QoS::AtMostOnce, true);
+    let topics: Vec<String> = broker.retained_matching("status/+").unwrap().into_iter().map(|m| m.topic.path).collect();
+    assert_eq!(topics, vec!["status/device", "status/gateway"]);
+    assert_eq!(broker.retained_matching("#").unwrap().len(), 3);
+    assert!(broker.retained_matching("status/#/x").is_err());
+
+    // a late subscriber gets the retained message
+    let mut monitor = connect(&broker, ClientOptions::new(), "monitor");
+    monitor.subscribe(("status/#".to_string(), QoS::AtLeastOnce)).unwrap();
+    let mut messages = vec![next_message(&mut monitor), next_message(&mut monitor)];
+    messages.sort_by(|a, b| a.topic.path.cmp(&b.topic.path));
+    assert!(messages.iter().all(|message| message.retain));
+    assert_eq!((messages[0].topic.path.as_str(), &messages[0].payload[..]), ("status/device", &b"online"[..]));
+    assert_eq!(messages[1].topic.path, "status/gateway");
+
+    // the device goes away without a DISCONNECT
+    assert!(broker.drop_client("device"));
+    let message = next_message(&mut monitor);
+    assert_eq!((message.topic.path.as_str(), &message.payload[..]), ("status/device", &b"offline"[..]));
+    // after the PUBACK still buffered the client finds the connection closed
+    assert!((0..3).any(|_| device.poll(Duration::from_millis(50)).is_err()));
+    assert_eq!(broker.clients(), vec!["monitor"]);
+}
+
+#[test]
+fn mock_broker_bridge_test() {
+    let site = MockBroker::new();
+    let cloud = MockBroker::new();
+    let mut bridge = Bridge::new(connect(&site, ClientOptions::new(), "bridge"),
+                                 connect(&cloud, ClientOptions::new(), "bridge"));
+    let mut sensors = BridgeTopic::new("sensors/#", Direction::Both, QoS::AtLeastOnce);
+    sensors.set_remote_prefix("site1/");
+    bridge.add_topic(sensors).unwrap();
+    bridge.add_topic(BridgeTopic::new("alerts/#", Direction::Out, QoS::AtMostOnce)).unwrap();
+    let mut any_site = BridgeTopic::new("sensors/#", Direction::In, QoS::AtLeastOnce);
+    any_site.set_remote_prefix("+/");
+    assert!(bridge.add_topic(any_site).is_err());
+
+    let forward = |bridge: &mut Bridge| {
+        let mut forwarded = 0;
+        for _ in 0..10 {
+            forwarded += bridge.poll(Duration::from_millis(20)).unwrap();
+        }
+        forwarded
+    };
+
+    site.publish("sensors/temp", b"21", QoS::AtLeastOnce, false);
+    // forwarded once, the echo from the cloud isn't sent back
+    assert_eq!(forward(&mut bridge), 1);
+    let published: Vec<(String, QoS)> = cloud.published().iter().map(|m| (m.topic.path.clone(), m.qos)).collect();
+    assert_eq!(published, vec![("site1/sensors/temp".to_string(), QoS::AtLeastOnce)]);
+    assert!(site.published().is_empty());
+
+    cloud.publish("site1/sensors/setpoint", b"19", QoS::AtLeastOnce, false);
+    assert_eq!(forward(&mut bridge), 1);
+    assert_eq!(site.published()[0].topic.path, "sensors/setpoint");
+
+    // downgraded, and not mirrored back in
+    site.publish("alerts/door", b"open", QoS::AtLeastOnce, false);
+    cloud.publish("alerts/smoke", b"1", QoS::AtLeastOnce, false);
+    assert_eq!(forward(&mut bridge), 1);
+    let last = cloud.published().pop().unwrap();
+    assert_eq!((last.topic.path.as_str(), last.qos), ("alerts/door", QoS::AtMostOnce));
+    assert_eq!(site.published().len(), 1);
+
+    // `site1/#` matches `site1` too, which has no topic on the site
+    let site = MockBroker::new();
+    let cloud = MockBroker::new();
+    let mut bridge = Bridge::new(connect(&site, ClientOptions::new(), "bridge"),
+                                 connect(&cloud, ClientOptions::new(), "bridge"));
+    let mut everything = BridgeTopic::new("#", Direction::Both, QoS::AtLeastOnce);
+    everything.set_remote_prefix("site1/");
+    bridge.add_topic(everything).unwrap();
+    cloud.publish("site1", b"stray", QoS::AtLeastOnce, false);
+    cloud.publish("site1/", b"empty", QoS::AtLeastOnce, false);
+    cloud.publish("site1/door", b"open", QoS::AtLeastOnce, false);
+    assert_eq!(forward(&mut bridge), 1);
+    let published: Vec<String> = site.published().iter().map(|m| m.topic.path.clone()).collect();
+    assert_eq!(published, vec!["door"]);
+}
+
+#[test]
+fn mock_broker_proxy_test() {
+    let broker = MockBroker::new();
+    let path = env::temp_dir().join(format!("mqttc_proxy_{}.queue", process::id()));
+    let _ = fs::remove_file(&path);
+    let proxy = Arc::new(Proxy::new(connect(&broker, ClientOptions::new(), "proxy"),
+                                    Box::new(FileQueue::open(&path).unwrap())));
+
+    let mut listener = NetworkOptions::new().bind("127.0.0.1:18841").unwrap();
+    let serving = proxy.clone();
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        serving.serve(stream).unwrap();
+    });
+
+    let mut opts = ClientOptions::new();
+    opts.set_client_id("vehicle".to_string());
+    let mut vehicle = opts.connect("127.0.0.1:18841", NetworkOptions::new()).unwrap();
+    vehicle.publish("vehicle/gps", "52.5,13.4", PubOpt::at_least_once()).unwrap();
+    vehicle.publish("vehicle/speed", "80", PubOpt::at_least_once()).unwrap();
+    // acknowledged by the proxy, nothing upstream yet
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !vehicle.pending().is_empty() && Instant::now() < deadline {
+        vehicle.poll(Duration::from_millis(50)).unwrap();
+    }
+    assert!(vehicle.pending().is_empty());
+    assert!(broker.published().is_empty());
+    vehicle.disconnect_gracefully(Duration::from_secs(1)).unwrap();
+    server.join().unwrap();
+
+    assert_eq!(proxy.forward(Duration::from_secs(5)).unwrap(), 2);
+    let published: Vec<String> = broker.published().iter().map(|m| m.topic.path.clone()).collect();
+    assert_eq!(published, vec!["vehicle/gps", "vehicle/speed"]);
+    assert_eq!(proxy.forward(Duration::from_secs(5)).unwrap(), 0);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn mock_broker_proxy_qos2_test() {
+    let broker = MockBroker::new();
+    let path = env::temp_dir().join(format!("mqttc_proxy_qos2_{}.queue", process::id()));
+    let _ = fs::remove_file(&path);
+    let proxy = Arc::new(Proxy::new(connect(&broker, ClientOptions::new(), "proxy"),
+                                    Box::new(FileQueue::open(&path).unwrap())));
+
+    let mut listener = NetworkOptions::new().bind("127.0.0.1:18842").unwrap();
+    let serving = proxy.clone();
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        serving.serve(stream).unwrap();
+    });
+
+    let mut vehicle = TcpStream::connect("127.0.0.1:18842").unwrap();
+    let mut exchange = |packet: &[u8], reply: &[u8]| {
+        vehicle.write_all(packet).unwrap();
+        let mut read = vec![0; reply.len()];
+        vehicle.read_exact(&mut read).unwrap();
+        assert_eq!(read, reply);
+    };
+    exchange(&[0x10, 0x0D, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x04, 0x02, 0x00, 0x3C, 0x00, 0x01, b'v'],
+             &[0x20, 0x02, 0x00, 0x00]);
+    let publish = [0x34, 0x08, 0x00, 0x03, b'a', b'/', b'b', 0x00, 0x01, b'x'];
+    exchange(&publish, &[0x50, 0x02, 0x00, 0x01]);
+    // PUBREC went missing, the message comes again with DUP
+    let mut dup = publish;
+    dup[0] |= 0x08;
+    exchange(&dup, &[0x50, 0x02, 0x00, 0x01]);
+    exchange(&[0x62, 0x02, 0x00, 0x01], &[0x70, 0x02, 0x00, 0x01]);
+    // the packet identifier is free again
+    exchange(&publish, &[0x50, 0x02, 0x00, 0x01]);
+    exchange(&[0x62, 0x02, 0x00, 0x01], &[0x70, 0x02, 0x00, 0x01]);
+    vehicle.write_all(&[0xE0, 0x00]).unwrap();
+    server.join().unwrap();
+
+    // queued once for each PUBREL
+    drop(proxy);
+    assert_eq!(FileQueue::open(&path).unwrap().seqs(None, 10).unwrap().len(), 2);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn mock_broker_proxy_shutdown_test() {
+    let broker = MockBroker::new();
+    let path = env::temp_dir().join(format!("mqttc_proxy_shutdown_{}.queue", process::id()));
+    let _ = fs::remove_file(&path);
+    let proxy = Proxy::new(connect(&broker, ClientOptions::new(), "proxy"),
+                           Box::new(FileQueue::open(&path).unwrap()));
+    let handle = proxy.spawn(NetworkOptions::new().bind("127.0.0.1:18843").unwrap()).unwrap();
+
+    let mut opts = ClientOptions::new();
+    opts.set_client_id("vehicle".to_string());
+    let mut vehicle = opts.connect("127.0.0.1:18843", NetworkOptions::new()).unwrap();
+    vehicle.publish("vehicle/gps", "52.5,13.4", PubOpt::at_least_once()).unwrap();
+    // forwarded by the proxy's own thread
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while broker.published().is_empty() && Instant::now() < deadline {
+        vehicle.poll(Duration::from_millis(50)).unwrap();
+    }
+    assert_eq!(broker.published().len(), 1);
+
+    // returns with the vehicle still connected
+    handle.shutdown().unwrap();
+    assert!(TcpStream::connect("127.0.0.1:18843").is_err());
+    fs::remove_file(&path).unwrap();
+}
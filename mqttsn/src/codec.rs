@@ -0,0 +1,539 @@
+use {Error, Result, QoS, ReturnCode, Topic, PROTOCOL_ID};
+use packet::{
+    Packet,
+    Advertise,
+    GwInfo,
+    Connect,
+    WillTopic,
+    Register,
+    Regack,
+    Publish,
+    Puback,
+    Subscribe,
+    Suback,
+    Unsubscribe
+};
+
+const DUP: u8 = 0b10000000;
+const RETAIN: u8 = 0b00010000;
+const WILL: u8 = 0b00001000;
+const CLEAN_SESSION: u8 = 0b00000100;
+const TOPIC_ID_TYPE: u8 = 0b00000011;
+
+// A first length byte of 0x01 means a two byte length follows
+const THREE_BYTE_LENGTH: u8 = 0x01;
+const MAX_PACKET_SIZE: usize = 65535;
+
+const ADVERTISE: u8 = 0x00;
+const SEARCHGW: u8 = 0x01;
+const GWINFO: u8 = 0x02;
+const CONNECT: u8 = 0x04;
+const CONNACK: u8 = 0x05;
+const WILLTOPICREQ: u8 = 0x06;
+const WILLTOPIC: u8 = 0x07;
+const WILLMSGREQ: u8 = 0x08;
+const WILLMSG: u8 = 0x09;
+const REGISTER: u8 = 0x0A;
+const REGACK: u8 = 0x0B;
+const PUBLISH: u8 = 0x0C;
+const PUBACK: u8 = 0x0D;
+const PUBCOMP: u8 = 0x0E;
+const PUBREC: u8 = 0x0F;
+const PUBREL: u8 = 0x10;
+const SUBSCRIBE: u8 = 0x12;
+const SUBACK: u8 = 0x13;
+const UNSUBSCRIBE: u8 = 0x14;
+const UNSUBACK: u8 = 0x15;
+const PINGREQ: u8 = 0x16;
+const PINGRESP: u8 = 0x17;
+const DISCONNECT: u8 = 0x18;
+
+struct Body<'a> {
+    buf: &'a [u8],
+    pos: usize
+}
+
+impl<'a> Body<'a> {
+    fn u8(&mut self) -> Result<u8> {
+        if self.pos >= self.buf.len() {
+            return Err(Error::IncorrectPacketFormat);
+        }
+        self.pos += 1;
+        Ok(self.buf[self.pos - 1])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let hi = try!(self.u8()) as u16;
+        let lo = try!(self.u8()) as u16;
+        Ok(hi << 8 | lo)
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.buf[self.pos..];
+        self.pos = self.buf.len();
+        rest
+    }
+
+    fn string(&mut self) -> Result<String> {
+        Ok(try!(String::from_utf8(self.rest().to_vec())))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos == self.buf.len()
+    }
+
+    fn end(&self) -> Result<()> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::IncorrectLength)
+        }
+    }
+
+    // Topic of PUBLISH
+    fn topic_id(&mut self, flags: u8) -> Result<Topic> {
+        match flags & TOPIC_ID_TYPE {
+            0b00 => Ok(Topic::Id(try!(self.u16()))),
+            0b01 => Ok(Topic::Predefined(try!(self.u16()))),
+            0b10 => Ok(Topic::Short([try!(self.u8()), try!(self.u8())])),
+            _ => Err(Error::UnsupportedTopicIdType)
+        }
+    }
+
+    // Topic of SUBSCRIBE and UNSUBSCRIBE, which is the rest of the packet
+    fn topic(&mut self, flags: u8) -> Result<Topic> {
+        let topic = match flags & TOPIC_ID_TYPE {
+            0b00 => Topic::Name(try!(self.string())),
+            0b01 => Topic::Predefined(try!(self.u16())),
+            0b10 => Topic::Short([try!(self.u8()), try!(self.u8())]),
+            _ => return Err(Error::UnsupportedTopicIdType)
+        };
+        try!(self.end());
+        Ok(topic)
+    }
+}
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.push((value >> 8) as u8);
+    buf.push(value as u8);
+}
+
+fn put_topic_id(buf: &mut Vec<u8>, topic: &Topic) -> Result<()> {
+    match *topic {
+        Topic::Id(id) | Topic::Predefined(id) => put_u16(buf, id),
+        Topic::Short(ref name) => buf.extend_from_slice(name),
+        Topic::Name(_) => return Err(Error::UnsupportedTopicIdType)
+    }
+    Ok(())
+}
+
+fn put_topic(buf: &mut Vec<u8>, topic: &Topic) -> Result<()> {
+    match *topic {
+        Topic::Name(ref name) => buf.extend_from_slice(name.as_bytes()),
+        Topic::Predefined(id) => put_u16(buf, id),
+        Topic::Short(ref name) => buf.extend_from_slice(name),
+        Topic::Id(_) => return Err(Error::UnsupportedTopicIdType)
+    }
+    Ok(())
+}
+
+fn flags(dup: bool, qos: QoS, retain: bool) -> u8 {
+    let mut flags = qos.to_flags();
+    if dup {
+        flags |= DUP;
+    }
+    if retain {
+        flags |= RETAIN;
+    }
+    flags
+}
+
+impl Packet {
+    /// Decodes a packet from a whole datagram
+    pub fn decode(buf: &[u8]) -> Result<Packet> {
+        if buf.len() < 2 {
+            return Err(Error::IncorrectPacketFormat);
+        }
+        let (len, header) = if buf[0] == THREE_BYTE_LENGTH {
+            if buf.len() < 4 {
+                return Err(Error::IncorrectPacketFormat);
+            }
+            ((buf[1] as usize) << 8 | buf[2] as usize, 3)
+        } else {
+            (buf[0] as usize, 1)
+        };
+        if len != buf.len() {
+            return Err(Error::IncorrectLength);
+        }
+        let mut body = Body { buf: &buf[header + 1..], pos: 0 };
+
+        let packet = match buf[header] {
+            ADVERTISE => Packet::Advertise(Advertise {
+                gw_id: try!(body.u8()),
+                duration: try!(body.u16())
+            }),
+            SEARCHGW => Packet::SearchGw(try!(body.u8())),
+            GWINFO => Packet::GwInfo(GwInfo {
+                gw_id: try!(body.u8()),
+                gw_addr: body.rest().to_vec()
+            }),
+            CONNECT => {
+                let flags = try!(body.u8());
+                if try!(body.u8()) != PROTOCOL_ID {
+                    return Err(Error::UnsupportedProtocolId);
+                }
+                Packet::Connect(Box::new(Connect {
+                    will: flags & WILL != 0,
+                    clean_session: flags & CLEAN_SESSION != 0,
+                    duration: try!(body.u16()),
+                    client_id: try!(body.string())
+                }))
+            },
+            CONNACK => Packet::Connack(try!(ReturnCode::from_u8(try!(body.u8())))),
+            WILLTOPICREQ => Packet::WillTopicReq,
+            WILLTOPIC => {
+                if body.is_empty() {
+                    Packet::WillTopic(None)
+                } else {
+                    let flags = try!(body.u8());
+                    Packet::WillTopic(Some(WillTopic {
+                        qos: QoS::from_flags(flags),
+                        retain: flags & RETAIN != 0,
+                        topic: try!(body.string())
+                    }))
+                }
+            },
+            WILLMSGREQ => Packet::WillMsgReq,
+            WILLMSG => Packet::WillMsg(body.rest().to_vec()),
+            REGISTER => Packet::Register(Box::new(Register {
+                topic_id: try!(body.u16()),
+                msg_id: try!(body.u16()),
+                topic_name: try!(body.string())
+            })),
+            REGACK => Packet::Regack(Regack {
+                topic_id: try!(body.u16()),
+                msg_id: try!(body.u16()),
+                code: try!(ReturnCode::from_u8(try!(body.u8())))
+            }),
+            PUBLISH => {
+                let flags = try!(body.u8());
+                Packet::Publish(Box::new(Publish {
+                    dup: flags & DUP != 0,
+                    qos: QoS::from_flags(flags),
+                    retain: flags & RETAIN != 0,
+                    topic: try!(body.topic_id(flags)),
+                    msg_id: try!(body.u16()),
+                    payload: body.rest().to_vec()
+                }))
+            },
+            PUBACK => Packet::Puback(Puback {
+                topic_id: try!(body.u16()),
+                msg_id: try!(body.u16()),
+                code: try!(ReturnCode::from_u8(try!(body.u8())))
+            }),
+            PUBCOMP => Packet::Pubcomp(try!(body.u16())),
+            PUBREC => Packet::Pubrec(try!(body.u16())),
+            PUBREL => Packet::Pubrel(try!(body.u16())),
+            SUBSCRIBE => {
+                let flags = try!(body.u8());
+                Packet::Subscribe(Box::new(Subscribe {
+                    dup: flags & DUP != 0,
+                    qos: QoS::from_flags(flags),
+                    msg_id: try!(body.u16()),
+                    topic: try!(body.topic(flags))
+                }))
+            },
+            SUBACK => Packet::Suback(Suback {
+                qos: QoS::from_flags(try!(body.u8())),
+                topic_id: try!(body.u16()),
+                msg_id: try!(body.u16()),
+                code: try!(ReturnCode::from_u8(try!(body.u8())))
+            }),
+            UNSUBSCRIBE => {
+                let flags = try!(body.u8());
+                Packet::Unsubscribe(Box::new(Unsubscribe {
+                    msg_id: try!(body.u16()),
+                    topic: try!(body.topic(flags))
+                }))
+            },
+            UNSUBACK => Packet::Unsuback(try!(body.u16())),
+            PINGREQ => {
+                if body.is_empty() {
+                    Packet::Pingreq(None)
+                } else {
+                    Packet::Pingreq(Some(try!(body.string())))
+                }
+            },
+            PINGRESP => Packet::Pingresp,
+            DISCONNECT => {
+                if body.is_empty() {
+                    Packet::Disconnect(None)
+                } else {
+                    Packet::Disconnect(Some(try!(body.u16())))
+                }
+            },
+            _ => return Err(Error::UnsupportedPacketType)
+        };
+        try!(body.end());
+        Ok(packet)
+    }
+
+    /// Encodes the packet into one datagram
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut body = Vec::new();
+        let typ = match *self {
+            Packet::Advertise(ref advertise) => {
+                body.push(advertise.gw_id);
+                put_u16(&mut body, advertise.duration);
+                ADVERTISE
+            },
+            Packet::SearchGw(radius) => {
+                body.push(radius);
+                SEARCHGW
+            },
+            Packet::GwInfo(ref gwinfo) => {
+                body.push(gwinfo.gw_id);
+                body.extend_from_slice(&gwinfo.gw_addr);
+                GWINFO
+            },
+            Packet::Connect(ref connect) => {
+                let mut flags = 0;
+                if connect.will {
+                    flags |= WILL;
+                }
+                if connect.clean_session {
+                    flags |= CLEAN_SESSION;
+                }
+                body.push(flags);
+                body.push(PROTOCOL_ID);
+                put_u16(&mut body, connect.duration);
+                body.extend_from_slice(connect.client_id.as_bytes());
+                CONNECT
+            },
+            Packet::Connack(code) => {
+                body.push(code.to_u8());
+                CONNACK
+            },
+            Packet::WillTopicReq => WILLTOPICREQ,
+            Packet::WillTopic(ref will) => {
+                if let Some(ref will) = *will {
+                    body.push(flags(false, will.qos, will.retain));
+                    body.extend_from_slice(will.topic.as_bytes());
+                }
+                WILLTOPIC
+            },
+            Packet::WillMsgReq => WILLMSGREQ,
+            Packet::WillMsg(ref message) => {
+                body.extend_from_slice(message);
+                WILLMSG
+            },
+            Packet::Register(ref register) => {
+                put_u16(&mut body, register.topic_id);
+                put_u16(&mut body, register.msg_id);
+                body.extend_from_slice(register.topic_name.as_bytes());
+                REGISTER
+            },
+            Packet::Regack(ref regack) => {
+                put_u16(&mut body, regack.topic_id);
+                put_u16(&mut body, regack.msg_id);
+                body.push(regack.code.to_u8());
+                REGACK
+            },
+            Packet::Publish(ref publish) => {
+                body.push(flags(publish.dup, publish.qos, publish.retain) | publish.topic.id_type());
+                try!(put_topic_id(&mut body, &publish.topic));
+                put_u16(&mut body, publish.msg_id);
+                body.extend_from_slice(&publish.payload);
+                PUBLISH
+            },
+            Packet::Puback(ref puback) => {
+                put_u16(&mut body, puback.topic_id);
+                put_u16(&mut body, puback.msg_id);
+                body.push(puback.code.to_u8());
+                PUBACK
+            },
+            Packet::Pubcomp(msg_id) => {
+                put_u16(&mut body, msg_id);
+                PUBCOMP
+            },
+            Packet::Pubrec(msg_id) => {
+                put_u16(&mut body, msg_id);
+                PUBREC
+            },
+            Packet::Pubrel(msg_id) => {
+                put_u16(&mut body, msg_id);
+                PUBREL
+            },
+            Packet::Subscribe(ref subscribe) => {
+                body.push(flags(subscribe.dup, subscribe.qos, false) | subscribe.topic.id_type());
+                put_u16(&mut body, subscribe.msg_id);
+                try!(put_topic(&mut body, &subscribe.topic));
+                SUBSCRIBE
+            },
+            Packet::Suback(ref suback) => {
+                body.push(suback.qos.to_flags());
+                put_u16(&mut body, suback.topic_id);
+                put_u16(&mut body, suback.msg_id);
+                body.push(suback.code.to_u8());
+                SUBACK
+            },
+            Packet::Unsubscribe(ref unsubscribe) => {
+                body.push(unsubscribe.topic.id_type());
+                put_u16(&mut body, unsubscribe.msg_id);
+                try!(put_topic(&mut body, &unsubscribe.topic));
+                UNSUBSCRIBE
+            },
+            Packet::Unsuback(msg_id) => {
+                put_u16(&mut body, msg_id);
+                UNSUBACK
+            },
+            Packet::Pingreq(ref client_id) => {
+                if let Some(ref client_id) = *client_id {
+                    body.extend_from_slice(client_id.as_bytes());
+                }
+                PINGREQ
+            },
+            Packet::Pingresp => PINGRESP,
+            Packet::Disconnect(duration) => {
+                if let Some(duration) = duration {
+                    put_u16(&mut body, duration);
+                }
+                DISCONNECT
+            }
+        };
+
+        let mut buf = Vec::with_capacity(body.len() + 4);
+        if body.len() + 2 <= 0xFF {
+            buf.push((body.len() + 2) as u8);
+        } else if body.len() + 4 <= MAX_PACKET_SIZE {
+            buf.push(THREE_BYTE_LENGTH);
+            put_u16(&mut buf, (body.len() + 4) as u16);
+        } else {
+            return Err(Error::PacketTooLarge);
+        }
+        buf.push(typ);
+        buf.extend_from_slice(&body);
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {Error, QoS, ReturnCode, Topic};
+    use packet::*;
+
+    fn roundtrip(packet: Packet) -> Vec<u8> {
+        let buf = packet.encode().unwrap();
+        assert_eq!(Packet::decode(&buf).unwrap(), packet);
+        buf
+    }
+
+    #[test]
+    fn codec_roundtrip_test() {
+        let packets = vec![
+            Packet::Advertise(Advertise { gw_id: 1, duration: 900 }),
+            Packet::SearchGw(2),
+            Packet::GwInfo(GwInfo { gw_id: 1, gw_addr: vec![] }),
+            Packet::Connect(Box::new(Connect {
+                will: true,
+                clean_session: true,
+                duration: 60,
+                client_id: "sensor".to_string()
+            })),
+            Packet::Connack(ReturnCode::RejectedCongestion),
+            Packet::WillTopicReq,
+            Packet::WillTopic(None),
+            Packet::WillTopic(Some(WillTopic { qos: QoS::AtLeastOnce, retain: true, topic: "w".to_string() })),
+            Packet::WillMsgReq,
+            Packet::WillMsg(b"gone".to_vec()),
+            Packet::Register(Box::new(Register { topic_id: 0, msg_id: 3, topic_name: "a/b".to_string() })),
+            Packet::Regack(Regack { topic_id: 1, msg_id: 3, code: ReturnCode::Accepted }),
+            Packet::Puback(Puback { topic_id: 1, msg_id: 4, code: ReturnCode::RejectedInvalidTopicId }),
+            Packet::Pubcomp(5),
+            Packet::Pubrec(5),
+            Packet::Pubrel(5),
+            Packet::Subscribe(Box::new(Subscribe {
+                dup: false,
+                qos: QoS::ExactlyOnce,
+                msg_id: 6,
+                topic: Topic::Name("a/+".to_string())
+            })),
+            Packet::Subscribe(Box::new(Subscribe {
+                dup: true,
+                qos: QoS::AtMostOnce,
+                msg_id: 6,
+                topic: Topic::Short(*b"ab")
+            })),
+            Packet::Suback(Suback { qos: QoS::AtLeastOnce, topic_id: 0, msg_id: 6, code: ReturnCode::Accepted }),
+            Packet::Unsubscribe(Box::new(Unsubscribe { msg_id: 7, topic: Topic::Predefined(9) })),
+            Packet::Unsuback(7),
+            Packet::Pingreq(None),
+            Packet::Pingreq(Some("sensor".to_string())),
+            Packet::Pingresp,
+            Packet::Disconnect(None),
+            Packet::Disconnect(Some(300))
+        ];
+        for packet in packets {
+            roundtrip(packet);
+        }
+    }
+
+    #[test]
+    fn codec_publish_test() {
+        let buf = roundtrip(Packet::Publish(Box::new(Publish {
+            dup: false,
+            qos: QoS::MinusOne,
+            retain: true,
+            topic: Topic::Predefined(1),
+            msg_id: 0,
+            payload: b"21.5".to_vec()
+        })));
+        assert_eq!(buf, vec![0x0B, 0x0C, 0b01110001, 0x00, 0x01, 0x00, 0x00, b'2', b'1', b'.', b'5']);
+
+        // needs the three byte length
+        let buf = roundtrip(Packet::Publish(Box::new(Publish {
+            dup: true,
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            topic: Topic::Id(1),
+            msg_id: 2,
+            payload: vec![0x55; 300]
+        })));
+        assert_eq!(&buf[..4], &[0x01, 0x01, 0x35, 0x0C]);
+
+        let publish = Packet::Publish(Box::new(Publish {
+            dup: false,
+            qos: QoS::AtMostOnce,
+            retain: false,
+            topic: Topic::Name("a/b".to_string()),
+            msg_id: 0,
+            payload: vec![]
+        }));
+        match publish.encode() {
+            Err(Error::UnsupportedTopicIdType) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+    }
+
+    #[test]
+    fn codec_decode_error_test() {
+        match Packet::decode(&[0x03, 0x16]) {
+            Err(Error::IncorrectLength) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+        match Packet::decode(&[0x02, 0xFF]) {
+            Err(Error::UnsupportedPacketType) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+        // CONNECT of protocol id 2
+        match Packet::decode(&[0x06, 0x04, 0x04, 0x02, 0x00, 0x3C]) {
+            Err(Error::UnsupportedProtocolId) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+        // PUBACK without its return code
+        match Packet::decode(&[0x06, 0x0D, 0x00, 0x01, 0x00, 0x02]) {
+            Err(Error::IncorrectPacketFormat) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+    }
+}
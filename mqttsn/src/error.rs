@@ -0,0 +1,71 @@
+use std::result;
+use std::io;
+use std::fmt;
+use std::error;
+use std::string::FromUtf8Error;
+use mqttc;
+
+pub type Result<T> = result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    IncorrectPacketFormat,
+    IncorrectLength,
+    UnsupportedPacketType,
+    UnsupportedProtocolId,
+    UnsupportedReturnCode,
+    UnsupportedTopicIdType,
+    PacketTooLarge,
+    StringMustBeUtf8,
+    Client(mqttc::Error),
+    Io(io::Error)
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<FromUtf8Error> for Error {
+    fn from(_: FromUtf8Error) -> Error {
+        Error::StringMustBeUtf8
+    }
+}
+
+impl From<mqttc::Error> for Error {
+    fn from(err: mqttc::Error) -> Error {
+        Error::Client(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::write(f, format_args!("{:?}", *self))
+    }
+}
+
+impl error::Error for Error {
+    fn description<'a>(&'a self) -> &'a str {
+        match *self {
+            Error::IncorrectPacketFormat => "Incorrect Packet Format",
+            Error::IncorrectLength => "Incorrect Length",
+            Error::UnsupportedPacketType => "Unsupported Packet Type",
+            Error::UnsupportedProtocolId => "Unsupported Protocol Id",
+            Error::UnsupportedReturnCode => "Unsupported Return Code",
+            Error::UnsupportedTopicIdType => "Unsupported Topic Id Type",
+            Error::PacketTooLarge => "Packet Too Large",
+            Error::StringMustBeUtf8 => "String Must Be Utf 8",
+            Error::Client(ref err) => err.description(),
+            Error::Io(ref err) => err.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::Client(ref err) => Some(err),
+            Error::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
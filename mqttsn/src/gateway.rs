@@ -0,0 +1,762 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{UdpSocket, SocketAddr, ToSocketAddrs};
+use std::time::{Duration, Instant};
+use mqtt3::{LastWill, Message, validate_topic_name, validate_topic_filter};
+use mqttc::{self, ClientOptions, ClientHandle, PubOpt};
+use netopt::NetworkOptions;
+use {Result, QoS, ReturnCode, Topic};
+use packet::{
+    Packet,
+    GwInfo,
+    Connect,
+    WillTopic,
+    Register,
+    Regack,
+    Publish,
+    Puback,
+    Subscribe,
+    Suback,
+    Unsubscribe
+};
+
+// How long `run` waits for a datagram before delivering messages
+const POLL_INTERVAL_MS: u64 = 50;
+const MAX_DATAGRAM_SIZE: usize = 65535;
+// How long a disconnecting client's publishes get to reach the broker
+const DISCONNECT_TIMEOUT_MS: u64 = 500;
+
+pub struct GatewayOptions {
+    gw_id: u8,
+    username: Option<String>,
+    password: Option<String>,
+    predefined: HashMap<u16, String>,
+    max_buffered: usize,
+    network: Box<Fn() -> NetworkOptions + Send>
+}
+
+impl GatewayOptions {
+    /// Gateway settings
+    ///
+    /// - The gateway id is 1
+    /// - At most 100 messages are kept for a sleeping client
+    /// - The broker is connected to over plain TCP
+    pub fn new() -> GatewayOptions {
+        GatewayOptions {
+            gw_id: 1,
+            username: None,
+            password: None,
+            predefined: HashMap::new(),
+            max_buffered: 100,
+            network: Box::new(NetworkOptions::new)
+        }
+    }
+
+    pub fn set_gateway_id(&mut self, gw_id: u8) -> &mut GatewayOptions {
+        self.gw_id = gw_id; self
+    }
+
+    /// Username the broker connections use, MQTT-SN has no credentials
+    pub fn set_username(&mut self, username: String) -> &mut GatewayOptions {
+        self.username = Some(username); self
+    }
+
+    pub fn set_password(&mut self, password: String) -> &mut GatewayOptions {
+        self.password = Some(password); self
+    }
+
+    /// Topic id the clients know without registering it. Clients need these
+    /// to publish with QoS -1.
+    pub fn add_predefined_topic(&mut self, topic_id: u16, topic: String) -> &mut GatewayOptions {
+        self.predefined.insert(topic_id, topic); self
+    }
+
+    /// Messages kept for each sleeping client, older ones are dropped
+    pub fn set_max_buffered(&mut self, messages: usize) -> &mut GatewayOptions {
+        self.max_buffered = messages; self
+    }
+
+    /// Creates the network options of every broker connection, e.g. to use TLS
+    pub fn set_network_options<F>(&mut self, network: F) -> &mut GatewayOptions
+        where F: Fn() -> NetworkOptions + Send + 'static
+    {
+        self.network = Box::new(network); self
+    }
+
+    pub fn bind<A: ToSocketAddrs, B: ToSocketAddrs>(self, addr: A, broker: B) -> Result<Gateway> {
+        let broker = try!(try!(broker.to_socket_addrs()).next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::AddrNotAvailable, "broker address resolves to nothing")
+        }));
+        let socket = try!(UdpSocket::bind(addr));
+        info!(" MQTT-SN gateway on {} for {}", try!(socket.local_addr()), broker);
+        Ok(Gateway {
+            opts: self,
+            socket: socket,
+            broker: broker,
+            sessions: HashMap::new(),
+            pending: HashMap::new(),
+            anonymous: None
+        })
+    }
+
+    fn client_options(&self) -> ClientOptions {
+        let mut opts = ClientOptions::new();
+        if let Some(ref username) = self.username {
+            opts.set_username(username.clone());
+        }
+        if let Some(ref password) = self.password {
+            opts.set_password(password.clone());
+        }
+        opts
+    }
+}
+
+/// Translates between MQTT-SN clients on UDP and an MQTT broker.
+///
+/// Every client gets a connection to the broker of its own, with the client
+/// id, clean session flag and will of its CONNECT. Topic ids are kept per
+/// client and sent with REGISTER before the first message of a topic.
+///
+/// - A PUBLISH is acknowledged once it's queued on the broker connection,
+///   QoS 2 publishes are queued on PUBREL.
+/// - SUBACK grants the requested QoS without waiting for the broker.
+/// - Messages to a client aren't retransmitted.
+/// - While a client sleeps its messages are kept until it sends PINGREQ with
+///   its client id or connects again.
+/// - Clients silent for one and a half times their keep alive or sleep
+///   duration are dropped, so the broker publishes their will.
+/// - QoS -1 publishes go out over a connection shared by all clients.
+///
+/// The gateway doesn't send ADVERTISE, clients find it with SEARCHGW.
+/// Broker connections are made on the gateway thread.
+pub struct Gateway {
+    opts: GatewayOptions,
+    socket: UdpSocket,
+    broker: SocketAddr,
+    sessions: HashMap<SocketAddr, Session>,
+    // CONNECTs waiting for the will topic and message
+    pending: HashMap<SocketAddr, (Box<Connect>, Option<WillTopic>)>,
+    anonymous: Option<ClientHandle>
+}
+
+impl Gateway {
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(try!(self.socket.local_addr()))
+    }
+
+    /// Serves clients until the socket fails
+    pub fn run(&mut self) -> Result<()> {
+        loop {
+            try!(self.poll(Duration::from_millis(POLL_INTERVAL_MS)));
+        }
+    }
+
+    /// Handles at most one datagram, waiting up to `timeout` for it, then
+    /// passes on the messages from the broker
+    pub fn poll(&mut self, timeout: Duration) -> Result<()> {
+        try!(self.socket.set_read_timeout(Some(timeout)));
+        let mut buf = [0; MAX_DATAGRAM_SIZE];
+        match self.socket.recv_from(&mut buf) {
+            Ok((len, addr)) => match Packet::decode(&buf[..len]) {
+                Ok(packet) => try!(self.handle(addr, packet)),
+                Err(err) => warn!("{}: {:?}", addr, err)
+            },
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock ||
+                            err.kind() == io::ErrorKind::TimedOut => (),
+            // A client went away since the last datagram was sent to it
+            Err(ref err) if err.kind() == io::ErrorKind::ConnectionRefused ||
+                            err.kind() == io::ErrorKind::ConnectionReset => (),
+            Err(err) => return Err(err.into())
+        }
+        try!(self.deliver());
+        self.expire();
+        Ok(())
+    }
+
+    fn handle(&mut self, addr: SocketAddr, packet: Packet) -> Result<()> {
+        debug!("{} -> {:?}", addr, packet);
+        if let Some(session) = self.sessions.get_mut(&addr) {
+            session.last_seen = Instant::now();
+        }
+        match packet {
+            Packet::SearchGw(_) => {
+                let gwinfo = GwInfo { gw_id: self.opts.gw_id, gw_addr: Vec::new() };
+                self.send(addr, Packet::GwInfo(gwinfo))
+            },
+            Packet::Connect(connect) => self.connect(addr, connect),
+            Packet::WillTopic(will) => match self.pending.remove(&addr) {
+                Some((connect, _)) => match will {
+                    Some(will) => {
+                        self.pending.insert(addr, (connect, Some(will)));
+                        self.send(addr, Packet::WillMsgReq)
+                    },
+                    None => self.open(addr, connect, None)
+                },
+                None => Ok(())
+            },
+            Packet::WillMsg(message) => match self.pending.remove(&addr) {
+                Some((connect, Some(will))) => {
+                    // MQTT 3.1.1 wills are strings
+                    let last_will = LastWill {
+                        topic: will.topic,
+                        message: String::from_utf8_lossy(&message).into_owned(),
+                        qos: will.qos.to_mqtt(),
+                        retain: will.retain
+                    };
+                    self.open(addr, connect, Some(last_will))
+                },
+                _ => Ok(())
+            },
+            Packet::Publish(ref publish) if publish.qos == QoS::MinusOne => {
+                self.publish_anonymous(publish);
+                Ok(())
+            },
+            Packet::Pingreq(client_id) => self.ping(addr, client_id),
+            Packet::Disconnect(duration) => self.disconnect(addr, duration),
+            packet => {
+                let replies = match self.sessions.get_mut(&addr) {
+                    Some(session) => session.handle(packet, &self.opts.predefined),
+                    None => {
+                        warn!("{} isn't connected, dropping {:?}", addr, packet);
+                        return Ok(());
+                    }
+                };
+                for reply in replies {
+                    try!(self.send(addr, reply));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn connect(&mut self, addr: SocketAddr, connect: Box<Connect>) -> Result<()> {
+        self.pending.remove(&addr);
+        let wakes_up = match self.sessions.get_mut(&addr) {
+            Some(ref mut session) if session.client_id == connect.client_id &&
+                                     !connect.clean_session && !connect.will => {
+                session.asleep = false;
+                session.duration = Duration::from_secs(connect.duration as u64);
+                true
+            },
+            _ => false
+        };
+        if wakes_up {
+            return self.send(addr, Packet::Connack(ReturnCode::Accepted));
+        }
+
+        if let Some(session) = self.sessions.remove(&addr) {
+            session.close();
+        }
+        if connect.will {
+            self.pending.insert(addr, (connect, None));
+            return self.send(addr, Packet::WillTopicReq);
+        }
+        self.open(addr, connect, None)
+    }
+
+    fn open(&mut self, addr: SocketAddr, connect: Box<Connect>, last_will: Option<LastWill>) -> Result<()> {
+        let mut opts = self.opts.client_options();
+        opts.set_client_id(connect.client_id.clone())
+            .set_clean_session(connect.clean_session)
+            .set_last_will_opt(last_will);
+        let code = match opts.connect(self.broker, (self.opts.network)()) {
+            Ok(client) => {
+                info!("{} connected as {}", addr, connect.client_id);
+                self.sessions.insert(addr, Session::new(&connect, client.spawn()));
+                ReturnCode::Accepted
+            },
+            Err(err) => {
+                error!("{} can't be connected to the broker: {:?}", addr, err);
+                ReturnCode::RejectedCongestion
+            }
+        };
+        self.send(addr, Packet::Connack(code))
+    }
+
+    fn ping(&mut self, addr: SocketAddr, client_id: Option<String>) -> Result<()> {
+        let mut replies = Vec::new();
+        if let Some(session) = self.sessions.get_mut(&addr) {
+            // A sleeping client stays awake until PINGRESP
+            if session.asleep && client_id.as_ref() == Some(&session.client_id) {
+                let _ = session.receive(self.opts.max_buffered);
+                while let Some(message) = session.buffered.pop_front() {
+                    replies.extend(session.forward(message, &self.opts.predefined));
+                }
+            }
+        }
+        replies.push(Packet::Pingresp);
+        for reply in replies {
+            try!(self.send(addr, reply));
+        }
+        Ok(())
+    }
+
+    fn disconnect(&mut self, addr: SocketAddr, duration: Option<u16>) -> Result<()> {
+        self.pending.remove(&addr);
+        match duration {
+            Some(duration) => {
+                if let Some(session) = self.sessions.get_mut(&addr) {
+                    info!("{} sleeps for {}s", addr, duration);
+                    session.asleep = true;
+                    session.duration = Duration::from_secs(duration as u64);
+                }
+            },
+            None => {
+                if let Some(session) = self.sessions.remove(&addr) {
+                    info!("{} disconnected", addr);
+                    session.close();
+                }
+            }
+        }
+        self.send(addr, Packet::Disconnect(None))
+    }
+
+    fn publish_anonymous(&mut self, publish: &Publish) {
+        let topic = match publish.topic {
+            Topic::Predefined(_) | Topic::Short(_) => topic_name(&publish.topic, &HashMap::new(), &self.opts.predefined),
+            _ => None
+        };
+        let topic = match topic {
+            Some(topic) => topic,
+            None => {
+                warn!("QoS -1 publish to unknown topic {:?}", publish.topic);
+                return;
+            }
+        };
+        if self.anonymous.is_none() {
+            match self.opts.client_options().connect(self.broker, (self.opts.network)()) {
+                Ok(client) => self.anonymous = Some(client.spawn()),
+                Err(err) => {
+                    error!("QoS -1 publish can't be connected to the broker: {:?}", err);
+                    return;
+                }
+            }
+        }
+        let pubopt = PubOpt::new(QoS::MinusOne.to_mqtt(), publish.retain);
+        let result = self.anonymous.as_ref().unwrap().publish(topic, publish.payload.clone(), pubopt);
+        if let Err(err) = result {
+            error!("QoS -1 publish failed: {:?}", err);
+            // connect again next time
+            self.anonymous = None;
+        }
+    }
+
+    fn deliver(&mut self) -> Result<()> {
+        let mut outgoing = Vec::new();
+        let mut lost = Vec::new();
+        for (addr, session) in self.sessions.iter_mut() {
+            if session.receive(self.opts.max_buffered).is_err() {
+                lost.push(*addr);
+            }
+            if !session.asleep {
+                while let Some(message) = session.buffered.pop_front() {
+                    for packet in session.forward(message, &self.opts.predefined) {
+                        outgoing.push((*addr, packet));
+                    }
+                }
+            }
+        }
+        for addr in lost {
+            info!("{} lost the broker connection", addr);
+            self.sessions.remove(&addr);
+            outgoing.push((addr, Packet::Disconnect(None)));
+        }
+        for (addr, packet) in outgoing {
+            try!(self.send(addr, packet));
+        }
+        Ok(())
+    }
+
+    fn expire(&mut self) {
+        let expired: Vec<SocketAddr> = self.sessions.iter()
+            .filter(|&(_, session)| session.expired())
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in expired {
+            info!("{} timed out", addr);
+            if let Some(session) = self.sessions.remove(&addr) {
+                session.abort();
+            }
+        }
+    }
+
+    fn send(&self, addr: SocketAddr, packet: Packet) -> Result<()> {
+        debug!("{} <- {:?}", addr, packet);
+        try!(self.socket.send_to(&try!(packet.encode()), addr));
+        Ok(())
+    }
+}
+
+struct Session {
+    client_id: String,
+    client: ClientHandle,
+    // Keep alive while awake, sleep duration while asleep
+    duration: Duration,
+    last_seen: Instant,
+    asleep: bool,
+    topics: HashMap<u16, String>,
+    topic_ids: HashMap<String, u16>,
+    last_topic_id: u16,
+    last_msg_id: u16,
+    buffered: VecDeque<Box<Message>>,
+    // QoS 2 publishes waiting for PUBREL
+    incoming: HashMap<u16, (String, Box<Publish>)>
+}
+
+impl Session {
+    fn new(connect: &Connect, client: ClientHandle) -> Session {
+        Session {
+            client_id: connect.client_id.clone(),
+            client: client,
+            duration: Duration::from_secs(connect.duration as u64),
+            last_seen: Instant::now(),
+            asleep: false,
+            topics: HashMap::new(),
+            topic_ids: HashMap::new(),
+            last_topic_id: 0,
+            last_msg_id: 0,
+            buffered: VecDeque::new(),
+            incoming: HashMap::new()
+        }
+    }
+
+    fn handle(&mut self, packet: Packet, predefined: &HashMap<u16, String>) -> Vec<Packet> {
+        match packet {
+            Packet::Register(register) => {
+                let (topic_id, code) = if validate_topic_name(&register.topic_name).is_ok() {
+                    (self.register(register.topic_name), ReturnCode::Accepted)
+                } else {
+                    (0, ReturnCode::RejectedNotSupported)
+                };
+                vec![Packet::Regack(Regack { topic_id: topic_id, msg_id: register.msg_id, code: code })]
+            },
+            Packet::Publish(publish) => self.publish(publish, predefined),
+            Packet::Pubrel(msg_id) => {
+                if let Some((topic, publish)) = self.incoming.remove(&msg_id) {
+                    self.publish_upstream(topic, &publish);
+                }
+                vec![Packet::Pubcomp(msg_id)]
+            },
+            Packet::Pubrec(msg_id) => vec![Packet::Pubrel(msg_id)],
+            Packet::Puback(_) | Packet::Pubcomp(_) | Packet::Regack(_) | Packet::Pingresp => Vec::new(),
+            Packet::Subscribe(subscribe) => self.subscribe(subscribe, predefined),
+            Packet::Unsubscribe(unsubscribe) => self.unsubscribe(unsubscribe, predefined),
+            packet => {
+                warn!("{} sent unexpected {:?}", self.client_id, packet);
+                Vec::new()
+            }
+        }
+    }
+
+    fn publish(&mut self, publish: Box<Publish>, predefined: &HashMap<u16, String>) -> Vec<Packet> {
+        let topic_id = match publish.topic {
+            Topic::Id(id) | Topic::Predefined(id) => id,
+            Topic::Short(name) => (name[0] as u16) << 8 | name[1] as u16,
+            Topic::Name(_) => 0
+        };
+        let topic = match topic_name(&publish.topic, &self.topics, predefined) {
+            Some(ref topic) if validate_topic_name(topic).is_ok() => topic.clone(),
+            _ => {
+                let puback = Puback {
+                    topic_id: topic_id,
+                    msg_id: publish.msg_id,
+                    code: ReturnCode::RejectedInvalidTopicId
+                };
+                return vec![Packet::Puback(puback)];
+            }
+        };
+
+        if publish.qos == QoS::ExactlyOnce {
+            // Waits for PUBREL, so a retransmitted PUBLISH goes out once
+            let msg_id = publish.msg_id;
+            self.incoming.insert(msg_id, (topic, publish));
+            return vec![Packet::Pubrec(msg_id)];
+        }
+        let code = self.publish_upstream(topic, &publish);
+        if publish.qos == QoS::AtLeastOnce || code != ReturnCode::Accepted {
+            vec![Packet::Puback(Puback { topic_id: topic_id, msg_id: publish.msg_id, code: code })]
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn publish_upstream(&self, topic: String, publish: &Publish) -> ReturnCode {
+        let pubopt = PubOpt::new(publish.qos.to_mqtt(), publish.retain);
+        match self.client.publish(topic, publish.payload.clone(), pubopt) {
+            Ok(_) => ReturnCode::Accepted,
+            Err(err) => {
+                error!("{} publish failed: {:?}", self.client_id, err);
+                ReturnCode::RejectedCongestion
+            }
+        }
+    }
+
+    fn subscribe(&mut self, subscribe: Box<Subscribe>, predefined: &HashMap<u16, String>) -> Vec<Packet> {
+        let msg_id = subscribe.msg_id;
+        let suback = move |qos, topic_id, code| {
+            vec![Packet::Suback(Suback { qos: qos, topic_id: topic_id, msg_id: msg_id, code: code })]
+        };
+        if subscribe.qos == QoS::MinusOne {
+            return suback(QoS::AtMostOnce, 0, ReturnCode::RejectedNotSupported);
+        }
+        let filter = match topic_name(&subscribe.topic, &self.topics, predefined) {
+            Some(ref filter) if validate_topic_filter(filter).is_ok() => filter.clone(),
+            _ => return suback(subscribe.qos, 0, ReturnCode::RejectedInvalidTopicId)
+        };
+        // Filters without wildcards get an id, so the messages don't need a REGISTER
+        let topic_id = match subscribe.topic {
+            Topic::Name(ref name) if validate_topic_name(name).is_ok() => self.register(name.clone()),
+            Topic::Predefined(id) => id,
+            _ => 0
+        };
+        if let Err(err) = self.client.subscribe((filter, subscribe.qos.to_mqtt())) {
+            error!("{} subscribe failed: {:?}", self.client_id, err);
+            return suback(subscribe.qos, 0, ReturnCode::RejectedCongestion);
+        }
+        suback(subscribe.qos, topic_id, ReturnCode::Accepted)
+    }
+
+    fn unsubscribe(&mut self, unsubscribe: Box<Unsubscribe>, predefined: &HashMap<u16, String>) -> Vec<Packet> {
+        if let Some(filter) = topic_name(&unsubscribe.topic, &self.topics, predefined) {
+            if let Err(err) = self.client.unsubscribe(vec![filter]) {
+                error!("{} unsubscribe failed: {:?}", self.client_id, err);
+            }
+        }
+        vec![Packet::Unsuback(unsubscribe.msg_id)]
+    }
+
+    /// Moves the messages from the broker to the buffer. Fails once the
+    /// broker connection is gone.
+    fn receive(&mut self, max_buffered: usize) -> mqttc::Result<()> {
+        while let Some(message) = try!(self.client.try_recv()) {
+            if self.buffered.len() >= max_buffered {
+                warn!("{} sleeps too long, dropping a message", self.client_id);
+                self.buffered.pop_front();
+            }
+            self.buffered.push_back(message);
+        }
+        Ok(())
+    }
+
+    /// The packets which pass the message on, REGISTER first for new topics
+    fn forward(&mut self, message: Box<Message>, predefined: &HashMap<u16, String>) -> Vec<Packet> {
+        let name = message.topic.path();
+        let mut packets = Vec::new();
+        let topic = match predefined.iter().find(|&(_, topic)| *topic == name) {
+            Some((&id, _)) => Topic::Predefined(id),
+            None if name.len() == 2 => {
+                let bytes = name.as_bytes();
+                Topic::Short([bytes[0], bytes[1]])
+            },
+            None => match self.topic_ids.get(&name).cloned() {
+                Some(id) => Topic::Id(id),
+                None => {
+                    let id = self.register(name.clone());
+                    let msg_id = self.next_msg_id();
+                    packets.push(Packet::Register(Box::new(Register {
+                        topic_id: id,
+                        msg_id: msg_id,
+                        topic_name: name
+                    })));
+                    Topic::Id(id)
+                }
+            }
+        };
+        let qos = QoS::from_mqtt(message.qos);
+        let msg_id = if qos == QoS::AtMostOnce { 0 } else { self.next_msg_id() };
+        packets.push(Packet::Publish(Box::new(Publish {
+            dup: false,
+            qos: qos,
+            retain: message.retain,
+            topic: topic,
+            msg_id: msg_id,
+            payload: message.payload.to_vec()
+        })));
+        packets
+    }
+
+    fn register(&mut self, name: String) -> u16 {
+        if let Some(&id) = self.topic_ids.get(&name) {
+            return id;
+        }
+        self.last_topic_id = next_id(self.last_topic_id);
+        if let Some(old) = self.topics.insert(self.last_topic_id, name.clone()) {
+            self.topic_ids.remove(&old);
+        }
+        self.topic_ids.insert(name, self.last_topic_id);
+        self.last_topic_id
+    }
+
+    fn next_msg_id(&mut self) -> u16 {
+        self.last_msg_id = next_id(self.last_msg_id);
+        self.last_msg_id
+    }
+
+    fn expired(&self) -> bool {
+        self.duration != Duration::from_secs(0) && self.last_seen.elapsed() > self.duration * 3 / 2
+    }
+
+    /// Disconnects from the broker, the will is dropped
+    fn close(self) {
+        if let Err(err) = self.client.disconnect_gracefully(Duration::from_millis(DISCONNECT_TIMEOUT_MS)) {
+            warn!("{} {:?}", self.client_id, err);
+        }
+    }
+
+    /// Drops the broker connection, which publishes the will
+    fn abort(self) {
+        let _ = self.client.disconnect();
+    }
+}
+
+// Ids are never zero
+fn next_id(id: u16) -> u16 {
+    if id == u16::max_value() { 1 } else { id + 1 }
+}
+
+fn topic_name(topic: &Topic, registered: &HashMap<u16, String>, predefined: &HashMap<u16, String>) -> Option<String> {
+    match *topic {
+        Topic::Name(ref name) => Some(name.clone()),
+        Topic::Id(id) => registered.get(&id).cloned(),
+        Topic::Predefined(id) => predefined.get(&id).cloned(),
+        Topic::Short(name) => String::from_utf8(name.to_vec()).ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::net::{SocketAddr, UdpSocket};
+    use std::thread;
+    use std::time::Duration;
+    use netopt::{NetworkStream, NetworkOptions};
+    use netopt::mock::MockStream;
+    use {Error, QoS, ReturnCode, Topic};
+    use packet::*;
+    use super::{Gateway, GatewayOptions};
+
+    fn gateway(broker: &MockStream) -> Gateway {
+        let broker = broker.clone();
+        let mut opts = GatewayOptions::new();
+        opts.set_network_options(move || {
+            let mut netopt = NetworkOptions::new();
+            netopt.attach(NetworkStream::Mock(broker.clone()));
+            netopt
+        });
+        opts.bind("127.0.0.1:0", "127.0.0.1:1883").unwrap()
+    }
+
+    fn sensor() -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        socket
+    }
+
+    fn recv(socket: &UdpSocket) -> Packet {
+        let mut buf = [0; 1024];
+        let (len, _) = socket.recv_from(&mut buf).unwrap();
+        Packet::decode(&buf[..len]).unwrap()
+    }
+
+    fn connect(will: bool) -> Packet {
+        Packet::Connect(Box::new(Connect {
+            will: will,
+            clean_session: true,
+            duration: 60,
+            client_id: "sensor".to_string()
+        }))
+    }
+
+    #[test]
+    fn gateway_session_test() {
+        let mut broker = MockStream::with_vec(vec![
+            0b00100000, 0x02, 0x00, 0x00, // connack
+            0x30, 0x07, 0x00, 0x03, b'a', b'/', b'b', b'h', b'i' // publish
+        ]);
+        let mut gateway = gateway(&broker);
+        let sensor = sensor();
+        let addr = sensor.local_addr().unwrap();
+
+        gateway.handle(addr, Packet::SearchGw(1)).unwrap();
+        assert_eq!(recv(&sensor), Packet::GwInfo(GwInfo { gw_id: 1, gw_addr: vec![] }));
+
+        gateway.handle(addr, connect(false)).unwrap();
+        assert_eq!(recv(&sensor), Packet::Connack(ReturnCode::Accepted));
+        let written = broker.take_vec();
+        assert_eq!(written[0], 0x10);
+        assert!(written.windows(6).any(|bytes| bytes == b"sensor"));
+
+        let register = Register { topic_id: 0, msg_id: 1, topic_name: "c/d".to_string() };
+        gateway.handle(addr, Packet::Register(Box::new(register))).unwrap();
+        assert_eq!(recv(&sensor), Packet::Regack(Regack { topic_id: 1, msg_id: 1, code: ReturnCode::Accepted }));
+
+        let publish = Publish {
+            dup: false,
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            topic: Topic::Id(7),
+            msg_id: 2,
+            payload: vec![]
+        };
+        gateway.handle(addr, Packet::Publish(Box::new(publish))).unwrap();
+        assert_eq!(recv(&sensor), Packet::Puback(Puback {
+            topic_id: 7,
+            msg_id: 2,
+            code: ReturnCode::RejectedInvalidTopicId
+        }));
+
+        // The message from the broker comes with its topic registered, then
+        // the mock runs out of data, which ends the session
+        for _ in 0..100 {
+            gateway.deliver().unwrap();
+            if gateway.sessions.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(recv(&sensor), Packet::Register(Box::new(Register {
+            topic_id: 2,
+            msg_id: 1,
+            topic_name: "a/b".to_string()
+        })));
+        assert_eq!(recv(&sensor), Packet::Publish(Box::new(Publish {
+            dup: false,
+            qos: QoS::AtMostOnce,
+            retain: false,
+            topic: Topic::Id(2),
+            msg_id: 0,
+            payload: b"hi".to_vec()
+        })));
+        assert_eq!(recv(&sensor), Packet::Disconnect(None));
+    }
+
+    #[test]
+    fn gateway_will_test() {
+        let mut broker = MockStream::with_vec(vec![0b00100000, 0x02, 0x00, 0x00]);
+        let mut gateway = gateway(&broker);
+        let sensor = sensor();
+        let addr = sensor.local_addr().unwrap();
+
+        gateway.handle(addr, connect(true)).unwrap();
+        assert_eq!(recv(&sensor), Packet::WillTopicReq);
+        let will = WillTopic { qos: QoS::AtLeastOnce, retain: false, topic: "w".to_string() };
+        gateway.handle(addr, Packet::WillTopic(Some(will))).unwrap();
+        assert_eq!(recv(&sensor), Packet::WillMsgReq);
+        gateway.handle(addr, Packet::WillMsg(b"gone".to_vec())).unwrap();
+        assert_eq!(recv(&sensor), Packet::Connack(ReturnCode::Accepted));
+
+        // CONNECT with the will flag and QoS 1
+        let written = broker.take_vec();
+        assert_eq!(written[9] & 0b00011100, 0b00001100);
+        assert!(written.windows(4).any(|bytes| bytes == b"gone"));
+    }
+
+    #[test]
+    fn gateway_no_broker_address_test() {
+        let none: &[SocketAddr] = &[];
+        match GatewayOptions::new().bind("127.0.0.1:0", none) {
+            Err(Error::Io(ref err)) if err.kind() == io::ErrorKind::AddrNotAvailable => (),
+            _ => panic!("empty broker address should fail")
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//! MQTT-SN 1.2 packets and a gateway which connects MQTT-SN clients, such as
+//! sensors on a UDP network, to a regular MQTT broker.
+
+#[macro_use] extern crate log;
+extern crate mqtt3;
+extern crate mqttc;
+extern crate netopt;
+
+mod error;
+mod packet;
+mod codec;
+mod gateway;
+
+pub use error::{
+    Error,
+    Result
+};
+
+pub use packet::{
+    Packet,
+    Advertise,
+    GwInfo,
+    Connect,
+    WillTopic,
+    Register,
+    Regack,
+    Publish,
+    Puback,
+    Subscribe,
+    Suback,
+    Unsubscribe
+};
+
+pub use gateway::{
+    Gateway,
+    GatewayOptions
+};
+
+const PROTOCOL_ID: u8 = 0x01;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QoS {
+    AtMostOnce,
+    AtLeastOnce,
+    ExactlyOnce,
+    /// QoS -1, publishing without connecting first. Only predefined and
+    /// short topic ids can be used.
+    MinusOne
+}
+
+impl QoS {
+    pub fn from_flags(flags: u8) -> QoS {
+        match (flags & 0b01100000) >> 5 {
+            0 => QoS::AtMostOnce,
+            1 => QoS::AtLeastOnce,
+            2 => QoS::ExactlyOnce,
+            _ => QoS::MinusOne
+        }
+    }
+
+    pub fn to_flags(&self) -> u8 {
+        let bits = match *self {
+            QoS::AtMostOnce => 0,
+            QoS::AtLeastOnce => 1,
+            QoS::ExactlyOnce => 2,
+            QoS::MinusOne => 3
+        };
+        bits << 5
+    }
+
+    /// The QoS used with the broker, -1 becomes 0
+    pub fn to_mqtt(&self) -> mqtt3::QoS {
+        match *self {
+            QoS::AtMostOnce | QoS::MinusOne => mqtt3::QoS::AtMostOnce,
+            QoS::AtLeastOnce => mqtt3::QoS::AtLeastOnce,
+            QoS::ExactlyOnce => mqtt3::QoS::ExactlyOnce
+        }
+    }
+
+    pub fn from_mqtt(qos: mqtt3::QoS) -> QoS {
+        match qos {
+            mqtt3::QoS::AtMostOnce => QoS::AtMostOnce,
+            mqtt3::QoS::AtLeastOnce => QoS::AtLeastOnce,
+            mqtt3::QoS::ExactlyOnce => QoS::ExactlyOnce
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReturnCode {
+    Accepted,
+    RejectedCongestion,
+    RejectedInvalidTopicId,
+    RejectedNotSupported
+}
+
+impl ReturnCode {
+    pub fn to_u8(&self) -> u8 {
+        match *self {
+            ReturnCode::Accepted => 0,
+            ReturnCode::RejectedCongestion => 1,
+            ReturnCode::RejectedInvalidTopicId => 2,
+            ReturnCode::RejectedNotSupported => 3
+        }
+    }
+
+    pub fn from_u8(byte: u8) -> Result<ReturnCode> {
+        match byte {
+            0 => Ok(ReturnCode::Accepted),
+            1 => Ok(ReturnCode::RejectedCongestion),
+            2 => Ok(ReturnCode::RejectedInvalidTopicId),
+            3 => Ok(ReturnCode::RejectedNotSupported),
+            _ => Err(Error::UnsupportedReturnCode)
+        }
+    }
+}
+
+/// How a PUBLISH, SUBSCRIBE or UNSUBSCRIBE refers to its topic
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Topic {
+    /// Full topic name, SUBSCRIBE and UNSUBSCRIBE only
+    Name(String),
+    /// Id registered with REGISTER or returned in SUBACK, PUBLISH only
+    Id(u16),
+    /// Id both sides know in advance
+    Predefined(u16),
+    /// Two character topic name
+    Short([u8; 2])
+}
+
+impl Topic {
+    pub fn id_type(&self) -> u8 {
+        match *self {
+            Topic::Name(_) | Topic::Id(_) => 0b00,
+            Topic::Predefined(_) => 0b01,
+            Topic::Short(_) => 0b10
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mqtt3;
+    use super::QoS;
+
+    #[test]
+    fn qos_flags_test() {
+        for qos in [QoS::AtMostOnce, QoS::AtLeastOnce, QoS::ExactlyOnce, QoS::MinusOne].iter() {
+            assert_eq!(QoS::from_flags(qos.to_flags() | 0b10011111), *qos);
+        }
+        assert_eq!(QoS::MinusOne.to_mqtt(), mqtt3::QoS::AtMostOnce);
+        assert_eq!(QoS::from_mqtt(mqtt3::QoS::ExactlyOnce), QoS::ExactlyOnce);
+    }
+}
@@ -0,0 +1,114 @@
+use {QoS, ReturnCode, Topic};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Packet {
+    Advertise(Advertise),
+    SearchGw(u8), // radius
+    GwInfo(GwInfo),
+    Connect(Box<Connect>),
+    Connack(ReturnCode),
+    WillTopicReq,
+    // None deletes the will
+    WillTopic(Option<WillTopic>),
+    WillMsgReq,
+    WillMsg(Vec<u8>),
+    Register(Box<Register>),
+    Regack(Regack),
+    Publish(Box<Publish>),
+    Puback(Puback),
+    Pubcomp(u16),
+    Pubrec(u16),
+    Pubrel(u16),
+    Subscribe(Box<Subscribe>),
+    Suback(Suback),
+    Unsubscribe(Box<Unsubscribe>),
+    Unsuback(u16),
+    // The client id is sent by sleeping clients checking for messages
+    Pingreq(Option<String>),
+    Pingresp,
+    // The duration in seconds makes the client go to sleep
+    Disconnect(Option<u16>)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Advertise {
+    pub gw_id: u8,
+    pub duration: u16
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GwInfo {
+    pub gw_id: u8,
+    // Only filled in when a client answers for the gateway
+    pub gw_addr: Vec<u8>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Connect {
+    pub will: bool,
+    pub clean_session: bool,
+    pub duration: u16,
+    pub client_id: String
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WillTopic {
+    pub qos: QoS,
+    pub retain: bool,
+    pub topic: String
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Register {
+    pub topic_id: u16,
+    pub msg_id: u16,
+    pub topic_name: String
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Regack {
+    pub topic_id: u16,
+    pub msg_id: u16,
+    pub code: ReturnCode
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Publish {
+    pub dup: bool,
+    pub qos: QoS,
+    pub retain: bool,
+    pub topic: Topic,
+    // Zero for QoS 0 and -1
+    pub msg_id: u16,
+    pub payload: Vec<u8>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Puback {
+    pub topic_id: u16,
+    pub msg_id: u16,
+    pub code: ReturnCode
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Subscribe {
+    pub dup: bool,
+    pub qos: QoS,
+    pub msg_id: u16,
+    pub topic: Topic
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Suback {
+    pub qos: QoS,
+    // Zero when subscribed to a wildcard filter
+    pub topic_id: u16,
+    pub msg_id: u16,
+    pub code: ReturnCode
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Unsubscribe {
+    pub msg_id: u16,
+    pub topic: Topic
+}
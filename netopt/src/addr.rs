@@ -0,0 +1,71 @@
+//! Host and port in the text form of addresses, with IPv6 literals in
+//! brackets as in URLs.
+
+use std::io;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+/// `host:port`, with an IPv6 literal in brackets so the result goes to
+/// `connect` and `bind` as it is
+pub fn join_host_port(host: &str, port: u16) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Splits `host`, `host:port`, `[ipv6]` or `[ipv6]:port`, with
+/// `default_port` if the port is left out. An IPv6 literal without
+/// brackets is taken as a host alone. The host comes without brackets.
+pub fn split_host_port(addr: &str, default_port: u16) -> io::Result<(String, u16)> {
+    let (host, port) = if addr.starts_with('[') {
+        let end = try!(addr.find(']').ok_or_else(|| invalid("missing ] in address")));
+        match &addr[end + 1..] {
+            "" => (&addr[1..end], None),
+            rest if rest.starts_with(':') => (&addr[1..end], Some(&rest[1..])),
+            _ => return Err(invalid("unexpected text after ] in address"))
+        }
+    } else {
+        match addr.rfind(':') {
+            Some(i) if !addr[..i].contains(':') => (&addr[..i], Some(&addr[i + 1..])),
+            _ => (addr, None)
+        }
+    };
+    if host.is_empty() {
+        return Err(invalid("empty host in address"));
+    }
+    let port = match port {
+        Some(port) => try!(port.parse().map_err(|_| invalid("invalid port in address"))),
+        None => default_port
+    };
+    Ok((host.to_string(), port))
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{SocketAddr, ToSocketAddrs};
+    use super::{join_host_port, split_host_port};
+
+    #[test]
+    fn host_port_test() {
+        let split = |addr| split_host_port(addr, 1883).ok();
+        assert_eq!(split("localhost"), Some(("localhost".to_string(), 1883)));
+        assert_eq!(split("10.0.0.1:8883"), Some(("10.0.0.1".to_string(), 8883)));
+        assert_eq!(split("[::1]"), Some(("::1".to_string(), 1883)));
+        assert_eq!(split("[fe80::1%eth0]:8883"), Some(("fe80::1%eth0".to_string(), 8883)));
+        assert_eq!(split("::1"), Some(("::1".to_string(), 1883)));
+        assert_eq!(split("[::1"), None);
+        assert_eq!(split("[::1]8883"), None);
+        assert_eq!(split("[]:8883"), None);
+        assert_eq!(split("host:port"), None);
+
+        assert_eq!(join_host_port("::1", 8883), "[::1]:8883");
+        assert_eq!(join_host_port("[::1]", 8883), "[::1]:8883");
+        assert_eq!(join_host_port("10.0.0.1", 1883), "10.0.0.1:1883");
+        let addr: Vec<SocketAddr> = join_host_port("::1", 1883).to_socket_addrs().unwrap().collect();
+        assert_eq!(addr, vec!["[::1]:1883".parse().unwrap()]);
+    }
+}
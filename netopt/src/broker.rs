@@ -0,0 +1,417 @@
+use std::collections::HashMap;
+use std::io::{self, Cursor, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Sender, Receiver, RecvTimeoutError};
+use std::time::Duration;
+use mqtt3::{self, MqttRead, MqttWrite, Packet, Connack, ConnectReturnCode, Publish, Suback,
+            SubscribeReturnCodes, Message, LastWill, PacketIdentifier, QoS, TopicPath};
+
+// A connection from a client
+struct Conn {
+    sender: Sender<Vec<u8>>,
+    // Bytes written by the client, not a whole packet yet
+    input: Vec<u8>,
+    // Set by CONNECT
+    client_id: Option<String>,
+    will: Option<LastWill>,
+    last_pid: PacketIdentifier
+}
+
+struct Session {
+    subscriptions: Vec<(TopicPath, QoS)>,
+    clean_session: bool
+}
+
+#[derive(Default)]
+struct State {
+    conns: HashMap<usize, Conn>,
+    next_conn: usize,
+    sessions: HashMap<String, Session>,
+    retained: HashMap<String, Message>,
+    published: Vec<Message>
+}
+
+/// Broker living in the process, for tests that need no network. Clients
+/// connect to it with `NetworkOptions::attach_broker` and talk MQTT 3.1.1
+/// over a `ChannelStream`; it answers right away in the thread that writes.
+///
+/// Subscriptions, retained messages, wills and persistent sessions work
+/// like on a real broker, but messages for clients that are away aren't
+/// kept and nothing is authenticated.
+#[derive(Clone, Default)]
+pub struct MockBroker {
+    state: Arc<Mutex<State>>
+}
+
+impl MockBroker {
+    pub fn new() -> MockBroker {
+        MockBroker::default()
+    }
+
+    /// New connection to the broker, which is what `NetworkOptions`
+    /// connects with
+    pub fn connect(&self) -> ChannelStream {
+        let (sender, receiver) = mpsc::channel();
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_conn;
+        state.next_conn += 1;
+        state.conns.insert(id, Conn {
+            sender: sender,
+            input: Vec::new(),
+            client_id: None,
+            will: None,
+            last_pid: PacketIdentifier::zero()
+        });
+        ChannelStream {
+            broker: self.clone(),
+            conn: id,
+            receiver: Arc::new(Mutex::new(receiver)),
+            buffer: Arc::new(Mutex::new(Cursor::new(Vec::new()))),
+            read_timeout: Arc::new(Mutex::new(None))
+        }
+    }
+
+    /// Messages published by the clients so far, wills included
+    pub fn published(&self) -> Vec<Message> {
+        self.state.lock().unwrap().published.clone()
+    }
+
+    /// Topic filters of the session of `client_id` with their QoS
+    pub fn subscriptions(&self, client_id: &str) -> Vec<(String, QoS)> {
+        match self.state.lock().unwrap().sessions.get(client_id) {
+            Some(session) => session.subscriptions.iter().map(|&(ref filter, qos)| (filter.path.clone(), qos)).collect(),
+            None => Vec::new()
+        }
+    }
+
+    /// Ids of the clients connected
+    pub fn clients(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        let mut clients: Vec<String> = state.conns.values().filter_map(|conn| conn.client_id.clone()).collect();
+        clients.sort();
+        clients
+    }
+
+    pub fn retained(&self, topic: &str) -> Option<Message> {
+        self.state.lock().unwrap().retained.get(topic).cloned()
+    }
+
+    /// Retained messages on the topics `filter` matches, in topic order.
+    /// Fails if the filter isn't valid.
+    pub fn retained_matching(&self, filter: &str) -> mqtt3::Result<Vec<Message>> {
+        try!(mqtt3::validate_topic_filter(filter));
+        let filter = try!(TopicPath::from_str(filter));
+        let mut messages: Vec<Message> = self.state.lock().unwrap().retained.values()
+            .filter(|message| message.topic.matches(&filter))
+            .cloned()
+            .collect();
+        messages.sort_by(|a, b| a.topic.path.cmp(&b.topic.path));
+        Ok(messages)
+    }
+
+    /// Sends a message to the subscribers as if a client published it
+    pub fn publish(&self, topic: &str, payload: &[u8], qos: QoS, retain: bool) {
+        let mut message = Message::new(TopicPath::from(topic), Arc::new(payload.to_vec()), qos);
+        message.retain = retain;
+        self.state.lock().unwrap().route(message);
+    }
+
+    /// Cuts the connection of `client_id` without a DISCONNECT, like a
+    /// network failure. Its will is published. False if it isn't
+    /// connected.
+    pub fn drop_client(&self, client_id: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match state.conn_of(client_id) {
+            Some(id) => {
+                state.close(id, false);
+                true
+            }
+            None => false
+        }
+    }
+
+    fn write(&self, conn: usize, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        match state.conns.get_mut(&conn) {
+            Some(conn) => conn.input.extend_from_slice(buf),
+            None => return Err(io::Error::new(io::ErrorKind::BrokenPipe, "connection closed by the broker"))
+        }
+        while let Some(packet) = state.next_packet(conn) {
+            state.handle(conn, packet);
+        }
+        Ok(buf.len())
+    }
+
+    fn shutdown(&self, conn: usize) {
+        self.state.lock().unwrap().close(conn, false);
+    }
+}
+
+impl State {
+    fn conn_of(&self, client_id: &str) -> Option<usize> {
+        self.conns.iter()
+            .find(|&(_, conn)| conn.client_id.as_ref().map_or(false, |id| id == client_id))
+            .map(|(&id, _)| id)
+    }
+
+    fn send(&self, conn: usize, packet: &Packet) {
+        if let Some(conn) = self.conns.get(&conn) {
+            let mut encoded = Cursor::new(Vec::with_capacity(packet.size()));
+            encoded.write_packet(packet).unwrap();
+            let _ = conn.sender.send(encoded.into_inner());
+        }
+    }
+
+    // Next whole packet written on `conn`. A malformed one closes it.
+    fn next_packet(&mut self, id: usize) -> Option<Packet> {
+        let result = {
+            let conn = match self.conns.get_mut(&id) {
+                Some(conn) => conn,
+                None => return None
+            };
+            if conn.input.len() < 2 {
+                return None;
+            }
+            match mqtt3::decode_remaining_length(&conn.input[1..]) {
+                Ok(Some((len, size))) if conn.input.len() >= 1 + size + len => {
+                    let bytes: Vec<u8> = conn.input.drain(..1 + size + len).collect();
+                    Cursor::new(bytes).read_packet().map_err(|_| ())
+                }
+                Ok(_) => return None,
+                Err(_) => Err(())
+            }
+        };
+        match result {
+            Ok(packet) => Some(packet),
+            Err(_) => {
+                self.close(id, false);
+                None
+            }
+        }
+    }
+
+    fn handle(&mut self, id: usize, packet: Packet) {
+        let connected = self.conns.get(&id).map_or(false, |conn| conn.client_id.is_some());
+        match packet {
+            Packet::Connect(connect) => {
+                if connected {
+                    return self.close(id, false);
+                }
+                // A client connecting again takes over the session
+                if let Some(old) = self.conn_of(&connect.client_id) {
+                    self.close(old, false);
+                }
+                if connect.clean_session {
+                    self.sessions.remove(&connect.client_id);
+                }
+                let session_present = self.sessions.contains_key(&connect.client_id);
+                self.sessions.entry(connect.client_id.clone()).or_insert(Session {
+                    subscriptions: Vec::new(),
+                    clean_session: true
+                }).clean_session = connect.clean_session;
+                {
+                    let conn = self.conns.get_mut(&id).unwrap();
+                    conn.client_id = Some(connect.client_id.clone());
+                    conn.will = connect.last_will.clone();
+                }
+                self.send(id, &Packet::Connack(Connack {
+                    session_present: session_present,
+                    code: ConnectReturnCode::Accepted
+                }));
+            }
+            // Nothing else before CONNECT
+            _ if !connected => self.close(id, false),
+            Packet::Publish(publish) => {
+                let (qos, pid) = (publish.qos, publish.pid);
+                match Message::from_pub(publish) {
+                    Ok(message) => {
+                        self.published.push((*message).clone());
+                        self.route(*message);
+                    }
+                    Err(_) => return self.close(id, false)
+                }
+                match (qos, pid) {
+                    (QoS::AtLeastOnce, Some(pid)) => self.send(id, &Packet::Puback(pid)),
+                    (QoS::ExactlyOnce, Some(pid)) => self.send(id, &Packet::Pubrec(pid)),
+                    _ => ()
+                }
+            }
+            Packet::Pubrel(pid) => self.send(id, &Packet::Pubcomp(pid)),
+            Packet::Pubrec(pid) => self.send(id, &Packet::Pubrel(pid)),
+            Packet::Puback(_) | Packet::Pubcomp(_) => (),
+            Packet::Subscribe(subscribe) => {
+                let client_id = self.conns[&id].client_id.clone().unwrap();
+                let mut return_codes = Vec::new();
+                let mut filters = Vec::new();
+                for topic in &subscribe.topics {
+                    let filter = mqtt3::validate_topic_filter(&topic.topic_path)
+                                     .and_then(|_| TopicPath::from_str(&topic.topic_path));
+                    match filter {
+                        Ok(filter) => {
+                            let subscriptions = &mut self.sessions.get_mut(&client_id).unwrap().subscriptions;
+                            subscriptions.retain(|&(ref path, _)| path.path != filter.path);
+                            subscriptions.push((filter.clone(), topic.qos));
+                            return_codes.push(SubscribeReturnCodes::Success(topic.qos));
+                            filters.push((filter, topic.qos));
+                        }
+                        Err(_) => return_codes.push(SubscribeReturnCodes::Failure)
+                    }
+                }
+                self.send(id, &Packet::Suback(Box::new(Suback {
+                    pid: subscribe.pid,
+                    return_codes: return_codes
+                })));
+                let mut retained: Vec<(Message, QoS)> = Vec::new();
+                for (filter, qos) in filters {
+                    for message in self.retained.values() {
+                        if message.topic.matches(&filter) {
+                            retained.push((message.clone(), qos));
+                        }
+                    }
+                }
+                for (message, qos) in retained {
+                    self.deliver(id, &message, qos, true);
+                }
+            }
+            Packet::Unsubscribe(unsubscribe) => {
+                let client_id = self.conns[&id].client_id.clone().unwrap();
+                let subscriptions = &mut self.sessions.get_mut(&client_id).unwrap().subscriptions;
+                subscriptions.retain(|&(ref filter, _)| !unsubscribe.topics.contains(&filter.path));
+                self.send(id, &Packet::Unsuback(unsubscribe.pid));
+            }
+            Packet::Pingreq => self.send(id, &Packet::Pingresp),
+            Packet::Disconnect => self.close(id, true),
+            // Only the broker sends the others
+            _ => self.close(id, false)
+        }
+    }
+
+    // Stores a retained message and sends it to the subscribers
+    fn route(&mut self, message: Message) {
+        if message.retain {
+            if message.payload.is_empty() {
+                self.retained.remove(&message.topic.path);
+            } else {
+                self.retained.insert(message.topic.path.clone(), message.clone());
+            }
+        }
+        let mut deliveries = Vec::new();
+        for (&id, conn) in &self.conns {
+            let session = match conn.client_id.as_ref().and_then(|client_id| self.sessions.get(client_id)) {
+                Some(session) => session,
+                None => continue
+            };
+            // The highest QoS of the matching subscriptions
+            let qos = session.subscriptions.iter()
+                             .filter(|&&(ref filter, _)| message.topic.matches(filter))
+                             .map(|&(_, qos)| qos.to_u8())
+                             .max();
+            if let Some(qos) = qos {
+                deliveries.push((id, QoS::from_u8(qos).unwrap()));
+            }
+        }
+        for (id, qos) in deliveries {
+            self.deliver(id, &message, qos, false);
+        }
+    }
+
+    fn deliver(&mut self, id: usize, message: &Message, max_qos: QoS, retain: bool) {
+        let qos = if message.qos.to_u8() < max_qos.to_u8() { message.qos } else { max_qos };
+        let pid = match qos {
+            QoS::AtMostOnce => None,
+            _ => {
+                let conn = self.conns.get_mut(&id).unwrap();
+                conn.last_pid = match conn.last_pid.0 {
+                    65535 => PacketIdentifier(1),
+                    pid => PacketIdentifier(pid + 1)
+                };
+                Some(conn.last_pid)
+            }
+        };
+        self.send(id, &Packet::Publish(Box::new(Publish {
+            dup: false,
+            qos: qos,
+            retain: retain,
+            topic_name: message.topic.path.clone(),
+            pid: pid,
+            payload: message.payload.clone()
+        })));
+    }
+
+    // Ends a connection, the will is published unless it was a DISCONNECT
+    fn close(&mut self, id: usize, clean: bool) {
+        let conn = match self.conns.remove(&id) {
+            Some(conn) => conn,
+            None => return
+        };
+        if let Some(client_id) = conn.client_id {
+            if self.sessions.get(&client_id).map_or(false, |session| session.clean_session) {
+                self.sessions.remove(&client_id);
+            }
+        }
+        if let (false, Some(will)) = (clean, conn.will) {
+            let message = *Message::from_last_will(will);
+            self.published.push(message.clone());
+            self.route(message);
+        }
+    }
+}
+
+/// Client side of a connection to a `MockBroker`
+#[derive(Clone)]
+pub struct ChannelStream {
+    broker: MockBroker,
+    conn: usize,
+    receiver: Arc<Mutex<Receiver<Vec<u8>>>>,
+    // What is left of the last data received
+    buffer: Arc<Mutex<Cursor<Vec<u8>>>>,
+    read_timeout: Arc<Mutex<Option<Duration>>>
+}
+
+impl ChannelStream {
+    pub fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        *self.read_timeout.lock().unwrap() = dur;
+        Ok(())
+    }
+
+    /// Closes the connection, the broker publishes the will if no
+    /// DISCONNECT was written
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.broker.shutdown(self.conn);
+        Ok(())
+    }
+}
+
+impl Read for ChannelStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut buffer = self.buffer.lock().unwrap();
+        if buffer.position() as usize == buffer.get_ref().len() {
+            let receiver = self.receiver.lock().unwrap();
+            let timeout = *self.read_timeout.lock().unwrap();
+            let data = match timeout {
+                Some(timeout) => match receiver.recv_timeout(timeout) {
+                    Ok(data) => data,
+                    Err(RecvTimeoutError::Timeout) => return Err(io::Error::new(io::ErrorKind::WouldBlock, "read timed out")),
+                    // Closed by the broker
+                    Err(RecvTimeoutError::Disconnected) => return Ok(0)
+                },
+                None => match receiver.recv() {
+                    Ok(data) => data,
+                    Err(_) => return Ok(0)
+                }
+            };
+            *buffer = Cursor::new(data);
+        }
+        buffer.read(buf)
+    }
+}
+
+impl Write for ChannelStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.broker.write(self.conn, buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
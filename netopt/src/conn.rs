@@ -1,44 +1,116 @@
-use mqtt3::{MqttRead, MqttWrite};
-use std::io::{self, Read, Write};
+use mqtt3::{MqttRead, MqttWrite, BufferPool, Packet};
+use std::io::{self, ErrorKind, IoSlice, Read, Write};
 use std::net::Shutdown;
+use std::sync::Arc;
 use std::time::Duration;
 use {NetworkStream, NetworkReader, NetworkWriter};
 
+// Payload buffers kept for reuse
+const POOLED_BUFFERS: usize = 16;
+// Bytes written at once, as many as `BufWriter` holds
+const WRITE_BUFFER: usize = 8 * 1024;
+
+/// MQTT connection over a stream it owns alone. Reads and writes are
+/// buffered apart on the same stream, so it is never cloned.
 pub struct Connection {
-    stream: NetworkStream,
     reader: NetworkReader,
-    writer: NetworkWriter
+    output: Vec<u8>,
+    pool: BufferPool
 }
 
 impl Connection {
-    pub fn new(stream: &NetworkStream) -> io::Result<Connection> {
-        Ok(Connection {
-            stream: try!(stream.try_clone()),
-            reader: NetworkReader::new(try!(stream.try_clone())),
-            writer: NetworkWriter::new(try!(stream.try_clone()))
-        })
+    pub fn new(stream: NetworkStream) -> Connection {
+        Connection {
+            reader: NetworkReader::new(stream),
+            output: Vec::with_capacity(WRITE_BUFFER),
+            pool: BufferPool::new(POOLED_BUFFERS)
+        }
+    }
+
+    pub fn stream(&self) -> &NetworkStream {
+        self.reader.get_ref()
     }
 
     pub fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
-        self.stream.set_read_timeout(dur)
+        self.stream().set_read_timeout(dur)
+    }
+
+    /// True if a read can be served from the buffer, without touching the
+    /// stream
+    pub fn has_buffered_input(&self) -> bool {
+        !self.reader.buffer().is_empty() || self.stream().has_pending_input()
+    }
+
+    /// Gives the payload of a read PUBLISH back, the next one can reuse its
+    /// buffer
+    pub fn recycle(&mut self, payload: Arc<Vec<u8>>) {
+        self.pool.recycle(payload)
     }
 
     pub fn terminate(&self) -> io::Result<()> {
-        self.stream.shutdown(Shutdown::Both)
+        self.stream().shutdown(Shutdown::Both)
     }
 
-    pub fn split(self) -> (NetworkReader, NetworkWriter) {
-        (self.reader, self.writer)
+    /// Splits into halves which can be used from two threads, the only
+    /// place the stream is cloned
+    pub fn split(self) -> io::Result<(NetworkReader, NetworkWriter)> {
+        let mut writer = NetworkWriter::new(try!(self.stream().try_clone()));
+        try!(writer.write_all(&self.output));
+        Ok((self.reader, writer))
+    }
+
+    // Writes out the buffer, what the stream didn't take stays in it
+    fn _flush_output(&mut self) -> io::Result<()> {
+        let mut written = 0;
+        let mut result = Ok(());
+        while written < self.output.len() {
+            match self.reader.get_mut().write(&self.output[written..]) {
+                Ok(0) => {
+                    result = Err(io::Error::new(ErrorKind::WriteZero, "failed to write the buffered data"));
+                    break;
+                }
+                Ok(n) => written += n,
+                Err(ref err) if err.kind() == ErrorKind::Interrupted => (),
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            }
+        }
+        self.output.drain(..written);
+        result
     }
 }
 
 impl Write for Connection {
     fn write(&mut self, msg: &[u8]) -> io::Result<usize> {
-        self.writer.write(msg)
+        if self.output.len() + msg.len() > WRITE_BUFFER {
+            try!(self._flush_output());
+            if msg.len() >= WRITE_BUFFER {
+                return self.reader.get_mut().write(msg);
+            }
+        }
+        self.output.extend_from_slice(msg);
+        Ok(msg.len())
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        let len = bufs.iter().map(|buf| buf.len()).sum::<usize>();
+        if self.output.len() + len > WRITE_BUFFER {
+            try!(self._flush_output());
+            if len >= WRITE_BUFFER {
+                return self.reader.get_mut().write_vectored(bufs);
+            }
+        }
+        for buf in bufs {
+            self.output.extend_from_slice(buf);
+        }
+        Ok(len)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        self.writer.flush()
+        try!(self._flush_output());
+        self.reader.get_mut().flush()
     }
 }
 
@@ -48,5 +120,43 @@ impl Read for Connection {
     }
 }
 
-impl MqttRead for Connection {}
+impl MqttRead for Connection {
+    fn read_fixed_packet(&mut self) -> Option<Packet> {
+        self.reader.read_fixed_packet()
+    }
+
+    fn payload_buffer(&mut self, len: usize) -> Vec<u8> {
+        self.pool.take(len)
+    }
+}
 impl MqttWrite for Connection {}
+
+#[cfg(test)]
+mod test {
+    use mqtt3::{MqttRead, Packet};
+    use mock::MockStream;
+    use super::Connection;
+    use NetworkStream;
+
+    #[test]
+    fn connection_recycle_test() {
+        let stream = NetworkStream::Mock(MockStream::with_vec(vec![
+            0x30, 0x05, 0x00, 0x01, b'a', 0x01, 0x02,
+            0x30, 0x05, 0x00, 0x01, b'b', 0x03, 0x04
+        ]));
+        let mut conn = Connection::new(stream);
+        let first = match conn.read_packet().unwrap() {
+            Packet::Publish(publish) => publish.payload,
+            packet => panic!("unexpected {:?}", packet)
+        };
+        let ptr = first.as_ptr();
+        conn.recycle(first);
+        match conn.read_packet().unwrap() {
+            Packet::Publish(publish) => {
+                assert_eq!(*publish.payload, vec![0x03, 0x04]);
+                assert_eq!(publish.payload.as_ptr(), ptr);
+            }
+            packet => panic!("unexpected {:?}", packet)
+        }
+    }
+}
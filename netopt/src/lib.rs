@@ -1,10 +1,27 @@
 extern crate mqtt3;
+extern crate polling;
+extern crate socket2;
 #[cfg(feature = "ssl")]
 extern crate openssl;
+#[cfg(feature = "quic")]
+extern crate quinn;
+#[cfg(feature = "quic")]
+extern crate rustls;
+#[cfg(feature = "quic")]
+extern crate tokio;
+#[cfg(all(test, feature = "quic"))]
+extern crate rcgen;
 
 #[cfg(feature = "ssl")]
 mod ssl;
 mod tcp;
+mod udp;
+#[cfg(feature = "quic")]
+mod quic;
+mod broker;
+mod pcap;
+mod addr;
+mod wait;
 pub mod mock;
 pub mod conn;
 
@@ -22,13 +39,50 @@ pub use ssl::{
     SslError
 };
 
+#[cfg(feature = "ssl")]
+pub use ssl::{
+    Verifier,
+    X509,
+    X509StoreContext,
+    spki_sha256
+};
+
+pub use udp::{
+    UdpStream,
+    Datagram,
+    DatagramChannel
+};
+
+#[cfg(feature = "quic")]
+pub use quic::{
+    QuicConnector,
+    QuicStream
+};
+
 pub use conn::{
     Connection
 };
 
+pub use pcap::{
+    PcapWriter,
+    CaptureStream
+};
+
+pub use wait::{
+    Waiter,
+    Waker,
+    Wake
+};
+
+pub use addr::{
+    join_host_port,
+    split_host_port
+};
+
 #[cfg(not(feature = "ssl"))]
 pub mod ssl {
     use mock::MockStream;
+    use udp::{Datagram, DatagramChannel};
     use std::net::TcpStream;
     use std::io;
     use std::error::Error;
@@ -47,5 +101,13 @@ pub mod ssl {
         pub fn connect(&self, _: TcpStream) -> Result<SslStream, io::Error> {
             panic!("ssl disabled");
         }
+
+        pub fn dtls() -> Result<SslContext, Box<SslError>> {
+            panic!("ssl disabled");
+        }
+
+        pub fn connect_datagram(&self, _: Datagram) -> Result<Box<DatagramChannel>, io::Error> {
+            panic!("ssl disabled");
+        }
     }
 }
@@ -1,28 +1,35 @@
 use std::io::{self, Cursor, Read, Write};
 use std::sync::{Mutex, Arc};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::net::{SocketAddr, Shutdown};
 use std::time::Duration;
+use mqtt3::{MqttRead, Packet, LastWill};
+
+pub use broker::{MockBroker, ChannelStream};
 
 pub type MockCursor = Cursor<Vec<u8>>;
 
 #[derive(Clone)]
 pub struct MockStream {
     reader: Arc<Mutex<MockCursor>>,
-    writer: Arc<Mutex<MockCursor>>
+    writer: Arc<Mutex<MockCursor>>,
+    dropped: Arc<AtomicBool>
 }
 
 impl MockStream {
     pub fn new() -> MockStream {
         MockStream {
             reader: Arc::new(Mutex::new(MockCursor::new(Vec::new()))),
-            writer: Arc::new(Mutex::new(MockCursor::new(Vec::new())))
+            writer: Arc::new(Mutex::new(MockCursor::new(Vec::new()))),
+            dropped: Arc::new(AtomicBool::new(false))
         }
     }
 
     pub fn with_vec(vec: Vec<u8>) -> MockStream {
         MockStream {
             reader: Arc::new(Mutex::new(MockCursor::new(vec))),
-            writer: Arc::new(Mutex::new(MockCursor::new(Vec::new())))
+            writer: Arc::new(Mutex::new(MockCursor::new(Vec::new()))),
+            dropped: Arc::new(AtomicBool::new(false))
         }
     }
 
@@ -55,6 +62,52 @@ impl MockStream {
         cur_write.get_mut().extend_from_slice(vec_read.as_slice());
     }
 
+    /// Packets written since the last `take_vec`, up to the first that
+    /// can't be decoded
+    pub fn written_packets(&self) -> Vec<Packet> {
+        let written = self.writer.lock().unwrap().get_ref().to_vec();
+        let mut cursor = Cursor::new(written);
+        let mut packets = Vec::new();
+        while let Ok(packet) = cursor.read_packet() {
+            packets.push(packet);
+        }
+        packets
+    }
+
+    /// Will registered by the last CONNECT written, `None` if it had none
+    /// or a DISCONNECT followed, which makes the broker discard it
+    pub fn last_will(&self) -> Option<LastWill> {
+        let mut will = None;
+        for packet in self.written_packets() {
+            match packet {
+                Packet::Connect(connect) => will = connect.last_will,
+                Packet::Disconnect => will = None,
+                _ => ()
+            }
+        }
+        will
+    }
+
+    /// Breaks the connection like a network failure would, reads and writes
+    /// fail from now on. Returns the will a broker would publish for it.
+    pub fn drop_connection(&self) -> Option<LastWill> {
+        self.dropped.store(true, Ordering::SeqCst);
+        self.last_will()
+    }
+
+    /// Lets the stream be read and written again after `drop_connection`
+    pub fn restore_connection(&self) {
+        self.dropped.store(false, Ordering::SeqCst);
+    }
+
+    fn check_dropped(&self) -> io::Result<()> {
+        if self.dropped.load(Ordering::SeqCst) {
+            Err(io::Error::new(io::ErrorKind::ConnectionReset, "mock connection dropped"))
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn try_clone(&self) -> io::Result<MockStream> {
         panic!("mock try_clone called");
     }
@@ -82,6 +135,7 @@ impl MockStream {
 
 impl Write for MockStream {
     fn write(&mut self, msg: &[u8]) -> io::Result<usize> {
+        try!(self.check_dropped());
         self.writer.lock().unwrap().write(msg)
     }
 
@@ -92,13 +146,14 @@ impl Write for MockStream {
 
 impl Read for MockStream {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        try!(self.check_dropped());
         self.reader.lock().unwrap().read(buf)
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::io::{Read, Write};
+    use std::io::{ErrorKind, Read, Write};
     use super::MockStream;
 
     #[test]
@@ -134,4 +189,23 @@ mod test {
         mock.read_to_end(&mut vec).unwrap();
         assert_eq!(vec, vec![8,9,10]);
     }
+
+    #[test]
+    fn drop_connection_test() {
+        let mut mock = MockStream::with_vec(vec![1]);
+        let clonned = mock.clone();
+        // CONNECT with a QoS 1 will of "bye" on "a"
+        mock.write(&[0x10, 0x14, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x04, 0x0E, 0x00, 0x0A,
+                     0x00, 0x00, 0x00, 0x01, b'a', 0x00, 0x03, b'b', b'y', b'e']).unwrap();
+        assert_eq!(mock.written_packets().len(), 1);
+        let will = clonned.drop_connection().unwrap();
+        assert_eq!((will.topic.as_str(), will.message.as_str()), ("a", "bye"));
+        assert_eq!(mock.read(&mut [0]).unwrap_err().kind(), ErrorKind::ConnectionReset);
+        assert_eq!(mock.write(&[0xE0, 0x00]).unwrap_err().kind(), ErrorKind::ConnectionReset);
+
+        // a DISCONNECT discards the will
+        mock.restore_connection();
+        mock.write(&[0xE0, 0x00]).unwrap();
+        assert!(mock.drop_connection().is_none());
+    }
 }
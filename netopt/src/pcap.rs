@@ -0,0 +1,325 @@
+//! Captures of the traffic of a stream in the pcap format, for Wireshark.
+//!
+//! The bytes of each read and write go into an IPv4/TCP segment between
+//! made up addresses that keep the port of the peer, so the MQTT dissector
+//! picks up connections to 1883. A capture starts with the TCP handshake,
+//! every stream captured into the same file is a connection of its own.
+
+use std::cmp;
+use std::fs::File;
+use std::io::{self, IoSlice, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use NetworkStream;
+
+const LINKTYPE_RAW: u32 = 101;
+const SNAPLEN: u32 = 65535;
+// Keeps the IPv4 total length in 16 bits
+const MAX_SEGMENT: usize = 65495;
+const FIRST_PORT: u16 = 49152;
+
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+/// Writes packets to a pcap file
+pub struct PcapWriter {
+    out: Box<Write + Send>,
+    // Port of the next captured connection
+    next_port: u16
+}
+
+impl PcapWriter {
+    /// Starts a capture on `out` with the pcap file header
+    pub fn new<W: Write + Send + 'static>(mut out: W) -> io::Result<PcapWriter> {
+        let mut header = Vec::with_capacity(24);
+        push_u32_le(&mut header, 0xA1B2C3D4);
+        header.extend_from_slice(&[2, 0, 4, 0]);
+        // Time zone and accuracy of the timestamps
+        push_u32_le(&mut header, 0);
+        push_u32_le(&mut header, 0);
+        push_u32_le(&mut header, SNAPLEN);
+        push_u32_le(&mut header, LINKTYPE_RAW);
+        try!(out.write_all(&header));
+        Ok(PcapWriter {
+            out: Box::new(out),
+            next_port: FIRST_PORT
+        })
+    }
+
+    /// Starts a capture in a new file at `path`
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<PcapWriter> {
+        PcapWriter::new(try!(File::create(path)))
+    }
+
+    fn port(&mut self) -> u16 {
+        let port = self.next_port;
+        self.next_port = if port == u16::max_value() { FIRST_PORT } else { port + 1 };
+        port
+    }
+
+    fn segment(&mut self, from: (Ipv4Addr, u16), to: (Ipv4Addr, u16), seq: u32, ack: u32,
+               flags: u8, payload: &[u8]) -> io::Result<()> {
+        let mut tcp = Vec::with_capacity(20 + payload.len());
+        push_u16(&mut tcp, from.1);
+        push_u16(&mut tcp, to.1);
+        push_u32(&mut tcp, seq);
+        push_u32(&mut tcp, if flags & ACK != 0 { ack } else { 0 });
+        // 20 byte header, a window of 64K and no urgent data
+        tcp.extend_from_slice(&[5 << 4, flags, 0xFF, 0xFF, 0, 0, 0, 0]);
+        tcp.extend_from_slice(payload);
+        let mut pseudo = Vec::with_capacity(12);
+        pseudo.extend_from_slice(&from.0.octets());
+        pseudo.extend_from_slice(&to.0.octets());
+        pseudo.extend_from_slice(&[0, 6]);
+        push_u16(&mut pseudo, tcp.len() as u16);
+        let sum = checksum(&[&pseudo, &tcp]);
+        tcp[16] = (sum >> 8) as u8;
+        tcp[17] = sum as u8;
+
+        let mut ip = Vec::with_capacity(20);
+        ip.extend_from_slice(&[0x45, 0]);
+        push_u16(&mut ip, (20 + tcp.len()) as u16);
+        // No id, don't fragment, a TTL of 64 and TCP
+        ip.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]);
+        ip.extend_from_slice(&from.0.octets());
+        ip.extend_from_slice(&to.0.octets());
+        let sum = checksum(&[&ip]);
+        ip[10] = (sum >> 8) as u8;
+        ip[11] = sum as u8;
+
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let len = (ip.len() + tcp.len()) as u32;
+        let mut record = Vec::with_capacity(16 + len as usize);
+        push_u32_le(&mut record, time.as_secs() as u32);
+        push_u32_le(&mut record, time.subsec_micros());
+        push_u32_le(&mut record, len);
+        push_u32_le(&mut record, len);
+        record.extend_from_slice(&ip);
+        record.extend_from_slice(&tcp);
+        self.out.write_all(&record)
+    }
+}
+
+fn push_u16(buf: &mut Vec<u8>, n: u16) {
+    buf.extend_from_slice(&[(n >> 8) as u8, n as u8]);
+}
+
+fn push_u32(buf: &mut Vec<u8>, n: u32) {
+    buf.extend_from_slice(&[(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]);
+}
+
+fn push_u32_le(buf: &mut Vec<u8>, n: u32) {
+    buf.extend_from_slice(&[n as u8, (n >> 8) as u8, (n >> 16) as u8, (n >> 24) as u8]);
+}
+
+// Internet checksum of the parts laid end to end, each of even length but
+// the last
+fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    for part in parts {
+        for pair in part.chunks(2) {
+            sum += (pair[0] as u32) << 8 | pair.get(1).cloned().unwrap_or(0) as u32;
+        }
+    }
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+// One captured connection, shared by the clones of the stream
+struct Session {
+    pcap: Arc<Mutex<PcapWriter>>,
+    local: (Ipv4Addr, u16),
+    remote: (Ipv4Addr, u16),
+    // Next sequence number of each side
+    sent: u32,
+    received: u32,
+    closed: bool
+}
+
+impl Session {
+    fn record(&mut self, outgoing: bool, flags: u8, data: &[u8]) -> io::Result<()> {
+        let mut pcap = self.pcap.lock().unwrap();
+        let mut pos = 0;
+        // At least one segment, SYN and FIN come without data
+        loop {
+            let payload = &data[pos..cmp::min(pos + MAX_SEGMENT, data.len())];
+            if outgoing {
+                try!(pcap.segment(self.local, self.remote, self.sent, self.received, flags, payload));
+                self.sent = self.sent.wrapping_add(payload.len() as u32);
+            } else {
+                try!(pcap.segment(self.remote, self.local, self.received, self.sent, flags, payload));
+                self.received = self.received.wrapping_add(payload.len() as u32);
+            }
+            pos += payload.len();
+            if pos >= data.len() {
+                return Ok(());
+            }
+        }
+    }
+
+    fn handshake(&mut self) -> io::Result<()> {
+        try!(self.record(true, SYN, &[]));
+        // SYN and FIN take a sequence number
+        self.sent += 1;
+        try!(self.record(false, SYN | ACK, &[]));
+        self.received += 1;
+        self.record(true, ACK, &[])
+    }
+}
+
+/// Stream whose traffic goes into a capture as well. Failing to write the
+/// capture doesn't fail the stream.
+pub struct CaptureStream {
+    stream: Box<NetworkStream>,
+    session: Arc<Mutex<Session>>
+}
+
+impl CaptureStream {
+    /// Captures `stream` into `pcap`, starting with the handshake of a
+    /// connection to the port of its peer
+    pub fn new(stream: NetworkStream, pcap: Arc<Mutex<PcapWriter>>) -> CaptureStream {
+        let port = stream.peer_addr().map(|addr| addr.port()).unwrap_or(1883);
+        let local_port = pcap.lock().unwrap().port();
+        let mut session = Session {
+            pcap: pcap,
+            local: (Ipv4Addr::new(10, 0, 0, 1), local_port),
+            remote: (Ipv4Addr::new(10, 0, 0, 2), port),
+            sent: 1000,
+            received: 5000,
+            closed: false
+        };
+        let _ = session.handshake();
+        CaptureStream {
+            stream: Box::new(stream),
+            session: Arc::new(Mutex::new(session))
+        }
+    }
+
+    pub fn get_ref(&self) -> &NetworkStream {
+        &self.stream
+    }
+
+    pub fn try_clone(&self) -> io::Result<CaptureStream> {
+        Ok(CaptureStream {
+            stream: Box::new(try!(self.stream.try_clone())),
+            session: self.session.clone()
+        })
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    /// Shuts the stream down, closing the captured connection
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        {
+            let mut session = self.session.lock().unwrap();
+            if !session.closed {
+                session.closed = true;
+                let _ = session.record(true, FIN | ACK, &[]);
+                session.sent = session.sent.wrapping_add(1);
+            }
+        }
+        self.stream.shutdown(how)
+    }
+}
+
+impl Read for CaptureStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = try!(self.stream.read(buf));
+        if n > 0 {
+            let _ = self.session.lock().unwrap().record(false, PSH | ACK, &buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+impl Write for CaptureStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = try!(self.stream.write(buf));
+        if n > 0 {
+            let _ = self.session.lock().unwrap().record(true, PSH | ACK, &buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        let n = try!(self.stream.write_vectored(bufs));
+        let mut written = Vec::with_capacity(n);
+        for buf in bufs {
+            let take = cmp::min(n - written.len(), buf.len());
+            written.extend_from_slice(&buf[..take]);
+        }
+        if n > 0 {
+            let _ = self.session.lock().unwrap().record(true, PSH | ACK, &written);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        try!(self.stream.flush());
+        let session = self.session.lock().unwrap();
+        let mut pcap = session.pcap.lock().unwrap();
+        let _ = pcap.out.flush();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::Shutdown;
+    use std::process;
+    use std::sync::{Arc, Mutex};
+    use mock::MockStream;
+    use NetworkStream;
+    use super::{PcapWriter, CaptureStream, checksum};
+
+    // Payloads of the records, after the 16 byte record header and the
+    // IPv4 and TCP headers, with the TCP flags
+    fn segments(capture: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let mut segments = Vec::new();
+        let mut pos = 24;
+        while pos < capture.len() {
+            let len = capture[pos + 8] as usize | (capture[pos + 9] as usize) << 8;
+            let packet = &capture[pos + 16..pos + 16 + len];
+            assert_eq!(checksum(&[&packet[..20]]), 0);
+            segments.push((packet[33], packet[40..].to_vec()));
+            pos += 16 + len;
+        }
+        segments
+    }
+
+    #[test]
+    fn capture_stream_test() {
+        let path = env::temp_dir().join(format!("netopt_capture_{}.pcap", process::id()));
+        let pcap = Arc::new(Mutex::new(PcapWriter::create(&path).unwrap()));
+        let mock = MockStream::with_vec(vec![0x20, 0x02, 0x00, 0x00]);
+        let mut stream = CaptureStream::new(NetworkStream::Mock(mock.clone()), pcap);
+        stream.write_all(&[0xC0, 0x00]).unwrap();
+        let mut buf = [0; 4];
+        stream.try_clone().unwrap().read_exact(&mut buf).unwrap();
+        stream.shutdown(Shutdown::Both).unwrap();
+        stream.flush().unwrap();
+
+        let capture = fs::read(&path).unwrap();
+        assert_eq!(&capture[..4], &[0xD4, 0xC3, 0xB2, 0xA1]);
+        assert_eq!(segments(&capture), vec![
+            (0x02, vec![]),
+            (0x12, vec![]),
+            (0x10, vec![]),
+            (0x18, vec![0xC0, 0x00]),
+            (0x18, vec![0x20, 0x02, 0x00, 0x00]),
+            (0x11, vec![])
+        ]);
+        fs::remove_file(&path).unwrap();
+    }
+}
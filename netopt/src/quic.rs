@@ -0,0 +1,214 @@
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use quinn::{self, Endpoint, Connecting, Connection, SendStream, RecvStream, VarInt};
+use rustls;
+use tokio::runtime::{self, Runtime};
+use tokio::time;
+
+// ALPN of MQTT over QUIC
+const ALPN: &'static [u8] = b"mqtt";
+
+/// Opens QUIC connections to the broker. The MQTT byte stream goes over one
+/// bidirectional stream per connection.
+///
+/// Connections run on a runtime of their own, the streams block like TCP
+/// streams do. The connector keeps the TLS sessions of earlier connections,
+/// so with 0-RTT enabled a reconnect sends its first bytes without waiting
+/// for the handshake.
+pub struct QuicConnector {
+    runtime: Arc<Runtime>,
+    endpoint: Endpoint,
+    server_name: String,
+    zero_rtt: bool
+}
+
+impl QuicConnector {
+    /// `server_name` is checked against the certificate of the broker, which
+    /// has to be signed by one of `roots`
+    pub fn new(server_name: &str, roots: rustls::RootCertStore) -> io::Result<QuicConnector> {
+        let runtime = try!(runtime::Builder::new_multi_thread().worker_threads(1).enable_all().build());
+        let mut crypto = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        crypto.alpn_protocols = vec![ALPN.to_vec()];
+        crypto.enable_early_data = true;
+
+        let mut endpoint = {
+            let _guard = runtime.enter();
+            try!(Endpoint::client("[::]:0".parse().unwrap()))
+        };
+        endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(crypto)));
+        Ok(QuicConnector {
+            runtime: Arc::new(runtime),
+            endpoint: endpoint,
+            server_name: server_name.to_string(),
+            zero_rtt: false
+        })
+    }
+
+    /// Sends the first bytes of a resumed connection as 0-RTT data. If the
+    /// broker refuses them the stream fails, which the client handles like
+    /// any lost connection.
+    pub fn set_zero_rtt(&mut self, zero_rtt: bool) -> &mut QuicConnector {
+        self.zero_rtt = zero_rtt; self
+    }
+
+    pub fn connect(&self, addr: SocketAddr) -> io::Result<QuicStream> {
+        // quinn spawns the tasks of the connection on the runtime it's in
+        let _guard = self.runtime.enter();
+        let connecting = try!(self.endpoint.connect(addr, &self.server_name).map_err(|err| {
+            io::Error::new(io::ErrorKind::InvalidInput, err)
+        }));
+        let connection = if self.zero_rtt {
+            match connecting.into_0rtt() {
+                Ok((connection, _)) => connection,
+                // No session to resume yet
+                Err(connecting) => try!(self.handshake(connecting))
+            }
+        } else {
+            try!(self.handshake(connecting))
+        };
+        let (send, recv) = try!(self.runtime.block_on(connection.open_bi()).map_err(|err| {
+            io::Error::new(io::ErrorKind::ConnectionAborted, err)
+        }));
+        Ok(QuicStream {
+            runtime: self.runtime.clone(),
+            connection: connection,
+            send: Arc::new(Mutex::new(send)),
+            recv: Arc::new(Mutex::new(recv)),
+            read_timeout: Arc::new(Mutex::new(None))
+        })
+    }
+
+    fn handshake(&self, connecting: Connecting) -> io::Result<Connection> {
+        self.runtime.block_on(connecting).map_err(|err| {
+            io::Error::new(io::ErrorKind::ConnectionRefused, err)
+        })
+    }
+}
+
+/// Bidirectional QUIC stream, clones share it
+#[derive(Clone)]
+pub struct QuicStream {
+    runtime: Arc<Runtime>,
+    connection: Connection,
+    send: Arc<Mutex<SendStream>>,
+    recv: Arc<Mutex<RecvStream>>,
+    read_timeout: Arc<Mutex<Option<Duration>>>
+}
+
+impl QuicStream {
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.connection.remote_address())
+    }
+
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.connection.close(VarInt::from_u32(0), b"");
+        Ok(())
+    }
+
+    pub fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        *self.read_timeout.lock().unwrap() = dur;
+        Ok(())
+    }
+}
+
+impl Read for QuicStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = *self.read_timeout.lock().unwrap();
+        let mut recv = self.recv.lock().unwrap();
+        let result = match timeout {
+            Some(timeout) => {
+                // The timer has to be created on the runtime
+                let read = {
+                    let _guard = self.runtime.enter();
+                    time::timeout(timeout, recv.read(buf))
+                };
+                match self.runtime.block_on(read) {
+                    Ok(result) => result,
+                    Err(_) => return Err(io::Error::new(io::ErrorKind::WouldBlock, "read timed out"))
+                }
+            },
+            None => self.runtime.block_on(recv.read(buf))
+        };
+        match result {
+            Ok(Some(len)) => Ok(len),
+            // The broker finished the stream
+            Ok(None) => Ok(0),
+            Err(err) => Err(io::Error::new(io::ErrorKind::ConnectionAborted, err))
+        }
+    }
+}
+
+impl Write for QuicStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut send = self.send.lock().unwrap();
+        self.runtime.block_on(send.write(buf)).map_err(|err| {
+            io::Error::new(io::ErrorKind::ConnectionAborted, err)
+        })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::sync::Arc;
+    use std::sync::mpsc;
+    use std::thread;
+    use quinn::{Endpoint, ServerConfig};
+    use rcgen;
+    use rustls;
+    use tokio::runtime;
+    use super::QuicConnector;
+
+    #[test]
+    fn quic_loopback_test() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let der = rustls::Certificate(cert.serialize_der().unwrap());
+        let key = rustls::PrivateKey(cert.serialize_private_key_der());
+        let mut crypto = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(vec![der.clone()], key)
+            .unwrap();
+        crypto.alpn_protocols = vec![b"mqtt".to_vec()];
+
+        // echoes the first stream back
+        let (addr_tx, addr_rx) = mpsc::channel();
+        let server = thread::spawn(move || {
+            let runtime = runtime::Builder::new_multi_thread().worker_threads(1).enable_all().build().unwrap();
+            let endpoint = {
+                let _guard = runtime.enter();
+                let config = ServerConfig::with_crypto(Arc::new(crypto));
+                Endpoint::server(config, "127.0.0.1:0".parse().unwrap()).unwrap()
+            };
+            addr_tx.send(endpoint.local_addr().unwrap()).unwrap();
+            let connecting = runtime.block_on(endpoint.accept()).unwrap();
+            let connection = runtime.block_on(connecting).unwrap();
+            let (mut send, mut recv) = runtime.block_on(connection.accept_bi()).unwrap();
+            let mut buf = [0; 64];
+            while let Ok(Some(len)) = runtime.block_on(recv.read(&mut buf)) {
+                runtime.block_on(send.write_all(&buf[..len])).unwrap();
+            }
+        });
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(&der).unwrap();
+        let connector = QuicConnector::new("localhost", roots).unwrap();
+        let mut stream = connector.connect(addr_rx.recv().unwrap()).unwrap();
+        stream.write_all(&[0xC0, 0x00]).unwrap();
+        let mut pingreq = [0; 2];
+        stream.read_exact(&mut pingreq).unwrap();
+        assert_eq!(pingreq, [0xC0, 0x00]);
+
+        stream.shutdown().unwrap();
+        server.join().unwrap();
+    }
+}
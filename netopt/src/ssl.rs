@@ -1,22 +1,81 @@
-use std::net::TcpStream;
-use std::io;
+use std::net::{TcpStream, SocketAddr};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::raw::c_char;
 use std::sync::Arc;
 use std::path::Path;
+use std::time::Duration;
+use openssl::crypto::hash::{self, Type};
+use openssl::crypto::pkcs12::Pkcs12;
+use openssl::crypto::pkey::PKey;
 use openssl::ssl::{self, SslMethod, SSL_VERIFY_NONE, SSL_VERIFY_PEER, SSL_VERIFY_FAIL_IF_NO_PEER_CERT};
 use openssl::x509::X509FileType;
+pub use openssl::x509::{X509, X509StoreContext};
+use udp::{Datagram, DatagramChannel};
 
 pub type SslStream = ssl::SslStream<TcpStream>;
 pub type SslError = ssl::error::SslError;
+pub type DtlsStream = ssl::SslStream<Datagram>;
+
+/// Checks the certificates of the peer. It's called for every certificate
+/// of the chain, from the root down to the peer's own at depth 0, with
+/// whether OpenSSL accepted it. Returning `false` fails the handshake.
+pub type Verifier = Box<Fn(bool, &X509StoreContext) -> bool + Send + Sync>;
+
+// OpenSSL takes a plain function, the closure is passed as its data
+fn call_verifier(preverified: bool, ctx: &X509StoreContext, verifier: &Verifier) -> bool {
+    verifier(preverified, ctx)
+}
+
+fn client_context() -> Result<ssl::SslContext, SslError> {
+    let mut ctx = try!(ssl::SslContext::new(SslMethod::Sslv23));
+    try!(ctx.set_cipher_list("DEFAULT"));
+    Ok(ctx)
+}
+
+// Client context presenting `cert` and `key`, verifying the broker against
+// `ca` if there is one
+fn identity_context(cert: &X509, chain: &[X509], key: &PKey, ca: Option<&Path>) -> Result<ssl::SslContext, SslError> {
+    let mut ctx = try!(client_context());
+    try!(ctx.set_certificate(cert));
+    for cert in chain {
+        try!(ctx.add_extra_chain_cert(cert));
+    }
+    try!(ctx.set_private_key(key));
+    try!(ctx.check_private_key());
+    match ca {
+        Some(ca) => {
+            try!(ctx.set_CA_file(ca));
+            ctx.set_verify(SSL_VERIFY_PEER, None);
+        }
+        None => ctx.set_verify(SSL_VERIFY_NONE, None)
+    }
+    Ok(ctx)
+}
+
+fn open(path: &Path) -> Result<File, SslError> {
+    File::open(path).map_err(SslError::StreamError)
+}
+
+/// SHA-256 hash of the public key (SPKI) of `cert`, the form of the pins of
+/// `SslContext::with_pinned_keys`
+pub fn spki_sha256(cert: &X509) -> Vec<u8> {
+    hash::hash(Type::SHA256, &cert.public_key().save_pub())
+}
 
 #[derive(Debug, Clone)]
 pub struct SslContext {
-    inner: Arc<ssl::SslContext>
+    inner: Arc<ssl::SslContext>,
+    server_name: Option<String>
 }
 
+/// Doesn't verify the peer, use `with_ca` or `with_verifier` to check the
+/// broker
 impl Default for SslContext {
     fn default() -> SslContext {
         SslContext {
-            inner: Arc::new(ssl::SslContext::new(SslMethod::Tlsv1_2).unwrap())
+            inner: Arc::new(ssl::SslContext::new(SslMethod::Tlsv1_2).unwrap()),
+            server_name: None
         }
     }
 }
@@ -24,7 +83,8 @@ impl Default for SslContext {
 impl SslContext {
     pub fn new(context: ssl::SslContext) -> Self {
         SslContext {
-            inner: Arc::new(context)
+            inner: Arc::new(context),
+            server_name: None
         }
     }
 
@@ -35,7 +95,7 @@ impl SslContext {
         try!(ctx.set_certificate_file(cert.as_ref(), X509FileType::PEM));
         try!(ctx.set_private_key_file(key.as_ref(), X509FileType::PEM));
         ctx.set_verify(SSL_VERIFY_NONE, None);
-        Ok(SslContext { inner: Arc::new(ctx) })
+        Ok(SslContext::new(ctx))
     }
 
     pub fn with_cert_and_key_and_ca<C, K, A>(cert: C, key: K, ca: A) -> Result<SslContext, SslError>
@@ -46,7 +106,108 @@ impl SslContext {
         try!(ctx.set_private_key_file(key.as_ref(), X509FileType::PEM));
         try!(ctx.set_CA_file(ca.as_ref()));
         ctx.set_verify(SSL_VERIFY_PEER | SSL_VERIFY_FAIL_IF_NO_PEER_CERT, None);
-        Ok(SslContext { inner: Arc::new(ctx) })
+        Ok(SslContext::new(ctx))
+    }
+
+    /// Client context presenting the certificate and private key of PEM
+    /// files. An encrypted key is decrypted with `password`. With `ca` the
+    /// broker is verified against it.
+    pub fn with_pem_identity(cert: &Path, key: &Path, password: Option<&str>, ca: Option<&Path>) -> Result<SslContext, SslError> {
+        let cert = try!(X509::from_pem(&mut try!(open(cert))));
+        let mut key_file = try!(open(key));
+        let key = match password {
+            Some(password) => try!(PKey::private_key_from_pem_cb(&mut key_file, |buf: &mut [c_char]| {
+                let len = password.len().min(buf.len());
+                for (c, &b) in buf.iter_mut().zip(password.as_bytes()[..len].iter()) {
+                    *c = b as c_char;
+                }
+                len
+            })),
+            None => try!(PKey::private_key_from_pem(&mut key_file))
+        };
+        identity_context(&cert, &[], &key, ca).map(SslContext::new)
+    }
+
+    /// Client context presenting the certificate, its chain and the private
+    /// key of a PKCS#12 bundle (`.p12` or `.pfx`) protected by `password`.
+    /// With `ca` the broker is verified against it.
+    pub fn with_pkcs12(path: &Path, password: &str, ca: Option<&Path>) -> Result<SslContext, SslError> {
+        let mut der = Vec::new();
+        try!(try!(open(path)).read_to_end(&mut der).map_err(SslError::StreamError));
+        let identity = try!(try!(Pkcs12::from_der(&der)).parse(password));
+        identity_context(&identity.cert, &identity.chain, &identity.pkey, ca).map(SslContext::new)
+    }
+
+    /// Client context which checks the broker against the CA certificates
+    /// in `ca`
+    pub fn with_ca<A: AsRef<Path>>(ca: A) -> Result<SslContext, SslError> {
+        let mut ctx = try!(client_context());
+        try!(ctx.set_CA_file(ca.as_ref()));
+        ctx.set_verify(SSL_VERIFY_PEER, None);
+        Ok(SslContext::new(ctx))
+    }
+
+    /// Client context which leaves the check of the broker to `verify`.
+    /// With `ca` OpenSSL verifies the chain first and passes the outcome.
+    pub fn with_verifier<F>(ca: Option<&Path>, verify: F) -> Result<SslContext, SslError>
+        where F: Fn(bool, &X509StoreContext) -> bool + Send + Sync + 'static
+    {
+        let mut ctx = try!(client_context());
+        if let Some(ca) = ca {
+            try!(ctx.set_CA_file(ca));
+        }
+        let verifier: Verifier = Box::new(verify);
+        ctx.set_verify_with_data(SSL_VERIFY_PEER, call_verifier, verifier);
+        Ok(SslContext::new(ctx))
+    }
+
+    /// Client context which takes the broker only if the key of its own
+    /// certificate is pinned, see `spki_sha256`. With `ca` the chain has to
+    /// verify too, without it a self-signed certificate does.
+    pub fn with_pinned_keys(ca: Option<&Path>, pins: Vec<Vec<u8>>) -> Result<SslContext, SslError> {
+        let verify_chain = ca.is_some();
+        SslContext::with_verifier(ca, move |preverified, ctx| {
+            if verify_chain && !preverified {
+                return false;
+            }
+            if ctx.error_depth() > 0 {
+                return true;
+            }
+            match ctx.get_current_cert() {
+                Some(cert) => pins.contains(&spki_sha256(&cert)),
+                None => false
+            }
+        })
+    }
+
+    /// Client context which takes any broker, for labs with self-signed
+    /// certificates. Anyone on the path can read and change the traffic.
+    pub fn dangerous_insecure_skip_verify() -> Result<SslContext, SslError> {
+        let mut ctx = try!(client_context());
+        ctx.set_verify(SSL_VERIFY_NONE, None);
+        Ok(SslContext::new(ctx))
+    }
+
+    /// Host name sent as SNI when connecting, which brokers behind a shared
+    /// address use to pick the tenant
+    pub fn set_server_name(&mut self, name: &str) -> &mut SslContext {
+        self.server_name = Some(name.to_string()); self
+    }
+
+    /// Protocols offered with ALPN when connecting, like `x-amzn-mqtt-ca`
+    /// for AWS IoT on port 443. Has to be set before the context is cloned.
+    #[cfg(feature = "alpn")]
+    pub fn set_alpn_protocols(&mut self, protocols: &[&[u8]]) -> &mut SslContext {
+        Arc::get_mut(&mut self.inner).expect("ALPN set on a shared SslContext").set_alpn_protocols(protocols);
+        self
+    }
+
+    /// Context for `NetworkOptions::udp`, the peer isn't verified
+    pub fn dtls() -> Result<SslContext, SslError> {
+        let mut ctx = try!(ssl::SslContext::new(SslMethod::Dtlsv1));
+        try!(ctx.set_cipher_list("DEFAULT"));
+        ctx.set_verify(SSL_VERIFY_NONE, None);
+        Ok(SslContext::new(ctx))
     }
 
     pub fn accept(&self, stream: TcpStream) -> Result<SslStream, io::Error> {
@@ -57,9 +218,43 @@ impl SslContext {
     }
 
     pub fn connect(&self, stream: TcpStream) -> Result<SslStream, io::Error> {
-        match ssl::SslStream::connect(&*self.inner, stream) {
+        let ssl = try!(ssl::Ssl::new(&*self.inner).map_err(|err| {
+            io::Error::new(io::ErrorKind::Other, err)
+        }));
+        if let Some(ref name) = self.server_name {
+            try!(ssl.set_hostname(name).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err)));
+        }
+        match ssl::SslStream::connect(ssl, stream) {
             Ok(stream) => Ok(stream),
             Err(err) => Err(io::Error::new(io::ErrorKind::ConnectionAborted, err).into())
         }
     }
+
+    pub fn connect_datagram(&self, datagram: Datagram) -> Result<Box<DatagramChannel>, io::Error> {
+        match ssl::SslStream::connect(&*self.inner, datagram) {
+            Ok(stream) => Ok(Box::new(stream)),
+            Err(err) => Err(io::Error::new(io::ErrorKind::ConnectionAborted, err).into())
+        }
+    }
+}
+
+// A DTLS record is one datagram
+impl DatagramChannel for DtlsStream {
+    fn send(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.write_all(buf)
+    }
+
+    fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<Option<usize>> {
+        try!(self.get_ref().get_ref().set_read_timeout(Some(timeout)));
+        match self.read(buf) {
+            Ok(len) => Ok(Some(len)),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock ||
+                            err.kind() == io::ErrorKind::TimedOut => Ok(None),
+            Err(err) => Err(err)
+        }
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().get_ref().peer_addr()
+    }
 }
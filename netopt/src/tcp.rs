@@ -1,27 +1,60 @@
-use std::net::{TcpListener, TcpStream, SocketAddr, ToSocketAddrs, Shutdown, SocketAddrV4, Ipv4Addr};
-use std::io::{self, Read, Write, BufReader, BufWriter};
+use std::net::{TcpListener, TcpStream, SocketAddr, ToSocketAddrs, Shutdown, SocketAddrV4, IpAddr, Ipv4Addr,
+               Ipv6Addr};
+use std::io::{self, IoSlice, Read, Write, BufReader, BufWriter};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
 
+use socket2::{Domain, Protocol, Socket, Type};
 use mqtt3::{MqttRead, MqttWrite};
 use ssl::{SslContext, SslStream};
-use mock::MockStream;
+use mock::{MockStream, MockBroker, ChannelStream};
+use pcap::{PcapWriter, CaptureStream};
+use udp::{UdpStream, Datagram, DatagramChannel};
+#[cfg(feature = "quic")]
+use quic::{QuicConnector, QuicStream};
 
 use NetworkStream::{
     Tcp,
     Ssl,
-    Mock
+    Udp,
+    Mock,
+    Channel,
+    Capture
 };
+#[cfg(feature = "quic")]
+use NetworkStream::Quic;
 
 pub struct NetworkOptions {
     ssl: Option<SslContext>,
-    mock: Option<NetworkStream>
+    udp: bool,
+    #[cfg(feature = "quic")]
+    quic: Option<QuicConnector>,
+    mock: Option<NetworkStream>,
+    broker: Option<MockBroker>,
+    capture: Option<Arc<Mutex<PcapWriter>>>,
+    v6only: Option<bool>,
+    source_v4: Option<Ipv4Addr>,
+    source_v6: Option<Ipv6Addr>
 }
 
+// Backlog of the listeners, as std uses
+const LISTEN_BACKLOG: i32 = 128;
+
 impl NetworkOptions {
     pub fn new() -> NetworkOptions {
         NetworkOptions {
             ssl: None,
-            mock: None
+            udp: false,
+            #[cfg(feature = "quic")]
+            quic: None,
+            mock: None,
+            broker: None,
+            capture: None,
+            v6only: None,
+            source_v4: None,
+            source_v6: None
         }
     }
 
@@ -29,13 +62,58 @@ impl NetworkOptions {
         self.mock = Some(stream); self
     }
 
+    /// Connects to `broker` instead of the network, each connect being a
+    /// new connection to it
+    pub fn attach_broker(&mut self, broker: MockBroker) -> &mut NetworkOptions {
+        self.broker = Some(broker); self
+    }
+
+    /// Captures the traffic of the streams `connect` returns into `pcap`,
+    /// see `CaptureStream`
+    pub fn capture(&mut self, pcap: PcapWriter) -> &mut NetworkOptions {
+        self.capture = Some(Arc::new(Mutex::new(pcap))); self
+    }
+
     pub fn tls(&mut self, ssl: SslContext) -> &mut NetworkOptions {
         self.ssl = Some(ssl); self
     }
 
+    /// Connects over UDP instead of TCP, see `UdpStream`. Combined with
+    /// `tls` the datagrams are protected by DTLS, the context has to come
+    /// from `SslContext::dtls`. Only clients are supported, `bind` fails.
+    pub fn udp(&mut self) -> &mut NetworkOptions {
+        self.udp = true; self
+    }
+
+    /// Connects over QUIC instead of TCP. Reconnects of the client go
+    /// through the same connector, see `QuicConnector::set_zero_rtt`.
+    #[cfg(feature = "quic")]
+    pub fn quic(&mut self, connector: QuicConnector) -> &mut NetworkOptions {
+        self.quic = Some(connector); self
+    }
+
+    /// Whether IPv6 listeners take IPv6 connections only, or IPv4 ones too
+    /// so `[::]` serves both families. The system default otherwise.
+    pub fn set_v6only(&mut self, v6only: bool) -> &mut NetworkOptions {
+        self.v6only = Some(v6only); self
+    }
+
+    /// Binds TCP connections to addresses of the family of `addr` to it
+    /// before connecting. Set one of each family for a dual-stack name.
+    pub fn set_source_addr(&mut self, addr: IpAddr) -> &mut NetworkOptions {
+        match addr {
+            IpAddr::V4(addr) => self.source_v4 = Some(addr),
+            IpAddr::V6(addr) => self.source_v6 = Some(addr)
+        }
+        self
+    }
+
     pub fn bind<A: ToSocketAddrs>(&self, addr: A) -> io::Result<NetworkListener> {
+        if self.udp {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "UDP can't be listened on"));
+        }
         Ok(NetworkListener {
-            tcp: try!(TcpListener::bind(addr)),
+            tcp: try!(self._each_addr(addr, |addr| self._listen(addr))),
             ssl: match self.ssl {
                 Some(ref ssl) => Some(ssl.clone()),
                 None => None
@@ -44,16 +122,92 @@ impl NetworkOptions {
     }
 
     pub fn connect<A: ToSocketAddrs>(&self, addr: A) -> io::Result<NetworkStream> {
+        let stream = try!(self._connect(addr));
+        match self.capture {
+            Some(ref pcap) => Ok(Capture(CaptureStream::new(stream, pcap.clone()))),
+            None => Ok(stream)
+        }
+    }
+
+    fn _connect<A: ToSocketAddrs>(&self, addr: A) -> io::Result<NetworkStream> {
         if let Some(ref stream) = self.mock {
             return Ok(try!(stream.try_clone()));
         };
+        if let Some(ref broker) = self.broker {
+            return Ok(Channel(broker.connect()));
+        }
+
+        #[cfg(feature = "quic")]
+        {
+            if let Some(ref quic) = self.quic {
+                let addr = match try!(addr.to_socket_addrs()).next() {
+                    Some(addr) => addr,
+                    None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "no address"))
+                };
+                return Ok(Quic(try!(quic.connect(addr))));
+            }
+        }
 
-        let stream = try!(TcpStream::connect(addr));
+        if self.udp {
+            let datagram = try!(Datagram::connect(addr));
+            let channel: Box<DatagramChannel> = match self.ssl {
+                Some(ref ssl) => try!(ssl.connect_datagram(datagram)),
+                None => Box::new(datagram)
+            };
+            return Ok(Udp(UdpStream::new(channel)));
+        }
+
+        let stream = try!(self._each_addr(addr, |addr| self._connect_tcp(addr)));
         match self.ssl {
             Some(ref ssl) => Ok(NetworkStream::Ssl(try!(ssl.connect(stream)))),
             None => Ok(NetworkStream::Tcp(stream))
         }
     }
+
+    // Tries the addresses in turn like std, returning the last error
+    fn _each_addr<A, T, F>(&self, addr: A, mut f: F) -> io::Result<T>
+        where A: ToSocketAddrs, F: FnMut(SocketAddr) -> io::Result<T>
+    {
+        let mut last_err = None;
+        for addr in try!(addr.to_socket_addrs()) {
+            match f(addr) {
+                Ok(value) => return Ok(value),
+                Err(err) => last_err = Some(err)
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::AddrNotAvailable, "address resolves to nothing")
+        }))
+    }
+
+    // The socket is set up before bind, which std doesn't allow for
+    fn _listen(&self, addr: SocketAddr) -> io::Result<TcpListener> {
+        let socket = try!(Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP)));
+        if let (SocketAddr::V6(_), Some(v6only)) = (addr, self.v6only) {
+            try!(socket.set_only_v6(v6only));
+        }
+        // As std does, so a restarted server gets its port back
+        #[cfg(unix)]
+        try!(socket.set_reuse_address(true));
+        try!(socket.bind(&addr.into()));
+        try!(socket.listen(LISTEN_BACKLOG));
+        Ok(socket.into())
+    }
+
+    fn _connect_tcp(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let source = match addr {
+            SocketAddr::V4(_) => self.source_v4.map(IpAddr::V4),
+            SocketAddr::V6(_) => self.source_v6.map(IpAddr::V6)
+        };
+        let source = match source {
+            Some(source) => source,
+            None => return TcpStream::connect(addr)
+        };
+        let socket = try!(Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP)));
+        try!(socket.bind(&SocketAddr::new(source, 0).into()));
+        try!(socket.connect(&addr.into()));
+        Ok(socket.into())
+    }
 }
 
 pub struct NetworkListener {
@@ -62,6 +216,10 @@ pub struct NetworkListener {
 }
 
 impl NetworkListener {
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp.local_addr()
+    }
+
     pub fn accept(&mut self) -> io::Result<(NetworkStream, SocketAddr)> {
         let (stream, addr) = try!(self.tcp.accept());
         match self.ssl {
@@ -79,7 +237,12 @@ impl NetworkListener {
 pub enum NetworkStream {
     Tcp(TcpStream),
     Ssl(SslStream),
-    Mock(MockStream)
+    Udp(UdpStream),
+    #[cfg(feature = "quic")]
+    Quic(QuicStream),
+    Mock(MockStream),
+    Channel(ChannelStream),
+    Capture(CaptureStream)
 }
 
 impl NetworkStream {
@@ -87,7 +250,12 @@ impl NetworkStream {
         match *self {
             Tcp(ref s) => Ok(Tcp(try!(s.try_clone()))),
             Ssl(ref s) => Ok(Ssl(try!(s.try_clone()))),
-            Mock(ref s) => Ok(Mock(s.clone()))
+            Udp(ref s) => Ok(Udp(s.clone())),
+            #[cfg(feature = "quic")]
+            Quic(ref s) => Ok(Quic(s.clone())),
+            Mock(ref s) => Ok(Mock(s.clone())),
+            Channel(ref s) => Ok(Channel(s.clone())),
+            Capture(ref s) => Ok(Capture(try!(s.try_clone())))
         }
     }
 
@@ -95,7 +263,12 @@ impl NetworkStream {
         match *self {
             Tcp(ref s) => s.peer_addr(),
             Ssl(ref s) => s.get_ref().peer_addr(),
-            Mock(_) => Ok(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127,0,0,1), 80)))
+            Udp(ref s) => s.peer_addr(),
+            #[cfg(feature = "quic")]
+            Quic(ref s) => s.peer_addr(),
+            Mock(_) => Ok(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127,0,0,1), 80))),
+            Channel(_) => Ok(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127,0,0,1), 1883))),
+            Capture(ref s) => s.peer_addr()
         }
     }
 
@@ -103,7 +276,11 @@ impl NetworkStream {
         match *self {
             Tcp(ref s) => s.shutdown(how),
             Ssl(ref s) => s.get_ref().shutdown(how),
-            Mock(_) => Ok(())
+            #[cfg(feature = "quic")]
+            Quic(ref s) => s.shutdown(),
+            Channel(ref s) => s.shutdown(),
+            Capture(ref s) => s.shutdown(how),
+            Udp(_) | Mock(_) => Ok(())
         }
     }
 
@@ -111,15 +288,48 @@ impl NetworkStream {
         match *self {
             Tcp(ref s) => s.set_read_timeout(dur),
             Ssl(ref s) => s.get_ref().set_read_timeout(dur),
+            Udp(ref s) => s.set_read_timeout(dur),
+            #[cfg(feature = "quic")]
+            Quic(ref s) => s.set_read_timeout(dur),
+            Channel(ref s) => s.set_read_timeout(dur),
+            Capture(ref s) => s.get_ref().set_read_timeout(dur),
             Mock(_) => Ok(())
         }
     }
 
+    /// Socket to wait on for input, none for streams which aren't backed by
+    /// one of their own
+    #[cfg(unix)]
+    pub fn raw_fd(&self) -> Option<RawFd> {
+        match *self {
+            Tcp(ref s) => Some(s.as_raw_fd()),
+            #[cfg(feature = "ssl")]
+            Ssl(ref s) => Some(s.get_ref().as_raw_fd()),
+            Capture(ref s) => s.get_ref().raw_fd(),
+            _ => None
+        }
+    }
+
+    /// True if input was taken off the socket but not read yet, as TLS
+    /// records are
+    pub fn has_pending_input(&self) -> bool {
+        match *self {
+            #[cfg(feature = "ssl")]
+            Ssl(ref s) => s.ssl().pending() > 0,
+            Capture(ref s) => s.get_ref().has_pending_input(),
+            _ => false
+        }
+    }
+
     pub fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
         match *self {
             Tcp(ref s) => s.set_write_timeout(dur),
             Ssl(ref s) => s.get_ref().set_write_timeout(dur),
-            Mock(_) => Ok(())
+            // Writes give up after a fixed number of retransmits
+            Udp(_) | Mock(_) | Channel(_) => Ok(()),
+            Capture(ref s) => s.get_ref().set_write_timeout(dur),
+            #[cfg(feature = "quic")]
+            Quic(_) => Ok(())
         }
     }
 }
@@ -129,7 +339,12 @@ impl Read for NetworkStream {
         match *self {
             Tcp(ref mut s) => s.read(buf),
             Ssl(ref mut s) => s.read(buf),
-            Mock(ref mut s) => s.read(buf)
+            Udp(ref mut s) => s.read(buf),
+            #[cfg(feature = "quic")]
+            Quic(ref mut s) => s.read(buf),
+            Mock(ref mut s) => s.read(buf),
+            Channel(ref mut s) => s.read(buf),
+            Capture(ref mut s) => s.read(buf)
         }
     }
 }
@@ -139,7 +354,25 @@ impl Write for NetworkStream {
         match *self {
             Tcp(ref mut s) => s.write(buf),
             Ssl(ref mut s) => s.write(buf),
-            Mock(ref mut s) => s.write(buf)
+            Udp(ref mut s) => s.write(buf),
+            #[cfg(feature = "quic")]
+            Quic(ref mut s) => s.write(buf),
+            Mock(ref mut s) => s.write(buf),
+            Channel(ref mut s) => s.write(buf),
+            Capture(ref mut s) => s.write(buf)
+        }
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        match *self {
+            Tcp(ref mut s) => s.write_vectored(bufs),
+            Ssl(ref mut s) => s.write_vectored(bufs),
+            Udp(ref mut s) => s.write_vectored(bufs),
+            #[cfg(feature = "quic")]
+            Quic(ref mut s) => s.write_vectored(bufs),
+            Mock(ref mut s) => s.write_vectored(bufs),
+            Channel(ref mut s) => s.write_vectored(bufs),
+            Capture(ref mut s) => s.write_vectored(bufs)
         }
     }
 
@@ -147,7 +380,12 @@ impl Write for NetworkStream {
         match *self {
             Tcp(ref mut s) => s.flush(),
             Ssl(ref mut s) => s.flush(),
-            Mock(ref mut s) => s.flush()
+            Udp(ref mut s) => s.flush(),
+            #[cfg(feature = "quic")]
+            Quic(ref mut s) => s.flush(),
+            Mock(ref mut s) => s.flush(),
+            Channel(ref mut s) => s.flush(),
+            Capture(ref mut s) => s.flush()
         }
     }
 }
@@ -160,7 +398,8 @@ impl MqttWrite for NetworkStream {}
 
 #[cfg(test)]
 mod test {
-    use std::net::Shutdown;
+    use std::net::{IpAddr, Shutdown, UdpSocket};
+    use std::time::Duration;
     use std::io::{Read, Write};
     use std::thread;
     use super::{NetworkOptions, NetworkStream};
@@ -183,6 +422,60 @@ mod test {
         assert_eq!(req, vec![0, 1, 2, 3, 4, 5]);
     }
 
+    #[test]
+    fn tcp_v6only_test() {
+        let mut options = NetworkOptions::new();
+        options.set_v6only(false);
+        // Skipped on hosts without IPv6
+        let mut listener = match options.bind("[::]:0") {
+            Ok(listener) => listener,
+            Err(_) => return
+        };
+        let port = listener.local_addr().unwrap().port();
+        let _client = NetworkOptions::new().connect(("127.0.0.1", port)).unwrap();
+        // an IPv4 client on an IPv6 socket shows as a mapped address
+        let (_, peer) = listener.accept().unwrap();
+        assert_eq!(peer.ip(), "::ffff:127.0.0.1".parse::<IpAddr>().unwrap());
+
+        options.set_v6only(true);
+        let listener = options.bind("[::]:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert!(NetworkOptions::new().connect(("127.0.0.1", port)).is_err());
+        NetworkOptions::new().connect(("::1", port)).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn tcp_source_addr_test() {
+        let mut listener = NetworkOptions::new().bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut options = NetworkOptions::new();
+        // the whole of 127.0.0.0/8 is local on Linux
+        options.set_source_addr("127.0.0.2".parse().unwrap());
+        options.set_source_addr("::1".parse().unwrap());
+        let _client = options.connect(addr).unwrap();
+        let (_, peer) = listener.accept().unwrap();
+        assert_eq!(peer.ip(), "127.0.0.2".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn udp_connect_test() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let addr = server.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let mut client = NetworkOptions::new().udp().connect(addr).unwrap();
+            client.write(&[0, 1, 2]).unwrap();
+        });
+
+        let mut buf = [0; 16];
+        let (len, _) = server.recv_from(&mut buf).unwrap();
+        // DATA with sequence number 0
+        assert_eq!(&buf[..len], &[0, 0, 0, 0, 1, 2]);
+        assert!(NetworkOptions::new().udp().bind("127.0.0.1:0").is_err());
+    }
+
     #[test]
     fn tcp_attach_test() {
         let stream = NetworkStream::Mock(MockStream::with_vec(vec![0xFE, 0xFD]));
@@ -0,0 +1,322 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{UdpSocket, SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const DATA: u8 = 0;
+const ACK: u8 = 1;
+// Frame kind and sequence number
+const HEADER_SIZE: usize = 3;
+// Stays below the path MTU of most links
+const MAX_SEGMENT_SIZE: usize = 1200;
+const RETRANSMIT_MS: u64 = 200;
+const MAX_RETRANSMITS: u32 = 10;
+// Longest a reader or writer holds the channel while waiting for a datagram
+const POLL_SLICE_MS: u64 = 10;
+
+/// Something that sends and receives whole datagrams, a UDP socket or a DTLS
+/// session on top of one.
+pub trait DatagramChannel: Send {
+    fn send(&mut self, buf: &[u8]) -> io::Result<()>;
+
+    /// Waits up to `timeout` for a datagram, `None` if none came
+    fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<Option<usize>>;
+
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+}
+
+/// Connected UDP socket
+pub struct Datagram {
+    socket: UdpSocket
+}
+
+impl Datagram {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Datagram> {
+        let addr = match try!(addr.to_socket_addrs()).next() {
+            Some(addr) => addr,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "no address"))
+        };
+        let socket = match addr {
+            SocketAddr::V4(_) => try!(UdpSocket::bind("0.0.0.0:0")),
+            SocketAddr::V6(_) => try!(UdpSocket::bind("[::]:0"))
+        };
+        try!(socket.connect(addr));
+        Ok(Datagram::new(socket))
+    }
+
+    /// Wraps a socket which is connected already
+    pub fn new(socket: UdpSocket) -> Datagram {
+        Datagram { socket: socket }
+    }
+
+    pub fn get_ref(&self) -> &UdpSocket {
+        &self.socket
+    }
+}
+
+// Every read takes one datagram and every write sends one, as DTLS expects
+impl Read for Datagram {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.socket.recv(buf)
+    }
+}
+
+impl Write for Datagram {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.send(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl DatagramChannel for Datagram {
+    fn send(&mut self, buf: &[u8]) -> io::Result<()> {
+        try!(self.socket.send(buf));
+        Ok(())
+    }
+
+    fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<Option<usize>> {
+        try!(self.socket.set_read_timeout(Some(timeout)));
+        match self.socket.recv(buf) {
+            Ok(len) => Ok(Some(len)),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock ||
+                            err.kind() == io::ErrorKind::TimedOut => Ok(None),
+            // Nothing listens on the other side yet, resending may still succeed
+            Err(ref err) if err.kind() == io::ErrorKind::ConnectionRefused => Ok(None),
+            Err(err) => Err(err)
+        }
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.peer_addr()
+    }
+}
+
+struct State {
+    send_seq: u16,
+    acked: Option<u16>,
+    recv_seq: u16,
+    inbound: VecDeque<u8>,
+    read_timeout: Option<Duration>
+}
+
+struct Inner {
+    channel: Mutex<Box<DatagramChannel>>,
+    state: Mutex<State>,
+    writer: Mutex<()>
+}
+
+/// Ordered, reliable byte stream over datagrams, so MQTT can run on UDP.
+///
+/// Each write sends at most one numbered datagram and resends it until the
+/// other side acknowledges it; after 10 tries the write fails with
+/// `TimedOut`. Duplicates are acknowledged again and dropped. Sending one
+/// datagram at a time keeps the shim small, it suits the low rates of
+/// constrained links rather than bulk transfers.
+///
+/// Clones share the channel and the stream state.
+#[derive(Clone)]
+pub struct UdpStream {
+    inner: Arc<Inner>
+}
+
+impl UdpStream {
+    pub fn new(channel: Box<DatagramChannel>) -> UdpStream {
+        UdpStream {
+            inner: Arc::new(Inner {
+                channel: Mutex::new(channel),
+                state: Mutex::new(State {
+                    send_seq: 0,
+                    acked: None,
+                    recv_seq: 0,
+                    inbound: VecDeque::new(),
+                    read_timeout: None
+                }),
+                writer: Mutex::new(())
+            })
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.channel.lock().unwrap().peer_addr()
+    }
+
+    pub fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.inner.state.lock().unwrap().read_timeout = dur;
+        Ok(())
+    }
+
+    // Takes in at most one datagram
+    fn poll(&self, timeout: Duration) -> io::Result<()> {
+        let mut buf = [0; HEADER_SIZE + MAX_SEGMENT_SIZE];
+        let mut channel = self.inner.channel.lock().unwrap();
+        let len = match try!(channel.recv(&mut buf, timeout)) {
+            Some(len) if len >= HEADER_SIZE => len,
+            _ => return Ok(())
+        };
+        let seq = (buf[1] as u16) << 8 | buf[2] as u16;
+        let mut state = self.inner.state.lock().unwrap();
+        match buf[0] {
+            DATA => {
+                if seq == state.recv_seq {
+                    state.inbound.extend(&buf[HEADER_SIZE..len]);
+                    state.recv_seq = seq.wrapping_add(1);
+                }
+                // A resent one means the acknowledgement got lost
+                if seq == state.recv_seq.wrapping_sub(1) {
+                    try!(channel.send(&[ACK, buf[1], buf[2]]));
+                }
+            },
+            ACK => state.acked = Some(seq),
+            _ => ()
+        }
+        Ok(())
+    }
+}
+
+impl Read for UdpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = self.inner.state.lock().unwrap().read_timeout;
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let slice = Duration::from_millis(POLL_SLICE_MS);
+        loop {
+            {
+                let mut state = self.inner.state.lock().unwrap();
+                if !state.inbound.is_empty() {
+                    let len = cmp::min(buf.len(), state.inbound.len());
+                    for (i, byte) in state.inbound.drain(..len).enumerate() {
+                        buf[i] = byte;
+                    }
+                    return Ok(len);
+                }
+            }
+            let wait = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(io::Error::new(io::ErrorKind::WouldBlock, "read timed out"));
+                    }
+                    cmp::min(deadline - now, slice)
+                },
+                None => slice
+            };
+            try!(self.poll(wait));
+        }
+    }
+}
+
+impl Write for UdpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let _writer = self.inner.writer.lock().unwrap();
+        let len = cmp::min(buf.len(), MAX_SEGMENT_SIZE);
+        let seq = {
+            let mut state = self.inner.state.lock().unwrap();
+            let seq = state.send_seq;
+            state.send_seq = seq.wrapping_add(1);
+            seq
+        };
+        let mut frame = Vec::with_capacity(HEADER_SIZE + len);
+        frame.push(DATA);
+        frame.push((seq >> 8) as u8);
+        frame.push(seq as u8);
+        frame.extend_from_slice(&buf[..len]);
+
+        let retransmit = Duration::from_millis(RETRANSMIT_MS);
+        for _ in 0..MAX_RETRANSMITS {
+            try!(self.inner.channel.lock().unwrap().send(&frame));
+            let sent = Instant::now();
+            loop {
+                if self.inner.state.lock().unwrap().acked == Some(seq) {
+                    return Ok(len);
+                }
+                if sent.elapsed() >= retransmit {
+                    break;
+                }
+                try!(self.poll(Duration::from_millis(POLL_SLICE_MS)));
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::TimedOut, "datagram not acknowledged"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read, Write};
+    use std::net::UdpSocket;
+    use std::thread;
+    use std::time::Duration;
+    use super::{UdpStream, Datagram};
+
+    fn pair() -> (UdpSocket, UdpSocket) {
+        let a = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").unwrap();
+        a.connect(b.local_addr().unwrap()).unwrap();
+        b.connect(a.local_addr().unwrap()).unwrap();
+        (a, b)
+    }
+
+    #[test]
+    fn udp_stream_test() {
+        let (a, b) = pair();
+        let mut a = UdpStream::new(Box::new(Datagram::new(a)));
+        let mut b = UdpStream::new(Box::new(Datagram::new(b)));
+        let data: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+
+        let expected = data.clone();
+        let peer = thread::spawn(move || {
+            let mut received = vec![0; expected.len()];
+            b.read_exact(&mut received).unwrap();
+            assert_eq!(received, expected);
+            b.write_all(b"done").unwrap();
+        });
+        a.write_all(&data).unwrap();
+        let mut reply = [0; 4];
+        a.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"done");
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn udp_stream_retransmit_test() {
+        let (a, peer) = pair();
+        peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut stream = UdpStream::new(Box::new(Datagram::new(a)));
+
+        let mut writer = stream.clone();
+        let write = thread::spawn(move || writer.write(b"hi").unwrap());
+        let mut buf = [0; 16];
+        // the first one is "lost"
+        for _ in 0..2 {
+            let len = peer.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..len], &[0, 0, 0, b'h', b'i']);
+        }
+        peer.send(&[1, 0, 0]).unwrap();
+        assert_eq!(write.join().unwrap(), 2);
+
+        // a resent datagram is acknowledged again but read once
+        peer.send(&[0, 0, 0, b'x']).unwrap();
+        peer.send(&[0, 0, 0, b'x']).unwrap();
+        stream.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        assert_eq!(stream.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], b'x');
+        match stream.read(&mut buf) {
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => (),
+            result => panic!("unexpected {:?}", result)
+        }
+        for _ in 0..2 {
+            let len = peer.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..len], &[1, 0, 0]);
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+#[cfg(unix)]
+use std::time::Instant;
+use polling::{Event, Poller};
+use conn::Connection;
+
+/// What ended `Waiter::wait`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Wake {
+    /// The connection has input to read
+    Input,
+    /// A `Waker` was woken
+    Woken,
+    Timeout,
+    /// The stream isn't a socket that can be waited on, read it with a
+    /// timeout instead
+    Unsupported
+}
+
+/// Waits for input on a connection, another thread can cut the wait short
+/// with a `Waker`. The thread which reads the connection doesn't have to
+/// look for requests of other threads on a timer.
+pub struct Waiter {
+    poller: Arc<Poller>,
+    woken: Arc<AtomicBool>,
+    events: Vec<Event>
+}
+
+/// Wakes a `Waiter`, a wake while it isn't waiting ends its next wait
+#[derive(Clone)]
+pub struct Waker {
+    poller: Arc<Poller>,
+    woken: Arc<AtomicBool>
+}
+
+impl Waiter {
+    pub fn new() -> io::Result<Waiter> {
+        Ok(Waiter {
+            poller: Arc::new(try!(Poller::new())),
+            woken: Arc::new(AtomicBool::new(false)),
+            events: Vec::new()
+        })
+    }
+
+    pub fn waker(&self) -> Waker {
+        Waker {
+            poller: self.poller.clone(),
+            woken: self.woken.clone()
+        }
+    }
+
+    /// Waits until `conn` has input, a waker is woken or `timeout` passes
+    pub fn wait(&mut self, conn: &Connection, timeout: Option<Duration>) -> io::Result<Wake> {
+        if self.woken.swap(false, Ordering::SeqCst) {
+            return Ok(Wake::Woken);
+        }
+        if conn.has_buffered_input() {
+            return Ok(Wake::Input);
+        }
+        self._wait_stream(conn, timeout)
+    }
+
+    #[cfg(unix)]
+    fn _wait_stream(&mut self, conn: &Connection, timeout: Option<Duration>) -> io::Result<Wake> {
+        let fd = match conn.stream().raw_fd() {
+            Some(fd) => fd,
+            None => return Ok(Wake::Unsupported)
+        };
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            // Added for this wait only, the connection may be replaced before the next
+            self.events.clear();
+            try!(self.poller.add(fd, Event::readable(0)));
+            let timeout = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            let result = self.poller.wait(&mut self.events, timeout);
+            let _ = self.poller.delete(fd);
+            try!(result);
+            if !self.events.is_empty() {
+                return Ok(Wake::Input);
+            }
+            if self.woken.swap(false, Ordering::SeqCst) {
+                return Ok(Wake::Woken);
+            }
+            // Otherwise a wake taken before the wait left a notification behind
+            if deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+                return Ok(Wake::Timeout);
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn _wait_stream(&mut self, _: &Connection, _: Option<Duration>) -> io::Result<Wake> {
+        Ok(Wake::Unsupported)
+    }
+}
+
+impl Waker {
+    pub fn wake(&self) {
+        self.woken.store(true, Ordering::SeqCst);
+        // Fails only if the poller is broken, then the wait times out
+        let _ = self.poller.notify();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use conn::Connection;
+    use mock::MockStream;
+    use super::{Waiter, Wake};
+    use NetworkStream;
+
+    #[test]
+    #[cfg(unix)]
+    fn waiter_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        let conn = Connection::new(NetworkStream::Tcp(stream));
+        let mut waiter = Waiter::new().unwrap();
+
+        assert_eq!(waiter.wait(&conn, Some(Duration::from_millis(10))).unwrap(), Wake::Timeout);
+
+        let waker = waiter.waker();
+        let started = Instant::now();
+        let thread = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            waker.wake();
+        });
+        assert_eq!(waiter.wait(&conn, Some(Duration::from_secs(10))).unwrap(), Wake::Woken);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        thread.join().unwrap();
+
+        // woken before the wait
+        waiter.waker().wake();
+        assert_eq!(waiter.wait(&conn, Some(Duration::from_secs(10))).unwrap(), Wake::Woken);
+
+        peer.write_all(&[0xC0, 0x00]).unwrap();
+        assert_eq!(waiter.wait(&conn, Some(Duration::from_secs(10))).unwrap(), Wake::Input);
+
+        let mock = Connection::new(NetworkStream::Mock(MockStream::new()));
+        assert_eq!(waiter.wait(&mock, Some(Duration::from_millis(10))).unwrap(), Wake::Unsupported);
+    }
+}
@@ -3,7 +3,9 @@ use getopts::Options;
 use openssl::ssl::{SslMethod, SslContext, SslVerifyMode};
 use openssl::x509::X509FileType;
 use mqtt3::{LastWill, SubscribeTopic, QoS, Protocol};
-use super::command::{Command, SubscribeCommand, PublishCommand};
+use mqttc::Direction;
+use netopt::{join_host_port, split_host_port};
+use super::command::{Command, SubscribeCommand, PublishCommand, BridgeCommand};
 
 pub struct CLI {
     program: String,
@@ -32,6 +34,7 @@ impl CLI {
         match self.command.as_str() {
             "subscribe" | "sub" => Box::new(self.subscribe_parse()),
             "publish" | "pub" => Box::new(self.publish_parse()),
+            "bridge" => Box::new(self.bridge_parse()),
             "help" | _ => {
                 self.print_usage();
                 exit(0);
@@ -373,12 +376,95 @@ impl CLI {
         }
     }
 
+    pub fn bridge_parse(&self) -> BridgeCommand {
+        let default = BridgeCommand::default();
+
+        let mut opts = Options::new();
+        opts.optopt("a", "", "Address of the local server. Defaults to localhost", "address");
+        opts.optopt("p", "", "Port of the local server. Defaults to 1883", "port");
+        opts.optopt("", "remote", "Address and port of the remote server", "address:port");
+        opts.optmulti("", "in", "Topic filter to forward from the remote server. Can be repeated.", "filter");
+        opts.optmulti("", "out", "Topic filter to forward to the remote server. Can be repeated.", "filter");
+        opts.optmulti("", "both", "Topic filter to forward both ways. Can be repeated.", "filter");
+        opts.optopt("q", "", "Maximum quality of service level. Defaults to 1", "qos");
+        opts.optopt("", "local-prefix", "Prefix of the topics on the local server", "prefix");
+        opts.optopt("", "remote-prefix", "Prefix of the topics on the remote server", "prefix");
+        opts.optopt("k", "", "Keep alive the link with the servers then try to send ping request. Defaults to 30", "seconds");
+        opts.optopt("i", "", "Specifies a client id. Defaults to bridge", "client_id");
+        opts.optflag("d", "", "Show debug messages");
+
+        opts.optflag("h", "help", "Display this message");
+
+        let matches = match opts.parse(&self.arguments[..]) {
+            Ok(m) => { m }
+            Err(f) => {
+                self.cli_error(f.to_string());
+            }
+        };
+
+        if matches.opt_present("h") {
+            self.bridge_print_usage(opts);
+            exit(0);
+        };
+
+        let remote = matches.opt_str("remote").unwrap_or_else(|| self.cli_error("Please set the remote server"));
+        // Brackets an IPv6 literal, and adds the port if it's left out
+        let remote = match split_host_port(&remote, default.port) {
+            Ok((host, port)) => join_host_port(&host, port),
+            Err(err) => self.cli_error(format!("remote address: {}", err))
+        };
+        let mut topics = Vec::new();
+        for (name, direction) in vec![("in", Direction::In), ("out", Direction::Out), ("both", Direction::Both)] {
+            for filter in matches.opt_strs(name) {
+                topics.push((filter, direction));
+            }
+        }
+        if topics.is_empty() {
+            self.cli_error("Please set a topic to forward");
+        }
+
+        let port = if matches.opt_present("p") {
+            match matches.opt_str("p").unwrap().parse::<u16>() {
+                Ok(v) => v,
+                Err(_) => {
+                    self.cli_error("port format error");
+                }
+            }
+        } else {
+            default.port
+        };
+        let keep_alive = if matches.opt_present("k") {
+            match matches.opt_str("k").unwrap().parse::<u16>() {
+                Ok(v) => v,
+                Err(_) => {
+                    self.cli_error("keep alive format error");
+                }
+            }
+        } else {
+            default.keep_alive
+        };
+
+        BridgeCommand {
+            topics: topics,
+            qos: matches.opt_str("q").map_or(default.qos, |s| self.parse_qos(s)),
+            local_prefix: matches.opt_str("local-prefix").unwrap_or(default.local_prefix),
+            remote_prefix: matches.opt_str("remote-prefix").unwrap_or(default.remote_prefix),
+            address: matches.opt_str("a").unwrap_or(default.address),
+            port: port,
+            remote: remote,
+            keep_alive: keep_alive,
+            debug: matches.opt_present("d"),
+            client_id: matches.opt_str("i").unwrap_or(default.client_id)
+        }
+    }
+
     fn print_usage(&self) {
         let mut brief = "mqttc is a simple MQTT client that provides to publish message or subscribe to topics.\n\n".to_string();
         brief = brief + format!("Usage:\n    {} command\n    {} --help\n\n", self.program, self.program).as_str();
         brief = brief +         "Commands:\n";
         brief = brief +         "    publish/pub \tPublish message to a topic\n";
-        brief = brief +         "    subscribe/sub \tSubscribe to topics\n\n";
+        brief = brief +         "    subscribe/sub \tSubscribe to topics\n";
+        brief = brief +         "    bridge \t\tForward topics between two servers\n\n";
         print!("{}", brief);
     }
 
@@ -392,6 +478,11 @@ impl CLI {
         print!("{}", opts.usage(&brief));
     }
 
+    pub fn bridge_print_usage(&self, opts: Options) {
+        let brief = format!("Usage: {} bridge --remote ADDRESS [OPTIONS]", self.program);
+        print!("{}", opts.usage(&brief));
+    }
+
     fn parse_qos(&self, s: String) -> QoS {
         match s.parse::<u8>() {
             Ok(v) => {
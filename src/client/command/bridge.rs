@@ -0,0 +1,82 @@
+use std::time::Duration;
+use std::process::exit;
+use mqtt3::QoS;
+use netopt::{NetworkOptions, join_host_port};
+use mqttc::{ClientOptions, ReconnectMethod, Bridge, BridgeTopic, Direction};
+use super::{Command, print_error};
+use client::logger::set_stdout_logger;
+
+#[derive(Debug, Clone)]
+pub struct BridgeCommand {
+    // Topics
+    pub topics: Vec<(String, Direction)>,
+    pub qos: QoS,
+    pub local_prefix: String,
+    pub remote_prefix: String,
+
+    // Connection
+    pub address: String,
+    pub port: u16,
+    pub remote: String,
+    pub keep_alive: u16,
+
+    // Preferences
+    pub debug: bool,
+
+    // Authorization
+    pub client_id: String
+}
+
+impl Default for BridgeCommand {
+    fn default() -> BridgeCommand {
+        BridgeCommand {
+            topics: Vec::new(),
+            qos: QoS::AtLeastOnce,
+            local_prefix: String::new(),
+            remote_prefix: String::new(),
+            address: "localhost".to_string(),
+            port: 1883,
+            remote: String::new(),
+            keep_alive: 30,
+            debug: false,
+            client_id: "bridge".to_string()
+        }
+    }
+}
+
+impl BridgeCommand {
+    fn client_options(&self) -> ClientOptions {
+        let mut opts = ClientOptions::new();
+        opts.set_keep_alive(self.keep_alive);
+        opts.set_client_id(self.client_id.clone());
+        opts.set_reconnect(ReconnectMethod::ReconnectAfter(Duration::from_secs(1)));
+        opts
+    }
+}
+
+impl Command for BridgeCommand {
+    fn run(&self) -> ! {
+        if self.debug {
+            set_stdout_logger().unwrap();
+        }
+
+        debug!("{:?}", self);
+        let address = join_host_port(&self.address, self.port);
+        let local = self.client_options().connect(address.as_str(), NetworkOptions::new())
+                                          .expect("Can't connect to local server");
+        let remote = self.client_options().connect(self.remote.as_str(), NetworkOptions::new())
+                                           .expect("Can't connect to remote server");
+
+        let mut bridge = Bridge::new(local, remote);
+        for &(ref filter, direction) in &self.topics {
+            let mut topic = BridgeTopic::new(filter, direction, self.qos);
+            topic.set_local_prefix(&self.local_prefix).set_remote_prefix(&self.remote_prefix);
+            bridge.add_topic(topic).expect("Can't subscribe");
+        }
+
+        if let Err(err) = bridge.run() {
+            print_error(format!("bridge stopped: {}", err));
+        }
+        exit(64);
+    }
+}
@@ -1,10 +1,14 @@
 pub mod publish;
 pub mod subscribe;
+pub mod bridge;
 
 pub use client::command::publish::PublishCommand;
 pub use client::command::subscribe::SubscribeCommand;
+pub use client::command::bridge::BridgeCommand;
 
+use std::io::prelude::*;
 use std::collections::BTreeMap;
+use term;
 use mqtt3::{PacketIdentifier, Message};
 use mqttc::store;
 
@@ -20,9 +24,10 @@ impl LocalStorage {
     }
 }
 
-impl store::Store for LocalStorage {
+impl store::MessageStore for LocalStorage {
     fn put(&mut self, message: Box<Message>) -> store::Result<()> {
-        self.0.insert(message.pid.unwrap(), message);
+        let pid = try!(message.pid.ok_or(store::Error::MissingPid));
+        self.0.insert(pid, message);
         Ok(())
     }
 
@@ -38,3 +43,16 @@ impl store::Store for LocalStorage {
         Ok(())
     }
 }
+
+pub fn print_message<T: AsRef<str>, M: AsRef<str>>(title: T, message: M, color: u16) {
+    let mut t = term::stdout().unwrap();
+    t.fg(color).unwrap();
+    write!(t, "{:>14} ", title.as_ref()).unwrap();
+    t.reset().unwrap();
+    writeln!(t, "{}", message.as_ref()).unwrap();
+    t.reset().unwrap();
+}
+
+pub fn print_error<M: AsRef<str>>(message: M) {
+    print_message("Error", message, term::color::BRIGHT_RED);
+}
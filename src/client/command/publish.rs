@@ -5,7 +5,7 @@ use std::process::exit;
 
 use openssl::ssl;
 use mqtt3::{QoS, Protocol};
-use netopt::{NetworkOptions, SslContext};
+use netopt::{NetworkOptions, SslContext, join_host_port};
 use mqttc::{PubSub, ClientOptions, PubOpt};
 use super::{Command, LocalStorage};
 use client::logger::set_stdout_logger;
@@ -94,7 +94,7 @@ impl Command for PublishCommand {
             opts.set_client_id(client_id.clone());
         };
 
-        let address = format!("{}:{}", self.address, self.port);
+        let address = join_host_port(&self.address, self.port);
         let mut client = opts.connect(address.as_str(), netopt).expect("Can't connect to server");
 
         if let Some(ref message) = self.message {
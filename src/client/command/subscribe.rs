@@ -4,10 +4,10 @@ use openssl::ssl;
 use std::time::Duration;
 use std::process::exit;
 use mqtt3::{self, LastWill, SubscribeTopic, QoS, Protocol};
-use netopt::{NetworkOptions, SslContext};
+use netopt::{NetworkOptions, SslContext, join_host_port};
 use mqttc::store;
 use mqttc::{PubSub, ClientOptions, ReconnectMethod, Error};
-use super::{Command, LocalStorage};
+use super::{Command, LocalStorage, print_message, print_error};
 use client::logger::set_stdout_logger;
 
 #[derive(Debug, Clone)]
@@ -110,7 +110,7 @@ impl Command for SubscribeCommand {
             print_legend();
         };
 
-        let address = format!("{}:{}", self.address, self.port);
+        let address = join_host_port(&self.address, self.port);
 
         if !self.debug && !self.silence {
             print_message("Connecting to", address.as_str(), term::color::BRIGHT_GREEN);
@@ -177,6 +177,11 @@ impl Command for SubscribeCommand {
                             },
                             store::Error::Unavailable(_) => {
                                 // do nothing, just wait next pubrel
+                            },
+                            _ => {
+                                print_error(format!("{}", err));
+                                client.terminate();
+                                exit(64);
                             }
                         },
                         Error::Disconnected | Error::ConnectionAbort => {
@@ -225,16 +230,3 @@ fn print_topics(topics: &[SubscribeTopic]) {
     t.reset().unwrap();
     writeln!(t, "").unwrap();
 }
-
-fn print_message<T: AsRef<str>, M: AsRef<str>>(title: T, message: M, color: u16) {
-    let mut t = term::stdout().unwrap();
-    t.fg(color).unwrap();
-    write!(t, "{:>14} ", title.as_ref()).unwrap();
-    t.reset().unwrap();
-    writeln!(t, "{}", message.as_ref()).unwrap();
-    t.reset().unwrap();
-}
-
-fn print_error<M: AsRef<str>>(message: M) {
-    print_message("Error", message, term::color::BRIGHT_RED);
-}